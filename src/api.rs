@@ -0,0 +1,223 @@
+//! JSON REST API for integrating with a running mount.
+//!
+//! * `GET /versions` lists the versions, oldest first
+//! * `GET /versions/{n}/content` returns the content of version `n`
+//! * `POST /snapshot[?name={name}]` cuts a new version from the head,
+//!   optionally naming it
+//! * `POST /name?version={n}&name={name}` names version `n`, which shows it
+//!   in the mount as `.snapshots/{name}/`
+//! * `POST /revert?version={n}` cuts a new version holding the content of `n`
+//! * `POST /revert?name={name}` does the same for the version named `name`
+//! * `POST /revert?version={n}&range={start}..{end}` puts back only the bytes
+//!   in `start..end` as they were in `n`, the rest staying as in the head
+//! * `POST /undo` reverts to the version before the one the head holds, and
+//!   `POST /redo` takes that back
+//!
+//! Requests are refused with 403 unless made for the address the API listens
+//! on, and those that change anything also unless, from a browser, made by a
+//! page served there.
+
+use std::io;
+use std::net::TcpListener;
+
+use log::info;
+
+use crate::http::{self, Request, Response};
+use crate::store::{Revert, SharedStore};
+
+pub fn serve(listener: TcpListener, store: SharedStore) -> io::Result<()> {
+    http::serve(listener, move |request| route(&store, request))
+}
+
+fn route(store: &SharedStore, request: &Request) -> Response {
+    let (path, query) = request.path.split_once('?').unwrap_or((&request.path, ""));
+    let segments: Vec<&str> = path.trim_start_matches('/').split('/').collect();
+    match (request.method.as_str(), segments.as_slice()) {
+        _ if !request.for_this_host() => error(403, "unknown host"),
+        ("POST", _) if !request.same_origin() => error(403, "cross-origin request refused"),
+        ("GET", ["versions"]) => versions(store),
+        ("GET", ["versions", version, "content"]) => content(store, version),
+        ("POST", ["snapshot"]) => snapshot(store, query),
+        ("POST", ["name"]) => name(store, query),
+        ("POST", ["revert"]) => revert(store, query),
+        ("POST", ["undo"]) => undo(store, false),
+        ("POST", ["redo"]) => undo(store, true),
+        (_, ["versions", ..] | ["snapshot"] | ["name"] | ["revert"] | ["undo"] | ["redo"]) => {
+            error(405, "method not allowed")
+        },
+        _ => error(404, "not found"),
+    }
+}
+
+/// The value of `key` in `query`, if given.
+fn param<'a>(query: &'a str, key: &str) -> Option<&'a str> {
+    query.split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(k, _)| *k == key)
+        .map(|(_, value)| value)
+}
+
+fn json(status: u16, body: String) -> Response {
+    Response::new(status, "application/json", body + "\n")
+}
+
+fn error(status: u16, message: &str) -> Response {
+    json(status, format!("{{\"error\":\"{message}\"}}"))
+}
+
+fn versions(store: &SharedStore) -> Response {
+    let store = store.lock().unwrap();
+    let mut entries = Vec::new();
+    for version in store.versions() {
+        if let Ok(m) = store.version_meta(version) {
+            let names: Vec<String> = store.names.iter()
+                .filter(|&(_, &v)| v == version)
+                .map(|(name, _)| format!("\"{name}\""))
+                .collect();
+            let reverted = match store.reverts.get(&version) {
+                Some(Revert { from, range: Some(range) }) => {
+                    format!(",\"reverted_from\":{from},\"range\":[{},{}]", range.start, range.end)
+                },
+                Some(Revert { from, range: None }) => format!(",\"reverted_from\":{from}"),
+                None => String::new(),
+            };
+            entries.push(format!(
+                "{{\"version\":{version},\"size\":{},\"mtime\":\"{}\",\"created\":\"{}\",\"head\":{},\"names\":[{}]{reverted}}}",
+                m.len,
+                humantime::format_rfc3339_seconds(m.modified),
+                humantime::format_rfc3339_seconds(m.created),
+                version == store.version,
+                names.join(","),
+            ));
+        }
+    }
+    json(200, format!("[{}]", entries.join(",")))
+}
+
+fn content(store: &SharedStore, version: &str) -> Response {
+    let store = store.lock().unwrap();
+    let data = match version.parse::<usize>() {
+        Ok(version) if store.contains(version) => store.read_version(version).ok(),
+        _ => None,
+    };
+    match data {
+        Some(data) => Response::new(200, "application/octet-stream", data),
+        None => error(404, "no such version"),
+    }
+}
+
+fn snapshot(store: &SharedStore, query: &str) -> Response {
+    let mut store = store.lock().unwrap();
+    if store.version == 0 {
+        return error(404, "no such version");
+    }
+    let name = param(query, "name");
+    match name.map(|name| store.check_name(name)) {
+        Some(Err(e)) if e.kind() == io::ErrorKind::AlreadyExists => return error(409, &e.to_string()),
+        Some(Err(e)) => return error(400, &e.to_string()),
+        _ => {},
+    }
+    let head = match store.snapshot() {
+        Ok(head) => head,
+        Err(e) => return error(500, &e.kind().to_string()),
+    };
+    match name {
+        // the frozen copy, not the head that carries on
+        Some(name) => match store.name(name, head - 1) {
+            Ok(()) => {
+                info!("snapshot taken as version {} named {name:?}", head - 1);
+                json(201, format!("{{\"version\":{head},\"name\":\"{name}\"}}"))
+            },
+            Err(e) => error(500, &e.kind().to_string()),
+        },
+        None => {
+            info!("snapshot taken as version {}", head - 1);
+            json(201, format!("{{\"version\":{head}}}"))
+        },
+    }
+}
+
+fn name(store: &SharedStore, query: &str) -> Response {
+    let mut store = store.lock().unwrap();
+    let (version, name) = match (param(query, "version").and_then(|v| v.parse::<usize>().ok()), param(query, "name")) {
+        (Some(version), Some(name)) => (version, name),
+        _ => return error(400, "expected ?version=N&name=NAME"),
+    };
+    if !store.contains(version) {
+        return error(404, "no such version");
+    }
+    match store.name(name, version) {
+        Ok(()) => {
+            info!("named version {version} {name:?}");
+            json(201, format!("{{\"version\":{version},\"name\":\"{name}\"}}"))
+        },
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => error(409, &e.to_string()),
+        Err(e) if e.kind() == io::ErrorKind::InvalidInput => error(400, &e.to_string()),
+        Err(e) => error(500, &e.kind().to_string()),
+    }
+}
+
+fn undo(store: &SharedStore, redo: bool) -> Response {
+    let mut store = store.lock().unwrap();
+    let result = match redo {
+        true => store.redo(),
+        false => store.undo(),
+    };
+    match result {
+        Ok(head) => {
+            let from = store.reverts[&head].from;
+            info!("{} to version {from} as {head}", if redo { "redid" } else { "undid" });
+            json(201, format!("{{\"version\":{head},\"reverted_from\":{from}}}"))
+        },
+        Err(e) if e.kind() == io::ErrorKind::NotFound => error(409, &e.to_string()),
+        Err(e) => error(500, &e.kind().to_string()),
+    }
+}
+
+fn revert(shared: &SharedStore, query: &str) -> Response {
+    let store = shared.lock().unwrap();
+    let version = match (param(query, "version"), param(query, "name")) {
+        (Some(version), None) => version.parse::<usize>().ok(),
+        (None, Some(name)) => match store.names.get(name) {
+            Some(&version) => Some(version),
+            None => return error(404, "no such name"),
+        },
+        _ => None,
+    };
+    let version = match version {
+        Some(version) => version,
+        None => return error(400, "expected ?version=N or ?name=NAME"),
+    };
+    let range = match param(query, "range").map(|range| range.split_once("..")) {
+        Some(Some((start, end))) => match (start.parse::<u64>(), end.parse::<u64>()) {
+            (Ok(start), Ok(end)) => Some(start..end),
+            _ => return error(400, "expected &range=START..END"),
+        },
+        Some(None) => return error(400, "expected &range=START..END"),
+        None => None,
+    };
+    if !store.contains(version) {
+        return error(404, "no such version");
+    }
+    // read unlocked, as reading a large version would hold up the mount
+    let reader = store.reader();
+    drop(store);
+    let result = match &range {
+        Some(range) => reader
+            .read_range(version, range.clone())
+            .and_then(|data| shared.lock().unwrap().restore_range_from(version, range.clone(), data)),
+        None => reader
+            .read_version(version)
+            .and_then(|data| shared.lock().unwrap().restore_from(version, data)),
+    };
+    match result {
+        Ok(head) => {
+            info!("reverted to version {version} as {head}");
+            let range = range.map_or(String::new(), |r| format!(",\"range\":[{},{}]", r.start, r.end));
+            json(201, format!("{{\"version\":{head},\"reverted_from\":{version}{range}}}"))
+        },
+        Err(e) if e.kind() == io::ErrorKind::NotFound => error(404, "no such version"),
+        Err(e) if e.kind() == io::ErrorKind::InvalidInput => error(400, &e.to_string()),
+        Err(e) => error(500, &e.kind().to_string()),
+    }
+}
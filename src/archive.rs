@@ -0,0 +1,369 @@
+//! A read-only view of a store that isn't served, such as one copied from
+//! elsewhere, for browsing its history. Every version, the head included,
+//! is a file of its own, listed by number under `by-version`, by the day it
+//! was modified under `by-date/YYYY-MM-DD`, and by name under `named`.
+//!
+//! Nothing is written to the store: changes a crash interrupted are not
+//! settled, so versions read as they were left. Nor is it locked, so it may
+//! be served by another versionfs meanwhile. The view then follows the
+//! versions that one cuts, compacts and prunes, looking the store over
+//! again at most once a second; as versions are only ever published whole
+//! and a compacted form before the file it replaces is removed, each
+//! version can be read at any time but for the head, which is read rather
+//! than mapped as it may shrink under the reader.
+
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::OsStr;
+use std::fs;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+use fuser::{
+    Filesystem, Request, ReplyAttr, ReplyData, ReplyDirectory, ReplyEmpty, ReplyEntry, ReplyOpen, ReplyXattr,
+    FileAttr, FileType,
+};
+use libc::{EBADF, EINVAL, EIO, ENOENT, EROFS, O_ACCMODE, O_RDONLY, O_TRUNC};
+use log::{info, warn};
+
+use crate::store::{Content, Store, VersionMeta};
+use crate::{reply_xattr, xattr, ENOATTR, PARENT_ATTR, TTL};
+
+const ROOT_INO: u64 = 1;
+const VERSIONS_DIR_INO: u64 = 2;
+const DAYS_DIR_INO: u64 = 3;
+const NAMED_DIR_INO: u64 = 4;
+/// Directories of `by-date` are numbered from here, by day since the epoch
+const DAY_INO_BASE: u64 = 1 << 32;
+/// Versions are numbered from here, the same file under each of its names
+const VERSION_INO_BASE: u64 = 2 << 32;
+
+const VERSIONS_DIR: &str = "by-version";
+const DAYS_DIR: &str = "by-date";
+const NAMED_DIR: &str = "named";
+
+type DirEntry = (u64, FileType, String);
+
+pub struct Archive {
+    store: Store,
+    /// Size and times of each version, as they were when mounted
+    meta: BTreeMap<usize, VersionMeta>,
+    /// Versions by the day since the epoch they were modified, in UTC
+    days: BTreeMap<u64, Vec<usize>>,
+    /// When the store was last looked over
+    refreshed: Instant,
+    /// Content of the open versions, by file handle
+    open: HashMap<u64, Content>,
+    next_fh: u64,
+}
+
+impl Archive {
+    /// View of the versions of `store`, which must have been resumed.
+    /// Versions whose files can't be read are left out.
+    pub fn new(store: Store) -> Self {
+        let mut archive = Archive {
+            store,
+            meta: BTreeMap::new(),
+            days: BTreeMap::new(),
+            refreshed: Instant::now(),
+            open: HashMap::new(),
+            next_fh: 0,
+        };
+        archive.list_versions();
+        archive
+    }
+
+    /// Pick up the versions another versionfs serving the store cut or
+    /// pruned since the store was last looked over, a second ago or more.
+    fn refresh(&mut self) {
+        if self.refreshed.elapsed() < Duration::from_secs(1) {
+            return;
+        }
+        self.refreshed = Instant::now();
+        let head = self.store.version;
+        match self.store.resume() {
+            Ok(version) if version != head => info!("the store moved on to version {version}"),
+            Ok(_) => {},
+            Err(e) => warn!("cannot look the store over again: {e}"),
+        }
+        self.list_versions();
+    }
+
+    /// Take the size and times of the versions in the store. Those below the
+    /// head don't change once cut, so only the versions from the head last
+    /// seen on are looked at.
+    fn list_versions(&mut self) {
+        let mut known = std::mem::take(&mut self.meta);
+        let head = known.keys().next_back().copied().unwrap_or(0);
+        for version in self.store.versions() {
+            let meta = match known.remove(&version) {
+                Some(meta) if version < head => Some(meta),
+                _ => self.store.version_meta(version).ok(),
+            };
+            if let Some(meta) = meta {
+                self.meta.insert(version, meta);
+            }
+        }
+        self.days.clear();
+        for (&version, m) in &self.meta {
+            let day = m.modified.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs() / 86400);
+            self.days.entry(day).or_default().push(version);
+        }
+    }
+
+    fn version_of(&self, ino: u64) -> Option<usize> {
+        let version = ino.checked_sub(VERSION_INO_BASE)? as usize;
+        self.meta.contains_key(&version).then_some(version)
+    }
+
+    fn dir_attr(&self, ino: u64, subdirs: usize) -> FileAttr {
+        let attr = FileAttr { ino, nlink: 2 + subdirs as u32, perm: 0o555, ..PARENT_ATTR };
+        match fs::metadata(&self.store.target_dir) {
+            Ok(m) => {
+                FileAttr { uid: m.uid(), gid: m.gid(), mtime: m.modified().unwrap_or(UNIX_EPOCH), ..attr }
+            },
+            Err(_) => attr,
+        }
+    }
+
+    fn version_attr(&self, version: usize) -> FileAttr {
+        let m = &self.meta[&version];
+        let named = self.store.names.values().filter(|&&named| named == version).count();
+        let attr = FileAttr {
+            ino: VERSION_INO_BASE + version as u64,
+            size: m.len,
+            blocks: m.len.div_ceil(512),
+            mtime: m.modified,
+            crtime: m.created,
+            kind: FileType::RegularFile,
+            perm: 0o444,
+            nlink: 2 + named as u32,
+            ..PARENT_ATTR
+        };
+        let attr = match fs::metadata(self.store.xattr_path(version)) {
+            // the owner and permissions it had, less the right to write
+            Ok(m) => FileAttr { perm: (m.mode() & 0o7555) as u16, uid: m.uid(), gid: m.gid(), ..attr },
+            Err(_) => attr,
+        };
+        match self.store.is_symlink(version) {
+            // the permissions of symbolic links are never checked
+            true => FileAttr { kind: FileType::Symlink, perm: 0o777, ..attr },
+            false => attr,
+        }
+    }
+
+    fn attr(&self, ino: u64) -> Option<FileAttr> {
+        match ino {
+            ROOT_INO => Some(self.dir_attr(ino, 3)),
+            VERSIONS_DIR_INO | NAMED_DIR_INO => Some(self.dir_attr(ino, 0)),
+            DAYS_DIR_INO => Some(self.dir_attr(ino, self.days.len())),
+            ino if ino >= VERSION_INO_BASE => self.version_of(ino).map(|version| self.version_attr(version)),
+            ino if ino >= DAY_INO_BASE && self.days.contains_key(&(ino - DAY_INO_BASE)) => {
+                Some(self.dir_attr(ino, 0))
+            },
+            _ => None,
+        }
+    }
+
+    fn version_entry(&self, version: usize, name: String) -> DirEntry {
+        let kind = match self.store.is_symlink(version) {
+            true => FileType::Symlink,
+            false => FileType::RegularFile,
+        };
+        (VERSION_INO_BASE + version as u64, kind, name)
+    }
+
+    /// Entries of directory `ino`, with its `.` and `..`.
+    fn entries(&self, ino: u64) -> Option<Vec<DirEntry>> {
+        let parent = match ino {
+            ROOT_INO | VERSIONS_DIR_INO | DAYS_DIR_INO | NAMED_DIR_INO => ROOT_INO,
+            _ => DAYS_DIR_INO,
+        };
+        let mut entries = vec![
+            (ino, FileType::Directory, ".".to_string()),
+            (parent, FileType::Directory, "..".to_string()),
+        ];
+        match ino {
+            ROOT_INO => entries.extend([
+                (VERSIONS_DIR_INO, FileType::Directory, VERSIONS_DIR.to_string()),
+                (DAYS_DIR_INO, FileType::Directory, DAYS_DIR.to_string()),
+                (NAMED_DIR_INO, FileType::Directory, NAMED_DIR.to_string()),
+            ]),
+            VERSIONS_DIR_INO => entries.extend(
+                self.meta.keys().map(|&v| self.version_entry(v, self.store.name_for_version(v))),
+            ),
+            DAYS_DIR_INO => entries.extend(
+                self.days.keys().map(|&day| (DAY_INO_BASE + day, FileType::Directory, day_name(day))),
+            ),
+            NAMED_DIR_INO => entries.extend(
+                self.store
+                    .names
+                    .iter()
+                    .filter(|(_, version)| self.meta.contains_key(version))
+                    .map(|(name, &version)| self.version_entry(version, name.clone())),
+            ),
+            ino if (DAY_INO_BASE..VERSION_INO_BASE).contains(&ino) => {
+                let versions = self.days.get(&(ino - DAY_INO_BASE))?;
+                // HH.MM.SS-N.target, in the order they were modified
+                entries.extend(versions.iter().map(|&version| {
+                    let time = timestamp(&self.meta[&version])[11..19].replace(':', ".");
+                    self.version_entry(version, format!("{time}-{}", self.store.name_for_version(version)))
+                }));
+            },
+            _ => return None,
+        }
+        Some(entries)
+    }
+}
+
+/// When a version was modified, as `YYYY-MM-DDTHH:MM:SSZ`.
+fn timestamp(meta: &VersionMeta) -> String { humantime::format_rfc3339_seconds(meta.modified).to_string() }
+
+/// `YYYY-MM-DD` of `day` since the epoch.
+fn day_name(day: u64) -> String {
+    let time = UNIX_EPOCH + Duration::from_secs(day * 86400);
+    humantime::format_rfc3339_seconds(time).to_string()[..10].to_string()
+}
+
+impl Filesystem for Archive {
+    fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        info!("lookup {parent} {name:?}");
+        self.refresh();
+        let entries = match self.entries(parent) {
+            Some(entries) => entries,
+            None => return reply.error(ENOENT),
+        };
+        match entries.iter().find(|(_, _, entry)| entry.as_bytes() == name.as_bytes()) {
+            Some(&(ino, _, _)) => match self.attr(ino) {
+                Some(attr) => reply.entry(&TTL, &attr, 0),
+                None => reply.error(ENOENT),
+            },
+            None => reply.error(ENOENT),
+        }
+    }
+
+    fn getattr(&mut self, _req: &Request, ino: u64, reply: ReplyAttr) {
+        info!("getattr {ino}");
+        self.refresh();
+        match self.attr(ino) {
+            Some(attr) => reply.attr(&TTL, &attr),
+            None => reply.error(ENOENT),
+        }
+    }
+
+    fn readlink(&mut self, _req: &Request, ino: u64, reply: ReplyData) {
+        info!("readlink {ino}");
+        let version = match self.version_of(ino) {
+            Some(version) if self.store.is_symlink(version) => version,
+            Some(_) => return reply.error(EINVAL),
+            None => return reply.error(ENOENT),
+        };
+        match self.store.read_version(version) {
+            Ok(link) => reply.data(&link),
+            Err(e) => reply.error(e.raw_os_error().unwrap_or(EIO)),
+        }
+    }
+
+    fn open(&mut self, _req: &Request, ino: u64, flags: i32, reply: ReplyOpen) {
+        info!("open {ino} {flags:b}");
+        if flags & O_ACCMODE != O_RDONLY || flags & O_TRUNC != 0 {
+            return reply.error(EROFS);
+        }
+        let version = match self.version_of(ino) {
+            Some(version) => version,
+            None => return reply.error(ENOENT),
+        };
+        // compacted versions are rebuilt once, when opened
+        let content = match version == self.store.version {
+            true => self.store.read_version(version).map(Content::Rebuilt),
+            false => self.store.map_version(version),
+        };
+        match content {
+            Ok(content) => {
+                self.next_fh += 1;
+                self.open.insert(self.next_fh, content);
+                reply.opened(self.next_fh, 0);
+            },
+            Err(e) => reply.error(e.raw_os_error().unwrap_or(EIO)),
+        }
+    }
+
+    fn read(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        size: u32,
+        _flags: i32,
+        _lock: Option<u64>,
+        reply: ReplyData,
+    ) {
+        info!("read {ino} {fh}");
+        match self.open.get(&fh) {
+            Some(data) => {
+                let start = data.len().min(offset as usize);
+                let end = data.len().min(start + size as usize);
+                reply.data(&data[start..end]);
+            },
+            None => reply.error(EBADF),
+        }
+    }
+
+    fn release(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        fh: u64,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        _flush: bool,
+        reply: ReplyEmpty,
+    ) {
+        info!("release {ino} {fh}");
+        self.open.remove(&fh);
+        reply.ok();
+    }
+
+    fn readdir(&mut self, _req: &Request, ino: u64, _fh: u64, offset: i64, mut reply: ReplyDirectory) {
+        info!("readdir {ino}");
+        self.refresh();
+        let entries = match self.entries(ino) {
+            Some(entries) => entries,
+            None => return reply.error(ENOENT),
+        };
+        for (i, (ino, kind, name)) in entries.into_iter().enumerate().skip(offset as usize) {
+            if reply.add(ino, (i + 1) as i64, kind, &name) {
+                break;
+            }
+        }
+        reply.ok();
+    }
+
+    fn getxattr(&mut self, _req: &Request, ino: u64, name: &OsStr, size: u32, reply: ReplyXattr) {
+        info!("getxattr {ino} {name:?}");
+        let path = match self.version_of(ino) {
+            Some(version) if xattr::forwarded(name) => self.store.xattr_path(version),
+            _ => return reply.error(ENOATTR),
+        };
+        match xattr::get(&path, name) {
+            Ok(value) => reply_xattr(reply, size, &value),
+            Err(e) => reply.error(e.raw_os_error().unwrap_or(EIO)),
+        }
+    }
+
+    fn listxattr(&mut self, _req: &Request, ino: u64, size: u32, reply: ReplyXattr) {
+        info!("listxattr {ino}");
+        let names = match self.version_of(ino).map(|version| xattr::list(&self.store.xattr_path(version))) {
+            Some(Ok(names)) => names,
+            Some(Err(e)) => return reply.error(e.raw_os_error().unwrap_or(EIO)),
+            None => Vec::new(),
+        };
+        let mut list = Vec::new();
+        for name in names {
+            list.extend_from_slice(name.as_bytes());
+            list.push(0);
+        }
+        reply_xattr(reply, size, &list);
+    }
+}
@@ -0,0 +1,114 @@
+//! Measuring how fast a mount reads and writes its target, and what cutting
+//! versions costs, so that options can be tuned on the disks at hand.
+//!
+//! Reads go through the kernel's page cache as any program's do, so they
+//! tell what a program sees rather than what the disk does.
+
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+/// Bytes written or read at a time sequentially
+const BLOCK: usize = 1 << 20;
+/// Bytes written or read at a time at random offsets
+const PAGE: usize = 4096;
+/// Writes and reads at random offsets per measurement
+const RANDOM_OPS: usize = 256;
+
+/// What one size of target measured
+pub struct Measured {
+    pub size: u64,
+    /// Bytes per second writing the target from scratch, closing included
+    pub seq_write: f64,
+    /// Bytes per second reading it through
+    pub seq_read: f64,
+    /// Writes per second of a page at random offsets, cutting a version aside
+    pub rand_write: f64,
+    /// Reads per second of a page at random offsets
+    pub rand_read: f64,
+    /// Opening the target for writing, which copies it into a new version
+    pub open_write: Duration,
+    /// Freezing the head as a version of its own
+    pub snapshot: Duration,
+}
+
+/// xorshift64, enough to spread offsets and keep data from compressing
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn fill(&mut self, buf: &mut [u8]) {
+        for chunk in buf.chunks_mut(8) {
+            let bytes = self.next().to_le_bytes();
+            chunk.copy_from_slice(&bytes[..chunk.len()]);
+        }
+    }
+
+    /// A page-aligned offset within `size` bytes.
+    fn offset(&mut self, size: u64) -> u64 {
+        let pages = (size / PAGE as u64).max(1);
+        self.next() % pages * PAGE as u64
+    }
+}
+
+fn per_second(amount: f64, elapsed: Duration) -> f64 { amount / elapsed.as_secs_f64().max(1e-9) }
+
+/// Measure the target of a mount at `target` at `size` bytes, cutting
+/// versions through `snapshot`.
+pub fn measure(target: &Path, size: u64, snapshot: impl Fn() -> io::Result<()>) -> io::Result<Measured> {
+    let mut rng = Rng(0x9e37_79b9_7f4a_7c15 ^ size);
+    let mut block = vec![0u8; BLOCK];
+
+    let start = Instant::now();
+    let mut file = File::options().write(true).create(true).truncate(true).open(target)?;
+    let mut left = size;
+    while left > 0 {
+        let len = left.min(BLOCK as u64) as usize;
+        rng.fill(&mut block[..len]);
+        file.write_all(&block[..len])?;
+        left -= len as u64;
+    }
+    drop(file);
+    let seq_write = per_second(size as f64, start.elapsed());
+
+    let start = Instant::now();
+    let file = File::options().write(true).open(target)?;
+    let open_write = start.elapsed();
+    drop(file);
+
+    let file = File::options().write(true).open(target)?;
+    let start = Instant::now();
+    let mut page = vec![0u8; PAGE];
+    for _ in 0..RANDOM_OPS {
+        rng.fill(&mut page);
+        file.write_all_at(&page, rng.offset(size))?;
+    }
+    drop(file);
+    let rand_write = per_second(RANDOM_OPS as f64, start.elapsed());
+
+    let start = Instant::now();
+    let mut file = File::open(target)?;
+    while file.read(&mut block)? > 0 {}
+    let seq_read = per_second(size as f64, start.elapsed());
+
+    let start = Instant::now();
+    for _ in 0..RANDOM_OPS {
+        file.read_at(&mut page, rng.offset(size))?;
+    }
+    let rand_read = per_second(RANDOM_OPS as f64, start.elapsed());
+    drop(file);
+
+    let start = Instant::now();
+    snapshot()?;
+    let snapshot = start.elapsed();
+
+    Ok(Measured { size, seq_write, seq_read, rand_write, rand_read, open_write, snapshot })
+}
@@ -0,0 +1,63 @@
+//! Versions rebuilt from deltas or chunks, kept so that reading the same old
+//! version again doesn't rebuild it again, and likewise rendered diffs.
+
+use std::collections::VecDeque;
+
+/// Least recently used versions, up to `limit` bytes of content
+pub struct VersionCache {
+    limit: usize,
+    used: usize,
+    /// Most recently used last
+    entries: VecDeque<(usize, Vec<u8>)>,
+}
+
+impl VersionCache {
+    pub fn new(limit: usize) -> Self {
+        VersionCache { limit, used: 0, entries: VecDeque::new() }
+    }
+
+    pub fn get(&mut self, version: usize) -> Option<Vec<u8>> {
+        let i = self.entries.iter().position(|&(v, _)| v == version)?;
+        let entry = self.entries.remove(i)?;
+        self.entries.push_back(entry);
+        self.entries.back().map(|(_, data)| data.clone())
+    }
+
+    pub fn insert(&mut self, version: usize, data: Vec<u8>) {
+        if data.len() > self.limit {
+            return;
+        }
+        self.forget(|v| v == version);
+        self.used += data.len();
+        self.entries.push_back((version, data));
+        while self.used > self.limit {
+            let (_, data) = self.entries.pop_front().unwrap();
+            self.used -= data.len();
+        }
+    }
+
+    /// Drop the versions rebuilt from the content of `version`: the version
+    /// itself and those before it.
+    pub fn forget_through(&mut self, version: usize) {
+        self.forget(|v| v <= version);
+    }
+
+    pub fn remove(&mut self, version: usize) {
+        self.forget(|v| v == version);
+    }
+
+    pub fn clear(&mut self) {
+        self.forget(|_| true);
+    }
+
+    fn forget(&mut self, f: impl Fn(usize) -> bool) {
+        let used = &mut self.used;
+        self.entries.retain(|(v, data)| {
+            let keep = !f(*v);
+            if !keep {
+                *used -= data.len();
+            }
+            keep
+        });
+    }
+}
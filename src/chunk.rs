@@ -0,0 +1,168 @@
+//! Content-defined chunking, storing old versions as lists of chunks shared
+//! between versions.
+//!
+//! Versions are split with FastCDC: a gear hash rolls over the content and
+//! cuts a chunk where its top bits are zero, so the cut points depend on the
+//! content around them rather than on offsets. An edit in the middle of a
+//! file only changes the chunks it touches. Chunks are kept once, named by
+//! their hash, and a version becomes a manifest listing its chunks. The
+//! manifest records the hash it was written with, and chunks are checked
+//! against their names as they are read.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::hash::{self, Algorithm};
+use crate::interrupt;
+use crate::store::publish;
+
+const MAGIC: &str = "VFSCHUNKS1";
+
+const MIN_SIZE: usize = 16 << 10;
+const AVG_SIZE: usize = 64 << 10;
+const MAX_SIZE: usize = 256 << 10;
+/// Stricter than the average size below it and looser above, which
+/// normalizes chunk sizes around the average
+const MASK_SMALL: u64 = ((1 << 18) - 1) << (64 - 18);
+const MASK_LARGE: u64 = ((1 << 14) - 1) << (64 - 14);
+
+/// Random values for each byte, from splitmix64 so that they never change
+const GEAR: [u64; 256] = {
+    let mut gear = [0; 256];
+    let mut seed: u64 = 0;
+    let mut i = 0;
+    while i < 256 {
+        seed = seed.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = seed;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        gear[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    gear
+};
+
+/// Length of the first chunk of `data`.
+fn cut(data: &[u8]) -> usize {
+    if data.len() <= MIN_SIZE {
+        return data.len();
+    }
+    let end = data.len().min(MAX_SIZE);
+    let normal = end.min(AVG_SIZE);
+    let mut h = 0u64;
+    for (i, &b) in data.iter().enumerate().take(end).skip(MIN_SIZE) {
+        h = (h << 1).wrapping_add(GEAR[b as usize]);
+        let mask = if i < normal { MASK_SMALL } else { MASK_LARGE };
+        if h & mask == 0 {
+            return i + 1;
+        }
+    }
+    end
+}
+
+/// Split `data` into chunks.
+pub fn split(mut data: &[u8]) -> Vec<&[u8]> {
+    let mut chunks = Vec::new();
+    while !data.is_empty() {
+        let (chunk, rest) = data.split_at(cut(data));
+        chunks.push(chunk);
+        data = rest;
+    }
+    chunks
+}
+
+/// Where the chunk named `name` is kept in `dir`, spread over subdirectories
+/// by the first byte of its hash.
+fn chunk_path(dir: &Path, name: &str) -> PathBuf {
+    dir.join(&name[..2]).join(name)
+}
+
+/// The manifest of `data` with chunks named by their `algorithm` hash, and
+/// the chunks it lists as (name, content).
+pub fn manifest(data: &[u8], algorithm: Algorithm) -> (Vec<u8>, Vec<(String, &[u8])>) {
+    let mut manifest = format!("{MAGIC} {}\n", algorithm.name()).into_bytes();
+    let chunks: Vec<_> = split(data).into_iter().map(|chunk| (hash::hex(&algorithm.digest(chunk)), chunk)).collect();
+    for (name, chunk) in &chunks {
+        manifest.extend_from_slice(format!("{name} {}\n", chunk.len()).as_bytes());
+    }
+    (manifest, chunks)
+}
+
+/// Write the `chunks` that `dir` doesn't hold yet.
+pub fn write(dir: &Path, chunks: &[(String, &[u8])]) -> io::Result<()> {
+    for (name, chunk) in chunks {
+        let path = chunk_path(dir, name);
+        if !path.exists() {
+            fs::create_dir_all(path.parent().unwrap())?;
+            let tmp_path = path.with_extension("tmp");
+            fs::write(&tmp_path, chunk)?;
+            publish(&tmp_path, &path)?;
+        }
+    }
+    Ok(())
+}
+
+/// Store the chunks of `data` that `dir` doesn't hold yet, named by their
+/// `algorithm` hash, returning the manifest to rebuild it from.
+pub fn store(dir: &Path, data: &[u8], algorithm: Algorithm) -> io::Result<Vec<u8>> {
+    let (manifest, chunks) = manifest(data, algorithm);
+    write(dir, &chunks)?;
+    Ok(manifest)
+}
+
+/// The hash of the chunks listed in `manifest`, and the chunks as (name, length).
+fn entries(manifest: &[u8]) -> io::Result<(Algorithm, Vec<(&str, u64)>)> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "not a chunk manifest");
+    let manifest = std::str::from_utf8(manifest).map_err(|_| invalid())?;
+    let mut lines = manifest.lines();
+    let algorithm = match lines.next().and_then(|line| line.strip_prefix(MAGIC)) {
+        // the first manifests didn't name their hash
+        Some("") => Algorithm::Sha256,
+        Some(name) => Algorithm::parse(name.trim_start()).map_err(|_| invalid())?,
+        None => return Err(invalid()),
+    };
+    let entries = lines
+        .map(|line| {
+            let (name, len) = line.split_once(' ').ok_or_else(invalid)?;
+            if name.len() != 64 || !name.bytes().all(|b| b.is_ascii_hexdigit()) {
+                return Err(invalid());
+            }
+            Ok((name, len.parse().map_err(|_| invalid())?))
+        })
+        .collect::<io::Result<_>>()?;
+    Ok((algorithm, entries))
+}
+
+/// `manifest` naming its hash, if it is one of the first that didn't.
+pub fn name_hash(manifest: &[u8]) -> Option<Vec<u8>> {
+    let rest = manifest.strip_prefix(MAGIC.as_bytes())?.strip_prefix(b"\n")?;
+    let mut named = format!("{MAGIC} {}\n", Algorithm::Sha256.name()).into_bytes();
+    named.extend_from_slice(rest);
+    Some(named)
+}
+
+/// Rebuild the content listed in `manifest` from the chunks in `dir`.
+pub fn read(dir: &Path, manifest: &[u8]) -> io::Result<Vec<u8>> {
+    let (algorithm, entries) = entries(manifest)?;
+    let mut data = Vec::with_capacity(entries.iter().map(|&(_, len)| len as usize).sum());
+    for (name, len) in entries {
+        interrupt::check()?;
+        let chunk = fs::read(chunk_path(dir, name))?;
+        if chunk.len() as u64 != len || hash::hex(&algorithm.digest(&chunk)) != name {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("chunk {name} is corrupt")));
+        }
+        data.extend_from_slice(&chunk);
+    }
+    Ok(data)
+}
+
+/// The chunks listed in `manifest`, as (name, length).
+pub fn list(manifest: &[u8]) -> io::Result<Vec<(String, u64)>> {
+    Ok(entries(manifest)?.1.into_iter().map(|(name, len)| (name.to_string(), len)).collect())
+}
+
+/// Length of the content listed in `manifest`.
+pub fn target_len(manifest: &[u8]) -> io::Result<u64> {
+    Ok(entries(manifest)?.1.iter().map(|&(_, len)| len).sum())
+}
@@ -0,0 +1,195 @@
+//! Background compaction of old versions into deltas.
+//!
+//! Versions older than a configured age are replaced by a delta against the
+//! next version. Only versions whose successor is itself no longer the head
+//! are compacted, so every delta is taken against content that won't change.
+//!
+//! Every `keyframe`-th version is kept whole, so rebuilding any version takes
+//! at most `keyframe - 1` deltas.
+//!
+//! With `chunked`, versions are replaced by a manifest of content-defined
+//! chunks instead, shared with every other version holding the same chunks.
+//! Those don't depend on other versions, so no keyframes are kept.
+//!
+//! With `compress` rules, versions of the types they give a level for are
+//! replaced by zstd frames instead, which don't depend on other versions
+//! either. The others are compacted as without rules.
+
+use std::fs::{self, File};
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use log::{info, warn};
+
+use crate::{chunk, delta, xattr, zstd};
+use crate::compress::Rules;
+use crate::hash::Algorithm;
+use crate::store::{discard, keep_ownership, publish, Change, SharedStore};
+
+/// Bounds on how long to wait between compaction passes
+const MIN_INTERVAL: Duration = Duration::from_secs(1);
+const MAX_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Settings of the compactor, which may be changed while it runs.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Policy {
+    /// Minimum age of a version before it is compacted
+    pub after: Duration,
+    /// Bytes per second of IO to stay under, unlimited if `None`
+    pub rate: Option<u64>,
+    /// Versions that are multiples of this are never compacted
+    pub keyframe: usize,
+    /// Compact into chunks rather than deltas
+    pub chunked: bool,
+    /// Hash naming the chunks
+    pub hash: Algorithm,
+    /// Levels to compress versions at by file type
+    pub compress: Option<Rules>,
+}
+
+/// A policy shared with the compactor; compaction pauses while it is `None`.
+pub type SharedPolicy = Arc<Mutex<Option<Policy>>>;
+
+pub struct Compactor {
+    store: SharedStore,
+    policy: SharedPolicy,
+    /// Versions below this one have already been looked at
+    next: usize,
+}
+
+impl Compactor {
+    pub fn new(store: SharedStore, policy: SharedPolicy) -> Self {
+        Compactor { store, policy, next: 1 }
+    }
+
+    pub fn spawn(mut self) {
+        thread::spawn(move || loop {
+            let interval = match self.policy() {
+                Some(policy) => policy.after.clamp(MIN_INTERVAL, MAX_INTERVAL),
+                None => MAX_INTERVAL,
+            };
+            thread::sleep(interval);
+            if let Some(policy) = self.policy() {
+                self.run(&policy);
+            }
+        });
+    }
+
+    fn policy(&self) -> Option<Policy> {
+        self.policy.lock().unwrap().clone()
+    }
+
+    fn run(&mut self, policy: &Policy) {
+        loop {
+            let version = self.next;
+            if version + 1 >= self.store.lock().unwrap().version {
+                return;
+            }
+            match self.compact(version, policy) {
+                Ok(Some(io_bytes)) => throttle(policy.rate, io_bytes),
+                Ok(None) => return,
+                // corrupt, or pruned meanwhile: trying again won't help
+                Err(e) if matches!(e.kind(), io::ErrorKind::InvalidData | io::ErrorKind::NotFound) => {
+                    warn!("compacting version {version} failed, skipping it: {e}");
+                },
+                // left to the next pass, as running out of space and the like pass
+                Err(e) => {
+                    warn!("compacting version {version} failed: {e}");
+                    return;
+                },
+            }
+            self.next += 1;
+        }
+    }
+
+    /// Compact `version` if it is older than `policy.after`, returning the
+    /// bytes of IO spent, or `None` if it is not old enough yet.
+    fn compact(&self, version: usize, policy: &Policy) -> io::Result<Option<u64>> {
+        let (target_name, path, base_path, delta_path, chunks_path, compressed_path, chunks_dir) = {
+            let store = self.store.lock().unwrap();
+            (
+                store.target.clone(),
+                store.path_for_version(version),
+                store.path_for_version(version + 1),
+                store.path_for_delta(version),
+                store.path_for_chunks(version),
+                store.path_for_compressed(version),
+                store.chunks_dir(),
+            )
+        };
+        let modified = match fs::metadata(&path) {
+            Ok(m) => m.modified()?,
+            // already compacted
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Some(0)),
+            Err(e) => return Err(e),
+        };
+        let age = SystemTime::now().duration_since(modified).unwrap_or_default();
+        if age < policy.after {
+            return Ok(None);
+        }
+
+        let target = fs::read(&path)?;
+        let level = policy.compress.as_ref().and_then(|rules| rules.level(&target_name, &target));
+        let (compacted, compacted_path, io_bytes) = if let Some(level) = level {
+            let frame = zstd::encode(&target, level);
+            if frame.len() >= target.len() {
+                return Ok(Some(target.len() as u64));
+            }
+            (frame, compressed_path, target.len() as u64)
+        } else if policy.chunked {
+            let manifest = chunk::store(&chunks_dir, &target, policy.hash)?;
+            (manifest, chunks_path, target.len() as u64 * 2)
+        } else if version.is_multiple_of(policy.keyframe.max(1)) {
+            return Ok(Some(target.len() as u64));
+        } else if !self.store.lock().unwrap().contains(version + 1) {
+            // the version after it was pruned
+            return Ok(Some(target.len() as u64));
+        } else {
+            let base = match fs::read(&base_path) {
+                Ok(base) => base,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                    self.store.lock().unwrap().read_version(version + 1)?
+                },
+                Err(e) => return Err(e),
+            };
+            let delta = delta::encode(&base, &target);
+            let io_bytes = (target.len() + base.len()) as u64;
+            if delta.len() >= target.len() {
+                return Ok(Some(io_bytes));
+            }
+            (delta, delta_path.clone(), io_bytes)
+        };
+
+        let mut tmp_path = compacted_path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        self.store.lock().unwrap().begin(Change::Compact(version))?;
+        let replace = || {
+            fs::write(&tmp_path, &compacted)?;
+            File::options().write(true).open(&tmp_path)?.set_modified(modified)?;
+            xattr::keep(&path, tmp_path.as_ref())?;
+            keep_ownership(&path, tmp_path.as_ref())?;
+            let store = self.store.lock().unwrap();
+            // pruned meanwhile, or left with nothing to apply the delta to
+            if !store.contains(version) || compacted_path == delta_path && !store.contains(version + 1) {
+                fs::remove_file(&tmp_path)?;
+                return Err(io::Error::new(io::ErrorKind::NotFound, "pruned while compacting"));
+            }
+            publish(tmp_path.as_ref(), &compacted_path)?;
+            store.seal(&compacted_path);
+            discard(&path)
+        };
+        let replaced = replace();
+        self.store.lock().unwrap().end(Change::Compact(version))?;
+        replaced?;
+        info!("compacted version {version}: {} -> {} bytes", target.len(), compacted.len());
+        Ok(Some(io_bytes + compacted.len() as u64))
+    }
+}
+
+fn throttle(rate: Option<u64>, io_bytes: u64) {
+    if let Some(rate) = rate {
+        thread::sleep(Duration::from_secs_f64(io_bytes as f64 / rate.max(1) as f64));
+    }
+}
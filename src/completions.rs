@@ -0,0 +1,223 @@
+//! Shell completions, generated from the definitions of the command line so
+//! they never fall behind it.
+//!
+//! Options are completed for the subcommand given, or for the mount when
+//! there is none; their values from the choices they accept, or else as
+//! file names, as are positional arguments.
+
+use std::ffi::OsString;
+use std::path::PathBuf;
+
+use clap::{value_parser, Arg, Command};
+
+/// Shells completions can be generated for
+pub const SHELLS: [&str; 3] = ["bash", "zsh", "fish"];
+
+/// Completions of `cmd` for `shell`, one of `SHELLS`.
+pub fn generate(cmd: &mut Command, shell: &str) -> String {
+    cmd.build();
+    match shell {
+        "bash" => bash(cmd),
+        "zsh" => zsh(cmd),
+        "fish" => fish(cmd),
+        _ => unreachable!("no completions for {shell}"),
+    }
+}
+
+/// Options of `cmd` worth offering, in the order they were defined
+fn options<'a, 'help>(cmd: &'a Command<'help>) -> impl Iterator<Item = &'a Arg<'help>> {
+    cmd.get_arguments().filter(|arg| !arg.is_positional() && !arg.is_hide_set())
+}
+
+/// Subcommands of `cmd` worth offering
+fn subcommands<'a, 'help>(cmd: &'a Command<'help>) -> impl Iterator<Item = &'a Command<'help>> {
+    cmd.get_subcommands().filter(|sub| sub.get_name() != "help")
+}
+
+/// The spellings of option `arg`, short first
+fn flags(arg: &Arg) -> Vec<String> {
+    let short = arg.get_short().map(|short| format!("-{short}"));
+    let long = arg.get_long().map(|long| format!("--{long}"));
+    short.into_iter().chain(long).collect()
+}
+
+/// The values option `arg` accepts, if it accepts only some
+fn choices(arg: &Arg) -> Vec<String> {
+    if !arg.is_takes_value_set() {
+        return Vec::new();
+    }
+    let values = arg.get_value_parser().possible_values().into_iter().flatten();
+    values.filter(|value| !value.is_hide_set()).map(|value| value.get_name().to_string()).collect()
+}
+
+/// Whether `arg` takes a path, completed as file names
+fn takes_path(arg: &Arg) -> bool {
+    let id = arg.get_value_parser().type_id();
+    id == value_parser!(PathBuf).type_id() || id == value_parser!(OsString).type_id()
+}
+
+/// First line of the help of `arg`
+fn help<'a>(arg: &Arg<'a>) -> &'a str {
+    arg.get_help().unwrap_or_default().lines().next().unwrap_or_default()
+}
+
+fn bash(cmd: &Command) -> String {
+    let name = cmd.get_name();
+    let mut out = format!("_{name}() {{\n");
+    out += "    local cur prev sub opts i\n";
+    out += "    cur=\"${COMP_WORDS[COMP_CWORD]}\"\n";
+    out += "    prev=\"${COMP_WORDS[COMP_CWORD-1]}\"\n";
+    out += "    sub=\"\"\n";
+    out += "    for ((i = 1; i < COMP_CWORD; i++)); do\n";
+    out += "        case \"${COMP_WORDS[i]}\" in\n";
+    let names: Vec<&str> = subcommands(cmd).map(|sub| sub.get_name()).collect();
+    out += &format!("            {}) sub=\"${{COMP_WORDS[i]}}\"; break ;;\n", names.join("|"));
+    out += "        esac\n";
+    out += "    done\n";
+    // options taking values, by subcommand
+    out += "    case \"$sub:$prev\" in\n";
+    let within = std::iter::once(("", cmd)).chain(subcommands(cmd).map(|sub| (sub.get_name(), sub)));
+    for (sub, command) in within {
+        for arg in options(command).filter(|arg| arg.is_takes_value_set()) {
+            let cases: Vec<String> = flags(arg).iter().map(|flag| format!("{sub}:{flag}")).collect();
+            out += &format!("        {})\n", cases.join("|"));
+            let words = match choices(arg) {
+                _ if takes_path(arg) => "-f".to_string(),
+                choices => format!("-W \"{}\"", choices.join(" ")),
+            };
+            out += &format!("            COMPREPLY=($(compgen {words} -- \"$cur\"))\n");
+            out += "            return ;;\n";
+        }
+    }
+    out += "    esac\n";
+    out += "    case \"$sub\" in\n";
+    let mut top: Vec<String> = options(cmd).flat_map(flags).collect();
+    top.extend(names.iter().map(|&name| name.to_string()));
+    out += &format!("        \"\") opts=\"{}\" ;;\n", top.join(" "));
+    for sub in subcommands(cmd) {
+        let mut opts: Vec<String> = options(sub).flat_map(flags).collect();
+        opts.extend(sub.get_positionals().flat_map(choices));
+        out += &format!("        {}) opts=\"{}\" ;;\n", sub.get_name(), opts.join(" "));
+    }
+    out += "    esac\n";
+    out += "    COMPREPLY=($(compgen -W \"$opts\" -- \"$cur\"))\n";
+    out += "    if [[ $cur != -* ]]; then\n";
+    out += "        COMPREPLY+=($(compgen -f -- \"$cur\"))\n";
+    out += "    fi\n";
+    out += "}\n";
+    out += &format!("complete -o filenames -F _{name} {name}\n");
+    out
+}
+
+/// `s` safe inside single quotes and the brackets of a `_arguments` spec
+fn escape(s: &str) -> String {
+    s.replace('\'', "'\\''").replace('[', "\\[").replace(']', "\\]").replace(':', "\\:")
+}
+
+/// How `_arguments` completes the values of `arg`
+fn zsh_action(arg: &Arg) -> String {
+    match choices(arg) {
+        _ if takes_path(arg) => "_files".to_string(),
+        choices if choices.is_empty() => " ".to_string(),
+        choices => format!("({})", choices.join(" ")),
+    }
+}
+
+/// `_arguments` specs of the options and positional arguments of `cmd`
+fn zsh_specs(cmd: &Command) -> Vec<String> {
+    let mut specs = Vec::new();
+    for arg in options(cmd) {
+        let value = match arg.is_takes_value_set() {
+            true => format!(":{}:{}", arg.get_id().to_lowercase(), zsh_action(arg)),
+            false => String::new(),
+        };
+        let repeat = match arg.is_multiple_occurrences_set() {
+            true => "*",
+            false => "",
+        };
+        for flag in flags(arg) {
+            specs.push(format!("'{repeat}{flag}[{}]{value}'", escape(help(arg))));
+        }
+    }
+    for arg in cmd.get_positionals().filter(|arg| !arg.is_hide_set()) {
+        let repeat = match arg.is_multiple_values_set() {
+            true => "*",
+            false => "",
+        };
+        specs.push(format!("'{repeat}:{}:{}'", escape(help(arg)), zsh_action(arg)));
+    }
+    specs
+}
+
+fn zsh(cmd: &Command) -> String {
+    let name = cmd.get_name();
+    let mut out = format!("#compdef {name}\n\n_{name}() {{\n");
+    out += "    local i sub\n";
+    out += "    for ((i = 2; i < CURRENT; i++)); do\n";
+    out += "        case $words[i] in\n";
+    let names: Vec<&str> = subcommands(cmd).map(|sub| sub.get_name()).collect();
+    out += &format!("            ({}) sub=$words[i]; break ;;\n", names.join("|"));
+    out += "        esac\n";
+    out += "    done\n";
+    out += "    if [[ -n $sub ]]; then\n";
+    out += "        words=(${words[i,-1]})\n";
+    out += "        (( CURRENT -= i - 1 ))\n";
+    out += "    fi\n";
+    out += "    case $sub in\n";
+    for sub in subcommands(cmd) {
+        out += &format!("        ({})\n", sub.get_name());
+        out += &format!("            _arguments -s {}\n", zsh_specs(sub).join(" \\\n                "));
+        out += "            ;;\n";
+    }
+    out += "        (*)\n";
+    out += "            local -a subcommands\n";
+    out += "            subcommands=(\n";
+    for sub in subcommands(cmd) {
+        let about = escape(sub.get_about().unwrap_or_default());
+        out += &format!("                '{}:{about}'\n", sub.get_name());
+    }
+    out += "            )\n";
+    out += &format!("            _arguments -s {}\n", zsh_specs(cmd).join(" \\\n                "));
+    out += "            _describe -t commands subcommand subcommands\n";
+    out += "            ;;\n";
+    out += "    esac\n";
+    out += "}\n\n";
+    out += &format!("_{name} \"$@\"\n");
+    out
+}
+
+/// `s` safe inside single quotes for fish
+fn fish_escape(s: &str) -> String { s.replace('\\', "\\\\").replace('\'', "\\'") }
+
+fn fish(cmd: &Command) -> String {
+    let name = cmd.get_name();
+    let mut out = String::new();
+    for sub in subcommands(cmd) {
+        let about = fish_escape(sub.get_about().unwrap_or_default());
+        let sub = sub.get_name();
+        out += &format!("complete -c {name} -n __fish_use_subcommand -f -a {sub} -d '{about}'\n");
+    }
+    let seen = |sub: &Command| format!("'__fish_seen_subcommand_from {}'", sub.get_name());
+    let within = std::iter::once(("__fish_use_subcommand".to_string(), cmd))
+        .chain(subcommands(cmd).map(|sub| (seen(sub), sub)));
+    for (condition, command) in within {
+        for arg in options(command) {
+            let mut line = format!("complete -c {name} -n {condition}");
+            if let Some(short) = arg.get_short() {
+                line += &format!(" -s {short}");
+            }
+            if let Some(long) = arg.get_long() {
+                line += &format!(" -l {long}");
+            }
+            match choices(arg) {
+                _ if !arg.is_takes_value_set() => {},
+                _ if takes_path(arg) => line += " -r -F",
+                choices if choices.is_empty() => line += " -r -f",
+                choices => line += &format!(" -r -f -a '{}'", choices.join(" ")),
+            }
+            line += &format!(" -d '{}'\n", fish_escape(help(arg)));
+            out += &line;
+        }
+    }
+    out
+}
@@ -0,0 +1,145 @@
+//! Choosing how hard to compress a version, by the type of the target.
+//!
+//! Rules map file types to zstd levels, e.g. `jpg=off,log=19,*=3`. A type is
+//! an extension of the target, or the format its content starts like, since
+//! targets aren't always named after what they hold. Formats that are
+//! compressed already are left alone unless a rule names them, as compressing
+//! them again only burns CPU.
+
+use std::ffi::OsStr;
+use std::path::Path;
+
+use crate::zstd;
+
+/// Leading bytes of formats that are compressed already, and their types
+const COMPRESSED: [(usize, &[u8], &str); 14] = [
+    (0, b"\xff\xd8\xff", "jpg"),
+    (0, b"\x89PNG\r\n\x1a\n", "png"),
+    (0, b"GIF8", "gif"),
+    (8, b"WEBP", "webp"),
+    (0, b"PK\x03\x04", "zip"),
+    (0, b"\x1f\x8b", "gz"),
+    (0, b"BZh", "bz2"),
+    (0, b"\xfd7zXZ\x00", "xz"),
+    (0, b"\x28\xb5\x2f\xfd", "zst"),
+    (0, b"7z\xbc\xaf\x27\x1c", "7z"),
+    (0, b"Rar!\x1a\x07", "rar"),
+    (4, b"ftyp", "mp4"),
+    (0, b"OggS", "ogg"),
+    (0, b"fLaC", "flac"),
+];
+
+/// The type of an already compressed format `data` starts like.
+fn sniff(data: &[u8]) -> Option<&'static str> {
+    COMPRESSED
+        .iter()
+        .find(|(offset, magic, _)| data.get(*offset..offset + magic.len()) == Some(magic))
+        .map(|&(_, _, kind)| kind)
+}
+
+/// Compression levels by file type, `None` leaving versions uncompressed
+#[derive(Clone, Debug, PartialEq)]
+pub struct Rules(Vec<(String, Option<u32>)>);
+
+impl Rules {
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let rules = s
+            .split(',')
+            .map(|rule| {
+                let (kind, level) = rule
+                    .split_once('=')
+                    .ok_or_else(|| format!("expected TYPE=LEVEL, got {rule:?}"))?;
+                let level = match level.trim() {
+                    "off" => None,
+                    level => Some(zstd::parse_level(level)?),
+                };
+                Ok((kind.trim().trim_start_matches('.').to_ascii_lowercase(), level))
+            })
+            .collect::<Result<_, String>>()?;
+        Ok(Rules(rules))
+    }
+
+    fn get(&self, kind: &str) -> Option<Option<u32>> {
+        self.0.iter().find(|(k, _)| k == kind).map(|&(_, level)| level)
+    }
+
+    /// The level to compress the content `data` of the target `name` at:
+    /// the rule for its extension, else the one for its sniffed format,
+    /// else the `*` rule unless it is compressed already.
+    pub fn level(&self, name: &OsStr, data: &[u8]) -> Option<u32> {
+        let extension = Path::new(name).extension().and_then(OsStr::to_str).map(str::to_ascii_lowercase);
+        if let Some(level) = extension.and_then(|ext| self.get(&ext)) {
+            return level;
+        }
+        match sniff(data) {
+            Some(kind) => self.get(kind).flatten(),
+            None => self.get("*").flatten(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PNG: &[u8] = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR";
+    const WEBP: &[u8] = b"RIFF\x24\0\0\0WEBPVP8 ";
+    const MP4: &[u8] = b"\0\0\0\x20ftypisom";
+    const GZ: &[u8] = b"\x1f\x8b\x08\0";
+
+    fn level(rules: &str, name: &str, data: &[u8]) -> Option<u32> {
+        Rules::parse(rules).unwrap().level(OsStr::new(name), data)
+    }
+
+    #[test]
+    fn parses_rules() {
+        let rules = Rules::parse("jpg=off, .LOG = 19,*=3").unwrap();
+        let expected = [("jpg", None), ("log", Some(19)), ("*", Some(3))];
+        assert_eq!(rules, Rules(expected.iter().map(|&(kind, level)| (kind.to_owned(), level)).collect()));
+        assert!(Rules::parse("jpg").is_err());
+        assert!(Rules::parse("log=0").is_err());
+        assert!(Rules::parse("log=20").is_err());
+        assert!(Rules::parse("log=fast").is_err());
+    }
+
+    #[test]
+    fn sniffs_compressed_formats() {
+        assert_eq!(sniff(PNG), Some("png"));
+        assert_eq!(sniff(WEBP), Some("webp"));
+        assert_eq!(sniff(MP4), Some("mp4"));
+        assert_eq!(sniff(GZ), Some("gz"));
+        assert_eq!(sniff(&crate::zstd::encode(b"text", 3)), Some("zst"));
+        assert_eq!(sniff(b"plain text"), None);
+        // too short for the magic at its offset
+        assert_eq!(sniff(b"RIFF"), None);
+        assert_eq!(sniff(b""), None);
+    }
+
+    #[test]
+    fn extension_rules_come_first() {
+        assert_eq!(level("log=19,*=3", "app.log", b"text"), Some(19));
+        assert_eq!(level("log=19,*=3", "APP.LOG", b"text"), Some(19));
+        // even over what the content looks like
+        assert_eq!(level("log=19,*=3", "app.log", GZ), Some(19));
+        assert_eq!(level("jpg=off,*=3", "photo.jpg", b"text"), None);
+    }
+
+    #[test]
+    fn sniffed_formats_come_next() {
+        // a rule naming the format
+        assert_eq!(level("png=5,*=3", "image", PNG), Some(5));
+        assert_eq!(level("png=5,*=3", "image.dat", PNG), Some(5));
+        // compressed formats are left alone otherwise
+        assert_eq!(level("*=3", "image", PNG), None);
+        assert_eq!(level("*=3", "movie.bin", MP4), None);
+        assert_eq!(level("*=3", "archive", GZ), None);
+    }
+
+    #[test]
+    fn the_star_rule_comes_last() {
+        assert_eq!(level("log=19,*=3", "notes.txt", b"text"), Some(3));
+        assert_eq!(level("log=19,*=3", "notes", b"text"), Some(3));
+        assert_eq!(level("log=19,*=off", "notes", b"text"), None);
+        assert_eq!(level("log=19", "notes", b"text"), None);
+    }
+}
@@ -0,0 +1,104 @@
+//! The configuration file given with `--config`.
+//!
+//! Each line sets a long option as `key = value`, e.g. `compact-after = 3600`,
+//! with `true` or `false` for flags. Blank lines and lines starting with `#`
+//! are ignored. Options given on the command line take precedence.
+//!
+//! A `[NAME]` line starts the options of a target served in the subdirectory
+//! `NAME` of the mountpoint, on top of those before the first such line.
+
+use std::ffi::OsString;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Whether `line` sets `key`.
+fn sets(line: &str, key: &str) -> bool {
+    !line.trim_start().starts_with('#') && line.split_once('=').is_some_and(|(k, _)| k.trim() == key)
+}
+
+/// The name of the target whose options `line` starts, if it does.
+fn section(line: &str) -> Option<&str> {
+    line.trim().strip_prefix('[')?.strip_suffix(']').map(str::trim)
+}
+
+/// The options of a file, as the equivalent command-line arguments
+pub struct Config {
+    /// Options of every target
+    pub common: Vec<OsString>,
+    /// Options of each target, by the name of its subdirectory
+    pub targets: Vec<(String, Vec<OsString>)>,
+}
+
+/// Read the file at `path`.
+pub fn read(path: &Path) -> io::Result<Config> {
+    let mut config = Config { common: Vec::new(), targets: Vec::new() };
+    for (i, line) in fs::read_to_string(path)?.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let invalid = |msg: &str| {
+            io::Error::new(io::ErrorKind::InvalidData, format!("line {}: {msg}", i + 1))
+        };
+        if let Some(name) = section(line) {
+            if name.is_empty() || name.contains('/') || name == "." || name == ".." {
+                return Err(invalid("expected `[NAME]` naming a subdirectory"));
+            }
+            if config.targets.iter().any(|(other, _)| other == name) {
+                return Err(invalid(&format!("target {name:?} is given twice")));
+            }
+            config.targets.push((name.to_string(), Vec::new()));
+            continue;
+        }
+        let (key, value) = match line.split_once('=') {
+            Some((key, value)) if !key.trim().is_empty() => (key.trim(), value.trim()),
+            _ => return Err(invalid("expected `key = value`")),
+        };
+        match config.targets.last_mut() {
+            Some((_, args)) => args.extend(arg(key, value)),
+            None => config.common.extend(arg(key, value)),
+        }
+    }
+    Ok(config)
+}
+
+/// Read the options of every target in the file at `path`.
+pub fn load(path: &Path) -> io::Result<Vec<OsString>> {
+    read(path).map(|config| config.common)
+}
+
+/// The command-line argument setting `key` to `value`, if any.
+pub fn arg(key: &str, value: &str) -> Option<OsString> {
+    match value {
+        "true" => Some(format!("--{key}").into()),
+        "false" => None,
+        _ => Some(format!("--{key}={value}").into()),
+    }
+}
+
+/// Set `key` to `value` for every target in the file at `path`, in place of
+/// any earlier setting of it, leaving the rest of the file as it was.
+pub fn set(path: &Path, key: &str, value: &str) -> io::Result<()> {
+    let mut lines = Vec::new();
+    let mut found = false;
+    let mut targets = None;
+    for line in fs::read_to_string(path)?.lines() {
+        if targets.is_none() && section(line).is_some() {
+            targets = Some(lines.len());
+        }
+        if targets.is_some() || !sets(line, key) {
+            lines.push(line.to_string());
+        } else if !found {
+            lines.push(format!("{key} = {value}"));
+            found = true;
+        }
+    }
+    if !found {
+        // ahead of the options of the first target
+        lines.insert(targets.unwrap_or(lines.len()), format!("{key} = {value}"));
+    }
+    let tmp_path = path.with_extension("tmp");
+    fs::write(&tmp_path, lines.join("\n") + "\n")?;
+    fs::rename(&tmp_path, path)
+}
@@ -0,0 +1,134 @@
+//! Converting the versions of an unmounted store to another layout in place.
+//!
+//! Versions are converted oldest first, so the deltas of the versions not
+//! converted yet can still be applied to the ones above them, and a version
+//! converted to a delta is taken against content that stays the same. Each
+//! version is read back from its new form before the old one is removed.
+
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::PathBuf;
+
+use crate::hash::Algorithm;
+use crate::store::{discard, keep_ownership, publish, Change, Store};
+use crate::{chunk, delta, xattr, zstd};
+
+/// How the versions below the head are kept
+#[derive(Clone, Copy)]
+pub enum Layout {
+    /// Each version whole
+    Flat,
+    /// zstd frames at this level
+    Compressed(u32),
+    /// Deltas against the version after, every K-th version kept whole
+    Delta(usize),
+    /// Manifests of chunks named by this hash
+    Chunked(Algorithm),
+}
+
+/// What a conversion did, or would do
+#[derive(Default)]
+pub struct Report {
+    pub versions: usize,
+    /// Bytes the converted versions took before
+    pub before: u64,
+    /// Bytes they take in the new layout, chunks shared between them counted once
+    pub after: u64,
+}
+
+/// Convert every version below the head to `layout`, or with `dry_run` only
+/// work out how much space that saves. Versions a delta or zstd frame
+/// wouldn't shrink are kept whole, as compaction does.
+pub fn convert(store: &mut Store, layout: Layout, dry_run: bool) -> io::Result<Report> {
+    let mut report = Report::default();
+    // hard linked versions are counted once
+    let mut files = HashSet::new();
+    let mut chunks_seen = HashSet::new();
+    let head = store.version;
+    for version in store.versions().into_iter().filter(|&version| version < head) {
+        // the store was cleaned up, so each version is kept in one form
+        let source = store.xattr_path(version);
+        let meta = fs::metadata(&source)?;
+        let data = store.read_version(version)?;
+        let mut chunks = Vec::new();
+        let mut base = Vec::new();
+        let (converted, path) = match layout {
+            Layout::Flat => (None, store.path_for_version(version)),
+            Layout::Compressed(level) => {
+                let frame = zstd::encode(&data, level);
+                match frame.len() < data.len() {
+                    true => (Some(frame), store.path_for_compressed(version)),
+                    false => (None, store.path_for_version(version)),
+                }
+            },
+            // a keyframe, or the version after it was pruned
+            Layout::Delta(every) if version.is_multiple_of(every.max(1)) || !store.contains(version + 1) => {
+                (None, store.path_for_version(version))
+            },
+            Layout::Delta(_) => {
+                base = store.read_version(version + 1)?;
+                let delta = delta::encode(&base, &data);
+                match delta.len() < data.len() {
+                    true => (Some(delta), store.path_for_delta(version)),
+                    false => (None, store.path_for_version(version)),
+                }
+            },
+            Layout::Chunked(algorithm) => {
+                let (manifest, listed) = chunk::manifest(&data, algorithm);
+                chunks = listed;
+                (Some(manifest), store.path_for_chunks(version))
+            },
+        };
+        if path == source {
+            continue;
+        }
+        report.versions += 1;
+        if files.insert((meta.dev(), meta.ino())) {
+            report.before += meta.len();
+        }
+        report.after += converted.as_ref().map_or(data.len(), Vec::len) as u64;
+        for (name, chunk) in &chunks {
+            if chunks_seen.insert(name.clone()) {
+                report.after += chunk.len() as u64;
+            }
+        }
+        if dry_run {
+            continue;
+        }
+
+        chunk::write(&store.chunks_dir(), &chunks)?;
+        let mut tmp_path = path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        let tmp_path = PathBuf::from(tmp_path);
+        fs::write(&tmp_path, converted.as_deref().unwrap_or(&data))?;
+        let written = fs::read(&tmp_path)?;
+        let read_back = match (&converted, layout) {
+            (None, _) => written,
+            (Some(_), Layout::Chunked(_)) => chunk::read(&store.chunks_dir(), &written)?,
+            (Some(_), Layout::Compressed(_)) => zstd::decode(&written)?,
+            (Some(_), _) => delta::apply(&base, &written)?,
+        };
+        if read_back != data {
+            fs::remove_file(&tmp_path)?;
+            let msg = format!("version {version} doesn't read back the same once converted");
+            return Err(io::Error::new(io::ErrorKind::InvalidData, msg));
+        }
+        // a whole version takes precedence over compacted forms left next to
+        // it, so only compacting needs the journal to settle a crash
+        let change = converted.is_some().then_some(Change::Compact(version));
+        if let Some(change) = change {
+            store.begin(change)?;
+        }
+        File::options().write(true).open(&tmp_path)?.set_modified(meta.modified()?)?;
+        xattr::keep(&source, &tmp_path)?;
+        keep_ownership(&source, &tmp_path)?;
+        publish(&tmp_path, &path)?;
+        discard(&source)?;
+        if let Some(change) = change {
+            store.end(change)?;
+        }
+    }
+    Ok(report)
+}
@@ -0,0 +1,95 @@
+//! Cron-style schedules for `--snapshot-cron`.
+//!
+//! The five usual fields (minute, hour, day of month, month, day of week),
+//! each `*`, a number, a range `a-b`, a step `*/n` or `a-b/n`, or a list of
+//! those separated by commas. As in cron, a day matches if either the day of
+//! month or the day of week does when both are restricted. Times are local.
+
+use std::time::SystemTime;
+
+/// Bitsets of the values each field matches
+#[derive(Clone, Debug, PartialEq)]
+pub struct Schedule {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    /// Whether the day fields were `*`
+    any_day: bool,
+    any_weekday: bool,
+}
+
+/// Parse one field of values in `min..=max` into a bitset.
+fn field(s: &str, min: u32, max: u32) -> Result<u64, String> {
+    let mut bits = 0;
+    for part in s.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, step.parse::<u32>().map_err(|e| format!("{part:?}: {e}"))?),
+            None => (part, 1),
+        };
+        let (from, to) = match range {
+            "*" => (min, max),
+            _ => {
+                let value = |s: &str| s.parse::<u32>().map_err(|e| format!("{part:?}: {e}"));
+                match range.split_once('-') {
+                    Some((from, to)) => (value(from)?, value(to)?),
+                    // `a/n` runs from a to the end
+                    None if part.contains('/') => (value(range)?, max),
+                    None => (value(range)?, value(range)?),
+                }
+            },
+        };
+        if step == 0 {
+            return Err(format!("{part:?}: the step must be at least 1"));
+        }
+        if from < min || to > max || from > to {
+            return Err(format!("{part:?} is out of {min}-{max}"));
+        }
+        for v in (from..=to).step_by(step as usize) {
+            bits |= 1 << v;
+        }
+    }
+    Ok(bits)
+}
+
+/// Parse a schedule such as `0 9-17 * * 1-5`.
+pub fn parse(s: &str) -> Result<Schedule, String> {
+    let fields: Vec<&str> = s.split_whitespace().collect();
+    let [minutes, hours, days, months, weekdays] = fields[..] else {
+        return Err("expected 5 fields: minute hour day-of-month month day-of-week".to_string());
+    };
+    let mut weekday_bits = field(weekdays, 0, 7)?;
+    // both 0 and 7 are Sunday
+    if weekday_bits & (1 << 7) != 0 {
+        weekday_bits |= 1;
+    }
+    Ok(Schedule {
+        minutes: field(minutes, 0, 59)?,
+        hours: field(hours, 0, 23)?,
+        days: field(days, 1, 31)?,
+        months: field(months, 1, 12)?,
+        weekdays: weekday_bits,
+        any_day: days == "*",
+        any_weekday: weekdays == "*",
+    })
+}
+
+impl Schedule {
+    /// Whether the minute of `time` is on the schedule.
+    pub fn matches(&self, time: SystemTime) -> bool {
+        let secs = time.duration_since(SystemTime::UNIX_EPOCH).map_or(0, |d| d.as_secs()) as libc::time_t;
+        let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+        if unsafe { libc::localtime_r(&secs, &mut tm) }.is_null() {
+            return false;
+        }
+        let has = |bits: u64, v: libc::c_int| bits & (1 << v) != 0;
+        let day = has(self.days, tm.tm_mday);
+        let weekday = has(self.weekdays, tm.tm_wday);
+        let day = match (self.any_day, self.any_weekday) {
+            (false, false) => day || weekday,
+            _ => day && weekday,
+        };
+        has(self.minutes, tm.tm_min) && has(self.hours, tm.tm_hour) && has(self.months, tm.tm_mon + 1) && day
+    }
+}
@@ -0,0 +1,157 @@
+//! Binary deltas between versions, used to store old versions compactly.
+//!
+//! A delta starts with a header carrying the base and target lengths,
+//! followed by operations rebuilding the target from the base: copying a
+//! range of the base, or inserting literal bytes.
+
+use std::collections::HashMap;
+use std::io::{self, Read};
+
+const MAGIC: &[u8] = b"VFSDELTA1\n";
+/// Granularity at which matches against the base are looked up
+const BLOCK: usize = 32;
+const PRIME: u64 = 1_099_511_628_211;
+
+const OP_COPY: u8 = 0;
+const OP_INSERT: u8 = 1;
+
+/// Encode the delta rebuilding `target` from `base`.
+pub fn encode(base: &[u8], target: &[u8]) -> Vec<u8> {
+    let mut out = MAGIC.to_vec();
+    put_varint(&mut out, base.len() as u64);
+    put_varint(&mut out, target.len() as u64);
+
+    let mut index = HashMap::new();
+    for (i, block) in base.chunks_exact(BLOCK).enumerate() {
+        index.entry(hash(block)).or_insert(i * BLOCK);
+    }
+
+    let top = PRIME.wrapping_pow(BLOCK as u32 - 1);
+    let mut literal = 0;
+    let mut i = 0;
+    let mut h = if target.len() >= BLOCK { hash(&target[..BLOCK]) } else { 0 };
+    while i + BLOCK <= target.len() {
+        let found = index.get(&h)
+            .copied()
+            .filter(|&off| base[off..off + BLOCK] == target[i..i + BLOCK]);
+        if let Some(off) = found {
+            let (mut start, mut from) = (i, off);
+            while start > literal && from > 0 && target[start - 1] == base[from - 1] {
+                start -= 1;
+                from -= 1;
+            }
+            let mut len = i - start + BLOCK;
+            while start + len < target.len() && from + len < base.len()
+                && target[start + len] == base[from + len] {
+                len += 1;
+            }
+            put_insert(&mut out, &target[literal..start]);
+            out.push(OP_COPY);
+            put_varint(&mut out, from as u64);
+            put_varint(&mut out, len as u64);
+            i = start + len;
+            literal = i;
+            if i + BLOCK <= target.len() {
+                h = hash(&target[i..i + BLOCK]);
+            }
+            continue;
+        }
+        if i + BLOCK < target.len() {
+            h = h.wrapping_sub((target[i] as u64).wrapping_mul(top))
+                .wrapping_mul(PRIME)
+                .wrapping_add(target[i + BLOCK] as u64);
+        }
+        i += 1;
+    }
+    put_insert(&mut out, &target[literal..]);
+    out
+}
+
+/// Rebuild the target of `delta` from `base`.
+pub fn apply(base: &[u8], delta: &[u8]) -> io::Result<Vec<u8>> {
+    let mut input = delta;
+    let (base_len, target_len) = read_header(&mut input)?;
+    if base_len != base.len() as u64 {
+        return Err(invalid("delta base length mismatch"));
+    }
+
+    let mut out = Vec::with_capacity(target_len as usize);
+    while let Some((&op, rest)) = input.split_first() {
+        input = rest;
+        match op {
+            OP_COPY => {
+                let from = get_varint(&mut input)? as usize;
+                let len = get_varint(&mut input)? as usize;
+                let range = base.get(from..from.saturating_add(len))
+                    .ok_or_else(|| invalid("delta copies past the end of its base"))?;
+                out.extend_from_slice(range);
+            },
+            OP_INSERT => {
+                let len = get_varint(&mut input)? as usize;
+                if len > input.len() {
+                    return Err(invalid("truncated delta"));
+                }
+                let (data, rest) = input.split_at(len);
+                out.extend_from_slice(data);
+                input = rest;
+            },
+            _ => return Err(invalid("unknown delta operation")),
+        }
+    }
+    if out.len() as u64 != target_len {
+        return Err(invalid("delta target length mismatch"));
+    }
+    Ok(out)
+}
+
+/// Length of the content a delta rebuilds, read from its header only.
+pub fn target_len<R: Read>(reader: R) -> io::Result<u64> {
+    let mut header = Vec::new();
+    reader.take((MAGIC.len() + 20) as u64).read_to_end(&mut header)?;
+    read_header(&mut header.as_slice()).map(|(_, target_len)| target_len)
+}
+
+fn read_header(input: &mut &[u8]) -> io::Result<(u64, u64)> {
+    match input.strip_prefix(MAGIC) {
+        Some(rest) => *input = rest,
+        None => return Err(invalid("not a delta")),
+    }
+    Ok((get_varint(input)?, get_varint(input)?))
+}
+
+fn hash(block: &[u8]) -> u64 {
+    block.iter().fold(0u64, |h, &b| h.wrapping_mul(PRIME).wrapping_add(b as u64))
+}
+
+fn put_insert(out: &mut Vec<u8>, data: &[u8]) {
+    if !data.is_empty() {
+        out.push(OP_INSERT);
+        put_varint(out, data.len() as u64);
+        out.extend_from_slice(data);
+    }
+}
+
+fn put_varint(out: &mut Vec<u8>, mut n: u64) {
+    while n >= 0x80 {
+        out.push(n as u8 | 0x80);
+        n >>= 7;
+    }
+    out.push(n as u8);
+}
+
+fn get_varint(input: &mut &[u8]) -> io::Result<u64> {
+    let mut n = 0u64;
+    for shift in (0..64).step_by(7) {
+        let (&b, rest) = input.split_first().ok_or_else(|| invalid("truncated delta"))?;
+        *input = rest;
+        n |= ((b & 0x7f) as u64) << shift;
+        if b & 0x80 == 0 {
+            return Ok(n);
+        }
+    }
+    Err(invalid("malformed delta"))
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
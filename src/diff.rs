@@ -0,0 +1,223 @@
+//! Line-based unified diffs, used to render `.versions/diff/N`.
+
+use std::collections::HashMap;
+use std::ops::Range;
+
+const CONTEXT: usize = 3;
+
+#[derive(Clone, Copy)]
+enum Edit {
+    Equal(usize, usize),
+    Delete(usize, usize),
+    Insert(usize, usize),
+}
+
+impl Edit {
+    fn is_change(&self) -> bool { !matches!(self, Edit::Equal(..)) }
+
+    /// Position in the old and new line lists where this edit starts.
+    fn pos(&self) -> (usize, usize) {
+        match *self {
+            Edit::Equal(i, j) | Edit::Delete(i, j) | Edit::Insert(i, j) => (i, j),
+        }
+    }
+}
+
+/// Render the unified diff turning `old` into `new`.
+///
+/// Identical inputs produce an empty diff; inputs containing NUL bytes are
+/// treated as binary and only reported as differing, as are inputs too far
+/// apart to diff in reasonable time.
+pub fn unified(old: &[u8], new: &[u8], old_name: &str, new_name: &str) -> Vec<u8> {
+    if old == new {
+        return Vec::new();
+    }
+    if old.contains(&0) || new.contains(&0) {
+        return format!("Binary files {old_name} and {new_name} differ\n").into_bytes();
+    }
+
+    let a: Vec<&[u8]> = old.split_inclusive(|&b| b == b'\n').collect();
+    let b: Vec<&[u8]> = new.split_inclusive(|&b| b == b'\n').collect();
+    let edits = match edit_script(&a, &b) {
+        Some(edits) => edits,
+        None => return format!("Files {old_name} and {new_name} differ too much to show\n").into_bytes(),
+    };
+
+    let mut out = format!("--- {old_name}\n+++ {new_name}\n").into_bytes();
+    for (start, end) in hunks(&edits) {
+        let hunk = &edits[start..end];
+        let (old_start, new_start) = hunk[0].pos();
+        let old_len = hunk.iter().filter(|e| !matches!(e, Edit::Insert(..))).count();
+        let new_len = hunk.iter().filter(|e| !matches!(e, Edit::Delete(..))).count();
+        out.extend(format!(
+            "@@ -{} +{} @@\n",
+            range(old_start, old_len),
+            range(new_start, new_len),
+        ).bytes());
+        for edit in hunk {
+            let (prefix, line) = match *edit {
+                Edit::Equal(i, _) => (b' ', a[i]),
+                Edit::Delete(i, _) => (b'-', a[i]),
+                Edit::Insert(_, j) => (b'+', b[j]),
+            };
+            out.push(prefix);
+            out.extend_from_slice(line);
+            if !line.ends_with(b"\n") {
+                out.extend_from_slice(b"\n\\ No newline at end of file\n");
+            }
+        }
+    }
+    out
+}
+
+fn range(start: usize, len: usize) -> String {
+    match len {
+        0 => format!("{start},0"),
+        1 => format!("{}", start + 1),
+        _ => format!("{},{}", start + 1, len),
+    }
+}
+
+/// Group changed edits into `[start, end)` ranges padded with context lines.
+fn hunks(edits: &[Edit]) -> Vec<(usize, usize)> {
+    let mut hunks: Vec<(usize, usize)> = Vec::new();
+    for (i, _) in edits.iter().enumerate().filter(|(_, e)| e.is_change()) {
+        let start = i.saturating_sub(CONTEXT);
+        let end = (i + 1 + CONTEXT).min(edits.len());
+        match hunks.last_mut() {
+            Some(last) if start <= last.1 => last.1 = end,
+            _ => hunks.push((start, end)),
+        }
+    }
+    hunks
+}
+
+/// Diagonals Myers' search may step through before a diff is given up on
+/// as too costly, bounding the time rendering one takes
+const MAX_STEPS: usize = 1 << 26;
+
+/// The search ran past `MAX_STEPS`.
+struct TooCostly;
+
+/// Myers' O(ND) shortest edit script between two line lists, in linear
+/// space: the middle snake of the optimal path is found by searching from
+/// both ends at once, and the halves on either side of it are diffed in
+/// turn. `None` if that takes more than `MAX_STEPS`.
+fn edit_script(a: &[&[u8]], b: &[&[u8]]) -> Option<Vec<Edit>> {
+    // lines are compared many times over, so as numbers rather than bytes
+    let mut ids = HashMap::new();
+    let mut id = |line: &[u8]| {
+        let next = ids.len();
+        *ids.entry(line.to_vec()).or_insert(next)
+    };
+    let a: Vec<usize> = a.iter().map(|line| id(line)).collect();
+    let b: Vec<usize> = b.iter().map(|line| id(line)).collect();
+    let mut edits = Vec::new();
+    let mut steps = 0;
+    match diff_range(&a, &b, 0..a.len(), 0..b.len(), &mut edits, &mut steps) {
+        Ok(()) => Some(edits),
+        Err(TooCostly) => None,
+    }
+}
+
+/// Append the edits turning `a[xs]` into `b[ys]` to `edits`.
+fn diff_range(
+    a: &[usize],
+    b: &[usize],
+    mut xs: Range<usize>,
+    mut ys: Range<usize>,
+    edits: &mut Vec<Edit>,
+    steps: &mut usize,
+) -> Result<(), TooCostly> {
+    while !xs.is_empty() && !ys.is_empty() && a[xs.start] == b[ys.start] {
+        edits.push(Edit::Equal(xs.start, ys.start));
+        xs.start += 1;
+        ys.start += 1;
+    }
+    let mut suffix = 0;
+    while suffix < xs.len() && suffix < ys.len() && a[xs.end - 1 - suffix] == b[ys.end - 1 - suffix] {
+        suffix += 1;
+    }
+    xs.end -= suffix;
+    ys.end -= suffix;
+
+    if xs.is_empty() {
+        edits.extend(ys.clone().map(|j| Edit::Insert(xs.start, j)));
+    } else if ys.is_empty() {
+        edits.extend(xs.clone().map(|i| Edit::Delete(i, ys.start)));
+    } else {
+        // with common ends stripped and neither side empty, the path takes
+        // at least two edits, so both halves are smaller than the whole
+        let ((x, y), (u, v)) = middle_snake(&a[xs.clone()], &b[ys.clone()], steps)?;
+        let (x, y, u, v) = (xs.start + x, ys.start + y, xs.start + u, ys.start + v);
+        diff_range(a, b, xs.start..x, ys.start..y, edits, steps)?;
+        edits.extend((x..u).zip(y..v).map(|(i, j)| Edit::Equal(i, j)));
+        diff_range(a, b, u..xs.end, v..ys.end, edits, steps)?;
+    }
+    edits.extend((0..suffix).map(|n| Edit::Equal(xs.end + n, ys.end + n)));
+    Ok(())
+}
+
+/// Where a run of equal lines starts and ends, as `(x, y)` in the old and new
+type Snake = ((usize, usize), (usize, usize));
+
+/// The middle snake of the shortest edit script turning `a` into `b`,
+/// searched for from the start and the end at once.
+fn middle_snake(a: &[usize], b: &[usize], steps: &mut usize) -> Result<Snake, TooCostly> {
+    let (n, m) = (a.len() as isize, b.len() as isize);
+    let delta = n - m;
+    let odd = delta % 2 != 0;
+    let max = (n + m + 1) / 2;
+    let offset = max + 1;
+    // furthest x on each diagonal k = x - y, searching forward, and furthest
+    // x from the end on each diagonal of the reversed inputs, backward
+    let mut forward = vec![0isize; 2 * offset as usize + 1];
+    let mut backward = vec![0isize; 2 * offset as usize + 1];
+    let at = |k: isize| (k + offset) as usize;
+    for d in 0..=max {
+        for k in (-d..=d).step_by(2) {
+            *steps += 1;
+            let mut x = if k == -d || (k != d && forward[at(k - 1)] < forward[at(k + 1)]) {
+                forward[at(k + 1)]
+            } else {
+                forward[at(k - 1)] + 1
+            };
+            let mut y = x - k;
+            let start = (x, y);
+            while x < n && y < m && a[x as usize] == b[y as usize] {
+                x += 1;
+                y += 1;
+            }
+            forward[at(k)] = x;
+            // the backward search has reached diagonal k, d - 1 edits in
+            let c = delta - k;
+            if odd && (-(d - 1)..=d - 1).contains(&c) && x + backward[at(c)] >= n {
+                return Ok(((start.0 as usize, start.1 as usize), (x as usize, y as usize)));
+            }
+        }
+        for k in (-d..=d).step_by(2) {
+            *steps += 1;
+            let mut x = if k == -d || (k != d && backward[at(k - 1)] < backward[at(k + 1)]) {
+                backward[at(k + 1)]
+            } else {
+                backward[at(k - 1)] + 1
+            };
+            let mut y = x - k;
+            let end = (x, y);
+            while x < n && y < m && a[(n - 1 - x) as usize] == b[(m - 1 - y) as usize] {
+                x += 1;
+                y += 1;
+            }
+            backward[at(k)] = x;
+            let c = delta - k;
+            if !odd && (-d..=d).contains(&c) && forward[at(c)] + x >= n {
+                let start = ((n - x) as usize, (m - y) as usize);
+                return Ok((start, ((n - end.0) as usize, (m - end.1) as usize)));
+            }
+        }
+        if *steps > MAX_STEPS {
+            return Err(TooCostly);
+        }
+    }
+    unreachable!("the searches meet within (n + m + 1) / 2 edits")
+}
@@ -0,0 +1,93 @@
+//! Shell-style patterns for picking targets by name with `--target`.
+//!
+//! `*` matches any run of characters, `?` any one character, and `[abc]`,
+//! `[a-z]` or `[!abc]` one character out of (or not out of) a set. Other
+//! characters, or any character after `\`, match themselves.
+
+/// Whether `s` has characters that make it a pattern rather than a name.
+pub fn is_pattern(s: &str) -> bool { s.contains(['*', '?', '[']) }
+
+/// Whether `name` matches `pattern` as a whole.
+pub fn matches(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    // where to resume after the last `*` if the rest fails to match
+    let mut star: Option<(usize, usize)> = None;
+    let (mut p, mut n) = (0, 0);
+    while n < name.len() {
+        let step = match pattern.get(p) {
+            Some('*') => {
+                star = Some((p + 1, n));
+                p += 1;
+                continue;
+            },
+            Some('?') => Some(p + 1),
+            Some('[') => class(&pattern, p, name[n]),
+            Some('\\') if p + 1 < pattern.len() => (pattern[p + 1] == name[n]).then_some(p + 2),
+            Some(&c) => (c == name[n]).then_some(p + 1),
+            None => None,
+        };
+        match (step, star) {
+            (Some(next), _) => {
+                p = next;
+                n += 1;
+            },
+            // let the last `*` take one more character
+            (None, Some((after, from))) => {
+                star = Some((after, from + 1));
+                p = after;
+                n = from + 1;
+            },
+            (None, None) => return false,
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Match `c` against the set starting with the `[` at `start` of `pattern`,
+/// returning where the pattern goes on if it matches. An unclosed `[` only
+/// matches itself.
+fn class(pattern: &[char], start: usize, c: char) -> Option<usize> {
+    let mut i = start + 1;
+    let negated = matches!(pattern.get(i), Some('!' | '^'));
+    if negated {
+        i += 1;
+    }
+    let mut found = false;
+    let mut first = true;
+    loop {
+        match pattern.get(i) {
+            None => return (c == '[').then_some(start + 1),
+            Some(']') if !first => break,
+            Some(&from) if pattern.get(i + 1) == Some(&'-') && pattern.get(i + 2).is_some_and(|&to| to != ']') => {
+                found |= (from..=pattern[i + 2]).contains(&c);
+                i += 3;
+            },
+            Some(&member) => {
+                found |= member == c;
+                i += 1;
+            },
+        }
+        first = false;
+    }
+    (found != negated).then_some(i + 1)
+}
+
+/// Include and exclude patterns layered in the order given, the last one
+/// matching a name deciding whether it is taken. Names no pattern matches
+/// are taken unless the first pattern includes, as starting with an include
+/// narrows down from nothing rather than from everything.
+#[derive(Clone, Debug, Default)]
+pub struct Rules(Vec<(bool, String)>);
+
+impl Rules {
+    /// Rules from patterns each marked as including or excluding, in order.
+    pub fn new(rules: Vec<(bool, String)>) -> Self { Rules(rules) }
+
+    pub fn admits(&self, name: &str) -> bool {
+        match self.0.iter().rev().find(|(_, pattern)| matches(pattern, name)) {
+            Some(&(include, _)) => include,
+            None => self.0.first().is_none_or(|&(include, _)| !include),
+        }
+    }
+}
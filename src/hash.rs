@@ -0,0 +1,285 @@
+//! Hashes naming chunks by their content and verifying them: BLAKE3 by
+//! default, for speed, or SHA-256 where compliance calls for it.
+
+/// A hash function with 32-byte digests
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Algorithm {
+    Blake3,
+    Sha256,
+}
+
+impl Algorithm {
+    pub fn parse(s: &str) -> Result<Self, String> {
+        match s {
+            "blake3" => Ok(Algorithm::Blake3),
+            "sha256" => Ok(Algorithm::Sha256),
+            _ => Err(format!("unknown hash {s:?}, expected blake3 or sha256")),
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Algorithm::Blake3 => "blake3",
+            Algorithm::Sha256 => "sha256",
+        }
+    }
+
+    pub fn digest(self, data: &[u8]) -> [u8; 32] {
+        match self {
+            Algorithm::Blake3 => blake3(data),
+            Algorithm::Sha256 => sha256(data),
+        }
+    }
+}
+
+const K: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+/// The initial state of SHA-256, which BLAKE3 also starts from
+const IV: [u32; 8] = [
+    0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19,
+];
+
+fn compress(state: &mut [u32; 8], block: &[u8]) {
+    let mut w = [0u32; 64];
+    for (i, word) in block.chunks_exact(4).enumerate() {
+        w[i] = u32::from_be_bytes(word.try_into().unwrap());
+    }
+    for i in 16..64 {
+        let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+        let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+        w[i] = w[i - 16].wrapping_add(s0).wrapping_add(w[i - 7]).wrapping_add(s1);
+    }
+    let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = *state;
+    for i in 0..64 {
+        let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+        let ch = (e & f) ^ (!e & g);
+        let t1 = h.wrapping_add(s1).wrapping_add(ch).wrapping_add(K[i]).wrapping_add(w[i]);
+        let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+        let maj = (a & b) ^ (a & c) ^ (b & c);
+        let t2 = s0.wrapping_add(maj);
+        (h, g, f, e, d, c, b, a) = (g, f, e, d.wrapping_add(t1), c, b, a, t1.wrapping_add(t2));
+    }
+    for (s, v) in state.iter_mut().zip([a, b, c, d, e, f, g, h]) {
+        *s = s.wrapping_add(v);
+    }
+}
+
+fn sha256(data: &[u8]) -> [u8; 32] {
+    let mut state = IV;
+    let mut blocks = data.chunks_exact(64);
+    for block in &mut blocks {
+        compress(&mut state, block);
+    }
+    // pad with a 1 bit, zeros and the length in bits
+    let rest = blocks.remainder();
+    let mut tail = [0u8; 128];
+    tail[..rest.len()].copy_from_slice(rest);
+    tail[rest.len()] = 0x80;
+    let len = if rest.len() < 56 { 64 } else { 128 };
+    tail[len - 8..len].copy_from_slice(&(data.len() as u64 * 8).to_be_bytes());
+    for block in tail[..len].chunks_exact(64) {
+        compress(&mut state, block);
+    }
+    let mut out = [0u8; 32];
+    for (bytes, s) in out.chunks_exact_mut(4).zip(state) {
+        bytes.copy_from_slice(&s.to_be_bytes());
+    }
+    out
+}
+
+const BLAKE3_CHUNK: usize = 1024;
+const CHUNK_START: u32 = 1;
+const CHUNK_END: u32 = 2;
+const PARENT: u32 = 4;
+const ROOT: u32 = 8;
+const PERMUTATION: [usize; 16] = [2, 6, 3, 10, 7, 0, 4, 13, 1, 11, 12, 5, 9, 14, 15, 8];
+
+fn g(state: &mut [u32; 16], (a, b, c, d): (usize, usize, usize, usize), x: u32, y: u32) {
+    state[a] = state[a].wrapping_add(state[b]).wrapping_add(x);
+    state[d] = (state[d] ^ state[a]).rotate_right(16);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_right(12);
+    state[a] = state[a].wrapping_add(state[b]).wrapping_add(y);
+    state[d] = (state[d] ^ state[a]).rotate_right(8);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_right(7);
+}
+
+/// The BLAKE3 compression function, keeping only the chaining value.
+fn blake3_compress(cv: &[u32; 8], block: &[u8], counter: u64, flags: u32) -> [u32; 8] {
+    let mut m = [0u32; 16];
+    let mut padded = [0u8; 64];
+    padded[..block.len()].copy_from_slice(block);
+    for (i, word) in padded.chunks_exact(4).enumerate() {
+        m[i] = u32::from_le_bytes(word.try_into().unwrap());
+    }
+    let mut state = [0u32; 16];
+    state[..8].copy_from_slice(cv);
+    state[8..12].copy_from_slice(&IV[..4]);
+    state[12] = counter as u32;
+    state[13] = (counter >> 32) as u32;
+    state[14] = block.len() as u32;
+    state[15] = flags;
+    for round in 0..7 {
+        if round > 0 {
+            m = PERMUTATION.map(|i| m[i]);
+        }
+        g(&mut state, (0, 4, 8, 12), m[0], m[1]);
+        g(&mut state, (1, 5, 9, 13), m[2], m[3]);
+        g(&mut state, (2, 6, 10, 14), m[4], m[5]);
+        g(&mut state, (3, 7, 11, 15), m[6], m[7]);
+        g(&mut state, (0, 5, 10, 15), m[8], m[9]);
+        g(&mut state, (1, 6, 11, 12), m[10], m[11]);
+        g(&mut state, (2, 7, 8, 13), m[12], m[13]);
+        g(&mut state, (3, 4, 9, 14), m[14], m[15]);
+    }
+    let mut out = [0u32; 8];
+    for i in 0..8 {
+        out[i] = state[i] ^ state[i + 8];
+    }
+    out
+}
+
+/// Chaining value of the chunk numbered `counter`.
+fn blake3_chunk(chunk: &[u8], counter: u64, root: u32) -> [u32; 8] {
+    let blocks: Vec<&[u8]> = match chunk.is_empty() {
+        true => vec![&[]],
+        false => chunk.chunks(64).collect(),
+    };
+    let mut cv = IV;
+    for (i, block) in blocks.iter().enumerate() {
+        let mut flags = if i == 0 { CHUNK_START } else { 0 };
+        if i == blocks.len() - 1 {
+            flags |= CHUNK_END | root;
+        }
+        cv = blake3_compress(&cv, block, counter, flags);
+    }
+    cv
+}
+
+/// Chaining value of the subtree over `data`, starting at chunk `counter`.
+/// Left subtrees hold the largest power of two chunks that leaves some for
+/// the right.
+fn blake3_tree(data: &[u8], counter: u64, root: u32) -> [u32; 8] {
+    if data.len() <= BLAKE3_CHUNK {
+        return blake3_chunk(data, counter, root);
+    }
+    let chunks = data.len().div_ceil(BLAKE3_CHUNK);
+    let left_chunks = 1 << (usize::BITS - 1 - (chunks - 1).leading_zeros());
+    let (left, right) = data.split_at(left_chunks * BLAKE3_CHUNK);
+    let mut block = [0u8; 64];
+    let cvs = blake3_tree(left, counter, 0).into_iter().chain(blake3_tree(right, counter + left_chunks as u64, 0));
+    for (bytes, word) in block.chunks_exact_mut(4).zip(cvs) {
+        bytes.copy_from_slice(&word.to_le_bytes());
+    }
+    blake3_compress(&IV, &block, 0, PARENT | root)
+}
+
+fn blake3(data: &[u8]) -> [u8; 32] {
+    let mut out = [0u8; 32];
+    for (bytes, word) in out.chunks_exact_mut(4).zip(blake3_tree(data, 0, ROOT)) {
+        bytes.copy_from_slice(&word.to_le_bytes());
+    }
+    out
+}
+
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The input of `len` bytes the BLAKE3 test vectors hash.
+    fn counting(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    #[test]
+    fn sha256_nist_vectors() {
+        let vectors: [(&[u8], &str); 4] = [
+            (b"", "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"),
+            (b"abc", "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"),
+            (
+                b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq",
+                "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1",
+            ),
+            (
+                b"abcdefghbcdefghicdefghijdefghijkefghijklfghijklmghijklmn\
+                  hijklmnoijklmnopjklmnopqklmnopqrlmnopqrsmnopqrstnopqrstu",
+                "cf5b16a778af8380036ce59e7b0492370b249b11e8f07a51afac45037afee9d1",
+            ),
+        ];
+        for (input, digest) in vectors {
+            assert_eq!(hex(&sha256(input)), digest, "{:?}", String::from_utf8_lossy(input));
+        }
+        let digest = "cdc76e5c9914fb9281a1c7e284d73e67f1809a48a497200e046d39ccc7112cd0";
+        assert_eq!(hex(&sha256(&vec![b'a'; 1_000_000])), digest);
+    }
+
+    #[test]
+    fn sha256_padding_boundaries() {
+        // where the length does or doesn't fit in the last block, from sha256sum
+        let vectors = [
+            (55, "463eb28e72f82e0a96c0a4cc53690c571281131f672aa229e0d45ae59b598b59"),
+            (56, "da2ae4d6b36748f2a318f23e7ab1dfdf45acdc9d049bd80e59de82a60895f562"),
+            (63, "29af2686fd53374a36b0846694cc342177e428d1647515f078784d69cdb9e488"),
+            (64, "fdeab9acf3710362bd2658cdc9a29e8f9c757fcf9811603a8c447cd1d9151108"),
+            (119, "da18797ed7c3a777f0847f429724a2d8cd5138e6ed2895c3fa1a6d39d18f7ec6"),
+            (120, "f52b23db1fbb6ded89ef42a23ce0c8922c45f25c50b568a93bf1c075420bbb7c"),
+        ];
+        for (len, digest) in vectors {
+            assert_eq!(hex(&sha256(&counting(len))), digest, "{len} bytes");
+        }
+    }
+
+    #[test]
+    fn blake3_official_vectors() {
+        // the first 32 bytes of the unkeyed hashes in test_vectors.json
+        let vectors = [
+            (0, "af1349b9f5f9a1a6a0404dea36dcc9499bcb25c9adc112b7cc9a93cae41f3262"),
+            (1, "2d3adedff11b61f14c886e35afa036736dcd87a74d27b5c1510225d0f592e213"),
+            (63, "e9bc37a594daad83be9470df7f7b3798297c3d834ce80ba85d6e207627b7db7b"),
+            (64, "4eed7141ea4a5cd4b788606bd23f46e212af9cacebacdc7d1f4c6dc7f2511b98"),
+            (65, "de1e5fa0be70df6d2be8fffd0e99ceaa8eb6e8c93a63f2d8d1c30ecb6b263dee"),
+            (1023, "10108970eeda3eb932baac1428c7a2163b0e924c9a9e25b35bba72b28f70bd11"),
+            (1024, "42214739f095a406f3fc83deb889744ac00df831c10daa55189b5d121c855af7"),
+            (1025, "d00278ae47eb27b34faecf67b4fe263f82d5412916c1ffd97c8cb7fb814b8444"),
+            (2048, "e776b6028c7cd22a4d0ba182a8bf62205d2ef576467e838ed6f2529b85fba24a"),
+            (2049, "5f4d72f40d7a5f82b15ca2b2e44b1de3c2ef86c426c95c1af0b6879522563030"),
+            (3072, "b98cb0ff3623be03326b373de6b9095218513e64f1ee2edd2525c7ad1e5cffd2"),
+            (3073, "7124b49501012f81cc7f11ca069ec9226cecb8a2c850cfe644e327d22d3e1cd3"),
+            (4096, "015094013f57a5277b59d8475c0501042c0b642e531b0a1c8f58d2163229e969"),
+            (4097, "9b4052b38f1c5fc8b1f9ff7ac7b27cd242487b3d890d15c96a1c25b8aa0fb995"),
+            (5120, "9cadc15fed8b5d854562b26a9536d9707cadeda9b143978f319ab34230535833"),
+            (8193, "bab6c09cb8ce8cf459261398d2e7aef35700bf488116ceb94a36d0f5f1b7bc3b"),
+            (16384, "f875d6646de28985646f34ee13be9a576fd515f76b5b0a26bb324735041ddde4"),
+            (31744, "62b6960e1a44bcc1eb1a611a8d6235b6b4b78f32e7abc4fb4c6cdcce94895c47"),
+            (102400, "bc3e3d41a1146b069abffad3c0d44860cf664390afce4d9661f7902e7943e085"),
+        ];
+        for (len, digest) in vectors {
+            assert_eq!(hex(&blake3(&counting(len))), digest, "{len} bytes");
+        }
+        assert_eq!(hex(&blake3(b"abc")), "6437b3ac38465133ffb63b75273a8db548c558465d79db03fd359c6cd5bd9d85");
+    }
+
+    #[test]
+    fn algorithms_by_name() {
+        for algorithm in [Algorithm::Blake3, Algorithm::Sha256] {
+            assert_eq!(Algorithm::parse(algorithm.name()), Ok(algorithm));
+        }
+        assert!(Algorithm::parse("md5").is_err());
+        assert_eq!(Algorithm::Sha256.digest(b"abc"), sha256(b"abc"));
+        assert_eq!(Algorithm::Blake3.digest(b"abc"), blake3(b"abc"));
+    }
+}
@@ -0,0 +1,174 @@
+//! Checking that a mount is served and its store is well, for monitoring
+//! systems, which take the exit code as Nagios plugins have it: 0 when all
+//! is well, 1 on warnings and 2 when something is down.
+//!
+//! Everything is asked of the mount itself, which knows where its store is,
+//! so the checks need no more than the mountpoint.
+
+use std::ffi::OsStr;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use crate::{pidfile, store, xattr};
+
+/// How long the mount has to answer
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long a change may stay in the journal before it counts as stuck
+const STUCK_AFTER: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Outcome {
+    Ok,
+    Warning,
+    Critical,
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Outcome::Ok => "ok",
+            Outcome::Warning => "warning",
+            Outcome::Critical => "critical",
+        })
+    }
+}
+
+/// What one check found
+pub struct Check {
+    pub name: &'static str,
+    pub outcome: Outcome,
+    pub detail: String,
+}
+
+impl Check {
+    fn new(name: &'static str, outcome: Outcome, detail: impl Into<String>) -> Self {
+        Check { name, outcome, detail: detail.into() }
+    }
+}
+
+/// The worst outcome of `checks`, which is the exit code as an integer.
+pub fn worst(checks: &[Check]) -> Outcome {
+    checks.iter().map(|check| check.outcome).max().unwrap_or(Outcome::Ok)
+}
+
+/// Check the mount at `mountpoint`, warning if its store holds more than
+/// `max_versions`.
+pub fn check(mountpoint: &Path, max_versions: Option<usize>) -> Vec<Check> {
+    let mut checks = vec![match pidfile::running(mountpoint) {
+        Ok(Some(daemon)) => Check::new("daemon", Outcome::Ok, format!("pid {}", daemon.pid)),
+        Ok(None) => Check::new("daemon", Outcome::Critical, "versionfs is not serving it"),
+        Err(e) => Check::new("daemon", Outcome::Critical, format!("cannot check: {e}")),
+    }];
+    // a mount whose daemon hangs hangs whoever asks it, so it is asked aside
+    let (sender, receiver) = mpsc::channel();
+    let mountpoint = mountpoint.to_path_buf();
+    thread::spawn(move || {
+        let _ = sender.send(check_mount(&mountpoint, max_versions));
+    });
+    match receiver.recv_timeout(TIMEOUT) {
+        Ok(more) => checks.extend(more),
+        Err(_) => {
+            let detail = format!("no answer within {}s", TIMEOUT.as_secs());
+            checks.push(Check::new("mount", Outcome::Critical, detail));
+        },
+    }
+    checks
+}
+
+/// The target served at `mountpoint` and the store keeping it.
+fn find_target(mountpoint: &Path) -> io::Result<(PathBuf, PathBuf)> {
+    for entry in fs::read_dir(mountpoint)? {
+        let path = entry?.path();
+        if let Ok(store) = xattr::get(&path, OsStr::new(xattr::STORE)) {
+            return Ok((path, PathBuf::from(String::from_utf8_lossy(&store).into_owned())));
+        }
+    }
+    Err(io::Error::new(io::ErrorKind::NotFound, "no versioned target in it"))
+}
+
+/// The value of the status attribute `name` of `target`.
+fn status(target: &Path, name: &str) -> io::Result<String> {
+    Ok(String::from_utf8_lossy(&xattr::get(target, OsStr::new(name))?).into_owned())
+}
+
+fn check_mount(mountpoint: &Path, max_versions: Option<usize>) -> Vec<Check> {
+    let (target, target_dir) = match find_target(mountpoint) {
+        Ok(found) => found,
+        Err(e) => return vec![Check::new("mount", Outcome::Critical, e.to_string())],
+    };
+    let head = status(&target, xattr::VERSION).ok().and_then(|version| version.parse::<usize>().ok());
+    let versions: Option<Vec<usize>> = fs::read_dir(mountpoint.join(".versions/diff")).ok().map(|entries| {
+        entries.filter_map(|entry| entry.ok()?.file_name().to_str()?.parse().ok()).collect()
+    });
+    let mut checks = vec![match (head, &versions) {
+        (Some(head), Some(_)) => Check::new("mount", Outcome::Ok, format!("version {head} of {target:?}")),
+        _ => Check::new("mount", Outcome::Critical, format!("cannot list the versions of {target:?}")),
+    }];
+
+    // asked of the daemon rather than tried, as whoever monitors it may not
+    // be allowed to write to the store, and the daemon finds out itself
+    checks.push(match status(&target, xattr::DEGRADED) {
+        Ok(reason) if reason.is_empty() => {
+            Check::new("store", Outcome::Ok, format!("{target_dir:?} takes changes"))
+        },
+        Ok(reason) => Check::new("store", Outcome::Critical, format!("read-only: {reason}")),
+        Err(e) => {
+            Check::new("store", Outcome::Critical, format!("cannot ask whether it takes changes: {e}"))
+        },
+    });
+
+    checks.push(check_manifest(mountpoint, &target, &target_dir, head, versions.as_deref()));
+
+    if let Some(versions) = versions {
+        checks.push(match max_versions {
+            Some(max) if versions.len() > max => {
+                let detail = format!("{} versions, more than {max}", versions.len());
+                Check::new("retention", Outcome::Warning, detail)
+            },
+            _ => Check::new("retention", Outcome::Ok, format!("{} versions", versions.len())),
+        });
+    }
+    checks
+}
+
+/// Whether the store agrees with itself: no change stuck half made, the
+/// head the newest version, and every name naming a version that reads.
+fn check_manifest(
+    mountpoint: &Path,
+    target: &Path,
+    target_dir: &Path,
+    head: Option<usize>,
+    versions: Option<&[usize]>,
+) -> Check {
+    let journal = target_dir.join(store::JOURNAL_FILE);
+    if let Ok(meta) = fs::metadata(&journal) {
+        let age = meta.modified().ok().and_then(|modified| SystemTime::now().duration_since(modified).ok());
+        if meta.len() > 0 && age.is_some_and(|age| age >= STUCK_AFTER) {
+            let changes = fs::read_to_string(&journal).unwrap_or_default();
+            let changes = changes.lines().collect::<Vec<_>>().join(", ");
+            return Check::new("manifest", Outcome::Critical, format!("changes left unfinished: {changes}"));
+        }
+    }
+    let newest = versions.and_then(|versions| versions.iter().max().copied());
+    if let (Some(head), Some(newest)) = (head, newest) {
+        if head != newest {
+            let detail = format!("the head is version {head}, but {newest} is newer");
+            return Check::new("manifest", Outcome::Critical, detail);
+        }
+    }
+    let name = target.file_name().unwrap_or_default();
+    let named = fs::read_dir(mountpoint.join(".snapshots")).into_iter().flatten().flatten();
+    for entry in named {
+        if let Err(e) = fs::metadata(entry.path().join(name)) {
+            let msg = format!("version named {:?} doesn't read: {e}", entry.file_name());
+            return Check::new("manifest", Outcome::Critical, msg);
+        }
+    }
+    Check::new("manifest", Outcome::Ok, "consistent")
+}
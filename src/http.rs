@@ -0,0 +1,173 @@
+//! A tiny HTTP/1.1 server, just enough for the web UI.
+
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::Arc;
+use std::thread;
+
+use log::{info, warn};
+
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    /// Headers as sent, names lowercased
+    pub headers: Vec<(String, String)>,
+    /// The address the request came in on
+    pub local: SocketAddr,
+}
+
+impl Request {
+    /// The value of header `name`, lowercase, if sent.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(n, _)| n == name).map(|(_, value)| value.as_str())
+    }
+
+    /// Whether the request was made for the address it came in on. Browsers
+    /// ask for the host a request is meant for in `Host`, which a page can't
+    /// forge, so a page that had its own name rebound to this address is
+    /// turned away here.
+    pub fn for_this_host(&self) -> bool {
+        let host = match self.header("host") {
+            Some(host) => host,
+            None => return false,
+        };
+        let port = self.local.port().to_string();
+        match host.rsplit_once(':') {
+            Some((name, p)) if p == port => {
+                let name = name.trim_start_matches('[').trim_end_matches(']');
+                self.local.ip().is_unspecified()
+                    || name.parse().ok() == Some(self.local.ip())
+                    || name.eq_ignore_ascii_case("localhost") && self.local.ip().is_loopback()
+            },
+            _ => false,
+        }
+    }
+
+    /// Whether the request was made for the address it came in on, and by a
+    /// page served from it if by a page at all. Browsers let any page post a
+    /// form to any address, loopback included, but always say which page
+    /// posted it in `Origin`.
+    pub fn same_origin(&self) -> bool {
+        let host = self.header("host").unwrap_or_default();
+        self.for_this_host() && self.header("origin").is_none_or(|origin| origin == format!("http://{host}"))
+    }
+}
+
+pub struct Response {
+    pub status: u16,
+    pub headers: Vec<(&'static str, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    pub fn new(status: u16, content_type: &str, body: impl Into<Vec<u8>>) -> Self {
+        Response {
+            status,
+            headers: vec![("Content-Type", content_type.to_string())],
+            body: body.into(),
+        }
+    }
+
+    pub fn text(status: u16, body: impl Into<Vec<u8>>) -> Self {
+        Response::new(status, "text/plain; charset=utf-8", body)
+    }
+
+    pub fn redirect(location: &str) -> Self {
+        let mut response = Response::text(303, "");
+        response.headers.push(("Location", location.to_string()));
+        response
+    }
+
+    pub fn not_found() -> Self { Response::text(404, "not found\n") }
+
+    pub fn with_header(mut self, name: &'static str, value: String) -> Self {
+        self.headers.push((name, value));
+        self
+    }
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        201 => "Created",
+        303 => "See Other",
+        400 => "Bad Request",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        _ => "Internal Server Error",
+    }
+}
+
+/// Serve `handler` on `listener` from a background thread, one thread per connection.
+pub fn serve<F>(listener: TcpListener, handler: F) -> io::Result<()>
+where
+    F: Fn(&Request) -> Response + Send + Sync + 'static,
+{
+    info!("listening on http://{}", listener.local_addr()?);
+    let handler = Arc::new(handler);
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let handler = handler.clone();
+            match stream {
+                Ok(stream) => {
+                    thread::spawn(move || {
+                        if let Err(e) = handle(stream, &*handler) {
+                            warn!("http connection failed: {e}");
+                        }
+                    });
+                },
+                Err(e) => warn!("http accept failed: {e}"),
+            }
+        }
+    });
+    Ok(())
+}
+
+fn handle<F>(stream: TcpStream, handler: &F) -> io::Result<()>
+where
+    F: Fn(&Request) -> Response,
+{
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let response = match read_request(&mut reader, stream.local_addr()?)? {
+        Ok(request) => {
+            info!("http {} {}", request.method, request.path);
+            handler(&request)
+        },
+        Err(status) => Response::text(status, format!("{}\n", reason(status))),
+    };
+    write_response(stream, &response)
+}
+
+fn read_request<R: BufRead>(reader: &mut R, local: SocketAddr) -> io::Result<Result<Request, u16>> {
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let mut parts = line.split_whitespace();
+    let (method, path) = match (parts.next(), parts.next()) {
+        (Some(method), Some(path)) => (method.to_string(), path.to_string()),
+        _ => return Ok(Err(400)),
+    };
+
+    let mut headers = Vec::new();
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 || line.trim_end().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            headers.push((name.trim().to_ascii_lowercase(), value.trim().to_string()));
+        }
+    }
+    Ok(Ok(Request { method, path, headers, local }))
+}
+
+fn write_response(mut stream: TcpStream, response: &Response) -> io::Result<()> {
+    let mut head = format!("HTTP/1.1 {} {}\r\n", response.status, reason(response.status));
+    for (name, value) in &response.headers {
+        head.push_str(&format!("{name}: {value}\r\n"));
+    }
+    head.push_str(&format!("Content-Length: {}\r\nConnection: close\r\n\r\n", response.body.len()));
+    stream.write_all(head.as_bytes())?;
+    stream.write_all(&response.body)?;
+    stream.flush()
+}
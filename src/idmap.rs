@@ -0,0 +1,31 @@
+//! Translation between the ids owning the store and the ids presented in the
+//! mount, for `--map-uid` and `--map-gid`.
+
+/// Pairs of (store id, mount id); unlisted ids are presented unchanged.
+#[derive(Clone, Debug, Default)]
+pub struct IdMap {
+    pairs: Vec<(u32, u32)>,
+}
+
+impl IdMap {
+    pub fn new(pairs: Vec<(u32, u32)>) -> Self {
+        IdMap { pairs }
+    }
+
+    /// The id presented for `id` owning something in the store.
+    pub fn to_mount(&self, id: u32) -> u32 {
+        self.pairs.iter().find(|&&(from, _)| from == id).map_or(id, |&(_, to)| to)
+    }
+
+    /// The id in the store for `id` given through the mount.
+    pub fn to_store(&self, id: u32) -> u32 {
+        self.pairs.iter().find(|&&(_, to)| to == id).map_or(id, |&(from, _)| from)
+    }
+}
+
+/// Parse a `FROM:TO` mapping.
+pub fn parse(s: &str) -> Result<(u32, u32), String> {
+    let (from, to) = s.split_once(':').ok_or("expected FROM:TO")?;
+    let id = |s: &str| s.parse::<u32>().map_err(|e| format!("{s:?}: {e}"));
+    Ok((id(from)?, id(to)?))
+}
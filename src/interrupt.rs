@@ -0,0 +1,80 @@
+//! Giving up on requests whose caller stopped waiting for them.
+//!
+//! fuser answers `FUSE_INTERRUPT` itself and serves one request at a time,
+//! so the interrupts the kernel sends never reach a request in progress.
+//! Instead, long-running operations poll whether the process that made the
+//! request has a signal pending that it neither blocks nor ignores, which is
+//! when the kernel sends an interrupt.
+
+use std::cell::Cell;
+use std::fs;
+use std::io;
+use std::time::{Duration, Instant};
+
+/// How often to look at the caller's signals
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The caller of a request, as far as it has been seen
+#[derive(Clone, Copy)]
+struct Caller {
+    pid: u32,
+    checked: Instant,
+    interrupted: bool,
+}
+
+thread_local! {
+    /// The caller of the request being served on this thread
+    static CALLER: Cell<Option<Caller>> = const { Cell::new(None) };
+}
+
+/// Stops watching the caller when dropped.
+pub struct Watch(());
+
+impl Drop for Watch {
+    fn drop(&mut self) {
+        CALLER.with(|caller| caller.set(None));
+    }
+}
+
+/// Let `check` fail once the thread `pid` is interrupted, until the returned
+/// guard is dropped.
+pub fn watch(pid: u32) -> Watch {
+    CALLER.with(|caller| caller.set(Some(Caller { pid, checked: Instant::now(), interrupted: false })));
+    Watch(())
+}
+
+/// Fail with `EINTR` if the caller being watched on this thread has been
+/// interrupted, and from then on. Cheap enough to call often, and always
+/// passes on threads not serving a request.
+pub fn check() -> io::Result<()> {
+    let mut caller = match CALLER.with(Cell::get) {
+        Some(caller) => caller,
+        None => return Ok(()),
+    };
+    if !caller.interrupted && caller.checked.elapsed() >= POLL_INTERVAL {
+        caller.checked = Instant::now();
+        caller.interrupted = interrupted(caller.pid);
+        CALLER.with(|cell| cell.set(Some(caller)));
+    }
+    match caller.interrupted {
+        true => Err(io::Error::from_raw_os_error(libc::EINTR)),
+        false => Ok(()),
+    }
+}
+
+/// Whether the thread `pid` has a signal pending that would interrupt it.
+fn interrupted(pid: u32) -> bool {
+    let status = match fs::read_to_string(format!("/proc/{pid}/status")) {
+        Ok(status) => status,
+        // gone altogether, or no procfs to tell
+        Err(e) => return e.kind() == io::ErrorKind::NotFound && cfg!(target_os = "linux"),
+    };
+    let mask = |key: &str| {
+        status
+            .lines()
+            .find_map(|line| line.strip_prefix(key))
+            .and_then(|value| u64::from_str_radix(value.trim(), 16).ok())
+            .unwrap_or(0)
+    };
+    (mask("SigPnd:") | mask("ShdPnd:")) & !mask("SigBlk:") & !mask("SigIgn:") != 0
+}
@@ -1,26 +1,122 @@
+mod api;
+mod archive;
+mod bench;
+mod cache;
+mod chunk;
+mod compact;
+mod completions;
+mod compress;
+mod config;
+mod convert;
+mod cron;
+mod delta;
+mod diff;
+mod glob;
+mod hash;
+mod health;
+mod http;
+mod idmap;
+mod man;
+mod interrupt;
+mod mmap;
+mod notify;
+mod pidfile;
+mod privilege;
+mod repair;
+mod retry;
+#[cfg(target_os = "linux")]
+mod sandbox;
+mod scratch;
+mod store;
+mod systemd;
+mod usage;
+#[cfg(target_os = "linux")]
+mod watch;
+mod web;
+mod xattr;
+mod zstd;
+
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::env;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::net::{SocketAddr, TcpListener};
+use std::os::unix::io::FromRawFd;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::ffi::{OsStr, OsString, CString};
-use std::time::{Duration, UNIX_EPOCH};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use std::fs;
-use std::os::unix::fs::MetadataExt;
+use std::io::Write;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::os::unix::ffi::OsStrExt;
 
-use log::info;
-use clap::{crate_version, arg, value_parser, Command};
+use log::{error, info, warn, LevelFilter};
+use clap::{crate_version, arg, value_parser, Arg, ArgMatches, Command};
 use libc::{
     c_int, c_void,
-    ENOENT, ENOSYS, EEXIST,
-    O_WRONLY, O_RDWR, O_TRUNC, O_CREAT,
+    ENOENT, ENOSYS, EEXIST, EACCES, EISDIR, EINVAL, EIO, EPERM, ENOTSUP, ERANGE, EROFS, ESTALE,
+    ENAMETOOLONG, ENOTDIR, ENOSPC, EDQUOT,
+    O_RDONLY, O_WRONLY, O_RDWR, O_TRUNC, O_CREAT, O_ACCMODE,
+    R_OK, W_OK, X_OK,
 };
+/// "No such attribute", which Linux spells `ENODATA`
+#[cfg(target_os = "linux")]
+use libc::ENODATA as ENOATTR;
+#[cfg(not(target_os = "linux"))]
+use libc::ENOATTR;
+/// macOS has no `O_DIRECT`, `F_NOCACHE` is set through `fcntl` instead
+#[cfg(not(target_os = "macos"))]
+use libc::O_DIRECT;
+#[cfg(target_os = "macos")]
+const O_DIRECT: c_int = 0;
+use compact::{Compactor, Policy};
+use idmap::IdMap;
+use mmap::Mmap;
+use scratch::Scratch;
+use store::{Content, Durability, Naming, Store, SharedStore};
 use fuser::{
     Filesystem,
-    Request, ReplyEntry, ReplyDirectory, ReplyData, ReplyAttr,
-    ReplyOpen, ReplyLseek, ReplyWrite,
-    FileType, FileAttr,
+    Request, ReplyEntry, ReplyDirectory, ReplyDirectoryPlus, ReplyData, ReplyAttr,
+    ReplyOpen, ReplyCreate, ReplyLseek, ReplyWrite, ReplyEmpty, ReplyXattr, TimeOrNow,
+    FileType, FileAttr, MountOption, BackgroundSession,
+    consts,
 };
 
 const TTL: Duration = Duration::from_secs(1);
 
+const ROOT_INO: u64 = 1;
+const TARGET_INO: u64 = 2;
+const VERSIONS_DIR_INO: u64 = 3;
+const DIFF_DIR_INO: u64 = 4;
+const SNAPSHOTS_DIR_INO: u64 = 5;
+const NAMED_DIR_INO: u64 = 6;
+/// `.versions/diff/N` is served under inode `DIFF_INO_BASE + N`.
+const DIFF_INO_BASE: u64 = 1 << 32;
+/// The snapshot directory of version N and the target in it
+const SNAPSHOT_DIR_INO_BASE: u64 = 2 << 32;
+const SNAPSHOT_INO_BASE: u64 = 3 << 32;
+/// `.snapshots/NAME`, numbered by a hash of the name so that the inode is
+/// the same across remounts; the target in it is that of the snapshot directory.
+const NAMED_DIR_INO_BASE: u64 = 4 << 32;
+/// Symbolic links made next to the target, numbered in the order they were made
+const LINK_INO_BASE: u64 = 5 << 32;
+/// Scratch files made next to the target, numbered in the order they were seen
+const SCRATCH_INO_BASE: u64 = 6 << 32;
+/// Bits of a per-version inode holding the version
+const VERSION_MASK: u64 = (1 << 32) - 1;
+
+/// The uid and gid root is squashed to
+const NOBODY: u32 = 65534;
+
+/// Longest name and symbolic link `--strict-posix` takes, as on Linux
+const NAME_MAX: usize = 255;
+const PATH_MAX: usize = 4096;
+
+const VERSIONS_DIR: &str = ".versions";
+const DIFF_DIR: &str = "diff";
+const SNAPSHOTS_DIR: &str = "snapshots";
+const NAMED_DIR: &str = ".snapshots";
+
 const PARENT_ATTR: FileAttr = FileAttr {
     ino: 1,
     size: 0,
@@ -39,298 +135,3424 @@ const PARENT_ATTR: FileAttr = FileAttr {
     blksize: 512,
 };
 
+#[cfg(target_os = "linux")]
 #[inline(always)]
 fn errno() -> i32 {
     unsafe { *libc::__errno_location() }
 }
 
-struct VersionFS {
-    /// ino: 1 root, 2 target, 3.. ino in default_dir
-    target: OsString,
-    target_dir: PathBuf,
-    version: usize,
+#[cfg(any(target_os = "macos", target_os = "freebsd"))]
+#[inline(always)]
+fn errno() -> i32 {
+    unsafe { *libc::__error() }
 }
 
-impl VersionFS {
-    fn path_for_version(&self, version: usize) -> PathBuf {
-        let filename = format!("{}.{}", version, self.target.to_str().unwrap());
-        self.target_dir.join(filename)
-    }
-
-    fn target_attr(&self, version: usize) -> Option<FileAttr> {
-        match version {
-            v if v > 0 => {
-                let size = fs::metadata(self.path_for_version(v))
-                    .and_then(|m| Ok(m.size()));
-                if let Ok(size) = size {
-                    Some(FileAttr {
-                        ino: 2,
-                        size: size,
-                        blocks: 1,
-                        atime: UNIX_EPOCH, // 1970-01-01 00:00:00
-                        mtime: UNIX_EPOCH,
-                        ctime: UNIX_EPOCH,
-                        crtime: UNIX_EPOCH,
-                        kind: FileType::RegularFile,
-                        perm: 0o777,
-                        nlink: 1,
-                        uid: 501,
-                        gid: 20,
-                        rdev: 0,
-                        flags: 0,
-                        blksize: 512,
-                    })
-                } else {
-                    None
-                }
-            },
+/// Attributes of a directory, owned by whoever owns the store.
+fn dir_attr(store: &Store, ino: u64) -> FileAttr {
+    let nlink = dir_nlink(store, ino);
+    match fs::metadata(&store.target_dir) {
+        Ok(m) => FileAttr { ino, nlink, uid: m.uid(), gid: m.gid(), ..PARENT_ATTR },
+        Err(_) => FileAttr { ino, nlink, ..PARENT_ATTR },
+    }
+}
+
+/// Tell the kernel there is no such name, as an entry with inode 0 that it
+/// keeps for as long as any other rather than asking again on every probe.
+fn reply_missing(reply: ReplyEntry) { reply.entry(&TTL, &FileAttr { ino: 0, ..PARENT_ATTR }, 0) }
+
+/// Links to directory `ino`: its entry, its ".", and the ".." of each of
+/// its subdirectories.
+fn dir_nlink(store: &Store, ino: u64) -> u32 {
+    let subdirs = match ino {
+        ROOT_INO if store.version > 0 => 2,
+        VERSIONS_DIR_INO => 2,
+        SNAPSHOTS_DIR_INO => snapshots(store).len(),
+        NAMED_DIR_INO => store.names.len(),
+        _ => 0,
+    };
+    2 + subdirs as u32
+}
+
+/// Names of snapshot `version`: one in each directory of `.snapshots` naming
+/// it, and one under `.versions/snapshots` unless a later version modified
+/// within the same second takes its place there.
+fn snapshot_nlink(store: &Store, version: usize) -> u32 {
+    let named = store.names.values().filter(|&&named| named == version).count();
+    let name = |version| store.version_meta(version).ok().map(|m| snapshot_name(m.modified));
+    let listed = match name(version) {
+        Some(own) => version == store.version || name(version + 1) != Some(own),
+        None => false,
+    };
+    (named + listed as usize) as u32
+}
+
+/// Whether `uid` in group `gid` may access a file with `attr` for `mask`, a
+/// combination of `R_OK`, `W_OK` and `X_OK`. Only the primary group is taken
+/// into account; `--default-permissions` has the kernel check the rest.
+fn permitted(attr: &FileAttr, uid: u32, gid: u32, mask: i32) -> bool {
+    let mask = mask as u16 & 0o7;
+    if uid == 0 {
+        // root may execute only what someone can
+        return mask & X_OK as u16 == 0 || attr.perm & 0o111 != 0;
+    }
+    let bits = if uid == attr.uid {
+        attr.perm >> 6
+    } else if gid == attr.gid {
+        attr.perm >> 3
+    } else {
+        attr.perm
+    };
+    bits & mask == mask
+}
+
+/// How recently the filesystem was used, for `--idle-timeout`.
+struct Activity {
+    last: Mutex<Instant>,
+    open_files: AtomicUsize,
+}
+
+impl Activity {
+    fn new() -> Self {
+        Activity { last: Mutex::new(Instant::now()), open_files: AtomicUsize::new(0) }
+    }
+
+    fn touch(&self) { *self.last.lock().unwrap() = Instant::now(); }
+
+    /// How long nothing has happened, or `None` while files are open.
+    fn idle_for(&self) -> Option<Duration> {
+        match self.open_files.load(Ordering::SeqCst) {
+            0 => Some(self.last.lock().unwrap().elapsed()),
             _ => None,
         }
     }
+}
 
-    fn current_target_attr(&self) -> Option<FileAttr> { self.target_attr(self.version) }
+struct VersionFS {
+    /// ino: 1 root, 2 target, 3 .versions, 4 .versions/diff, 5 .versions/snapshots,
+    /// DIFF_INO_BASE + N diff of N, SNAPSHOT_(DIR_)INO_BASE + N snapshot of N
+    store: SharedStore,
+    activity: Arc<Activity>,
+    uids: IdMap,
+    gids: IdMap,
+    /// Permissions are checked by the kernel rather than here
+    default_permissions: bool,
+    /// Requests from root are checked as coming from nobody
+    root_squash: bool,
+    /// Check name lengths and answer directory calls as POSIX has it
+    strict_posix: bool,
+    /// When names were last added to or removed from the root, which only
+    /// `--strict-posix` keeps
+    root_changed: Option<SystemTime>,
+    /// Listings taken when directories were opened, by handle
+    dirs: HashMap<u64, Vec<DirEntry>>,
+    /// Content of open snapshots and diffs, by handle
+    snapshots: HashMap<u64, Content>,
+    /// The head as mapped for each handle of the target, with its version;
+    /// dropped once written in place, and mapped anew once the head moves on
+    heads: HashMap<u64, (usize, Mmap)>,
+    /// Handles of the target opened for writing, which closing syncs
+    writable: HashSet<u64>,
+    /// Inodes of the symbolic links made next to the target, by name
+    links: BTreeMap<OsString, u64>,
+    /// Those symbolic links, by inode
+    pending: HashMap<u64, PendingLink>,
+    next_link: u64,
+    /// Other files made next to the target
+    scratch: Scratch,
+    next_fh: u64,
+    /// Hash of the head as last asked for, with its version, modification
+    /// time and size when hashed
+    head_hash: Option<((usize, SystemTime, u64), String)>,
+    /// What the head is hashed with, as chunks are
+    hash: hash::Algorithm,
+    /// Bytes of sequential writes to gather per handle of the target before
+    /// writing them out at once, none if 0
+    write_buffer: usize,
+    /// Writes gathered so far, by handle
+    buffers: HashMap<u64, Gathered>,
+    /// Version, modification time and size of the head when the target was
+    /// last opened, to tell whether the kernel may keep what it cached
+    opened_head: Option<(usize, SystemTime, u64)>,
+    /// Open the head as it is for writing when the store has no room for a
+    /// new version, rather than failing with `ENOSPC`
+    write_through_when_full: bool,
 }
 
-impl Filesystem for VersionFS {
-    fn init(&mut self, _req: &Request, _config: &mut fuser::KernelConfig) -> Result<(), c_int> {
-        self.version = 1;
-        let path = self.path_for_version(self.version);
-        fs::write(path, &[]).unwrap();
+/// Sequential writes through one handle of the target, not yet written out
+struct Gathered {
+    offset: i64,
+    data: Vec<u8>,
+}
+
+/// A symbolic link made next to the target, as `ln -sf` does before renaming
+/// it onto the target. Only kept in memory until then.
+struct PendingLink {
+    link: PathBuf,
+    /// Owner as in the store
+    uid: u32,
+    gid: u32,
+    created: SystemTime,
+}
+
+impl PendingLink {
+    fn attr(&self, ino: u64, nlink: u32) -> FileAttr {
+        FileAttr {
+            ino,
+            size: self.link.as_os_str().len() as u64,
+            blocks: 0,
+            atime: self.created,
+            mtime: self.created,
+            ctime: self.created,
+            crtime: self.created,
+            kind: FileType::Symlink,
+            perm: 0o777,
+            nlink,
+            uid: self.uid,
+            gid: self.gid,
+            rdev: 0,
+            flags: 0,
+            blksize: 4096,
+        }
+    }
+}
+
+impl VersionFS {
+    fn store(&self) -> MutexGuard<'_, Store> { self.store.lock().unwrap() }
+
+    /// Write out what was gathered through handle `fh`, if anything.
+    fn write_out(&mut self, store: &Store, fh: u64) -> Result<(), c_int> {
+        let gathered = match self.buffers.get_mut(&fh) {
+            Some(gathered) if !gathered.data.is_empty() => gathered,
+            _ => return Ok(()),
+        };
+        let data = std::mem::take(&mut gathered.data);
+        let mut done = 0;
+        while done < data.len() {
+            let offset = gathered.offset + done as i64;
+            let written = store.retry.run(|| match pwrite_direct(fh as i32, &data[done..], offset) {
+                -1 => Err(std::io::Error::last_os_error()),
+                ret => Ok(ret as usize),
+            });
+            // the writer has long been told these made it, so the error goes
+            // to whatever it asks next, as with writeback by the kernel
+            done += written.map_err(|e| e.raw_os_error().unwrap_or(EIO))?;
+        }
+        gathered.offset += done as i64;
+        self.heads.clear();
         Ok(())
     }
 
-    fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
-        info!("lookup {parent} {name:?}");
-        info!("self.version = {}", self.version);
-        if parent == 1 && name == self.target {
-            let attr =
-                self.target_attr(self.version)
-                    .or(self.target_attr(self.version - 1))
-                    .unwrap();
-            reply.entry(&TTL, &attr, 0);
-        } else {
-            reply.error(ENOENT);
+    /// Write out what was gathered through every handle, before the target
+    /// is looked at, cut or changed otherwise.
+    fn write_out_all(&mut self) -> Result<(), c_int> {
+        if self.buffers.values().all(|gathered| gathered.data.is_empty()) {
+            return Ok(());
         }
+        let shared = self.store.clone();
+        let store = shared.lock().unwrap();
+        let handles: Vec<u64> = self.buffers.keys().copied().collect();
+        handles.into_iter().try_for_each(|fh| self.write_out(&store, fh))
     }
 
-    fn getattr(&mut self, _req: &Request, ino: u64, reply: ReplyAttr) {
-        info!("getattr {ino}");
-        match ino {
-            1 => reply.attr(&TTL, &PARENT_ATTR),
-            2 if self.version > 0 => reply.attr(&TTL, &self.current_target_attr().unwrap()),
-            _ => reply.error(ENOENT),
+    /// Value of the attribute `name` of `ino` that the mount answers itself,
+    /// if it is one.
+    fn status_xattr(&mut self, ino: u64, name: &OsStr) -> Option<std::io::Result<String>> {
+        if ino != TARGET_INO {
+            return None;
+        }
+        let shared = self.store.clone();
+        let store = shared.lock().unwrap();
+        match name.to_str()? {
+            xattr::VERSION => Some(Ok(store.version.to_string())),
+            xattr::STORE => Some(Ok(store.target_dir.to_string_lossy().into_owned())),
+            xattr::HEAD_HASH => Some(self.head_hash(&store)),
+            xattr::HISTORY => Some(Ok(history(&store))),
+            xattr::DEGRADED => Some(Ok(store.degraded.clone().unwrap_or_default())),
+            _ => None,
         }
     }
 
-    fn mknod(
-        &mut self,
-        _req: &Request,
-        parent: u64,
-        name: &OsStr,
-        _mode: u32,
-        _umask: u32,
-        _rdev: u32,
-        reply: ReplyEntry,
-    ) {
-        info!("mknod {parent} {name:?}");
-        if parent == 1 && name == self.target {
-            reply.error(EEXIST);
-        } else {
-            reply.error(ENOSYS);
+    /// Hash of the head, hashed again only once it changed.
+    fn head_hash(&mut self, store: &Store) -> std::io::Result<String> {
+        let meta = store.version_meta(store.version)?;
+        let key = (store.version, meta.modified, meta.len);
+        if let Some((hashed, hash)) = &self.head_hash {
+            if *hashed == key {
+                return Ok(hash.clone());
+            }
         }
+        let digest = self.hash.digest(&store.map_version(store.version)?);
+        let hash = format!("{}:{}", self.hash.name(), hash::hex(&digest));
+        self.head_hash = Some((key, hash.clone()));
+        Ok(hash)
     }
 
-    fn read(
-        &mut self,
-        _req: &Request,
-        ino: u64,
-        _fh: u64,
-        offset: i64,
-        size: u32,
-        _flags: i32,
-        _lock: Option<u64>,
-        reply: ReplyData,
-    ) {
-        info!("read {_fh}");
-        if ino == 2 && self.version > 0 {
-            let path = self.path_for_version(self.version);
-            let data = fs::read(path).unwrap();
-            let start = offset as usize;
-            let end: usize = data.len().min(start + size as usize);
-            reply.data(&data[start..end]);
-        } else {
-            reply.error(ENOENT);
+    /// `attr` with its owner as presented in the mount.
+    fn present(&self, attr: FileAttr) -> FileAttr {
+        FileAttr { uid: self.uids.to_mount(attr.uid), gid: self.gids.to_mount(attr.gid), ..attr }
+    }
+
+    /// Presented attributes of `ino`, or the errno to reply with.
+    fn attr(&self, store: &mut Store, ino: u64) -> Result<FileAttr, c_int> {
+        let attr = match split_ino(ino) {
+            (ROOT_INO | VERSIONS_DIR_INO | DIFF_DIR_INO | SNAPSHOTS_DIR_INO | NAMED_DIR_INO, 0) => Some(dir_attr(store, ino)),
+            (TARGET_INO, 0) if store.version > 0 => current_target_attr(store),
+            (DIFF_INO_BASE, version) => diff_attr(store, version),
+            (SNAPSHOT_DIR_INO_BASE, version) => snapshot_dir_attr(store, version),
+            (SNAPSHOT_INO_BASE, version) => snapshot_attr(store, version),
+            (NAMED_DIR_INO_BASE, _) => named_version(store, ino)
+                .and_then(|version| snapshot_dir_attr(store, version))
+                .map(|attr| FileAttr { ino, ..attr }),
+            (LINK_INO_BASE, _) => match self.link_attr(ino) {
+                Some(attr) => Some(attr),
+                // renamed onto the target, which the kernel knows by this
+                // inode until it looks the name up again
+                None => current_target_attr(store).map(|attr| FileAttr { ino, ..attr }),
+            },
+            (SCRATCH_INO_BASE, _) => match self.scratch.path_of(ino) {
+                Some(path) => fs::symlink_metadata(path).ok().map(|meta| scratch_attr(ino, &meta)),
+                // likewise
+                None if self.scratch.adopted(ino) => {
+                    current_target_attr(store).map(|attr| FileAttr { ino, ..attr })
+                },
+                None => None,
+            },
+            _ => None,
+        };
+        let attr = match (ino, attr, self.root_changed) {
+            (ROOT_INO, Some(attr), Some(time)) => Some(FileAttr { mtime: time, ctime: time, ..attr }),
+            (_, attr, _) => attr,
+        };
+        match (ino, attr) {
+            (_, Some(attr)) => Ok(self.present(attr)),
+            (TARGET_INO, None) => Err(EIO),
+            // e.g. an NFS handle of a version that has been removed since
+            (ino, None) if split_ino(ino).1 != 0 => Err(ESTALE),
+            (_, None) => Err(ENOENT),
         }
     }
 
-    fn readdir(
-        &mut self,
-        _req: &Request,
-        ino: u64,
-        _fh: u64,
-        offset: i64,
-        mut reply: ReplyDirectory,
-    ) {
-        info!("readdir {ino} {_fh}");
-        if ino != 1 {
-            reply.error(ENOENT);
-            return;
+    /// The uid and gid that permissions are checked for.
+    fn caller(&self, req: &Request) -> (u32, u32) {
+        match (req.uid(), self.root_squash) {
+            (0, true) => (NOBODY, NOBODY),
+            _ => (req.uid(), req.gid()),
         }
+    }
 
-        let mut entries = vec![
-            (1, FileType::Directory, "."),
-            (1, FileType::Directory, ".."),
-        ];
+    /// Fail unless the caller may change attribute `name` of `ino`: its
+    /// owner for ACLs, root for security attributes.
+    fn check_xattr(&self, req: &Request, store: &mut Store, ino: u64, name: &OsStr) -> Result<(), c_int> {
+        let attr = self.attr(store, ino)?;
+        let (uid, _) = self.caller(req);
+        // the daemon may well be root, so the kernel can't be relied on here
+        if xattr::privileged(name) {
+            return if uid == 0 { Ok(()) } else { Err(EPERM) };
+        }
+        match self.default_permissions || uid == 0 || uid == attr.uid {
+            true => Ok(()),
+            false => Err(EPERM),
+        }
+    }
 
-        if self.version > 0 {
-            entries.push(
-                (2, FileType::RegularFile, self.target.to_str().unwrap())
-            );
+    /// Entries of directory `ino`, as of opening it if `fh` is a handle.
+    fn listing(&self, ino: u64, fh: u64) -> Option<Vec<DirEntry>> {
+        match self.dirs.get(&fh) {
+            Some(entries) => Some(entries.clone()),
+            None => self.entries(ino),
         }
+    }
 
-        for (i, entry) in entries.into_iter().enumerate().skip(offset as usize) {
-            if reply.add(entry.0, (i + 1) as i64, entry.1, entry.2) {
-                break;
+    /// Entries of directory `ino` as it is now.
+    fn entries(&self, ino: u64) -> Option<Vec<DirEntry>> {
+        let mut entries = dir_entries(&self.store(), ino)?;
+        if ino == ROOT_INO {
+            for (name, &ino) in &self.links {
+                entries.push((ino, FileType::Symlink, name.to_string_lossy().into()));
+            }
+            match self.scratch.entries() {
+                Ok(files) => for (ino, name) in files {
+                    entries.push((ino, FileType::RegularFile, name.to_string_lossy().into()));
+                },
+                Err(e) => warn!("cannot list the scratch files: {e}"),
             }
         }
-        reply.ok();
+        Some(entries)
     }
 
-    fn open(&mut self, _req: &Request, ino: u64, flags: i32, reply: ReplyOpen) {
-        info!("open {ino} {flags:b}");
-        match ino {
-            2 => {
-                if flags & O_WRONLY != 0 || flags & O_RDWR != 0 || flags & O_CREAT != 0 {
-                    self.version += 1;
-                    let newpath = self.path_for_version(self.version);
-                    if self.version > 1 && flags & O_TRUNC == 0 {
-                        let oldpath = self.path_for_version(self.version - 1);
-                        fs::copy(oldpath, newpath).unwrap();
-                    } else {
-                        fs::write(newpath, &[]).unwrap();
-                    }
-                }
-                let path = self.path_for_version(self.version);
-                let cpath = CString::new(path.to_str().unwrap()).unwrap();
-                match unsafe { libc::open(cpath.as_ptr(), flags) } {
-                    -1 => reply.error(errno()),
-                    fd => reply.opened(fd.try_into().unwrap(), flags.try_into().unwrap()),
-                };
-            },
-            _ => reply.error(ENOSYS),
+    /// Attributes of the symbolic link made next to the target as `ino`.
+    fn link_attr(&self, ino: u64) -> Option<FileAttr> {
+        let nlink = self.links.values().filter(|&&other| other == ino).count();
+        self.pending.get(&ino).map(|link| link.attr(ino, nlink as u32))
+    }
+
+    /// Remove `name` of a symbolic link made next to the target, and the
+    /// link along with its last name.
+    fn unname_link(&mut self, name: &OsStr) -> Option<u64> {
+        let ino = self.links.remove(name)?;
+        if !self.links.values().any(|&other| other == ino) {
+            self.pending.remove(&ino);
         }
+        Some(ino)
     }
 
-    fn release(
-        &mut self,
-        _req: &Request<'_>,
-        _ino: u64,
-        fh: u64,
-        flags: i32,
-        _lock_owner: Option<u64>,
-        _flush: bool,
-        reply: fuser::ReplyEmpty,
-    ) {
-        info!("release {fh} {flags:b}");
-        unsafe { libc::close(fh as i32); }
-        reply.ok();
+    fn permitted(&self, req: &Request, attr: &FileAttr, mask: i32) -> bool {
+        let (uid, gid) = self.caller(req);
+        self.default_permissions || permitted(attr, uid, gid, mask)
     }
 
-    fn write(
-        &mut self,
-        _req: &Request<'_>,
-        ino: u64,
-        fh: u64,
-        offset: i64,
-        data: &[u8],
-        _write_flags: u32,
-        flags: i32,
-        _lock_owner: Option<u64>,
-        reply: ReplyWrite,
-    ) {
-        info!("write {ino} {fh} {offset} {flags:b}");
-        let buf = data.as_ptr() as *const c_void;
-        match unsafe { libc::pwrite(fh as i32, buf, data.len(), offset) } {
-            -1 => reply.error(errno()),
-            ret => reply.written(ret as u32),
+    /// Fail with `ENAMETOOLONG` on names longer than `NAME_MAX` under
+    /// `--strict-posix`, which the kernel leaves to the filesystem.
+    fn check_name_len(&self, name: &OsStr) -> Result<(), c_int> {
+        match self.strict_posix && name.len() > NAME_MAX {
+            true => Err(ENAMETOOLONG),
+            false => Ok(()),
         }
     }
 
-    fn lseek(
-        &mut self,
-        _req: &Request<'_>,
-        ino: u64,
-        fh: u64,
-        offset: i64,
-        whence: i32,
-        reply: ReplyLseek,
-    ) {
-        info!("lseek {ino} {fh} {offset} {whence}");
-        match unsafe { libc::lseek(fh as i32, offset, whence) } {
-            -1 => reply.error(errno()),
-            ret => reply.offset(ret),
+    /// Note that a name was added to or removed from the root.
+    fn root_changed(&mut self) {
+        if self.root_changed.is_some() {
+            self.root_changed = Some(SystemTime::now());
         }
     }
 
-    fn setattr(
-        &mut self,
-        _req: &Request<'_>,
-        _ino: u64,
-        _mode: Option<u32>,
-        _uid: Option<u32>,
-        _gid: Option<u32>,
-        _size: Option<u64>,
-        _atime: Option<fuser::TimeOrNow>,
-        _mtime: Option<fuser::TimeOrNow>,
-        _ctime: Option<std::time::SystemTime>,
-        _fh: Option<u64>,
-        _crtime: Option<std::time::SystemTime>,
-        _chgtime: Option<std::time::SystemTime>,
-        _bkuptime: Option<std::time::SystemTime>,
-        _flags: Option<u32>,
-        reply: ReplyAttr,
-    ) {
-        info!("setattr");
-        reply.attr(&TTL, &self.current_target_attr().unwrap());
+    /// Fail unless the caller may add and remove names in the root.
+    fn check_root(&self, req: &Request, store: &mut Store) -> Result<(), c_int> {
+        match self.attr(store, ROOT_INO)? {
+            attr if self.permitted(req, &attr, W_OK | X_OK) => Ok(()),
+            _ => Err(EACCES),
+        }
+    }
+
+    /// Fail unless the caller may make `name` in directory `parent`, which
+    /// is only possible next to the target, under names not taken yet.
+    fn check_new_name(&self, req: &Request, parent: u64, name: &OsStr) -> Result<(), c_int> {
+        if parent != ROOT_INO {
+            return Err(EACCES);
+        }
+        self.check_name_len(name)?;
+        let mut store = self.store();
+        self.check_root(req, &mut store)?;
+        let reserved = name == store.target || name == VERSIONS_DIR || name == NAMED_DIR;
+        match reserved || self.links.contains_key(name) || self.scratch.contains(name) {
+            true => Err(EEXIST),
+            false => Ok(()),
+        }
+    }
+
+    /// Create the scratch file `name` for the caller and open it with
+    /// `flags`. Returns its attributes and the fd.
+    fn create_scratch(
+        &self,
+        req: &Request,
+        name: &OsStr,
+        mode: u32,
+        flags: i32,
+    ) -> Result<(FileAttr, c_int), c_int> {
+        let owner = (self.uids.to_store(req.uid()), self.gids.to_store(req.gid()));
+        let fd = self.scratch.create(name, mode, flags, owner).map_err(|e| e.raw_os_error().unwrap_or(EIO))?;
+        match self.scratch.lookup(name) {
+            Ok((ino, meta)) => Ok((self.present(scratch_attr(ino, &meta)), fd)),
+            Err(e) => {
+                unsafe { libc::close(fd) };
+                Err(e.raw_os_error().unwrap_or(EIO))
+            },
+        }
     }
 }
 
-fn main() {
-    let matches = Command::new("versionfs")
-        .version(crate_version!())
-        .author("Hmm")
-        .arg(
-            arg!(<MOUNT_POINT> "Where the FUSE should be mounted")
-                .required(true)
-                .value_parser(value_parser!(PathBuf)),
-        )
-        .arg(
-            arg!(-t --target <FILE> "The target file to be versioned")
-                .required(true)
-                .value_parser(value_parser!(OsString)),
-        )
-        .arg(
-            arg!(-o --target_dir <DIR> "Where the versions of the target file should be saved")
-                .required(true)
-                .value_parser(value_parser!(PathBuf)),
-        )
-        .get_matches();
+fn target_attr(store: &Store, version: usize) -> Option<FileAttr> {
+    match version {
+        v if v > 0 => {
+            let attr = file_attr(store, &fs::metadata(store.path_for_version(v)).ok()?);
+            Some(match store.is_symlink(v) {
+                // the permissions of symbolic links are never checked
+                true => FileAttr { kind: FileType::Symlink, perm: 0o777, ..attr },
+                false => attr,
+            })
+        },
+        _ => None,
+    }
+}
 
-    env_logger::init();
-    let fs = VersionFS{
-        target: matches.get_one::<OsString>("target").unwrap().clone(),
-        target_dir: matches.get_one::<PathBuf>("target_dir").unwrap().clone(),
-        version: 0,
+/// Whether `version` of the target is a file or a symbolic link.
+fn target_kind(store: &Store, version: usize) -> FileType {
+    match store.is_symlink(version) {
+        true => FileType::Symlink,
+        false => FileType::RegularFile,
+    }
+}
+
+/// Attributes of the target as backed by the file with `meta`.
+fn file_attr(store: &Store, meta: &fs::Metadata) -> FileAttr {
+    FileAttr {
+        ino: TARGET_INO,
+        size: meta.size(),
+        // st_blocks is in 512-byte units whatever the block size
+        blocks: meta.blocks(),
+        atime: meta.accessed().unwrap_or(UNIX_EPOCH),
+        mtime: meta.modified().unwrap_or(UNIX_EPOCH),
+        ctime: UNIX_EPOCH + Duration::new(meta.ctime().max(0) as u64, meta.ctime_nsec() as u32),
+        crtime: store.created().unwrap_or(UNIX_EPOCH),
+        kind: FileType::RegularFile,
+        perm: (meta.mode() & 0o7777) as u16,
+        nlink: 1,
+        uid: meta.uid(),
+        gid: meta.gid(),
+        rdev: 0,
+        flags: 0,
+        blksize: meta.blksize() as u32,
+    }
+}
+
+/// Attributes of the target as seen through the open file `fh`, which may
+/// be bound to an older version than the head.
+fn handle_attr(store: &Store, fh: u64) -> Option<FileAttr> {
+    // borrow the fd without closing it
+    let file = std::mem::ManuallyDrop::new(unsafe { fs::File::from_raw_fd(fh as i32) });
+    file.metadata().ok().map(|meta| file_attr(store, &meta))
+}
+
+/// Attributes of the scratch file known as `ino`, with `meta`.
+fn scratch_attr(ino: u64, meta: &fs::Metadata) -> FileAttr {
+    let kind = match meta.file_type() {
+        t if t.is_dir() => FileType::Directory,
+        t if t.is_symlink() => FileType::Symlink,
+        _ => FileType::RegularFile,
     };
-    let mountpoint = matches.get_one::<PathBuf>("MOUNT_POINT").unwrap();
+    FileAttr {
+        ino,
+        size: meta.size(),
+        blocks: meta.blocks(),
+        atime: meta.accessed().unwrap_or(UNIX_EPOCH),
+        mtime: meta.modified().unwrap_or(UNIX_EPOCH),
+        ctime: UNIX_EPOCH + Duration::new(meta.ctime().max(0) as u64, meta.ctime_nsec() as u32),
+        crtime: meta.created().unwrap_or(UNIX_EPOCH),
+        kind,
+        perm: (meta.mode() & 0o7777) as u16,
+        nlink: meta.nlink() as u32,
+        uid: meta.uid(),
+        gid: meta.gid(),
+        rdev: 0,
+        flags: 0,
+        blksize: meta.blksize() as u32,
+    }
+}
 
-    let mut daemon = fuser::spawn_mount2(fs, mountpoint, &[]).ok();
+/// Apply a setattr of the target to the version open as `fh`, or else to the
+/// head. Truncating the head without a handle is a write like any other, so
+/// it cuts a new version first.
+fn set_target_attr(
+    store: &mut Store,
+    fh: Option<u64>,
+    mode: Option<u32>,
+    owner: (Option<u32>, Option<u32>),
+    size: Option<u64>,
+    times: [Option<TimeOrNow>; 2],
+) -> std::io::Result<()> {
+    if fh.is_none() && size.is_some() {
+        store.new_version(size == Some(0))?;
+    }
+    set_file_attr(&store.path_for_version(store.version), fh, mode, owner, size, times)
+}
 
-    ctrlc::set_handler(move || {
-        std::mem::drop(daemon.take());
-        std::process::exit(0);
-    }).unwrap();
-    loop {
-        std::thread::sleep(Duration::from_secs(10));
+/// Apply a setattr to the file open as `fh`, or else to the one at `path`.
+fn set_file_attr(
+    path: &Path,
+    fh: Option<u64>,
+    mode: Option<u32>,
+    (uid, gid): (Option<u32>, Option<u32>),
+    size: Option<u64>,
+    times: [Option<TimeOrNow>; 2],
+) -> std::io::Result<()> {
+    let check = |ret: c_int| match ret {
+        -1 => Err(std::io::Error::last_os_error()),
+        _ => Ok(()),
+    };
+    let times = times.map(timespec);
+    let set_times = times.iter().any(|time| time.tv_nsec != libc::UTIME_OMIT);
+    // chown before chmod and truncate before setting times, as the former
+    // clear the set-id bits and bump the times
+    if let Some(fh) = fh {
+        let fd = fh as c_int;
+        if uid.is_some() || gid.is_some() {
+            // -1 leaves the id as it is
+            check(unsafe { libc::fchown(fd, uid.unwrap_or(u32::MAX), gid.unwrap_or(u32::MAX)) })?;
+        }
+        if let Some(size) = size {
+            check(unsafe { libc::ftruncate(fd, size as libc::off_t) })?;
+        }
+        if let Some(mode) = mode {
+            check(unsafe { libc::fchmod(fd, (mode & 0o7777) as libc::mode_t) })?;
+        }
+        if set_times {
+            check(unsafe { libc::futimens(fd, times.as_ptr()) })?;
+        }
+        return Ok(());
+    }
+    if uid.is_some() || gid.is_some() {
+        std::os::unix::fs::chown(path, uid, gid)?;
+    }
+    if let Some(size) = size {
+        fs::File::options().write(true).open(path)?.set_len(size)?;
+    }
+    if let Some(mode) = mode {
+        fs::set_permissions(path, fs::Permissions::from_mode(mode & 0o7777))?;
+    }
+    if set_times {
+        let cpath = CString::new(path.as_os_str().as_bytes()).unwrap();
+        check(unsafe { libc::utimensat(libc::AT_FDCWD, cpath.as_ptr(), times.as_ptr(), 0) })?;
+    }
+    Ok(())
+}
+
+/// A time to set through `utimensat`, `None` leaving it as it is.
+fn timespec(time: Option<TimeOrNow>) -> libc::timespec {
+    let (secs, nanos) = match time {
+        None => (0, libc::UTIME_OMIT),
+        Some(TimeOrNow::Now) => (0, libc::UTIME_NOW),
+        Some(TimeOrNow::SpecificTime(time)) => {
+            let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+            (since_epoch.as_secs() as libc::time_t, since_epoch.subsec_nanos() as _)
+        },
+    };
+    libc::timespec { tv_sec: secs, tv_nsec: nanos }
+}
+
+fn current_target_attr(store: &Store) -> Option<FileAttr> { target_attr(store, store.version) }
+
+fn diff_attr(store: &mut Store, version: usize) -> Option<FileAttr> {
+    if !store.contains(version) {
+        return None;
+    }
+    let crtime = store.version_meta(version).map_or(UNIX_EPOCH, |m| m.created);
+    // sized 0 rather than rendered for every stat and listing, as in /proc;
+    // opened for direct IO, it is read to the end whatever its size
+    Some(FileAttr {
+        ino: DIFF_INO_BASE + version as u64,
+        size: 0,
+        blocks: 0,
+        crtime,
+        perm: 0o444,
+        ..current_target_attr(store)?
+    })
+}
+
+/// Split `ino` into its base and, for per-version nodes, the version.
+fn split_ino(ino: u64) -> (u64, usize) {
+    (ino & !VERSION_MASK, (ino & VERSION_MASK) as usize)
+}
+
+/// The directory holding `ino`.
+fn parent_of(ino: u64) -> Option<u64> {
+    match split_ino(ino) {
+        (ROOT_INO | TARGET_INO | VERSIONS_DIR_INO | NAMED_DIR_INO, 0) => Some(ROOT_INO),
+        (DIFF_DIR_INO | SNAPSHOTS_DIR_INO, 0) => Some(VERSIONS_DIR_INO),
+        (DIFF_INO_BASE, _) => Some(DIFF_DIR_INO),
+        (SNAPSHOT_DIR_INO_BASE, _) => Some(SNAPSHOTS_DIR_INO),
+        (SNAPSHOT_INO_BASE, version) => Some(SNAPSHOT_DIR_INO_BASE + version as u64),
+        (NAMED_DIR_INO_BASE, _) | (LINK_INO_BASE, _) | (SCRATCH_INO_BASE, _) => Some(ROOT_INO),
+        _ => None,
+    }
+}
+
+/// Inodes of the directories in `.snapshots`, with their names and the
+/// versions they hold. Each comes from the 32-bit FNV-1a hash of its name,
+/// probing onward past those taken by names sorting before it, so that an
+/// inode only moves if a name hashing alike is added before it.
+fn named_inos(store: &Store) -> Vec<(u64, &String, usize)> {
+    let mut taken = HashSet::new();
+    store
+        .names
+        .iter()
+        .map(|(name, &version)| {
+            let hash = name.bytes().fold(0x811c9dc5u32, |hash, b| (hash ^ b as u32).wrapping_mul(0x01000193));
+            let mut ino = hash.max(1);
+            while !taken.insert(ino) {
+                ino = ino.wrapping_add(1).max(1);
+            }
+            (NAMED_DIR_INO_BASE + ino as u64, name, version)
+        })
+        .collect()
+}
+
+/// Inode of `.snapshots/NAME`.
+fn named_ino(store: &Store, name: &str) -> Option<u64> {
+    named_inos(store).into_iter().find(|&(_, named, _)| named == name).map(|(ino, ..)| ino)
+}
+
+/// The version that `.snapshots/NAME` with inode `ino` holds.
+fn named_version(store: &Store, ino: u64) -> Option<usize> {
+    named_inos(store).into_iter().find(|&(named, ..)| named == ino).map(|(.., version)| version)
+}
+
+/// Versions by the name of their snapshot directory, `@GMT-YYYY.MM.DD-HH.MM.SS`
+/// of their modification time as Samba's shadow_copy2 expects. Of versions
+/// modified within the same second, only the last is listed.
+fn snapshots(store: &Store) -> BTreeMap<String, usize> {
+    let mut snapshots = BTreeMap::new();
+    for version in store.versions() {
+        if let Ok(m) = store.version_meta(version) {
+            snapshots.insert(snapshot_name(m.modified), version);
+        }
+    }
+    snapshots
+}
+
+fn snapshot_name(time: SystemTime) -> String {
+    // 2026-10-15T12:00:00Z
+    let time = humantime::format_rfc3339_seconds(time).to_string();
+    format!("@GMT-{}-{}", time[..10].replace('-', "."), time[11..19].replace(':', "."))
+}
+
+fn snapshot_dir_attr(store: &Store, version: usize) -> Option<FileAttr> {
+    let m = store.version_meta(version).ok()?;
+    Some(FileAttr { mtime: m.modified, crtime: m.created, ..dir_attr(store, SNAPSHOT_DIR_INO_BASE + version as u64) })
+}
+
+fn snapshot_attr(store: &Store, version: usize) -> Option<FileAttr> {
+    let m = store.version_meta(version).ok()?;
+    Some(FileAttr {
+        ino: SNAPSHOT_INO_BASE + version as u64,
+        size: m.len,
+        blocks: m.len.div_ceil(512),
+        mtime: m.modified,
+        crtime: m.created,
+        kind: target_kind(store, version),
+        perm: if store.is_symlink(version) { 0o777 } else { 0o444 },
+        nlink: snapshot_nlink(store, version),
+        ..current_target_attr(store)?
+    })
+}
+
+/// The latest versions, newest first, as compact JSON for
+/// `user.versionfs.history`.
+fn history(store: &Store) -> String {
+    let entries: Vec<String> = store
+        .versions()
+        .into_iter()
+        .rev()
+        .take(xattr::HISTORY_LEN)
+        .filter_map(|version| {
+            let m = store.version_meta(version).ok()?;
+            let mtime = humantime::format_rfc3339_seconds(m.modified);
+            Some(format!("{{\"version\":{version},\"mtime\":\"{mtime}\",\"size\":{}}}", m.len))
+        })
+        .collect();
+    format!("[{}]", entries.join(","))
+}
+
+/// `s` as a JSON string, quotes included.
+fn json_string(s: &str) -> String {
+    let mut quoted = String::from('"');
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// The file holding the extended attributes of `ino`: the head for the
+/// target, the version for its snapshots.
+fn xattr_path(store: &Store, ino: u64) -> Option<PathBuf> {
+    match split_ino(ino) {
+        (TARGET_INO, 0) => Some(store.path_for_version(store.version)),
+        (SNAPSHOT_INO_BASE, version) if store.contains(version) => Some(store.xattr_path(version)),
+        _ => None,
+    }
+}
+
+/// Reply with `value`, or just its size if the caller asked for that.
+fn reply_xattr(reply: ReplyXattr, size: u32, value: &[u8]) {
+    match size {
+        0 => reply.size(value.len() as u32),
+        size if (size as usize) < value.len() => reply.error(ERANGE),
+        _ => reply.data(value),
+    }
+}
+
+/// How the kernel should treat a file opened with `flags`: `O_DIRECT` opens
+/// bypass its page cache too.
+fn fopen_flags(flags: i32) -> u32 {
+    match flags & O_DIRECT {
+        0 => 0,
+        _ => consts::FOPEN_DIRECT_IO,
+    }
+}
+
+/// Flush the writes through the open file `fd` to disk, only its data and
+/// what is needed to read it back with `datasync`.
+fn sync_fd(fd: c_int, datasync: bool) -> std::io::Result<()> {
+    // borrow the fd without closing it
+    let file = std::mem::ManuallyDrop::new(unsafe { fs::File::from_raw_fd(fd) });
+    match datasync {
+        true => file.sync_data(),
+        false => file.sync_all(),
+    }
+}
+
+/// `pwrite`, through an aligned copy of `data` if `fd` was opened with
+/// `O_DIRECT` and refuses the buffer FUSE handed over.
+fn pwrite_direct(fd: c_int, data: &[u8], offset: i64) -> isize {
+    let ret = unsafe { libc::pwrite(fd, data.as_ptr() as *const c_void, data.len(), offset) };
+    if ret != -1 || errno() != libc::EINVAL || unsafe { libc::fcntl(fd, libc::F_GETFL) } & O_DIRECT == 0 {
+        return ret;
+    }
+    // the page size satisfies the alignment of any block device
+    let layout = match std::alloc::Layout::from_size_align(data.len().max(1), 4096) {
+        Ok(layout) => layout,
+        Err(_) => return ret,
+    };
+    unsafe {
+        let buf = std::alloc::alloc(layout);
+        if buf.is_null() {
+            std::alloc::handle_alloc_error(layout);
+        }
+        std::ptr::copy_nonoverlapping(data.as_ptr(), buf, data.len());
+        let ret = libc::pwrite(fd, buf as *const c_void, data.len(), offset);
+        std::alloc::dealloc(buf, layout);
+        ret
+    }
+}
+
+/// A directory entry, as (ino, kind, name)
+type DirEntry = (u64, FileType, String);
+
+/// Entries of directory `ino`.
+fn dir_entries(store: &Store, ino: u64) -> Option<Vec<DirEntry>> {
+    let mut entries: Vec<DirEntry> = match ino {
+        ROOT_INO => vec![
+            (ROOT_INO, FileType::Directory, ".".into()),
+            (ROOT_INO, FileType::Directory, "..".into()),
+        ],
+        VERSIONS_DIR_INO => vec![
+            (VERSIONS_DIR_INO, FileType::Directory, ".".into()),
+            (ROOT_INO, FileType::Directory, "..".into()),
+            (DIFF_DIR_INO, FileType::Directory, DIFF_DIR.into()),
+            (SNAPSHOTS_DIR_INO, FileType::Directory, SNAPSHOTS_DIR.into()),
+        ],
+        DIFF_DIR_INO => vec![
+            (DIFF_DIR_INO, FileType::Directory, ".".into()),
+            (VERSIONS_DIR_INO, FileType::Directory, "..".into()),
+        ],
+        SNAPSHOTS_DIR_INO => vec![
+            (SNAPSHOTS_DIR_INO, FileType::Directory, ".".into()),
+            (VERSIONS_DIR_INO, FileType::Directory, "..".into()),
+        ],
+        NAMED_DIR_INO => vec![
+            (NAMED_DIR_INO, FileType::Directory, ".".into()),
+            (ROOT_INO, FileType::Directory, "..".into()),
+        ],
+        ino if split_ino(ino).0 == SNAPSHOT_DIR_INO_BASE && store.contains(split_ino(ino).1) => {
+            let version = split_ino(ino).1;
+            let kind = target_kind(store, version);
+            vec![
+                (ino, FileType::Directory, ".".into()),
+                (SNAPSHOTS_DIR_INO, FileType::Directory, "..".into()),
+                (SNAPSHOT_INO_BASE + version as u64, kind, store.target.to_string_lossy().into()),
+            ]
+        },
+        ino if split_ino(ino).0 == NAMED_DIR_INO_BASE => {
+            let version = named_version(store, ino)?;
+            let kind = target_kind(store, version);
+            vec![
+                (ino, FileType::Directory, ".".into()),
+                (NAMED_DIR_INO, FileType::Directory, "..".into()),
+                (SNAPSHOT_INO_BASE + version as u64, kind, store.target.to_string_lossy().into()),
+            ]
+        },
+        _ => return None,
+    };
+
+    if ino == ROOT_INO && store.version > 0 {
+        entries.push(
+            (TARGET_INO, target_kind(store, store.version), store.target.to_str().unwrap().into())
+        );
+        entries.push((VERSIONS_DIR_INO, FileType::Directory, VERSIONS_DIR.into()));
+        entries.push((NAMED_DIR_INO, FileType::Directory, NAMED_DIR.into()));
+    }
+    if ino == DIFF_DIR_INO {
+        for v in store.versions() {
+            entries.push((DIFF_INO_BASE + v as u64, FileType::RegularFile, v.to_string()));
+        }
+    }
+    if ino == SNAPSHOTS_DIR_INO {
+        for (name, v) in snapshots(store) {
+            entries.push((SNAPSHOT_DIR_INO_BASE + v as u64, FileType::Directory, name));
+        }
+    }
+    if ino == NAMED_DIR_INO {
+        for (ino, name, _) in named_inos(store) {
+            entries.push((ino, FileType::Directory, name.clone()));
+        }
+    }
+    Some(entries)
+}
+
+impl Filesystem for VersionFS {
+    fn init(&mut self, _req: &Request, config: &mut fuser::KernelConfig) -> Result<(), c_int> {
+        // let listings carry attributes, if the kernel can
+        let _ = config.add_capabilities(consts::FUSE_DO_READDIRPLUS | consts::FUSE_READDIRPLUS_AUTO);
+        // and lookups of "." and "..", as exporting over NFS needs
+        let _ = config.add_capabilities(consts::FUSE_EXPORT_SUPPORT);
+        // and, on macOS, creation times through getxtimes
+        #[cfg(target_os = "macos")]
+        let _ = config.add_capabilities(consts::FUSE_XTIMES);
+        let mut store = self.store();
+        if store.version == 0 {
+            if let Err(e) = store.new_version(true) {
+                error!("cannot cut the first version of {:?}: {e}", store.target);
+                return Err(e.raw_os_error().unwrap_or(EIO));
+            }
+        }
+        Ok(())
+    }
+
+    fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        if let Err(e) = self.write_out_all() {
+            return reply.error(e);
+        }
+        let mut store = self.store();
+        info!("lookup {parent} {name:?}");
+        self.activity.touch();
+        info!("store.version = {}", store.version);
+        if let Err(e) = self.check_name_len(name) {
+            return reply.error(e);
+        }
+        if name == "." || name == ".." {
+            let ino = match name == "." {
+                true => Some(parent),
+                false => parent_of(parent),
+            };
+            match ino.map(|ino| self.attr(&mut store, ino)) {
+                Some(Ok(attr)) => reply.entry(&TTL, &attr, 0),
+                Some(Err(e)) => reply.error(e),
+                None => reply.error(ESTALE),
+            }
+            return;
+        }
+        match parent {
+            ROOT_INO if name == store.target => {
+                let attr =
+                    target_attr(&store, store.version)
+                        .or(target_attr(&store, store.version - 1));
+                match attr {
+                    Some(attr) => reply.entry(&TTL, &self.present(attr), 0),
+                    None => reply.error(EIO),
+                }
+            },
+            ROOT_INO if name == VERSIONS_DIR => {
+                reply.entry(&TTL, &self.present(dir_attr(&store, VERSIONS_DIR_INO)), 0)
+            },
+            ROOT_INO if self.links.contains_key(name) => match self.link_attr(self.links[name]) {
+                Some(attr) => reply.entry(&TTL, &self.present(attr), 0),
+                None => reply_missing(reply),
+            },
+            ROOT_INO if name == NAMED_DIR => {
+                reply.entry(&TTL, &self.present(dir_attr(&store, NAMED_DIR_INO)), 0)
+            },
+            ROOT_INO => match self.scratch.lookup(name) {
+                Ok((ino, meta)) => reply.entry(&TTL, &self.present(scratch_attr(ino, &meta)), 0),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => reply_missing(reply),
+                Err(e) => reply.error(e.raw_os_error().unwrap_or(EIO)),
+            },
+            NAMED_DIR_INO => {
+                let attr = name.to_str()
+                    .and_then(|n| named_ino(&store, n))
+                    .and_then(|ino| self.attr(&mut store, ino).ok());
+                match attr {
+                    Some(attr) => reply.entry(&TTL, &attr, 0),
+                    None => reply_missing(reply),
+                }
+            },
+            VERSIONS_DIR_INO if name == DIFF_DIR => {
+                reply.entry(&TTL, &self.present(dir_attr(&store, DIFF_DIR_INO)), 0)
+            },
+            VERSIONS_DIR_INO if name == SNAPSHOTS_DIR => {
+                reply.entry(&TTL, &self.present(dir_attr(&store, SNAPSHOTS_DIR_INO)), 0)
+            },
+            DIFF_DIR_INO => {
+                let attr = name.to_str()
+                    .and_then(|n| n.parse::<usize>().ok())
+                    .and_then(|v| diff_attr(&mut store, v));
+                match attr {
+                    Some(attr) => reply.entry(&TTL, &self.present(attr), 0),
+                    None => reply_missing(reply),
+                }
+            },
+            SNAPSHOTS_DIR_INO => {
+                let attr = name.to_str()
+                    .and_then(|n| snapshots(&store).get(n).copied())
+                    .and_then(|v| snapshot_dir_attr(&store, v));
+                match attr {
+                    Some(attr) => reply.entry(&TTL, &self.present(attr), 0),
+                    None => reply_missing(reply),
+                }
+            },
+            parent if split_ino(parent).0 == SNAPSHOT_DIR_INO_BASE && name == store.target => {
+                match snapshot_attr(&store, split_ino(parent).1) {
+                    Some(attr) => reply.entry(&TTL, &self.present(attr), 0),
+                    None => reply_missing(reply),
+                }
+            },
+            parent if split_ino(parent).0 == NAMED_DIR_INO_BASE && name == store.target => {
+                match named_version(&store, parent).and_then(|version| snapshot_attr(&store, version)) {
+                    Some(attr) => reply.entry(&TTL, &self.present(attr), 0),
+                    None => reply_missing(reply),
+                }
+            },
+            _ => reply_missing(reply),
+        }
+    }
+
+    fn getattr(&mut self, _req: &Request, ino: u64, reply: ReplyAttr) {
+        info!("getattr {ino}");
+        self.activity.touch();
+        if let Err(e) = self.write_out_all() {
+            return reply.error(e);
+        }
+        match self.attr(&mut self.store(), ino) {
+            Ok(attr) => reply.attr(&TTL, &attr),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    #[cfg(target_os = "macos")]
+    fn getxtimes(&mut self, _req: &Request, ino: u64, reply: fuser::ReplyXTimes) {
+        info!("getxtimes {ino}");
+        self.activity.touch();
+        match self.attr(&mut self.store(), ino) {
+            Ok(attr) => reply.xtimes(UNIX_EPOCH, attr.crtime),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn access(&mut self, req: &Request, ino: u64, mask: i32, reply: ReplyEmpty) {
+        info!("access {ino} {mask:o}");
+        self.activity.touch();
+        match self.attr(&mut self.store(), ino) {
+            Ok(attr) if self.permitted(req, &attr, mask) => reply.ok(),
+            Ok(_) => reply.error(EACCES),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn readlink(&mut self, _req: &Request, ino: u64, reply: ReplyData) {
+        info!("readlink {ino}");
+        self.activity.touch();
+        if let Some(link) = self.pending.get(&ino) {
+            return reply.data(link.link.as_os_str().as_bytes());
+        }
+        let store = self.store();
+        let version = match split_ino(ino) {
+            (TARGET_INO, 0) | (LINK_INO_BASE, _) => store.version,
+            (SNAPSHOT_INO_BASE, version) if store.contains(version) => version,
+            _ => return reply.error(EINVAL),
+        };
+        if !store.is_symlink(version) {
+            return reply.error(EINVAL);
+        }
+        match store.read_version(version) {
+            Ok(link) => reply.data(&link),
+            Err(e) => reply.error(e.raw_os_error().unwrap_or(EIO)),
+        }
+    }
+
+    /// Symbolic links can only be made next to the target, to be renamed
+    /// onto it, which makes the target a symbolic link from the next version.
+    fn symlink(&mut self, req: &Request, parent: u64, name: &OsStr, link: &Path, reply: ReplyEntry) {
+        info!("symlink {parent} {name:?} {link:?}");
+        self.activity.touch();
+        if let Err(e) = self.check_new_name(req, parent, name) {
+            return reply.error(e);
+        }
+        if self.strict_posix && link.as_os_str().len() >= PATH_MAX {
+            return reply.error(ENAMETOOLONG);
+        }
+        let (uid, gid) = (req.uid(), req.gid());
+        let link = PendingLink {
+            link: link.to_path_buf(),
+            uid: self.uids.to_store(uid),
+            gid: self.gids.to_store(gid),
+            created: SystemTime::now(),
+        };
+        self.next_link += 1;
+        let ino = LINK_INO_BASE + self.next_link;
+        reply.entry(&TTL, &self.present(link.attr(ino, 1)), 0);
+        self.links.insert(name.to_os_string(), ino);
+        self.pending.insert(ino, link);
+        self.root_changed();
+    }
+
+    /// Symbolic links and scratch files may be renamed next to the target,
+    /// or onto it to cut a version holding them.
+    fn rename(
+        &mut self,
+        req: &Request,
+        parent: u64,
+        name: &OsStr,
+        newparent: u64,
+        newname: &OsStr,
+        flags: u32,
+        reply: ReplyEmpty,
+    ) {
+        info!("rename {parent} {name:?} {newparent} {newname:?} {flags:b}");
+        self.activity.touch();
+        if let Err(e) = self.write_out_all() {
+            return reply.error(e);
+        }
+        if let Err(e) = self.check_name_len(name).and(self.check_name_len(newname)) {
+            return reply.error(e);
+        }
+        let link = self.links.contains_key(name);
+        if parent != ROOT_INO || newparent != ROOT_INO || !link && !self.scratch.contains(name) {
+            return reply.error(EPERM);
+        }
+        if flags & libc::RENAME_EXCHANGE != 0 {
+            return reply.error(EINVAL);
+        }
+        let mut store = self.store();
+        if let Err(e) = self.check_root(req, &mut store) {
+            return reply.error(e);
+        }
+        if newname == VERSIONS_DIR || newname == NAMED_DIR {
+            return reply.error(EISDIR);
+        }
+        let taken = self.links.contains_key(newname) || self.scratch.contains(newname);
+        let exists = newname == store.target || taken;
+        if exists && flags & libc::RENAME_NOREPLACE != 0 {
+            return reply.error(EEXIST);
+        }
+        if newname == store.target {
+            match self.attr(&mut store, TARGET_INO) {
+                Ok(attr) if self.permitted(req, &attr, W_OK) => {},
+                Ok(_) => return reply.error(EACCES),
+                Err(e) => return reply.error(e),
+            }
+            let cut = match link {
+                true => store.new_symlink(self.pending[&self.links[name]].link.as_os_str()),
+                false => store.adopt(&self.scratch.path(name)),
+            };
+            if let Err(e) = cut {
+                warn!("cutting version {} failed: {e}", store.version + 1);
+                return reply.error(e.raw_os_error().unwrap_or(EIO));
+            }
+            drop(store);
+            if link {
+                self.unname_link(name);
+            } else if let Err(e) = self.scratch.remove(name, true) {
+                warn!("cannot remove {name:?} renamed onto the target: {e}");
+            }
+            self.root_changed();
+            return reply.ok();
+        }
+        drop(store);
+        let renamed = match link {
+            // a name of the same link stays
+            true if self.links.get(newname) == Some(&self.links[name]) => Ok(()),
+            true => {
+                let replaced = match self.scratch.contains(newname) {
+                    true => self.scratch.remove(newname, false),
+                    false => Ok(()),
+                };
+                replaced.map(|()| {
+                    let ino = self.links.remove(name).unwrap();
+                    self.unname_link(newname);
+                    self.links.insert(newname.to_os_string(), ino);
+                })
+            },
+            false => self.scratch.rename(name, newname).map(|()| {
+                self.unname_link(newname);
+            }),
+        };
+        match renamed {
+            Ok(()) => {
+                self.root_changed();
+                reply.ok()
+            },
+            Err(e) => reply.error(e.raw_os_error().unwrap_or(EIO)),
+        }
+    }
+
+    fn unlink(&mut self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        info!("unlink {parent} {name:?}");
+        self.activity.touch();
+        if let Err(e) = self.check_name_len(name) {
+            return reply.error(e);
+        }
+        let dir = parent == ROOT_INO && (name == VERSIONS_DIR || name == NAMED_DIR);
+        if dir && self.strict_posix {
+            return reply.error(EISDIR);
+        }
+        // the target and its history stay
+        if parent != ROOT_INO || name == self.store().target || dir {
+            return reply.error(EPERM);
+        }
+        if let Err(e) = self.check_root(req, &mut self.store()) {
+            return reply.error(e);
+        }
+        if self.unname_link(name).is_some() {
+            self.root_changed();
+            return reply.ok();
+        }
+        match self.scratch.remove(name, false) {
+            Ok(()) => {
+                self.root_changed();
+                reply.ok()
+            },
+            Err(e) => reply.error(e.raw_os_error().unwrap_or(EIO)),
+        }
+    }
+
+    /// Only symbolic links and scratch files made next to the target can have
+    /// other names, as the target and its versions are known by where they are.
+    fn link(&mut self, req: &Request, ino: u64, newparent: u64, newname: &OsStr, reply: ReplyEntry) {
+        info!("link {ino} {newparent} {newname:?}");
+        self.activity.touch();
+        let scratch = split_ino(ino).0 == SCRATCH_INO_BASE;
+        if !self.pending.contains_key(&ino) && !scratch {
+            return reply.error(EPERM);
+        }
+        if let Err(e) = self.check_new_name(req, newparent, newname) {
+            return reply.error(e);
+        }
+        if !scratch {
+            self.links.insert(newname.to_os_string(), ino);
+            self.root_changed();
+            return match self.link_attr(ino) {
+                Some(attr) => reply.entry(&TTL, &self.present(attr), 0),
+                None => reply.error(ENOENT),
+            };
+        }
+        match self.scratch.link(ino, newname).and_then(|()| self.scratch.lookup(newname)) {
+            Ok((ino, meta)) => {
+                self.root_changed();
+                reply.entry(&TTL, &self.present(scratch_attr(ino, &meta)), 0)
+            },
+            Err(e) => reply.error(e.raw_os_error().unwrap_or(EIO)),
+        }
+    }
+
+    /// Regular files made next to the target are kept as scratch files, for
+    /// programs that put lock or temporary files there.
+    fn mknod(
+        &mut self,
+        req: &Request,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        _rdev: u32,
+        reply: ReplyEntry,
+    ) {
+        info!("mknod {parent} {name:?} {mode:o}");
+        self.activity.touch();
+        if let Err(e) = self.check_new_name(req, parent, name) {
+            return reply.error(e);
+        }
+        if mode as libc::mode_t & libc::S_IFMT != libc::S_IFREG {
+            return reply.error(EPERM);
+        }
+        match self.create_scratch(req, name, mode & !umask, O_RDONLY) {
+            Ok((attr, fd)) => {
+                unsafe { libc::close(fd) };
+                self.root_changed();
+                reply.entry(&TTL, &attr, 0)
+            },
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn create(
+        &mut self,
+        req: &Request,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        flags: i32,
+        reply: ReplyCreate,
+    ) {
+        info!("create {parent} {name:?} {mode:o} {flags:b}");
+        self.activity.touch();
+        if let Err(e) = self.check_new_name(req, parent, name) {
+            return reply.error(e);
+        }
+        match self.create_scratch(req, name, mode & !umask, flags) {
+            Ok((attr, fd)) => {
+                self.activity.open_files.fetch_add(1, Ordering::SeqCst);
+                self.root_changed();
+                reply.created(&TTL, &attr, 0, fd as u64, fopen_flags(flags))
+            },
+            Err(e) => reply.error(e),
+        }
+    }
+
+    /// Directories can't be made, which `--strict-posix` answers as POSIX
+    /// has it rather than as an unimplemented call.
+    fn mkdir(&mut self, req: &Request, parent: u64, name: &OsStr, mode: u32, _umask: u32, reply: ReplyEntry) {
+        info!("mkdir {parent} {name:?} {mode:o}");
+        self.activity.touch();
+        if !self.strict_posix {
+            return reply.error(ENOSYS);
+        }
+        match self.check_new_name(req, parent, name) {
+            Ok(()) => reply.error(EPERM),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn rmdir(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        info!("rmdir {parent} {name:?}");
+        self.activity.touch();
+        if !self.strict_posix {
+            return reply.error(ENOSYS);
+        }
+        if let Err(e) = self.check_name_len(name) {
+            return reply.error(e);
+        }
+        let store = self.store();
+        let errno = match parent {
+            ROOT_INO if name == VERSIONS_DIR || name == NAMED_DIR => EPERM,
+            ROOT_INO if name == store.target || self.links.contains_key(name) => ENOTDIR,
+            ROOT_INO if self.scratch.contains(name) => ENOTDIR,
+            ROOT_INO => ENOENT,
+            // the history is read-only
+            _ => EROFS,
+        };
+        reply.error(errno)
+    }
+
+    fn read(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        size: u32,
+        _flags: i32,
+        _lock: Option<u64>,
+        reply: ReplyData,
+    ) {
+        info!("read {fh}");
+        self.activity.touch();
+        if split_ino(ino).0 == SCRATCH_INO_BASE {
+            let mut buf = vec![0u8; size as usize];
+            let len = unsafe { libc::pread(fh as c_int, buf.as_mut_ptr() as *mut c_void, buf.len(), offset) };
+            return match len {
+                -1 => reply.error(errno()),
+                len => reply.data(&buf[..len as usize]),
+            };
+        }
+        if let Err(e) = self.write_out_all() {
+            return reply.error(e);
+        }
+        let shared = self.store.clone();
+        let store = shared.lock().unwrap();
+        // writes and truncation take the store lock and drop the mappings, so
+        // the head can't shrink while mapped
+        let data = match split_ino(ino) {
+            (TARGET_INO, 0) if store.version > 0 => {
+                let version = store.version;
+                if !matches!(self.heads.get(&fh), Some((mapped, _)) if *mapped == version) {
+                    match store.retry.run(|| Mmap::open(&store.path_for_version(version))) {
+                        Ok(map) => { self.heads.insert(fh, (version, map)); },
+                        Err(_) => { self.heads.remove(&fh); },
+                    }
+                }
+                self.heads.get(&fh).map(|(_, map)| &**map)
+            },
+            (SNAPSHOT_INO_BASE | DIFF_INO_BASE, _) => self.snapshots.get(&fh).map(|content| &**content),
+            _ => None,
+        };
+        if let Some(data) = data {
+            let start = data.len().min(offset as usize);
+            let end: usize = data.len().min(start + size as usize);
+            reply.data(&data[start..end]);
+        } else {
+            reply.error(ENOENT);
+        }
+    }
+
+    fn readdir(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectory,
+    ) {
+        info!("readdir {ino} {fh}");
+        self.activity.touch();
+        let entries = match self.listing(ino, fh) {
+            Some(entries) => entries,
+            None => {
+                reply.error(ENOENT);
+                return;
+            },
+        };
+        for (i, entry) in entries.into_iter().enumerate().skip(offset as usize) {
+            if reply.add(entry.0, (i + 1) as i64, entry.1, &entry.2) {
+                break;
+            }
+        }
+        reply.ok();
+    }
+
+    fn readdirplus(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectoryPlus,
+    ) {
+        info!("readdirplus {ino} {fh}");
+        if let Err(e) = self.write_out_all() {
+            return reply.error(e);
+        }
+        self.activity.touch();
+        let entries = match self.listing(ino, fh) {
+            Some(entries) => entries,
+            None => {
+                reply.error(ENOENT);
+                return;
+            },
+        };
+        let mut store = self.store();
+        for (i, entry) in entries.into_iter().enumerate().skip(offset as usize) {
+            let attr = match self.attr(&mut store, entry.0) {
+                Ok(attr) => attr,
+                Err(_) => continue,
+            };
+            if reply.add(entry.0, (i + 1) as i64, &entry.2, &TTL, &attr, 0) {
+                break;
+            }
+        }
+        reply.ok();
+    }
+
+    fn getxattr(&mut self, _req: &Request, ino: u64, name: &OsStr, size: u32, reply: ReplyXattr) {
+        info!("getxattr {ino} {name:?}");
+        self.activity.touch();
+        if let Err(e) = self.write_out_all() {
+            return reply.error(e);
+        }
+        if let Some(value) = self.status_xattr(ino, name) {
+            return match value {
+                Ok(value) => reply_xattr(reply, size, value.as_bytes()),
+                Err(e) => reply.error(e.raw_os_error().unwrap_or(EIO)),
+            };
+        }
+        let store = self.store();
+        let path = match xattr_path(&store, ino) {
+            Some(path) if xattr::forwarded(name) => path,
+            _ => return reply.error(ENOATTR),
+        };
+        match xattr::get(&path, name) {
+            Ok(value) => reply_xattr(reply, size, &value),
+            Err(e) => reply.error(e.raw_os_error().unwrap_or(EIO)),
+        }
+    }
+
+    fn listxattr(&mut self, _req: &Request, ino: u64, size: u32, reply: ReplyXattr) {
+        info!("listxattr {ino}");
+        self.activity.touch();
+        let path = xattr_path(&self.store(), ino);
+        let names = match path.map(|path| xattr::list(&path)) {
+            Some(Ok(names)) => names,
+            Some(Err(e)) => return reply.error(e.raw_os_error().unwrap_or(EIO)),
+            None => Vec::new(),
+        };
+        let mut list = Vec::new();
+        for name in names {
+            list.extend_from_slice(name.as_bytes());
+            list.push(0);
+        }
+        if ino == TARGET_INO {
+            for name in xattr::STATUS {
+                list.extend_from_slice(name.as_bytes());
+                list.push(0);
+            }
+        }
+        reply_xattr(reply, size, &list);
+    }
+
+    fn setxattr(
+        &mut self,
+        req: &Request,
+        ino: u64,
+        name: &OsStr,
+        value: &[u8],
+        flags: i32,
+        _position: u32,
+        reply: ReplyEmpty,
+    ) {
+        info!("setxattr {ino} {name:?}");
+        self.activity.touch();
+        if ino != TARGET_INO || !xattr::forwarded(name) {
+            reply.error(ENOTSUP);
+            return;
+        }
+        let mut store = self.store();
+        if let Err(e) = self.check_xattr(req, &mut store, ino, name) {
+            return reply.error(e);
+        }
+        match xattr::set(&store.path_for_version(store.version), name, value, flags) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e.raw_os_error().unwrap_or(EIO)),
+        }
+    }
+
+    fn removexattr(&mut self, req: &Request, ino: u64, name: &OsStr, reply: ReplyEmpty) {
+        info!("removexattr {ino} {name:?}");
+        self.activity.touch();
+        if ino != TARGET_INO || !xattr::forwarded(name) {
+            reply.error(ENOATTR);
+            return;
+        }
+        let mut store = self.store();
+        if let Err(e) = self.check_xattr(req, &mut store, ino, name) {
+            return reply.error(e);
+        }
+        match xattr::remove(&store.path_for_version(store.version), name) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e.raw_os_error().unwrap_or(EIO)),
+        }
+    }
+
+    fn opendir(&mut self, _req: &Request, ino: u64, flags: i32, reply: ReplyOpen) {
+        info!("opendir {ino} {flags:b}");
+        self.activity.touch();
+        let entries = match self.entries(ino) {
+            Some(entries) => entries,
+            None => {
+                reply.error(ENOENT);
+                return;
+            },
+        };
+        // readdir of the handle sees the directory as it is now
+        self.next_fh += 1;
+        self.dirs.insert(self.next_fh, entries);
+        reply.opened(self.next_fh, 0);
+    }
+
+    fn releasedir(&mut self, _req: &Request, ino: u64, fh: u64, _flags: i32, reply: ReplyEmpty) {
+        info!("releasedir {ino} {fh}");
+        self.activity.touch();
+        self.dirs.remove(&fh);
+        reply.ok();
+    }
+
+    fn fsyncdir(&mut self, _req: &Request, ino: u64, fh: u64, _datasync: bool, reply: ReplyEmpty) {
+        info!("fsyncdir {ino} {fh}");
+        self.activity.touch();
+        let target_dir = self.store().target_dir.clone();
+        match fs::File::open(target_dir).and_then(|dir| dir.sync_all()) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e.raw_os_error().unwrap_or(EIO)),
+        }
+    }
+
+    fn open(&mut self, req: &Request, ino: u64, flags: i32, reply: ReplyOpen) {
+        info!("open {ino} {flags:b}");
+        self.activity.touch();
+        // seeding a new version or rebuilding a snapshot may take a while
+        let _caller = interrupt::watch(req.pid());
+        // a new version is copied from the head, gathered writes included
+        if let Err(e) = self.write_out_all() {
+            return reply.error(e);
+        }
+        let mask = match flags & O_ACCMODE {
+            O_WRONLY => W_OK,
+            O_RDWR => R_OK | W_OK,
+            _ if flags & O_TRUNC != 0 => R_OK | W_OK,
+            _ => R_OK,
+        };
+        match self.attr(&mut self.store(), ino) {
+            Ok(attr) if self.permitted(req, &attr, mask) => {},
+            Ok(_) => return reply.error(EACCES),
+            Err(e) => return reply.error(e),
+        }
+        match ino {
+            // a scratch file renamed onto the target, by its former inode
+            ino if self.scratch.adopted(ino) => self.open(req, TARGET_INO, flags, reply),
+            ino if split_ino(ino).0 == SCRATCH_INO_BASE => match self.scratch.open(ino, flags) {
+                Ok(fd) => {
+                    self.activity.open_files.fetch_add(1, Ordering::SeqCst);
+                    reply.opened(fd as u64, fopen_flags(flags));
+                },
+                Err(e) => reply.error(e.raw_os_error().unwrap_or(EIO)),
+            },
+            TARGET_INO => {
+                let shared = self.store.clone();
+                let mut store = shared.lock().unwrap();
+                if flags & O_WRONLY != 0 || flags & O_RDWR != 0 || flags & O_CREAT != 0 {
+                    match store.new_version(flags & O_TRUNC != 0) {
+                        Ok(()) => {},
+                        // the store is full, which the alerts tell of: better
+                        // to lose a version than the writes
+                        Err(e) if self.write_through_when_full
+                            && matches!(e.raw_os_error(), Some(ENOSPC | EDQUOT))
+                            && store.version > 0 =>
+                        {
+                            let (head, next) = (store.version, store.version + 1);
+                            error!("no room for version {next}, writing version {head} in place: {e}");
+                        },
+                        Err(e) => {
+                            warn!("cutting version {} failed: {e}", store.version + 1);
+                            return reply.error(e.raw_os_error().unwrap_or(EIO));
+                        },
+                    }
+                }
+                let path = store.path_for_version(store.version);
+                let cpath = CString::new(path.to_str().unwrap()).unwrap();
+                let fd = store.retry.run(|| match unsafe { libc::open(cpath.as_ptr(), flags) } {
+                    -1 => Err(std::io::Error::last_os_error()),
+                    fd => Ok(fd),
+                });
+                match fd {
+                    Ok(fd) => {
+                        // the kernel drops the pages it cached of the target on
+                        // every open unless told to keep them, which is only
+                        // right while the head is as it was at the last open:
+                        // snapshots, reverts and writes elsewhere change it
+                        let meta = store.version_meta(store.version).ok();
+                        let head = meta.map(|meta| (store.version, meta.modified, meta.len));
+                        let mut open_flags = fopen_flags(flags);
+                        if head.is_some() && head == self.opened_head {
+                            open_flags |= consts::FOPEN_KEEP_CACHE;
+                        }
+                        self.opened_head = head;
+                        if flags & (O_WRONLY | O_RDWR) != 0 {
+                            self.writable.insert(fd as u64);
+                            // written through, O_TRUNC truncated the head in place
+                            self.heads.clear();
+                        }
+                        // mapped once per open rather than on every read
+                        if flags & O_ACCMODE != O_WRONLY {
+                            if let Ok(map) = store.retry.run(|| Mmap::open(&path)) {
+                                self.heads.insert(fd as u64, (store.version, map));
+                            }
+                        }
+                        self.activity.open_files.fetch_add(1, Ordering::SeqCst);
+                        reply.opened(fd.try_into().unwrap(), open_flags);
+                    },
+                    Err(e) => reply.error(e.raw_os_error().unwrap_or(EIO)),
+                };
+            },
+            _ if flags & (O_WRONLY | O_RDWR) != 0 => reply.error(EACCES),
+            ino if split_ino(ino).0 == DIFF_INO_BASE => {
+                // rendered once per open rather than on every read
+                let diff = match self.store().diff_for_version(split_ino(ino).1) {
+                    Some(diff) => diff,
+                    None => return reply.error(ENOENT),
+                };
+                self.next_fh += 1;
+                self.snapshots.insert(self.next_fh, Content::Rebuilt(diff));
+                self.activity.open_files.fetch_add(1, Ordering::SeqCst);
+                reply.opened(self.next_fh, fopen_flags(flags) | consts::FOPEN_DIRECT_IO);
+            },
+            ino if split_ino(ino).0 == SNAPSHOT_INO_BASE => {
+                // mapped or rebuilt once per open rather than on every read
+                let data = match self.store().map_version(split_ino(ino).1) {
+                    Ok(data) => data,
+                    Err(e) => return reply.error(e.raw_os_error().unwrap_or(EIO)),
+                };
+                self.next_fh += 1;
+                self.snapshots.insert(self.next_fh, data);
+                self.activity.open_files.fetch_add(1, Ordering::SeqCst);
+                reply.opened(self.next_fh, fopen_flags(flags));
+            },
+            _ => reply.error(ENOSYS),
+        }
+    }
+
+    fn release(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        flags: i32,
+        _lock_owner: Option<u64>,
+        _flush: bool,
+        reply: fuser::ReplyEmpty,
+    ) {
+        info!("release {fh} {flags:b}");
+        self.activity.touch();
+        self.activity.open_files.fetch_sub(1, Ordering::SeqCst);
+        if ino == TARGET_INO {
+            let shared = self.store.clone();
+            if let Err(e) = self.write_out(&shared.lock().unwrap(), fh) {
+                warn!("writing out what was gathered through {fh}: {}", std::io::Error::from_raw_os_error(e));
+            }
+            self.buffers.remove(&fh);
+            self.writable.remove(&fh);
+            self.heads.remove(&fh);
+        }
+        match split_ino(ino).0 {
+            TARGET_INO | SCRATCH_INO_BASE => unsafe { libc::close(fh as i32); },
+            SNAPSHOT_INO_BASE | DIFF_INO_BASE => { self.snapshots.remove(&fh); },
+            _ => {},
+        }
+        reply.ok();
+    }
+
+    fn flush(&mut self, _req: &Request<'_>, ino: u64, fh: u64, _lock_owner: u64, reply: ReplyEmpty) {
+        info!("flush {ino} {fh}");
+        self.activity.touch();
+        if let Err(e) = self.write_out_all() {
+            return reply.error(e);
+        }
+        // close waits for this rather than for the release, so closing
+        // the target after writing returns once the version is on disk;
+        // closing it after reading has nothing to wait for
+        let written = ino == TARGET_INO && self.writable.contains(&fh);
+        if !written || self.store().durability == Durability::Relaxed {
+            return reply.ok();
+        }
+        match sync_fd(fh as i32, false) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e.raw_os_error().unwrap_or(EIO)),
+        }
+    }
+
+    fn fsync(&mut self, _req: &Request<'_>, ino: u64, fh: u64, datasync: bool, reply: ReplyEmpty) {
+        info!("fsync {ino} {fh} {datasync}");
+        self.activity.touch();
+        if let Err(e) = self.write_out_all() {
+            return reply.error(e);
+        }
+        // scratch files are synced when asked, as on any filesystem
+        let scratch = split_ino(ino).0 == SCRATCH_INO_BASE;
+        if !scratch && (ino != TARGET_INO || self.store().durability == Durability::Relaxed) {
+            return reply.ok();
+        }
+        match sync_fd(fh as i32, datasync) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e.raw_os_error().unwrap_or(EIO)),
+        }
+    }
+
+    fn write(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        data: &[u8],
+        _write_flags: u32,
+        flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyWrite,
+    ) {
+        info!("write {ino} {fh} {offset} {flags:b}");
+        self.activity.touch();
+        // held so that a snapshot never copies the head halfway through a write
+        let shared = self.store.clone();
+        let store = shared.lock().unwrap();
+        if self.write_buffer > 0 && ino == TARGET_INO && flags & O_DIRECT == 0 {
+            let gathered = self.buffers.entry(fh).or_insert(Gathered { offset, data: Vec::new() });
+            let follows = gathered.offset + gathered.data.len() as i64 == offset;
+            if !follows || gathered.data.len() + data.len() > self.write_buffer {
+                if let Err(e) = self.write_out(&store, fh) {
+                    return reply.error(e);
+                }
+            }
+            if data.len() < self.write_buffer {
+                let gathered = self.buffers.get_mut(&fh).unwrap();
+                if gathered.data.is_empty() {
+                    gathered.offset = offset;
+                }
+                gathered.data.extend_from_slice(data);
+                return reply.written(data.len() as u32);
+            }
+        }
+        let written = store.retry.run(|| match pwrite_direct(fh as i32, data, offset) {
+            -1 => Err(std::io::Error::last_os_error()),
+            ret => Ok(ret),
+        });
+        self.heads.clear();
+        match written {
+            Ok(ret) => reply.written(ret as u32),
+            Err(e) => reply.error(e.raw_os_error().unwrap_or(EIO)),
+        }
+    }
+
+    fn lseek(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        whence: i32,
+        reply: ReplyLseek,
+    ) {
+        info!("lseek {ino} {fh} {offset} {whence}");
+        self.activity.touch();
+        if let Err(e) = self.write_out_all() {
+            return reply.error(e);
+        }
+        match unsafe { libc::lseek(fh as i32, offset, whence) } {
+            -1 => reply.error(errno()),
+            ret => reply.offset(ret),
+        }
+    }
+
+    fn setattr(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        mode: Option<u32>,
+        uid: Option<u32>,
+        gid: Option<u32>,
+        size: Option<u64>,
+        atime: Option<TimeOrNow>,
+        mtime: Option<TimeOrNow>,
+        _ctime: Option<std::time::SystemTime>,
+        fh: Option<u64>,
+        _crtime: Option<std::time::SystemTime>,
+        _chgtime: Option<std::time::SystemTime>,
+        _bkuptime: Option<std::time::SystemTime>,
+        _flags: Option<u32>,
+        reply: ReplyAttr,
+    ) {
+        info!("setattr");
+        self.activity.touch();
+        if let Err(e) = self.write_out_all() {
+            return reply.error(e);
+        }
+        let shared = self.store.clone();
+        let mut store = shared.lock().unwrap();
+        if !self.default_permissions {
+            let attr = match self.attr(&mut store, ino) {
+                Ok(attr) => attr,
+                Err(e) => return reply.error(e),
+            };
+            let (caller, caller_gid) = self.caller(req);
+            let owner = caller == 0 || caller == attr.uid;
+            let writable = permitted(&attr, caller, caller_gid, W_OK);
+            let touches = |time: Option<TimeOrNow>| matches!(time, Some(TimeOrNow::Now));
+            let sets = |time: Option<TimeOrNow>| matches!(time, Some(TimeOrNow::SpecificTime(_)));
+            // only root gives files away, owners may only pick their own group;
+            // anyone who may write may touch, only owners may set other times
+            let allowed = (size.is_none() || writable)
+                && (mode.is_none() || owner)
+                && (uid.is_none() || uid == Some(attr.uid) || caller == 0)
+                && (gid.is_none() || gid == Some(attr.gid) || caller == 0 || (owner && gid == Some(caller_gid)))
+                && (!(touches(atime) || touches(mtime)) || owner || writable)
+                && (!(sets(atime) || sets(mtime)) || owner);
+            if !allowed {
+                // refused for lack of write permission rather than ownership
+                let writing = size.is_some() || (touches(atime) || touches(mtime)) && !owner;
+                return reply.error(if writing { EACCES } else { EPERM });
+            }
+        }
+        if split_ino(ino).0 == SCRATCH_INO_BASE {
+            let owner = (uid.map(|uid| self.uids.to_store(uid)), gid.map(|gid| self.gids.to_store(gid)));
+            let path = self.scratch.path_of(ino);
+            let set = path.as_ref().map(|path| set_file_attr(path, fh, mode, owner, size, [atime, mtime]));
+            if let Some(Err(e)) = set {
+                return reply.error(e.raw_os_error().unwrap_or(EIO));
+            }
+            return match self.attr(&mut store, ino) {
+                Ok(attr) => reply.attr(&TTL, &attr),
+                Err(e) => reply.error(e),
+            };
+        }
+        if ino != TARGET_INO {
+            // everything else is read-only
+            let ids = uid.is_some() || gid.is_some();
+            if mode.is_some() || ids || size.is_some() || atime.is_some() || mtime.is_some() {
+                return reply.error(EROFS);
+            }
+            return match self.attr(&mut store, ino) {
+                Ok(attr) => reply.attr(&TTL, &attr),
+                Err(e) => reply.error(e),
+            };
+        }
+        let owner = (uid.map(|uid| self.uids.to_store(uid)), gid.map(|gid| self.gids.to_store(gid)));
+        let set = set_target_attr(&mut store, fh, mode, owner, size, [atime, mtime]);
+        // truncated, so what was mapped past the end must not be read
+        if size.is_some() {
+            self.heads.clear();
+        }
+        if let Err(e) = set {
+            return reply.error(e.raw_os_error().unwrap_or(EIO));
+        }
+        // a writer truncating through its handle sees the version it has open
+        let attr = match (ino, fh) {
+            (TARGET_INO, Some(fh)) => handle_attr(&store, fh),
+            _ => current_target_attr(&store),
+        };
+        match attr {
+            Some(attr) => reply.attr(&TTL, &self.present(attr)),
+            None => reply.error(EIO),
+        }
+    }
+}
+
+/// Options that reloading the configuration applies without remounting
+const LIVE_OPTIONS: [&str; 11] = [
+    "compact-after", "compact-rate", "keyframe-every", "chunked", "hash", "compress", "idle-timeout",
+    "snapshot-every", "snapshot-cron", "log-level", "max-versions",
+];
+
+/// Set on SIGHUP, asking the main loop to reload the configuration
+static RELOAD: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn request_reload(_signal: c_int) {
+    RELOAD.store(true, Ordering::SeqCst);
+}
+
+/// Set on SIGUSR1, asking the main loop to cut a version
+static SNAPSHOT: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn request_snapshot(_signal: c_int) {
+    SNAPSHOT.store(true, Ordering::SeqCst);
+}
+
+/// Arguments locating an existing store, as given to the mount.
+fn store_args() -> [Arg<'static>; 4] {
+    [
+        arg!(-t --target <FILE> "The target file that is versioned")
+            .value_parser(value_parser!(OsString)),
+        arg!(-o --target_dir <DIR> "Where the versions of the target file are saved")
+            .value_parser(value_parser!(PathBuf)),
+        arg!(--sharded "The versions are spread over hashed subdirectories of target_dir"),
+        arg!(--naming <NAMING> "How version numbers are spelled in file names")
+            .required(false)
+            .default_value("plain")
+            .value_parser(["plain", "padded"]),
+    ]
+}
+
+fn cli() -> Command<'static> {
+    let mount_point = || {
+        arg!(<MOUNT_POINT> "Where the versionfs is mounted")
+            .value_parser(value_parser!(PathBuf))
+    };
+    let json = || arg!(--json "Print JSON for scripts and monitoring rather than text");
+    Command::new("versionfs")
+        .version(crate_version!())
+        .author("Hmm")
+        .args_override_self(true)
+        .args_conflicts_with_subcommands(true)
+        .subcommand_negates_reqs(true)
+        .subcommand(
+            Command::new("status")
+                .about("Report whether a versionfs daemon is serving a mountpoint")
+                .arg(mount_point())
+                .arg(json()),
+        )
+        .subcommand(
+            Command::new("stop")
+                .about("Unmount a mountpoint and stop its versionfs daemon")
+                .arg(mount_point()),
+        )
+        .subcommand(
+            Command::new("health")
+                .about("Check a mount for monitoring, exiting 0 if healthy, 1 on warnings and 2 if not")
+                .arg(mount_point())
+                .arg(
+                    arg!(--"max-versions" <N> "Warn once the store holds more versions than this")
+                        .required(false)
+                        .value_parser(value_parser!(usize)),
+                )
+                .arg(json()),
+        )
+        .subcommand(
+            Command::new("set")
+                .about("Change options of a running versionfs, saving them to its --config file")
+                .arg(mount_point())
+                .arg(
+                    arg!(<SETTINGS> "Options to change, as key=value")
+                        .multiple_values(true),
+                ),
+        )
+        .subcommand(
+            Command::new("systemd-unit")
+                .about("Print a systemd service unit mounting a versionfs")
+                .arg(mount_point())
+                .arg(
+                    arg!(-t --target <FILE> "The target file to be versioned")
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(
+                    arg!(-o --target_dir <DIR> "Where the versions of the target file should be saved")
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(
+                    arg!([ARGS] "Further options for the mount, after --")
+                        .multiple_values(true)
+                        .last(true),
+                ),
+        )
+        .subcommand(
+            Command::new("restore")
+                .about("Write a version of the target to another path, leaving the head as it is")
+                .args(store_args())
+                .arg(arg!(<VERSION> "The version to restore").value_parser(value_parser!(usize)))
+                .arg(arg!(<PATH> "Where to write it, which must not exist yet").value_parser(value_parser!(PathBuf))),
+        )
+        .subcommand(
+            Command::new("du")
+                .about("Report the space the versions of a store take, and what compaction and dedup save")
+                .args(store_args())
+                .arg(
+                    arg!(--top <N> "How many of the versions taking the most space to name")
+                        .required(false)
+                        .default_value("5")
+                        .value_parser(value_parser!(usize)),
+                )
+                .arg(json()),
+        )
+        .subcommand(
+            Command::new("mount-archive")
+                .about("Mount every version of a store that isn't served as a read-only file of its own")
+                .args(store_args())
+                .arg(mount_point()),
+        )
+        .subcommand(
+            Command::new("bench")
+                .about("Measure temporary mounts with each --durability and print how they compare")
+                .arg(
+                    arg!(--size <BYTES> "A size of target to measure, each one given in turn")
+                        .required(false)
+                        .multiple_occurrences(true)
+                        .default_values(&["1048576", "16777216", "67108864"])
+                        .value_parser(value_parser!(u64).range(1..)),
+                )
+                .arg(
+                    arg!(--dir <DIR> "Where to make the temporary stores, whose filesystem is measured")
+                        .required(false)
+                        .value_parser(value_parser!(PathBuf)),
+                ),
+        )
+        .subcommand(
+            Command::new("dedup")
+                .about("Hard link the versions of an unmounted store that are the same as the one before")
+                .args(store_args()),
+        )
+        .subcommand(
+            Command::new("repair")
+                .about("Set aside the versions of an unmounted store that no longer read back")
+                .args(store_args()),
+        )
+        .subcommand(
+            Command::new("convert")
+                .about("Rewrite the versions of an unmounted store in another layout")
+                .args(store_args())
+                .arg(
+                    arg!(--to <LAYOUT> "The layout to convert to")
+                        .value_parser(["flat", "compressed", "delta", "chunked"]),
+                )
+                .arg(
+                    arg!(--hash <ALGORITHM> "Hash naming and verifying chunks: blake3 or sha256")
+                        .required(false)
+                        .default_value("blake3")
+                        .value_parser(hash::Algorithm::parse),
+                )
+                .arg(
+                    arg!(--level <LEVEL> "zstd level of compressed versions, from 1 to 19")
+                        .required(false)
+                        .default_value("3")
+                        .value_parser(zstd::parse_level),
+                )
+                .arg(
+                    arg!(--"keyframe-every" <K> "Keep every K-th version whole when converting to deltas")
+                        .required(false)
+                        .default_value("16")
+                        .value_parser(value_parser!(u64).range(1..)),
+                )
+                .arg(arg!(--"dry-run" "Only report how much space the conversion would save")),
+        )
+        .subcommand(
+            Command::new("completions")
+                .about("Print completions of these commands and options for a shell")
+                .arg(arg!(<SHELL> "The shell to complete for").value_parser(completions::SHELLS)),
+        )
+        .subcommand(
+            Command::new("man")
+                .about("Print the man page of versionfs, in roff"),
+        )
+        .arg(
+            arg!(<MOUNT_POINT> "Where the FUSE should be mounted")
+                .required(true)
+                .value_parser(value_parser!(PathBuf)),
+        )
+        .arg(
+            arg!(-t --target <FILE> "The target file to be versioned, or a pattern like 'configs/*.yaml'")
+                .required_unless_present("config")
+                .value_parser(value_parser!(OsString)),
+        )
+        .arg(
+            arg!(-o --target_dir <DIR> "Where the versions of the target file should be saved")
+                .required_unless_present("config")
+                .value_parser(value_parser!(PathBuf)),
+        )
+        .arg(
+            arg!(--include <PATTERN> "Serve the files a --target pattern matches that match this too")
+                .required(false)
+                .multiple_occurrences(true),
+        )
+        .arg(
+            arg!(--exclude <PATTERN> "Leave out the files a --target pattern matches that match this, unless included after")
+                .required(false)
+                .multiple_occurrences(true),
+        )
+        .arg(
+            arg!(--"max-size" <BYTES> "Leave out the files a --target pattern matches that are bigger than this")
+                .required(false)
+                .value_parser(value_parser!(u64)),
+        )
+        .arg(
+            arg!(--"web-ui" <ADDR> "Serve a web UI for browsing the versions on this address")
+                .required(false)
+                .value_parser(value_parser!(SocketAddr)),
+        )
+        .arg(
+            arg!(--api <ADDR> "Serve the REST API on this address")
+                .required(false)
+                .value_parser(value_parser!(SocketAddr)),
+        )
+        .arg(
+            arg!(--"compact-after" <SECS> "Compact versions older than this into deltas in the background")
+                .required(false)
+                .value_parser(value_parser!(u64)),
+        )
+        .arg(
+            arg!(--"compact-rate" <BYTES> "Limit background compaction IO to this many bytes per second")
+                .required(false)
+                .requires("compact-after")
+                .value_parser(value_parser!(u64)),
+        )
+        .arg(
+            arg!(--"keyframe-every" <K> "Keep every K-th version whole when compacting")
+                .required(false)
+                .default_value("16")
+                .value_parser(value_parser!(u64).range(1..)),
+        )
+        .arg(
+            arg!(--chunked "Compact versions into chunks shared between versions rather than deltas")
+                .required(false)
+                .requires("compact-after"),
+        )
+        .arg(
+            arg!(--hash <ALGORITHM> "Hash naming and verifying chunks, and of the head: blake3 or sha256")
+                .required(false)
+                .default_value("blake3")
+                .value_parser(hash::Algorithm::parse),
+        )
+        .arg(
+            arg!(--compress <RULES> "Compact versions into zstd frames at levels by file type, e.g. jpg=off,log=19,*=3")
+                .required(false)
+                .requires("compact-after")
+                .value_parser(compress::Rules::parse),
+        )
+        .arg(
+            arg!(--"version-cache" <BYTES> "Keep up to this many bytes of versions rebuilt from deltas or chunks in memory")
+                .required(false)
+                .default_value("67108864")
+                .value_parser(value_parser!(usize)),
+        )
+        .arg(
+            arg!(--retries <N> "Retry store operations failing with transient errors, as on NFS, up to N times")
+                .required(false)
+                .default_value("3")
+                .value_parser(value_parser!(u32)),
+        )
+        .arg(
+            arg!(--"retry-backoff" <MS> "Wait this long before the first retry, doubling up to 5 seconds")
+                .required(false)
+                .default_value("100")
+                .value_parser(value_parser!(u64)),
+        )
+        .arg(
+            arg!(--durability <MODE> "Sync versions to disk before acknowledging them (strict) or not (relaxed)")
+                .required(false)
+                .default_value("strict")
+                .value_parser(["strict", "relaxed"]),
+        )
+        .arg(
+            arg!(--"when-full" <POLICY> "When the store is full: fail, or write the head in place")
+                .required(false)
+                .default_value("fail")
+                .value_parser(["fail", "write-through"]),
+        )
+        .arg(
+            arg!(--notify <WHERE> "Tell of versions cut and failures to cut them: desktop, for notify-send")
+                .required(false)
+                .multiple_occurrences(true)
+                .value_parser(["desktop"])
+                // notify-send could not be run from the sandbox
+                .conflicts_with("sandbox"),
+        )
+        .arg(
+            arg!(--"alert-exec" <COMMAND> "Shell command run when cutting versions keeps failing")
+                .required(false)
+                // nor could sh, and an alert lost to it would go unnoticed
+                .conflicts_with("sandbox"),
+        )
+        .arg(
+            arg!(--"alert-after" <N> "Failures in a row to cut versions that run the --alert-exec command")
+                .required(false)
+                .default_value("3")
+                .value_parser(value_parser!(u32).range(1..)),
+        )
+        .arg(
+            arg!(--immutable "Make versions immutable (chattr +i) once no longer the head, which takes root")
+                .required(false),
+        )
+        .arg(
+            arg!(--sharded "Spread the versions over hashed subdirectories of target_dir")
+                .required(false),
+        )
+        .arg(
+            arg!(--naming <NAMING> "How version numbers are spelled in file names")
+                .required(false)
+                .default_value("plain")
+                .value_parser(["plain", "padded"]),
+        )
+        .arg(
+            arg!(--"watch-store" "Watch target_dir for versions changed by other tools")
+                .required(false),
+        )
+        .arg(
+            arg!(--"max-versions" <N> "Keep at most N versions, removing the oldest unnamed ones to make room")
+                .required(false)
+                .value_parser(value_parser!(u64).range(2..)),
+        )
+        .arg(
+            arg!(--"low-space-action" <ACTION> "With under --low-space bytes free: none, or prune")
+                .required(false)
+                .default_value("none")
+                .value_parser(["none", "prune"]),
+        )
+        .arg(
+            arg!(--"low-space" <BYTES> "Bytes to keep free on the store's disk, besides room for the head")
+                .required(false)
+                .default_value("104857600")
+                .value_parser(value_parser!(u64)),
+        )
+        .arg(
+            arg!(--"idle-timeout" <SECS> "Unmount and exit after this long without filesystem activity")
+                .required(false)
+                .value_parser(value_parser!(u64)),
+        )
+        .arg(
+            arg!(--"snapshot-every" <SECS> "Cut a version this often, if the target changed since the last one")
+                .required(false)
+                .value_parser(value_parser!(u64).range(1..)),
+        )
+        .arg(
+            arg!(--"snapshot-cron" <SCHEDULE> "Cut a version on this cron schedule, if the target changed since the last one")
+                .required(false)
+                .value_parser(cron::parse),
+        )
+        .arg(
+            arg!(--"log-level" <LEVEL> "How much to log, unless RUST_LOG is set")
+                .required(false)
+                .default_value("error")
+                .value_parser(["off", "error", "warn", "info", "debug", "trace"]),
+        )
+        .arg(
+            arg!(--user <USER> "Once mounted, drop root privileges and run as this user")
+                .required(false),
+        )
+        .arg(
+            arg!(--group <GROUP> "Run as this group instead of the primary group of --user")
+                .required(false)
+                .requires("user"),
+        )
+        .arg(
+            arg!(--"map-uid" <MAPPING> "Present files owned by uid FROM in the store as owned by TO, given as FROM:TO")
+                .required(false)
+                .multiple_occurrences(true)
+                .value_parser(idmap::parse),
+        )
+        .arg(
+            arg!(--"map-gid" <MAPPING> "Present files of gid FROM in the store as of TO, given as FROM:TO")
+                .required(false)
+                .multiple_occurrences(true)
+                .value_parser(idmap::parse),
+        )
+        .arg(
+            arg!(--"default-permissions" "Leave permission checks to the kernel, taking supplementary groups into account")
+                .required(false),
+        )
+        .arg(
+            arg!(--"write-buffer" <BYTES> "Gather sequential writes to the target into writes of up to BYTES")
+                .required(false)
+                .default_value("0")
+                .value_parser(value_parser!(usize)),
+        )
+        .arg(
+            arg!(--"strict-posix" "Follow POSIX on name lengths, the times of the root, mkdir and rmdir")
+                .required(false),
+        )
+        .arg(
+            arg!(--"root-squash" "Check permissions of requests from root as if they came from nobody")
+                .required(false)
+                .conflicts_with("default-permissions"),
+        )
+        .arg(
+            arg!(--sandbox "Once set up, confine the daemon with Landlock and seccomp")
+                .required(false),
+        )
+        .arg(
+            arg!(-c --config <FILE> "Read further options from this file, re-read on SIGHUP")
+                .required(false)
+                .value_parser(value_parser!(PathBuf)),
+        )
+}
+
+fn main() {
+    let args: Vec<OsString> = env::args_os().collect();
+    let matches = cli().get_matches_from(&args);
+
+    // RUST_LOG decides what is logged when set, otherwise --log-level does
+    match env::var_os("RUST_LOG") {
+        Some(_) => env_logger::init(),
+        None => env_logger::Builder::new().filter_level(LevelFilter::Trace).init(),
+    }
+    set_log_level(&matches);
+    match matches.subcommand() {
+        Some(("status", sub)) => {
+            let mountpoint = sub.get_one::<PathBuf>("MOUNT_POINT").unwrap();
+            std::process::exit(pidfile::status(mountpoint, sub.contains_id("json")))
+        },
+        Some(("stop", sub)) => std::process::exit(pidfile::stop(sub.get_one::<PathBuf>("MOUNT_POINT").unwrap())),
+        Some(("health", sub)) => std::process::exit(health(sub)),
+        Some(("set", sub)) => std::process::exit(set(sub)),
+        Some(("systemd-unit", sub)) => print!("{}", systemd_unit(sub)),
+        Some(("restore", sub)) => std::process::exit(restore(sub)),
+        Some(("du", sub)) => std::process::exit(du(sub)),
+        Some(("mount-archive", sub)) => std::process::exit(mount_archive(sub)),
+        Some(("bench", sub)) => std::process::exit(bench(sub)),
+        Some(("dedup", sub)) => std::process::exit(dedup(sub)),
+        Some(("convert", sub)) => std::process::exit(convert(sub)),
+        Some(("repair", sub)) => std::process::exit(repair(sub)),
+        Some(("completions", sub)) => {
+            print!("{}", completions::generate(&mut cli(), sub.get_one::<String>("SHELL").unwrap()))
+        },
+        Some(("man", _)) => print!("{}", man::generate(&mut cli())),
+        _ => mount(&args, matches),
+    }
+}
+
+/// Save `SETTINGS` to the configuration file of the daemon serving
+/// `MOUNT_POINT` and have it reload, returning the exit code.
+fn set(matches: &ArgMatches) -> i32 {
+    let mountpoint = matches.get_one::<PathBuf>("MOUNT_POINT").unwrap();
+    let daemon = match pidfile::running(mountpoint) {
+        Ok(Some(daemon)) => daemon,
+        Ok(None) => {
+            eprintln!("versionfs is not serving {mountpoint:?}");
+            return 1;
+        },
+        Err(e) => {
+            eprintln!("cannot check {mountpoint:?}: {e}");
+            return 1;
+        },
+    };
+    let config = match daemon.config {
+        Some(config) => config,
+        None => {
+            eprintln!("versionfs serving {mountpoint:?} was started without --config");
+            return 1;
+        },
+    };
+    // check the settings along with the rest of the file before changing it
+    let mut args: Vec<OsString> = ["versionfs", "-t", "-", "-o", "-", "-"].map(OsString::from).into();
+    match config::load(&config) {
+        Ok(current) => args.extend(current),
+        Err(e) => {
+            eprintln!("cannot read {config:?}: {e}");
+            return 1;
+        },
+    }
+    let mut settings = Vec::new();
+    for setting in matches.get_many::<String>("SETTINGS").unwrap() {
+        let (key, value) = match setting.split_once('=') {
+            Some((key, value)) => (key.trim(), value.trim()),
+            None => {
+                eprintln!("expected key=value, got {setting:?}");
+                return 1;
+            },
+        };
+        args.extend(config::arg(key, value));
+        settings.push((key, value));
+    }
+    if let Err(e) = cli().try_get_matches_from(args) {
+        eprintln!("invalid settings: {e}");
+        return 1;
+    }
+    for (key, _) in &settings {
+        if !LIVE_OPTIONS.contains(key) {
+            println!("{key} takes effect on the next mount");
+        }
+    }
+    for (key, value) in settings {
+        if let Err(e) = config::set(&config, key, value) {
+            eprintln!("cannot update {config:?}: {e}");
+            return 1;
+        }
+    }
+    if unsafe { libc::kill(daemon.pid, libc::SIGHUP) } != 0 {
+        eprintln!("cannot signal pid {}: {}", daemon.pid, std::io::Error::last_os_error());
+        return 1;
+    }
+    0
+}
+
+/// Parse `args` with the options of the configuration file in front of them,
+/// followed by those of `target` if it names one, so that those given on the
+/// command line win and those of a target win over the rest of the file.
+fn with_config(args: &[OsString], config: &Path, target: Option<&str>) -> Result<ArgMatches, String> {
+    let file = config::read(config).map_err(|e| format!("cannot read {config:?}: {e}"))?;
+    let mut full = args[..1].to_vec();
+    full.extend(file.common);
+    if let Some(target) = target {
+        match file.targets.into_iter().find(|(name, _)| name == target) {
+            Some((_, options)) => full.extend(options),
+            None => return Err(format!("target {target:?} is no longer in {config:?}")),
+        }
+    }
+    full.extend(args[1..].iter().cloned());
+    cli().try_get_matches_from(full).map_err(|e| format!("invalid options in {config:?}: {e}"))
+}
+
+fn set_log_level(matches: &ArgMatches) {
+    if env::var_os("RUST_LOG").is_none() {
+        let level = matches.get_one::<String>("log-level").and_then(|l| l.parse().ok());
+        log::set_max_level(level.unwrap_or(LevelFilter::Error));
+    }
+}
+
+fn compact_policy(matches: &ArgMatches) -> Option<Policy> {
+    let after = matches.get_one::<u64>("compact-after")?;
+    Some(Policy {
+        after: Duration::from_secs(*after),
+        rate: matches.get_one::<u64>("compact-rate").copied(),
+        keyframe: *matches.get_one::<u64>("keyframe-every").unwrap() as usize,
+        chunked: matches.contains_id("chunked"),
+        hash: *matches.get_one::<hash::Algorithm>("hash").unwrap(),
+        compress: matches.get_one::<compress::Rules>("compress").cloned(),
+    })
+}
+
+/// Log the options that differ between `old` and `new`, and whether they
+/// took effect.
+fn log_changes(old: &ArgMatches, new: &ArgMatches) {
+    for arg in cli().get_arguments() {
+        let id = arg.get_id();
+        let values = |m: &ArgMatches| m.try_get_raw(id).ok().flatten().map(|v| v.map(OsStr::to_os_string).collect::<Vec<_>>());
+        if values(old) == values(new) {
+            continue;
+        }
+        if LIVE_OPTIONS.contains(&id) {
+            info!("reloaded {id}");
+        } else {
+            warn!("{id} changed, remount to apply it");
+        }
+    }
+}
+
+fn systemd_unit(matches: &ArgMatches) -> String {
+    // the unit runs from /, so every path has to be absolute
+    let absolute = |name: &str| {
+        let path = matches.get_one::<PathBuf>(name).unwrap();
+        std::fs::canonicalize(path).unwrap_or_else(|_| env::current_dir().unwrap().join(path))
+    };
+    let mountpoint = absolute("MOUNT_POINT");
+    let target = matches.get_one::<PathBuf>("target").unwrap();
+    let exe = env::current_exe().unwrap();
+    let mut command: Vec<String> = vec![
+        exe.to_string_lossy().into(),
+        "--target".into(),
+        target.to_string_lossy().into(),
+        "--target_dir".into(),
+        absolute("target_dir").to_string_lossy().into(),
+    ];
+    command.extend(matches.get_many::<String>("ARGS").into_iter().flatten().cloned());
+    command.push(mountpoint.to_string_lossy().into());
+    let description = format!("versionfs versioning {} at {}", target.display(), mountpoint.display());
+    systemd::unit(&description, &command)
+}
+
+/// The store given by `--target`, `--target_dir` and its layout options.
+fn store_from(matches: &ArgMatches) -> Store {
+    let mut store = Store::new(
+        matches.get_one::<OsString>("target").unwrap().clone(),
+        matches.get_one::<PathBuf>("target_dir").unwrap().clone(),
+    );
+    store.sharded = matches.contains_id("sharded");
+    store.naming = match matches.get_one::<String>("naming").unwrap().as_str() {
+        "padded" => Naming::Padded,
+        _ => Naming::Plain,
+    };
+    store
+}
+
+/// Write `VERSION` to `PATH`, with its extended attributes, returning the
+/// exit code. Only reads the store, so it works on a mounted one too.
+fn restore(matches: &ArgMatches) -> i32 {
+    let mut store = store_from(matches);
+    if let Err(e) = store.resume() {
+        eprintln!("cannot read {:?}: {e}", store.target_dir);
+        return 1;
+    }
+    let version = *matches.get_one::<usize>("VERSION").unwrap();
+    let path = matches.get_one::<PathBuf>("PATH").unwrap();
+    if !store.contains(version) {
+        eprintln!("there is no version {version} of {:?}", store.target);
+        return 1;
+    }
+    let written = store.read_version(version).and_then(|data| {
+        if store.is_symlink(version) {
+            return std::os::unix::fs::symlink(OsStr::from_bytes(&data), path);
+        }
+        fs::File::create_new(path)?.write_all(&data)?;
+        xattr::copy(&store.xattr_path(version), path)?;
+        store::keep_ownership(&store.xattr_path(version), path)
+    });
+    match written {
+        Ok(()) => {
+            println!("restored version {version} to {path:?}");
+            0
+        },
+        Err(e) => {
+            eprintln!("cannot restore version {version} to {path:?}: {e}");
+            1
+        },
+    }
+}
+
+/// Print the space each version takes, returning the exit code. Only reads
+/// the store, so it works on a mounted one too.
+fn du(matches: &ArgMatches) -> i32 {
+    let mut store = store_from(matches);
+    let usage = match store.resume().and_then(|_| usage::measure(&store)) {
+        Ok(usage) => usage,
+        Err(e) => {
+            eprintln!("cannot measure {:?}: {e}", store.target_dir);
+            return 1;
+        },
+    };
+    if matches.contains_id("json") {
+        let versions: Vec<String> = usage.versions.iter()
+            .map(|v| format!(
+                "{{\"version\":{},\"form\":\"{}\",\"size\":{},\"stored\":{},\"shared\":{}}}",
+                v.version, v.form, v.len, v.stored, v.shared,
+            ))
+            .collect();
+        println!(
+            "{{\"versions\":[{}],\"size\":{},\"stored\":{},\"compacted\":{},\"deduplicated\":{}}}",
+            versions.join(","),
+            usage.len(),
+            usage.stored(),
+            usage.compacted(),
+            usage.deduplicated(),
+        );
+        return 0;
+    }
+    println!("{:>8} {:>14} {:>14}  FORM", "VERSION", "SIZE", "STORED");
+    for v in &usage.versions {
+        println!("{:>8} {:>14} {:>14}  {}", v.version, v.len, v.stored, v.form);
+    }
+    let stored = usage.stored();
+    println!("{} versions: {} bytes of content in {stored} bytes", usage.versions.len(), usage.len());
+    println!(
+        "saved {} bytes by compaction and {} bytes by deduplication",
+        usage.compacted(),
+        usage.deduplicated(),
+    );
+    let mut largest: Vec<_> = usage.versions.iter().filter(|v| v.stored > 0).collect();
+    largest.sort_by_key(|v| std::cmp::Reverse(v.stored));
+    for v in largest.into_iter().take(*matches.get_one::<usize>("top").unwrap()) {
+        let share = (v.stored * 100).checked_div(stored).unwrap_or(0);
+        println!("version {} takes {} bytes, {share}% of the store", v.version, v.stored);
+    }
+    0
+}
+
+/// Mount the versions of the store at `MOUNT_POINT` read-only until
+/// interrupted or unmounted, returning the exit code. Nothing is written to
+/// the store, which isn't locked, so another versionfs may serve it.
+fn mount_archive(matches: &ArgMatches) -> i32 {
+    let mut store = store_from(matches);
+    if let Err(e) = store.resume() {
+        eprintln!("cannot read {:?}: {e}", store.target_dir);
+        return 1;
+    }
+    let mountpoint = matches.get_one::<PathBuf>("MOUNT_POINT").unwrap();
+    let target = store.target.clone();
+    match store.holder() {
+        Ok(Some(pid)) => info!("{:?} is served by versionfs {pid}, following its versions", store.target_dir),
+        Ok(None) => {},
+        Err(e) => warn!("cannot tell whether {:?} is served: {e}", store.target_dir),
+    }
+    let archive = archive::Archive::new(store);
+    let options = [MountOption::RO, MountOption::DefaultPermissions];
+    let session = match fuser::spawn_mount2(archive, mountpoint, &options) {
+        Ok(session) => session,
+        Err(e) => {
+            eprintln!("cannot mount {mountpoint:?}: {e}");
+            return 1;
+        },
+    };
+    info!("versions of {target:?} mounted read-only at {mountpoint:?}");
+    let (stop, stopped) = std::sync::mpsc::channel();
+    ctrlc::set_handler(move || {
+        let _ = stop.send(());
+    })
+    .unwrap();
+    // the session ends by itself when unmounted from outside
+    while !session.guard.is_finished() {
+        if stopped.recv_timeout(Duration::from_secs(1)).is_ok() {
+            break;
+        }
+    }
+    0
+}
+
+/// Check the mount at `MOUNT_POINT` and print what each check found, returning the exit code.
+fn health(matches: &ArgMatches) -> i32 {
+    let mountpoint = matches.get_one::<PathBuf>("MOUNT_POINT").unwrap();
+    let checks = health::check(mountpoint, matches.get_one::<usize>("max-versions").copied());
+    let worst = health::worst(&checks);
+    if matches.contains_id("json") {
+        let checks: Vec<String> = checks
+            .iter()
+            .map(|check| {
+                format!(
+                    "{{\"name\":\"{}\",\"status\":\"{}\",\"detail\":{}}}",
+                    check.name,
+                    check.outcome,
+                    json_string(&check.detail),
+                )
+            })
+            .collect();
+        println!("{{\"status\":\"{worst}\",\"checks\":[{}]}}", checks.join(","));
+    } else {
+        for check in &checks {
+            println!("{:<8} {:<9} {}", check.outcome.to_string().to_uppercase(), check.name, check.detail);
+        }
+    }
+    worst as i32
+}
+
+/// Measure a temporary target mounted with each durability in turn at each
+/// `--size`, and print the results side by side, returning the exit code.
+fn bench(matches: &ArgMatches) -> i32 {
+    let dir = matches.get_one::<PathBuf>("dir").cloned().unwrap_or_else(env::temp_dir);
+    let dir = dir.join(format!("versionfs-bench-{}", std::process::id()));
+    let sizes: Vec<u64> = matches.get_many::<u64>("size").unwrap().copied().collect();
+    println!(
+        "{:<10} {:>12} {:>13} {:>13} {:>12} {:>12} {:>12} {:>12}",
+        "DURABILITY", "SIZE", "SEQ WRITE", "SEQ READ", "RAND WRITE", "RAND READ", "OPEN WRITE", "SNAPSHOT",
+    );
+    let mut code = 0;
+    for durability in ["strict", "relaxed"] {
+        let measured = match bench_durability(&dir.join(durability), durability, &sizes) {
+            Ok(measured) => measured,
+            Err(e) => {
+                eprintln!("cannot measure --durability {durability}: {e}");
+                code = 1;
+                continue;
+            },
+        };
+        for m in measured {
+            println!(
+                "{durability:<10} {:>12} {:>9.1}MB/s {:>9.1}MB/s {:>10.0}/s {:>10.0}/s {:>10.2}ms {:>10.2}ms",
+                m.size,
+                m.seq_write / 1e6,
+                m.seq_read / 1e6,
+                m.rand_write,
+                m.rand_read,
+                m.open_write.as_secs_f64() * 1e3,
+                m.snapshot.as_secs_f64() * 1e3,
+            );
+        }
+    }
+    let _ = fs::remove_dir_all(&dir);
+    code
+}
+
+/// Mount a new store in `dir` with `durability` and measure it at `sizes`.
+fn bench_durability(dir: &Path, durability: &str, sizes: &[u64]) -> std::io::Result<Vec<bench::Measured>> {
+    let (target_dir, mountpoint, seed) = (dir.join("store"), dir.join("mnt"), dir.join("seed"));
+    fs::create_dir_all(&target_dir)?;
+    fs::create_dir_all(&mountpoint)?;
+    fs::write(&seed, [])?;
+    let args = [
+        OsStr::new("versionfs"),
+        OsStr::new("--target"),
+        OsStr::new("bench.dat"),
+        OsStr::new("--target_dir"),
+        target_dir.as_os_str(),
+        OsStr::new("--durability"),
+        OsStr::new(durability),
+        mountpoint.as_os_str(),
+    ];
+    let matches = cli().try_get_matches_from(args).map_err(|e| std::io::Error::other(e.to_string()))?;
+    let (served, session) = serve(None, Vec::new(), matches, &mountpoint, Some(&seed))?;
+    let target = mountpoint.join("bench.dat");
+    let snapshot = || served.store.lock().unwrap().snapshot().map(|_| ());
+    let measured = sizes.iter().map(|&size| bench::measure(&target, size, snapshot)).collect();
+    // unmounts
+    drop(session);
+    measured
+}
+
+/// Lock `store` so that it can't be mounted meanwhile. Errors are reported
+/// on stderr.
+fn lock_unmounted(store: &Store) -> Option<fs::File> {
+    match store.lock_dir() {
+        Ok(lock) => Some(lock),
+        Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+            eprintln!("{:?} is mounted, unmount it first", store.target_dir);
+            None
+        },
+        Err(e) => {
+            eprintln!("cannot lock {:?}: {e}", store.target_dir);
+            None
+        },
+    }
+}
+
+/// The store located by `matches` with its versions picked up, locked so
+/// that it can't be mounted meanwhile. Errors are reported on stderr.
+fn lock_store(matches: &ArgMatches) -> Option<(Store, fs::File)> {
+    let mut store = store_from(matches);
+    let lock = lock_unmounted(&store)?;
+    let opened = store.resume().and_then(|_| store.replay_journal()).and_then(|_| store.clean_up());
+    if let Err(e) = opened.and_then(|_| store.upgrade()) {
+        eprintln!("cannot read {:?}: {e}", store.target_dir);
+        return None;
+    }
+    Some((store, lock))
+}
+
+/// Hard link the versions that are the same as the one before, returning
+/// the exit code. The store is locked meanwhile, so it must not be mounted.
+fn dedup(matches: &ArgMatches) -> i32 {
+    let (store, _lock) = match lock_store(matches) {
+        Some(locked) => locked,
+        None => return 1,
+    };
+    match store.link_duplicates() {
+        Ok((linked, freed)) => {
+            println!("linked {linked} duplicate versions, reclaiming {freed} bytes");
+            0
+        },
+        Err(e) => {
+            eprintln!("cannot deduplicate {:?}: {e}", store.target_dir);
+            1
+        },
+    }
+}
+
+/// Convert the versions of the store to `--to`, returning the exit code.
+/// The store is locked meanwhile, so it must not be mounted.
+fn convert(matches: &ArgMatches) -> i32 {
+    let (mut store, _lock) = match lock_store(matches) {
+        Some(locked) => locked,
+        None => return 1,
+    };
+    let to = matches.get_one::<String>("to").unwrap();
+    let layout = match to.as_str() {
+        "flat" => convert::Layout::Flat,
+        "compressed" => convert::Layout::Compressed(*matches.get_one::<u32>("level").unwrap()),
+        "delta" => convert::Layout::Delta(*matches.get_one::<u64>("keyframe-every").unwrap() as usize),
+        _ => convert::Layout::Chunked(*matches.get_one::<hash::Algorithm>("hash").unwrap()),
+    };
+    let dry_run = matches.contains_id("dry-run");
+    match convert::convert(&mut store, layout, dry_run) {
+        Ok(report) => {
+            let saved = 100 - (report.after * 100).checked_div(report.before).unwrap_or(100) as i64;
+            println!(
+                "{} {} versions: {} bytes before, {} bytes {to} ({saved}% saved)",
+                if dry_run { "would convert" } else { "converted" },
+                report.versions,
+                report.before,
+                report.after,
+            );
+            0
+        },
+        Err(e) => {
+            eprintln!("cannot convert {:?}: {e}", store.target_dir);
+            1
+        },
+    }
+}
+
+/// Repair the store, reporting what history was lost, and return the exit
+/// code. The store is locked meanwhile, so it must not be mounted.
+fn repair(matches: &ArgMatches) -> i32 {
+    let mut store = store_from(matches);
+    let _lock = match lock_unmounted(&store) {
+        Some(lock) => lock,
+        None => return 1,
+    };
+    let report = match repair::repair(&mut store) {
+        Ok(report) => report,
+        Err(e) => {
+            eprintln!("cannot repair {:?}: {e}", store.target_dir);
+            return 1;
+        },
+    };
+    if report.settled + report.cleaned > 0 {
+        println!(
+            "settled {} changes a crash interrupted and cleaned up {} leftovers",
+            report.settled,
+            report.cleaned,
+        );
+    }
+    for (version, e) in &report.lost {
+        println!("lost version {version}: {e}");
+    }
+    for (name, version) in &report.names {
+        println!("dropped the name {name:?} of version {version}");
+    }
+    match report.lost.len() {
+        0 => println!("no history was lost"),
+        lost => println!("quarantined {lost} versions in {:?}", store.quarantine_dir()),
+    }
+    0
+}
+
+/// Cut a version from the head, unless it is the same as the version before.
+fn snapshot_if_changed(store: &SharedStore) {
+    let mut store = store.lock().unwrap();
+    match store.head_changed() {
+        Ok(true) => match store.snapshot() {
+            Ok(head) => info!("snapshot taken as version {}", head - 1),
+            Err(e) => warn!("cannot take a snapshot: {e}"),
+        },
+        Ok(false) => {},
+        Err(e) => warn!("cannot take a snapshot: {e}"),
+    }
+}
+
+/// Confine the daemon to the stores, removing its pid file and re-reading
+/// its configuration. Only a kernel or architecture without Landlock or
+/// seccomp leaves it unconfined, with a warning; any other failure is an
+/// error rather than a line in the log that is easily missed.
+#[cfg(target_os = "linux")]
+fn confine(
+    target_dirs: &[PathBuf],
+    pid_file: &std::path::Path,
+    config: Option<&std::path::Path>,
+) -> Result<(), String> {
+    let mut rules: Vec<_> = target_dirs.iter().map(|dir| (dir.as_path(), sandbox::READ_WRITE)).collect();
+    rules.push((pid_file.parent().unwrap(), sandbox::REMOVE));
+    // the configuration is replaced rather than rewritten, so allow the directory
+    if let Some(dir) = config.and_then(|config| config.parent()) {
+        rules.push((if dir.as_os_str().is_empty() { ".".as_ref() } else { dir }, sandbox::READ));
+    }
+    match sandbox::landlock(&rules) {
+        Ok(true) => info!("confined filesystem access with Landlock"),
+        Ok(false) => warn!("Landlock is not supported by this kernel"),
+        Err(e) => return Err(format!("cannot enable Landlock: {e}")),
+    }
+    match sandbox::seccomp() {
+        Ok(true) => info!("confined system calls with seccomp"),
+        Ok(false) => warn!("seccomp filtering is not supported on this architecture"),
+        Err(e) => return Err(format!("cannot enable seccomp: {e}")),
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn confine(
+    _target_dirs: &[PathBuf],
+    _pid_file: &std::path::Path,
+    _config: Option<&std::path::Path>,
+) -> Result<(), String> {
+    warn!("--sandbox needs Landlock and seccomp, which this platform lacks; ignored");
+    Ok(())
+}
+
+/// A target being served, with the options it was mounted with
+struct Served {
+    /// Its section of the configuration, if it has one of its own
+    section: Option<String>,
+    /// Options given after the command line, naming a file a pattern matched
+    extra: Vec<OsString>,
+    matches: ArgMatches,
+    store: SharedStore,
+    activity: Arc<Activity>,
+    policy: Arc<Mutex<Option<Policy>>>,
+    compacting: bool,
+    last_snapshot: Instant,
+    /// The store's lock, held while serving
+    _lock: fs::File,
+}
+
+/// The options of each target to serve, with the subdirectory of the
+/// mountpoint it goes in when the configuration names several.
+fn targets(
+    args: &[OsString],
+    matches: ArgMatches,
+    config: Option<&Path>,
+) -> Result<Vec<(Option<String>, ArgMatches)>, String> {
+    let path = match config {
+        Some(path) => path,
+        None => return Ok(vec![(None, matches)]),
+    };
+    let names = config::read(path).map_err(|e| format!("cannot read {path:?}: {e}"))?.targets;
+    let targets = match names.is_empty() {
+        true => vec![(None, with_config(args, path, None)?)],
+        false => names
+            .into_iter()
+            .map(|(name, _)| with_config(args, path, Some(&name)).map(|matches| (Some(name), matches)))
+            .collect::<Result<_, _>>()?,
+    };
+    for (name, matches) in &targets {
+        if !matches.contains_id("target") || !matches.contains_id("target_dir") {
+            return Err(match name {
+                Some(name) => format!("target {name:?} in {path:?} lacks a target or target_dir"),
+                None => format!("neither the command line nor {path:?} gives a target and target_dir"),
+            });
+        }
+    }
+    Ok(targets)
+}
+
+/// Lock the store given by `matches` and bring it up to date.
+fn open_store(matches: &ArgMatches) -> std::io::Result<(Store, fs::File)> {
+    let mut store = store_from(matches);
+    store.set_cache_limit(*matches.get_one::<usize>("version-cache").unwrap());
+    store.max_versions = matches.get_one::<u64>("max-versions").map(|&max| max as usize);
+    if matches.get_one::<String>("low-space-action").unwrap() == "prune" {
+        store.low_space = matches.get_one::<u64>("low-space").copied();
+    }
+    store.retry = retry::Retry {
+        attempts: matches.get_one::<u32>("retries").unwrap() + 1,
+        backoff: Duration::from_millis(*matches.get_one::<u64>("retry-backoff").unwrap()),
+    };
+    store.durability = match matches.get_one::<String>("durability").unwrap().as_str() {
+        "relaxed" => Durability::Relaxed,
+        _ => Durability::Strict,
+    };
+    store.immutable = matches.contains_id("immutable");
+    let mut notify = matches.get_many::<String>("notify").into_iter().flatten();
+    store.notify_desktop = notify.any(|to| to == "desktop");
+    if let Some(command) = matches.get_one::<String>("alert-exec") {
+        let after = *matches.get_one::<u32>("alert-after").unwrap();
+        store.alert = Some(notify::Alert::new(command.clone(), after));
+    }
+    let lock = store.lock_dir().map_err(|e| match e.kind() {
+        std::io::ErrorKind::WouldBlock => {
+            std::io::Error::new(e.kind(), "already mounted by another versionfs, mount-archive can browse it")
+        },
+        _ => e,
+    })?;
+    match store.resume()? {
+        0 => {},
+        head => info!("resuming at version {head}"),
+    }
+    if store.sharded {
+        let moved = store.migrate_to_sharded()?;
+        if moved > 0 {
+            info!("moved {moved} version files into shards");
+        }
+    }
+    match store.replay_journal()? {
+        0 => {},
+        settled => info!("settled {settled} changes a crash interrupted, at version {}", store.version),
+    }
+    match store.clean_up()? {
+        0 => {},
+        cleaned => info!("cleaned up {cleaned} leftovers of an interrupted run"),
+    }
+    match store.upgrade()? {
+        format if format < store::FORMAT && store.version > 0 => {
+            info!("upgraded the store from format {format} to {}", store::FORMAT)
+        },
+        _ => {},
+    }
+    if store.immutable {
+        if let Err(e) = store.seal_all() {
+            warn!("cannot make versions immutable, leaving them as they are: {e}");
+            store.immutable = false;
+        }
+    }
+    Ok((store, lock))
+}
+
+/// Mount the target given by `matches` at `mountpoint`. A new store starts
+/// out with the content of `seed`, if given.
+fn serve(
+    section: Option<String>,
+    extra: Vec<OsString>,
+    matches: ArgMatches,
+    mountpoint: &Path,
+    seed: Option<&Path>,
+) -> std::io::Result<(Served, BackgroundSession)> {
+    let (mut store, lock) = open_store(&matches)?;
+    if let (Some(seed), 0) = (seed, store.version) {
+        store.adopt(seed)?;
+    }
+    let scratch = Scratch::new(store.scratch_dir(), SCRATCH_INO_BASE);
+    let store = store.shared();
+    let activity = Arc::new(Activity::new());
+    let id_map = |name: &str| IdMap::new(matches.get_many::<(u32, u32)>(name).into_iter().flatten().copied().collect());
+    let fs = VersionFS{
+        store: store.clone(),
+        activity: activity.clone(),
+        uids: id_map("map-uid"),
+        gids: id_map("map-gid"),
+        default_permissions: matches.contains_id("default-permissions"),
+        root_squash: matches.contains_id("root-squash"),
+        strict_posix: matches.contains_id("strict-posix"),
+        root_changed: matches.contains_id("strict-posix").then(SystemTime::now),
+        dirs: HashMap::new(),
+        snapshots: HashMap::new(),
+        heads: HashMap::new(),
+        writable: HashSet::new(),
+        links: BTreeMap::new(),
+        pending: HashMap::new(),
+        next_link: 0,
+        scratch,
+        next_fh: 0,
+        head_hash: None,
+        hash: *matches.get_one::<hash::Algorithm>("hash").unwrap(),
+        write_buffer: *matches.get_one::<usize>("write-buffer").unwrap(),
+        buffers: HashMap::new(),
+        opened_head: None,
+        write_through_when_full: matches.get_one::<String>("when-full").unwrap() == "write-through",
+    };
+    let mut options = Vec::new();
+    if fs.default_permissions {
+        options.push(MountOption::DefaultPermissions);
+    }
+    // keep Finder from littering the store with ._ files, and name the
+    // volume after the target rather than "macFUSE Volume 0"
+    #[cfg(target_os = "macos")]
+    {
+        options.push(MountOption::CUSTOM("noappledouble".to_string()));
+        options.push(MountOption::CUSTOM(format!("volname={}", store.lock().unwrap().target.to_string_lossy())));
+    }
+    let session = fuser::spawn_mount2(fs, mountpoint, &options)?;
+    let policy = Arc::new(Mutex::new(compact_policy(&matches)));
+    let served = Served {
+        section,
+        extra,
+        matches,
+        store,
+        activity,
+        policy,
+        compacting: false,
+        last_snapshot: Instant::now(),
+        _lock: lock,
+    };
+    Ok((served, session))
+}
+
+impl Served {
+    /// Pick up `matches` re-read from the configuration.
+    fn reload(&mut self, matches: ArgMatches) {
+        log_changes(&self.matches, &matches);
+        self.matches = matches;
+        *self.policy.lock().unwrap() = compact_policy(&self.matches);
+        let max_versions = self.matches.get_one::<u64>("max-versions").map(|&max| max as usize);
+        self.store.lock().unwrap().max_versions = max_versions;
+        self.compact();
+    }
+
+    /// Start watching the store and compacting, as the options ask.
+    fn start(&mut self) {
+        if self.matches.contains_id("watch-store") {
+            #[cfg(target_os = "linux")]
+            watch::spawn(self.store.clone()).unwrap();
+            #[cfg(not(target_os = "linux"))]
+            warn!("--watch-store needs inotify, which this platform lacks; ignored");
+        }
+        self.compact();
+    }
+
+    /// Start compacting, if the policy asks for it and it hasn't started yet.
+    fn compact(&mut self) {
+        if !self.compacting && self.policy.lock().unwrap().is_some() {
+            Compactor::new(self.store.clone(), self.policy.clone()).spawn();
+            self.compacting = true;
+        }
+    }
+
+    /// Cut the versions that are due, called every second and told whether
+    /// a new minute started.
+    fn tick(&mut self, new_minute: bool) {
+        self.store.lock().unwrap().probe();
+        let secs = |name| self.matches.get_one::<u64>(name).map(|&secs| Duration::from_secs(secs));
+        let snapshot_every = secs("snapshot-every");
+        if snapshot_every.is_some_and(|every| self.last_snapshot.elapsed() >= every) {
+            self.last_snapshot = Instant::now();
+            snapshot_if_changed(&self.store);
+        }
+        let snapshot_cron = self.matches.get_one::<cron::Schedule>("snapshot-cron");
+        if new_minute && snapshot_cron.is_some_and(|cron| cron.matches(SystemTime::now())) {
+            snapshot_if_changed(&self.store);
+        }
+    }
+}
+
+/// A `--target` pattern, each file matching it being served as a target of
+/// its own, in a subdirectory of the mountpoint and of `target_dir`
+struct Pattern {
+    /// Its section of the configuration, if it has one of its own
+    section: Option<String>,
+    /// The directory of the files, and the pattern their names match
+    dir: PathBuf,
+    pattern: String,
+    rules: glob::Rules,
+    max_size: Option<u64>,
+    target_dir: PathBuf,
+    mountpoint: PathBuf,
+    /// Names of the files picked up so far
+    seen: BTreeSet<String>,
+}
+
+impl Pattern {
+    fn new(section: Option<String>, matches: &ArgMatches, mountpoint: PathBuf) -> Result<Self, String> {
+        let target = Path::new(matches.get_one::<OsString>("target").unwrap());
+        let dir = match target.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        if glob::is_pattern(&dir.to_string_lossy()) {
+            return Err(format!("only the file name of the target {target:?} may be a pattern"));
+        }
+        Ok(Pattern {
+            section,
+            dir: fs::canonicalize(dir).map_err(|e| format!("cannot read {dir:?}: {e}"))?,
+            pattern: target.file_name().unwrap().to_string_lossy().into(),
+            rules: rules(matches)?,
+            max_size: matches.get_one::<u64>("max-size").copied(),
+            target_dir: matches.get_one::<PathBuf>("target_dir").unwrap().clone(),
+            mountpoint,
+            seen: BTreeSet::new(),
+        })
+    }
+
+    /// Serve the files that match but haven't been picked up yet.
+    fn scan(&mut self, args: &[OsString], config: Option<&Path>) -> Vec<(Served, BackgroundSession)> {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) => {
+                warn!("cannot read {:?}: {e}", self.dir);
+                return Vec::new();
+            },
+        };
+        let mut found = Vec::new();
+        for entry in entries.flatten() {
+            let name = match entry.file_name().into_string() {
+                Ok(name) if glob::matches(&self.pattern, &name) && !self.seen.contains(&name) => name,
+                _ => continue,
+            };
+            let taken = match entry.metadata() {
+                Ok(meta) => meta.is_file() && self.max_size.is_none_or(|max| meta.len() <= max),
+                Err(_) => false,
+            };
+            if !taken || !self.rules.admits(&name) {
+                continue;
+            }
+            // tried once, so that a file that can't be served isn't warned about every second
+            self.seen.insert(name.clone());
+            match self.serve(&name, args, config) {
+                Ok(target) => {
+                    info!("serving {name:?}, which matches {:?}", self.pattern);
+                    found.push(target);
+                },
+                Err(e) => warn!("cannot serve {name:?}: {e}"),
+            }
+        }
+        found
+    }
+
+    fn serve(
+        &self,
+        name: &str,
+        args: &[OsString],
+        config: Option<&Path>,
+    ) -> Result<(Served, BackgroundSession), String> {
+        let target_dir = self.target_dir.join(name);
+        let mountpoint = self.mountpoint.join(name);
+        for dir in [&target_dir, &mountpoint] {
+            fs::create_dir_all(dir).map_err(|e| format!("cannot create {dir:?}: {e}"))?;
+        }
+        let extra = vec!["--target".into(), name.into(), "--target_dir".into(), target_dir.into()];
+        let matches = parse(args, &extra, config, self.section.as_deref())?;
+        let seed = self.dir.join(name);
+        let (mut target, session) = serve(self.section.clone(), extra, matches, &mountpoint, Some(&seed))
+            .map_err(|e| format!("cannot mount {mountpoint:?}: {e}"))?;
+        target.start();
+        Ok((target, session))
+    }
+}
+
+/// The `--include` and `--exclude` patterns, in the order given.
+fn rules(matches: &ArgMatches) -> Result<glob::Rules, String> {
+    let mut rules: Vec<_> = ["include", "exclude"]
+        .into_iter()
+        .flat_map(|name| {
+            let indices = matches.indices_of(name).into_iter().flatten();
+            let patterns = matches.get_many::<String>(name).into_iter().flatten();
+            indices.zip(patterns).map(move |(index, pattern)| (index, name == "include", pattern.clone()))
+        })
+        .collect();
+    rules.sort_by_key(|&(index, ..)| index);
+    // patterns pick files out of one directory, so there are no paths below
+    // it for rules to match yet
+    if let Some((.., pattern)) = rules.iter().find(|(.., pattern)| pattern.contains('/')) {
+        return Err(format!("{pattern:?} names a path, but rules only match the names of files yet"));
+    }
+    Ok(glob::Rules::new(rules.into_iter().map(|(_, include, pattern)| (include, pattern)).collect()))
+}
+
+/// Parse `args` followed by `extra`, with the options of `section` of the
+/// configuration if there is one.
+fn parse(
+    args: &[OsString],
+    extra: &[OsString],
+    config: Option<&Path>,
+    section: Option<&str>,
+) -> Result<ArgMatches, String> {
+    let args: Vec<OsString> = args.iter().chain(extra).cloned().collect();
+    match config {
+        Some(config) => with_config(&args, config, section),
+        None => cli().try_get_matches_from(args).map_err(|e| e.to_string()),
+    }
+}
+
+fn mount(args: &[OsString], matches: ArgMatches) {
+    let config = matches.get_one::<PathBuf>("config").cloned();
+    let mountpoint = matches.get_one::<PathBuf>("MOUNT_POINT").unwrap().clone();
+    let targets = targets(args, matches, config.as_deref()).unwrap_or_else(|e| {
+        eprintln!("{e}");
+        std::process::exit(1);
+    });
+    // options of the daemon rather than of a target are taken from the first
+    let (first, mut matches) = targets[0].clone();
+    set_log_level(&matches);
+    let identity = matches.get_one::<String>("user").map(|user| {
+        let group = matches.get_one::<String>("group").map(String::as_str);
+        privilege::lookup(user, group).unwrap_or_else(|e| {
+            eprintln!("{e}");
+            std::process::exit(1);
+        })
+    });
+    let pid_file = pidfile::path_for(&mountpoint).unwrap_or_else(|e| {
+        eprintln!("cannot mount {mountpoint:?}: {e}");
+        std::process::exit(1);
+    });
+    // one daemon per mountpoint, as another would mount over the first
+    match pidfile::running(&mountpoint) {
+        Ok(Some(daemon)) => {
+            eprintln!("versionfs is already serving {mountpoint:?} (pid {})", daemon.pid);
+            std::process::exit(1);
+        },
+        Ok(None) => {},
+        Err(e) => {
+            eprintln!("cannot check {mountpoint:?}: {e}");
+            std::process::exit(1);
+        },
+    }
+
+    let daemon = Arc::new(Mutex::new(Vec::new()));
+    let mut served = Vec::new();
+    let mut patterns = Vec::new();
+    for (name, matches) in targets {
+        let at = match &name {
+            Some(name) => mountpoint.join(name),
+            None => mountpoint.clone(),
+        };
+        if glob::is_pattern(&matches.get_one::<OsString>("target").unwrap().to_string_lossy()) {
+            match Pattern::new(name, &matches, at) {
+                Ok(pattern) => patterns.push(pattern),
+                Err(e) => {
+                    eprintln!("{e}");
+                    daemon.lock().unwrap().clear();
+                    std::process::exit(1);
+                },
+            }
+            continue;
+        }
+        if let Err(e) = fs::create_dir_all(&at) {
+            eprintln!("cannot create {at:?}: {e}");
+            std::process::exit(1);
+        }
+        match serve(name, Vec::new(), matches, &at, None) {
+            Ok((target, session)) => {
+                served.push(target);
+                daemon.lock().unwrap().push(session);
+            },
+            Err(e) => {
+                eprintln!("cannot mount {at:?}: {e}");
+                daemon.lock().unwrap().clear();
+                std::process::exit(1);
+            },
+        }
+    }
+    if let Err(e) = pidfile::write(&pid_file, config.as_deref()) {
+        eprintln!("cannot write {pid_file:?}: {e}");
+        daemon.lock().unwrap().clear();
+        std::process::exit(1);
+    }
+
+    // sockets passed by systemd take precedence over the configured addresses
+    // of the first target
+    let bind = |matches: &ArgMatches, name| {
+        matches.get_one::<SocketAddr>(name).map(|addr| {
+            TcpListener::bind(addr).unwrap_or_else(|e| {
+                eprintln!("cannot listen on {addr}: {e}");
+                daemon.lock().unwrap().clear();
+                std::process::exit(1);
+            })
+        })
+    };
+    let mut listeners: Vec<_> =
+        served.iter().map(|target| (bind(&target.matches, "web-ui"), bind(&target.matches, "api"))).collect();
+    for (name, fd) in systemd::listen_fds() {
+        let listener = unsafe { TcpListener::from_raw_fd(fd) };
+        match (name.as_str(), listeners.first_mut()) {
+            ("web-ui", Some(first)) => first.0 = Some(listener),
+            (_, Some(first)) => first.1 = Some(listener),
+            (_, None) => warn!("no target to serve the socket {name:?} passed by systemd for"),
+        }
+    }
+    // privileged ports are bound by now
+    if let Some(identity) = &identity {
+        if let Err(e) = privilege::drop_to(identity) {
+            eprintln!("cannot drop privileges: {e}");
+            daemon.lock().unwrap().clear();
+            std::process::exit(1);
+        }
+        for target in &served {
+            let target_dir = target.store.lock().unwrap().target_dir.clone();
+            if !privilege::can_write(&target_dir) {
+                warn!("{target_dir:?} is not writable after dropping privileges");
+            }
+        }
+    }
+    for (target, (web_ui, api)) in served.iter_mut().zip(listeners) {
+        if let Some(listener) = web_ui {
+            let on = listener.local_addr().map_or(String::new(), |addr| format!(" on {addr}"));
+            if let Err(e) = web::serve(listener, target.store.clone()) {
+                eprintln!("cannot serve the web UI{on}: {e}");
+                daemon.lock().unwrap().clear();
+                std::process::exit(1);
+            }
+        }
+        if let Some(listener) = api {
+            let on = listener.local_addr().map_or(String::new(), |addr| format!(" on {addr}"));
+            if let Err(e) = api::serve(listener, target.store.clone()) {
+                eprintln!("cannot serve the API{on}: {e}");
+                daemon.lock().unwrap().clear();
+                std::process::exit(1);
+            }
+        }
+        target.start();
+    }
+    // files matching a pattern share its options, so their stores aren't
+    // given the web UI and API, which would need an address each
+    let sessions = daemon.clone();
+    let scan = |patterns: &mut Vec<Pattern>, served: &mut Vec<Served>| {
+        for pattern in patterns {
+            for (target, session) in pattern.scan(args, config.as_deref()) {
+                served.push(target);
+                sessions.lock().unwrap().push(session);
+            }
+        }
+    };
+    scan(&mut patterns, &mut served);
+
+    if matches.contains_id("sandbox") {
+        let target_dirs = served.iter().map(|target| target.store.lock().unwrap().target_dir.clone());
+        if let Err(e) = confine(&target_dirs.collect::<Vec<_>>(), &pid_file, config.as_deref()) {
+            eprintln!("{e}");
+            daemon.lock().unwrap().clear();
+            std::process::exit(1);
+        }
+        if !patterns.is_empty() {
+            warn!("--sandbox keeps files that match a pattern from now on from being served");
+            patterns.clear();
+        }
+    }
+
+    let shutdown = move || {
+        systemd::notify("STOPPING=1");
+        daemon.lock().unwrap().clear();
+        let _ = std::fs::remove_file(&pid_file);
+        std::process::exit(0);
+    };
+    ctrlc::set_handler(shutdown.clone()).unwrap();
+    unsafe { libc::signal(libc::SIGHUP, request_reload as extern "C" fn(c_int) as libc::sighandler_t) };
+    unsafe { libc::signal(libc::SIGUSR1, request_snapshot as extern "C" fn(c_int) as libc::sighandler_t) };
+    systemd::notify("READY=1");
+
+    let minute = || SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs() / 60);
+    let mut last_minute = minute();
+    loop {
+        std::thread::sleep(Duration::from_secs(1));
+        if RELOAD.swap(false, Ordering::SeqCst) {
+            let path = match &config {
+                Some(path) => path,
+                None => {
+                    warn!("SIGHUP received, but there is no --config to reload");
+                    continue;
+                },
+            };
+            systemd::notify("RELOADING=1");
+            let mut reloaded = true;
+            for target in &mut served {
+                match parse(args, &target.extra, Some(path), target.section.as_deref()) {
+                    Ok(new) => target.reload(new),
+                    Err(e) => {
+                        warn!("keeping the current configuration: {e}");
+                        reloaded = false;
+                    },
+                }
+            }
+            if let Ok(new) = with_config(args, path, first.as_deref()) {
+                matches = new;
+            }
+            set_log_level(&matches);
+            if reloaded {
+                info!("reloaded {path:?}");
+            }
+            systemd::notify("READY=1");
+        }
+        if SNAPSHOT.swap(false, Ordering::SeqCst) {
+            for target in &served {
+                match target.store.lock().unwrap().snapshot() {
+                    Ok(head) => info!("snapshot taken as version {} on SIGUSR1", head - 1),
+                    Err(e) => warn!("cannot take a snapshot: {e}"),
+                }
+            }
+        }
+        let now = minute();
+        let new_minute = now != last_minute;
+        last_minute = now;
+        for target in &mut served {
+            target.tick(new_minute);
+        }
+        scan(&mut patterns, &mut served);
+        let idle_timeout = matches.get_one::<u64>("idle-timeout").map(|&s| Duration::from_secs(s));
+        let idle = served.iter().map(|target| target.activity.idle_for()).min().flatten();
+        if let (Some(timeout), Some(idle)) = (idle_timeout, idle) {
+            if idle >= timeout {
+                info!("idle for {}s, unmounting", idle.as_secs());
+                shutdown();
+            }
+        }
     }
 }
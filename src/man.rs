@@ -0,0 +1,95 @@
+//! A man page, generated from the definitions of the command line so it
+//! never falls behind it.
+
+use clap::{Arg, Command};
+
+/// `s` as roff text: backslashes and hyphens escaped, and lines kept from
+/// being read as requests.
+fn roff(s: &str) -> String {
+    s.lines()
+        .map(|line| {
+            let line = line.replace('\\', "\\e").replace('-', "\\-");
+            match line.starts_with('.') || line.starts_with('\'') {
+                true => format!("\\&{line}"),
+                false => line,
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// How `arg` is spelled in a synopsis, in bold with its value in italics
+fn usage(arg: &Arg) -> String {
+    let value = match arg.get_value_names() {
+        Some(names) => names.join(" "),
+        None => arg.get_id().to_uppercase(),
+    };
+    if arg.is_positional() {
+        return format!("\\fI{}\\fR", roff(&value));
+    }
+    let flags: Vec<String> = arg.get_short().map(|short| format!("\\fB\\-{short}\\fR"))
+        .into_iter()
+        .chain(arg.get_long().map(|long| format!("\\fB\\-\\-{}\\fR", roff(long))))
+        .collect();
+    match arg.is_takes_value_set() {
+        true => format!("{} \\fI{}\\fR", flags.join(", "), roff(&value)),
+        false => flags.join(", "),
+    }
+}
+
+/// Paragraphs describing the arguments of `cmd`
+fn arguments(cmd: &Command) -> String {
+    let mut out = String::new();
+    for arg in cmd.get_positionals().chain(cmd.get_arguments().filter(|arg| !arg.is_positional())) {
+        if arg.is_hide_set() {
+            continue;
+        }
+        out += &format!(".TP\n{}\n", usage(arg));
+        let mut help = arg.get_long_help().or_else(|| arg.get_help()).unwrap_or_default().to_string();
+        let choices: Vec<String> = match arg.is_takes_value_set() {
+            true => arg.get_value_parser().possible_values().into_iter().flatten()
+                .filter(|value| !value.is_hide_set())
+                .map(|value| value.get_name().to_string())
+                .collect(),
+            false => Vec::new(),
+        };
+        if !choices.is_empty() {
+            help += &format!(" (one of {})", choices.join(", "));
+        }
+        let defaults: Vec<_> = arg.get_default_values().iter().map(|value| value.to_string_lossy()).collect();
+        if !defaults.is_empty() && !arg.is_hide_default_value_set() {
+            help += &format!(" [default: {}]", defaults.join(", "));
+        }
+        out += &roff(&help);
+        out += "\n";
+    }
+    out
+}
+
+/// The man page of `cmd`, in section 1.
+pub fn generate(cmd: &mut Command) -> String {
+    cmd.build();
+    let name = cmd.get_name();
+    let version = cmd.get_version().unwrap_or_default();
+    let mut out = format!(".TH {} 1 \"\" \"{name} {version}\"\n", name.to_uppercase());
+    out += &format!(".SH NAME\n{name} \\- version a file on every change, through FUSE\n");
+    out += &format!(".SH SYNOPSIS\n\\fB{name}\\fR [\\fIOPTIONS\\fR]");
+    for arg in cmd.get_positionals() {
+        out += &format!(" {}", usage(arg));
+    }
+    out += &format!("\n.br\n\\fB{name}\\fR \\fISUBCOMMAND\\fR [\\fIOPTIONS\\fR]\n");
+    if let Some(about) = cmd.get_long_about().or_else(|| cmd.get_about()) {
+        out += &format!(".SH DESCRIPTION\n{}\n", roff(about));
+    }
+    out += ".SH OPTIONS\n";
+    out += &arguments(cmd);
+    out += ".SH SUBCOMMANDS\n";
+    for sub in cmd.get_subcommands().filter(|sub| sub.get_name() != "help") {
+        out += &format!(".SS \"{name} {}\"\n", sub.get_name());
+        if let Some(about) = sub.get_long_about().or_else(|| sub.get_about()) {
+            out += &format!("{}\n", roff(about));
+        }
+        out += &arguments(sub);
+    }
+    out
+}
@@ -0,0 +1,59 @@
+//! Read-only memory maps of version files.
+
+use std::fs::File;
+use std::io;
+use std::ops::Deref;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::ptr;
+
+/// A whole file mapped read-only and shared, so reads are served from the
+/// page cache without copying the file to the heap first.
+///
+/// The file must not shrink while mapped, or touching the lost pages raises
+/// `SIGBUS`. Version files only change under the store lock.
+pub struct Mmap {
+    ptr: *mut libc::c_void,
+    len: usize,
+}
+
+// the mapping is read-only
+unsafe impl Send for Mmap {}
+unsafe impl Sync for Mmap {}
+
+impl Mmap {
+    pub fn open(path: &Path) -> io::Result<Mmap> {
+        let file = File::open(path)?;
+        let len = file.metadata()?.len() as usize;
+        // mmap refuses empty mappings
+        if len == 0 {
+            return Ok(Mmap { ptr: ptr::null_mut(), len });
+        }
+        let ptr = unsafe {
+            libc::mmap(ptr::null_mut(), len, libc::PROT_READ, libc::MAP_SHARED, file.as_raw_fd(), 0)
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Mmap { ptr, len })
+    }
+}
+
+impl Deref for Mmap {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self.len {
+            0 => &[],
+            len => unsafe { std::slice::from_raw_parts(self.ptr as *const u8, len) },
+        }
+    }
+}
+
+impl Drop for Mmap {
+    fn drop(&mut self) {
+        if self.len > 0 {
+            unsafe { libc::munmap(self.ptr, self.len) };
+        }
+    }
+}
@@ -0,0 +1,85 @@
+//! Telling people of versions cut and of failures to cut them, rather than
+//! leaving it to the logs: on the desktop of whoever sits at the machine,
+//! and through an alert command when cutting versions keeps failing.
+//!
+//! Desktop notifications go through `notify-send`, which reaches the
+//! freedesktop notification service of the session the daemon runs in;
+//! without one they are dropped.
+
+use std::io;
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+
+use log::warn;
+
+/// Set once `notify-send` failed to start, so that is only reported once
+static UNAVAILABLE: AtomicBool = AtomicBool::new(false);
+
+/// Show `summary` and `body` on the desktop, as a failure if `failed`,
+/// without waiting for it.
+pub fn desktop(summary: &str, body: &str, failed: bool) {
+    let urgency = match failed {
+        true => "critical",
+        false => "normal",
+    };
+    let spawned = Command::new("notify-send")
+        .args(["--app-name=versionfs", "--urgency", urgency, summary, body])
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn();
+    match spawned {
+        // reaped aside, as the desktop may take its time answering
+        Ok(mut child) => { thread::spawn(move || child.wait()); },
+        Err(e) if !UNAVAILABLE.swap(true, Ordering::SeqCst) => warn!("cannot notify the desktop: {e}"),
+        Err(_) => {},
+    }
+}
+
+/// A command run through `sh -c` once cutting versions failed a number of
+/// times in a row, and again once it succeeds after that
+pub struct Alert {
+    command: String,
+    /// Failures in a row that set it off
+    after: u32,
+    failures: u32,
+}
+
+impl Alert {
+    pub fn new(command: String, after: u32) -> Self { Alert { command, after, failures: 0 } }
+
+    /// Count a failure to cut `version` of `target`, running the command if
+    /// it makes `after` in a row.
+    pub fn failed(&mut self, target: &str, version: usize, e: &io::Error) {
+        self.failures += 1;
+        if self.failures == self.after {
+            self.run("failing", target, version, &e.to_string());
+        }
+    }
+
+    /// Count `version` of `target` as cut, running the command if that ends
+    /// a run of failures it was run for.
+    pub fn succeeded(&mut self, target: &str, version: usize) {
+        if self.failures >= self.after {
+            self.run("recovered", target, version, "");
+        }
+        self.failures = 0;
+    }
+
+    fn run(&self, event: &str, target: &str, version: usize, error: &str) {
+        let spawned = Command::new("sh")
+            .args(["-c", &self.command])
+            .env("VERSIONFS_EVENT", event)
+            .env("VERSIONFS_TARGET", target)
+            .env("VERSIONFS_VERSION", version.to_string())
+            .env("VERSIONFS_FAILURES", self.failures.to_string())
+            .env("VERSIONFS_ERROR", error)
+            .stdin(Stdio::null())
+            .spawn();
+        match spawned {
+            Ok(mut child) => { thread::spawn(move || child.wait()); },
+            Err(e) => warn!("cannot run the alert command {:?}: {e}", self.command),
+        }
+    }
+}
@@ -0,0 +1,117 @@
+//! Per-mount pid files, letting `status` and `stop` find the daemon serving
+//! a mountpoint.
+
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+
+/// How long `stop` waits for the daemon to unmount and exit
+const STOP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Pid file of the daemon serving `mountpoint`, under `$XDG_RUNTIME_DIR` if set.
+pub fn path_for(mountpoint: &Path) -> io::Result<PathBuf> {
+    let mountpoint = fs::canonicalize(mountpoint)?;
+    let dir = env::var_os("XDG_RUNTIME_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(env::temp_dir)
+        .join("versionfs");
+    let name = mountpoint.to_string_lossy().replace('%', "%25").replace('/', "%2F");
+    Ok(dir.join(format!("{name}.pid")))
+}
+
+/// The live daemon serving a mountpoint
+pub struct Daemon {
+    pub pid: i32,
+    /// Its `--config` file, if it has one
+    pub config: Option<PathBuf>,
+}
+
+/// Write the pid file, followed by the absolute path of the `--config` file.
+pub fn write(path: &Path, config: Option<&Path>) -> io::Result<()> {
+    fs::create_dir_all(path.parent().unwrap())?;
+    let mut contents = format!("{}\n", std::process::id());
+    if let Some(config) = config {
+        contents.push_str(&format!("{}\n", fs::canonicalize(config)?.display()));
+    }
+    fs::write(path, contents)
+}
+
+fn alive(pid: i32) -> bool {
+    let ret = unsafe { libc::kill(pid, 0) };
+    ret == 0 || crate::errno() == libc::EPERM
+}
+
+/// The live daemon serving `mountpoint`, if any.
+pub fn running(mountpoint: &Path) -> io::Result<Option<Daemon>> {
+    let contents = match fs::read_to_string(path_for(mountpoint)?) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let mut lines = contents.lines();
+    let pid = lines.next().and_then(|pid| pid.trim().parse::<i32>().ok());
+    let config = lines.next().filter(|line| !line.is_empty()).map(PathBuf::from);
+    Ok(pid.filter(|&pid| alive(pid)).map(|pid| Daemon { pid, config }))
+}
+
+/// Report whether `mountpoint` is served, returning the exit code: 0 if it
+/// is, 3 if not (as LSB init scripts do). Printed as JSON with `json`.
+pub fn status(mountpoint: &Path, json: bool) -> i32 {
+    let quoted = crate::json_string(&mountpoint.to_string_lossy());
+    match running(mountpoint) {
+        Ok(Some(Daemon { pid, .. })) if json => {
+            println!("{{\"mountpoint\":{quoted},\"serving\":true,\"pid\":{pid}}}");
+            0
+        },
+        Ok(Some(Daemon { pid, .. })) => {
+            println!("versionfs is serving {mountpoint:?} (pid {pid})");
+            0
+        },
+        Ok(None) if json => {
+            println!("{{\"mountpoint\":{quoted},\"serving\":false}}");
+            3
+        },
+        Ok(None) => {
+            println!("versionfs is not serving {mountpoint:?}");
+            3
+        },
+        Err(e) => {
+            eprintln!("cannot check {mountpoint:?}: {e}");
+            4
+        },
+    }
+}
+
+/// Ask the daemon serving `mountpoint` to unmount and wait for it to exit.
+pub fn stop(mountpoint: &Path) -> i32 {
+    let pid = match running(mountpoint) {
+        Ok(Some(daemon)) => daemon.pid,
+        Ok(None) => {
+            eprintln!("versionfs is not serving {mountpoint:?}");
+            return 1;
+        },
+        Err(e) => {
+            eprintln!("cannot check {mountpoint:?}: {e}");
+            return 1;
+        },
+    };
+    if unsafe { libc::kill(pid, libc::SIGTERM) } != 0 {
+        eprintln!("cannot signal pid {pid}: {}", io::Error::last_os_error());
+        return 1;
+    }
+    let step = Duration::from_millis(100);
+    let mut waited = Duration::ZERO;
+    while alive(pid) {
+        if waited >= STOP_TIMEOUT {
+            eprintln!("pid {pid} did not exit within {}s", STOP_TIMEOUT.as_secs());
+            return 1;
+        }
+        thread::sleep(step);
+        waited += step;
+    }
+    println!("stopped versionfs serving {mountpoint:?}");
+    0
+}
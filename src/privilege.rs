@@ -0,0 +1,69 @@
+//! Dropping root privileges once the filesystem is mounted.
+
+use std::ffi::{CStr, CString};
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+/// The unprivileged user and group to run as
+pub struct Identity {
+    name: CString,
+    uid: libc::uid_t,
+    gid: libc::gid_t,
+}
+
+/// Look up `user` and `group` (names or numeric ids), defaulting to the
+/// primary group of `user`. Done before mounting, so a typo fails early.
+pub fn lookup(user: &str, group: Option<&str>) -> io::Result<Identity> {
+    let not_found = |what: &str| io::Error::new(io::ErrorKind::NotFound, format!("no such {what}"));
+    let cuser = CString::new(user)?;
+    let pw = match user.parse::<libc::uid_t>() {
+        Ok(uid) => unsafe { libc::getpwuid(uid) },
+        Err(_) => unsafe { libc::getpwnam(cuser.as_ptr()) },
+    };
+    if pw.is_null() {
+        return Err(not_found(&format!("user {user:?}")));
+    }
+    let (name, uid, mut gid) = unsafe {
+        (CStr::from_ptr((*pw).pw_name).to_owned(), (*pw).pw_uid, (*pw).pw_gid)
+    };
+    if let Some(group) = group {
+        gid = match group.parse::<libc::gid_t>() {
+            Ok(gid) => gid,
+            Err(_) => {
+                let cgroup = CString::new(group)?;
+                let gr = unsafe { libc::getgrnam(cgroup.as_ptr()) };
+                if gr.is_null() {
+                    return Err(not_found(&format!("group {group:?}")));
+                }
+                unsafe { (*gr).gr_gid }
+            },
+        };
+    }
+    Ok(Identity { name, uid, gid })
+}
+
+/// Switch to `identity` for good, including its supplementary groups.
+pub fn drop_to(identity: &Identity) -> io::Result<()> {
+    let check = |ret: libc::c_int| match ret {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    };
+    // the group is an int on macOS
+    check(unsafe { libc::initgroups(identity.name.as_ptr(), identity.gid as _) })?;
+    check(unsafe { libc::setgid(identity.gid) })?;
+    check(unsafe { libc::setuid(identity.uid) })?;
+    // there must be no way back
+    if identity.uid != 0 && unsafe { libc::setuid(0) } == 0 {
+        return Err(io::Error::other("root privileges could be regained"));
+    }
+    Ok(())
+}
+
+/// Whether the current user may write to `path`.
+pub fn can_write(path: &Path) -> bool {
+    match CString::new(path.as_os_str().as_bytes()) {
+        Ok(cpath) => unsafe { libc::access(cpath.as_ptr(), libc::W_OK) == 0 },
+        Err(_) => false,
+    }
+}
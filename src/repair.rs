@@ -0,0 +1,55 @@
+//! Repairing a damaged store in place.
+//!
+//! Every version is read back, which checks chunks against their hashes and
+//! zstd frames against their checksums, and applies each delta. Versions
+//! that fail, or whose files are gone, are moved to the quarantine
+//! directory and dropped from the store as if pruned, so that the others
+//! read as before. A version kept as a delta against a lost one can't be
+//! rebuilt, so it is lost with it. The bookkeeping files are then written
+//! afresh from what could be read of them.
+
+use std::io;
+
+use crate::store::Store;
+
+/// What a repair did
+#[derive(Default)]
+pub struct Report {
+    /// Changes a crash interrupted, settled
+    pub settled: usize,
+    /// Leftovers of interrupted runs removed
+    pub cleaned: usize,
+    /// Versions that no longer read back, with why
+    pub lost: Vec<(usize, io::Error)>,
+    /// Names dropped, with the versions they named
+    pub names: Vec<(String, usize)>,
+}
+
+/// Repair `store`, which must be locked.
+pub fn repair(store: &mut Store) -> io::Result<Report> {
+    let mut report = Report::default();
+    store.resume()?;
+    report.settled = store.replay_journal()?;
+    report.cleaned = store.clean_up()?;
+    // newest first, to tell which deltas are lost along with their base
+    for version in store.versions().into_iter().rev() {
+        let e = match store.read_version(version) {
+            Ok(_) => continue,
+            // the head is always whole, so it can only fail to be read
+            Err(e) if version == store.version => return Err(e),
+            Err(e) => e,
+        };
+        let base_lost = report.lost.last().is_some_and(|&(lost, _)| lost == version + 1);
+        let e = match base_lost && store.path_for_delta(version).exists() {
+            true => io::Error::other(format!("it is a delta against version {}, which is lost", version + 1)),
+            false => e,
+        };
+        report.lost.push((version, e));
+    }
+    report.lost.reverse();
+    for &(version, _) in &report.lost {
+        store.quarantine(version)?;
+    }
+    report.names = store.rebuild_bookkeeping()?;
+    Ok(report)
+}
@@ -0,0 +1,55 @@
+//! Retrying store operations that fail for a moment, as they do on network
+//! filesystems while the server is slow or briefly unreachable.
+
+use std::io;
+use std::thread;
+use std::time::Duration;
+
+use libc::{EAGAIN, EBUSY, EINTR, EIO, ENOLCK, ESTALE, ETIMEDOUT};
+use log::warn;
+
+use crate::interrupt;
+
+/// The longest to wait between two attempts
+const MAX_BACKOFF: Duration = Duration::from_secs(5);
+
+/// How often to try an operation, and how long to wait after the first
+/// failure, doubling with each one after
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Retry {
+    pub attempts: u32,
+    pub backoff: Duration,
+}
+
+impl Retry {
+    /// Try once and give up.
+    pub fn none() -> Self {
+        Retry { attempts: 1, backoff: Duration::ZERO }
+    }
+
+    /// Run `op` until it succeeds, fails for good, runs out of attempts, or
+    /// the caller of the request stops waiting.
+    pub fn run<T>(&self, mut op: impl FnMut() -> io::Result<T>) -> io::Result<T> {
+        let mut backoff = self.backoff;
+        let mut attempt = 1;
+        loop {
+            match op() {
+                Err(e) if attempt < self.attempts && transient(&e) => {
+                    interrupt::check()?;
+                    warn!("attempt {attempt} failed, retrying in {backoff:?}: {e}");
+                    thread::sleep(backoff);
+                    backoff = (backoff * 2).min(MAX_BACKOFF);
+                    attempt += 1;
+                },
+                result => return result,
+            }
+        }
+    }
+}
+
+/// Whether `e` may go away by itself. `ESTALE` is, since the path is looked
+/// up again, and NFS soft mounts report timeouts as `EIO`.
+pub fn transient(e: &io::Error) -> bool {
+    matches!(e.raw_os_error(), Some(EAGAIN | EBUSY | EINTR | EIO | ENOLCK | ESTALE | ETIMEDOUT))
+        || matches!(e.kind(), io::ErrorKind::Interrupted | io::ErrorKind::TimedOut)
+}
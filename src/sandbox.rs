@@ -0,0 +1,179 @@
+//! Confinement of the daemon once it is set up: Landlock limits filesystem
+//! access to the paths it still needs, and a seccomp filter limits it to the
+//! system calls used while serving.
+
+use std::ffi::CString;
+use std::fs::File;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::path::Path;
+
+use libc::{c_long, sock_filter, BPF_ABS, BPF_JEQ, BPF_JMP, BPF_K, BPF_LD, BPF_RET, BPF_W};
+
+const SYS_LANDLOCK_CREATE_RULESET: c_long = 444;
+const SYS_LANDLOCK_ADD_RULE: c_long = 445;
+const SYS_LANDLOCK_RESTRICT_SELF: c_long = 446;
+const LANDLOCK_RULE_PATH_BENEATH: libc::c_int = 1;
+
+/// Landlock access rights (ABI 1)
+const ACCESS_FS_EXECUTE: u64 = 1 << 0;
+const ACCESS_FS_WRITE_FILE: u64 = 1 << 1;
+const ACCESS_FS_READ_FILE: u64 = 1 << 2;
+const ACCESS_FS_READ_DIR: u64 = 1 << 3;
+const ACCESS_FS_REMOVE_DIR: u64 = 1 << 4;
+const ACCESS_FS_REMOVE_FILE: u64 = 1 << 5;
+const ACCESS_FS_MAKE_DIR: u64 = 1 << 7;
+const ACCESS_FS_MAKE_REG: u64 = 1 << 8;
+const ACCESS_FS_ALL: u64 = (1 << 13) - 1;
+
+/// Rights over the store: everything but executing and special files
+pub const READ_WRITE: u64 = ACCESS_FS_READ_FILE | ACCESS_FS_WRITE_FILE | ACCESS_FS_READ_DIR
+    | ACCESS_FS_REMOVE_DIR | ACCESS_FS_REMOVE_FILE | ACCESS_FS_MAKE_DIR | ACCESS_FS_MAKE_REG;
+pub const READ: u64 = ACCESS_FS_READ_FILE | ACCESS_FS_READ_DIR;
+pub const REMOVE: u64 = ACCESS_FS_REMOVE_FILE;
+
+#[repr(C)]
+struct RulesetAttr {
+    handled_access_fs: u64,
+}
+
+#[repr(C, packed)]
+struct PathBeneathAttr {
+    allowed_access: u64,
+    parent_fd: i32,
+}
+
+/// Deny all filesystem access but `rules`, each granting rights beneath a
+/// path. Returns `false` if the kernel doesn't support Landlock.
+pub fn landlock(rules: &[(&Path, u64)]) -> io::Result<bool> {
+    let attr = RulesetAttr { handled_access_fs: ACCESS_FS_ALL };
+    let size = std::mem::size_of::<RulesetAttr>();
+    let ruleset = unsafe { libc::syscall(SYS_LANDLOCK_CREATE_RULESET, &attr as *const RulesetAttr, size, 0) };
+    if ruleset < 0 {
+        let e = io::Error::last_os_error();
+        return match e.raw_os_error() {
+            Some(libc::ENOSYS | libc::EOPNOTSUPP) => Ok(false),
+            _ => Err(e),
+        };
+    }
+    let ruleset = ruleset as libc::c_int;
+    let result = (|| {
+        for &(path, access) in rules {
+            let cpath = CString::new(path.as_os_str().as_bytes())?;
+            let fd = unsafe { libc::open(cpath.as_ptr(), libc::O_PATH | libc::O_CLOEXEC) };
+            if fd < 0 {
+                return Err(io::Error::other(format!("{path:?}: {}", io::Error::last_os_error())));
+            }
+            let file = unsafe { File::from_raw_fd(fd) };
+            // only file rights apply to a file
+            let access = match file.metadata()?.is_dir() {
+                true => access,
+                false => access & (ACCESS_FS_EXECUTE | ACCESS_FS_WRITE_FILE | ACCESS_FS_READ_FILE),
+            };
+            let rule = PathBeneathAttr { allowed_access: access, parent_fd: file.as_raw_fd() };
+            let ret = unsafe {
+                libc::syscall(SYS_LANDLOCK_ADD_RULE, ruleset, LANDLOCK_RULE_PATH_BENEATH, &rule as *const PathBeneathAttr, 0)
+            };
+            if ret < 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        no_new_privs()?;
+        match unsafe { libc::syscall(SYS_LANDLOCK_RESTRICT_SELF, ruleset, 0) } {
+            0 => Ok(true),
+            _ => Err(io::Error::last_os_error()),
+        }
+    })();
+    unsafe { libc::close(ruleset) };
+    result
+}
+
+fn no_new_privs() -> io::Result<()> {
+    match unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: u32 = 0xc000_003e;
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: u32 = 0xc000_00b7;
+
+/// System calls made while serving: the FUSE loop, file IO and extended
+/// attributes, threads and memory, the HTTP listeners, inotify, signals and
+/// unmounting.
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+const ALLOWED_SYSCALLS: &[c_long] = &[
+    libc::SYS_read, libc::SYS_write, libc::SYS_readv, libc::SYS_writev,
+    libc::SYS_pread64, libc::SYS_pwrite64, libc::SYS_openat, libc::SYS_close,
+    libc::SYS_fstat, libc::SYS_newfstatat, libc::SYS_statx, libc::SYS_lseek,
+    libc::SYS_getdents64, libc::SYS_readlinkat, libc::SYS_getcwd, libc::SYS_statfs,
+    libc::SYS_fstatfs, libc::SYS_fcntl, libc::SYS_flock, libc::SYS_ftruncate,
+    libc::SYS_fsync, libc::SYS_fdatasync, libc::SYS_fallocate, libc::SYS_utimensat,
+    libc::SYS_fchmod, libc::SYS_fchmodat, libc::SYS_fchown, libc::SYS_fchownat,
+    libc::SYS_renameat, libc::SYS_renameat2, libc::SYS_linkat, libc::SYS_unlinkat,
+    libc::SYS_mkdirat, libc::SYS_faccessat, libc::SYS_copy_file_range,
+    libc::SYS_lgetxattr, libc::SYS_llistxattr, libc::SYS_lsetxattr, libc::SYS_lremovexattr,
+    libc::SYS_dup, libc::SYS_dup3, libc::SYS_pipe2, libc::SYS_eventfd2,
+    libc::SYS_ioctl, libc::SYS_mmap, libc::SYS_munmap, libc::SYS_mprotect,
+    libc::SYS_mremap, libc::SYS_madvise, libc::SYS_brk, libc::SYS_futex,
+    libc::SYS_clone, libc::SYS_clone3, libc::SYS_set_robust_list, libc::SYS_rseq,
+    libc::SYS_sched_yield, libc::SYS_sched_getaffinity, libc::SYS_exit, libc::SYS_exit_group,
+    libc::SYS_gettid, libc::SYS_getpid, libc::SYS_getuid, libc::SYS_geteuid,
+    libc::SYS_getgid, libc::SYS_getegid, libc::SYS_tgkill, libc::SYS_prlimit64,
+    libc::SYS_getrandom, libc::SYS_clock_gettime, libc::SYS_clock_nanosleep, libc::SYS_nanosleep,
+    libc::SYS_rt_sigaction, libc::SYS_rt_sigprocmask, libc::SYS_rt_sigreturn, libc::SYS_sigaltstack,
+    libc::SYS_socket, libc::SYS_accept, libc::SYS_accept4, libc::SYS_recvfrom,
+    libc::SYS_sendto, libc::SYS_recvmsg, libc::SYS_sendmsg, libc::SYS_shutdown,
+    libc::SYS_setsockopt, libc::SYS_getsockopt, libc::SYS_getsockname, libc::SYS_getpeername,
+    libc::SYS_ppoll, libc::SYS_pselect6, libc::SYS_epoll_pwait, libc::SYS_epoll_ctl,
+    libc::SYS_inotify_add_watch, libc::SYS_inotify_rm_watch, libc::SYS_umount2, libc::SYS_wait4,
+    #[cfg(target_arch = "x86_64")] libc::SYS_open,
+    #[cfg(target_arch = "x86_64")] libc::SYS_stat,
+    #[cfg(target_arch = "x86_64")] libc::SYS_lstat,
+    #[cfg(target_arch = "x86_64")] libc::SYS_poll,
+    #[cfg(target_arch = "x86_64")] libc::SYS_rename,
+    #[cfg(target_arch = "x86_64")] libc::SYS_link,
+    #[cfg(target_arch = "x86_64")] libc::SYS_unlink,
+    #[cfg(target_arch = "x86_64")] libc::SYS_mkdir,
+    #[cfg(target_arch = "x86_64")] libc::SYS_access,
+    #[cfg(target_arch = "x86_64")] libc::SYS_chmod,
+    #[cfg(target_arch = "x86_64")] libc::SYS_chown,
+    #[cfg(target_arch = "x86_64")] libc::SYS_readlink,
+    #[cfg(target_arch = "x86_64")] libc::SYS_epoll_wait,
+    #[cfg(target_arch = "x86_64")] libc::SYS_arch_prctl,
+];
+
+/// Fail any system call outside of `ALLOWED_SYSCALLS` with `EPERM`.
+/// Returns `false` on architectures without a list.
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+pub fn seccomp() -> io::Result<bool> {
+    let stmt = |code: u32, k: u32| sock_filter { code: code as u16, jt: 0, jf: 0, k };
+    let jump = |k: u32, jt: u8, jf: u8| sock_filter { code: (BPF_JMP | BPF_JEQ | BPF_K) as u16, jt, jf, k };
+    let mut filter = vec![
+        // offsetof(struct seccomp_data, arch)
+        stmt(BPF_LD | BPF_W | BPF_ABS, 4),
+        jump(AUDIT_ARCH, 1, 0),
+        stmt(BPF_RET | BPF_K, libc::SECCOMP_RET_KILL_PROCESS),
+        // offsetof(struct seccomp_data, nr)
+        stmt(BPF_LD | BPF_W | BPF_ABS, 0),
+    ];
+    for &nr in ALLOWED_SYSCALLS {
+        filter.push(jump(nr as u32, 0, 1));
+        filter.push(stmt(BPF_RET | BPF_K, libc::SECCOMP_RET_ALLOW));
+    }
+    filter.push(stmt(BPF_RET | BPF_K, libc::SECCOMP_RET_ERRNO | libc::EPERM as u32));
+    let prog = libc::sock_fprog { len: filter.len() as u16, filter: filter.as_mut_ptr() };
+    no_new_privs()?;
+    match unsafe { libc::prctl(libc::PR_SET_SECCOMP, libc::SECCOMP_MODE_FILTER, &prog as *const libc::sock_fprog) } {
+        0 => Ok(true),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+pub fn seccomp() -> io::Result<bool> {
+    Ok(false)
+}
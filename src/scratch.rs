@@ -0,0 +1,161 @@
+//! Files made in the mount next to the target, such as lock files and the
+//! temporary files editors write before renaming them onto the target. They
+//! are kept as they are in a directory of the store, outside of versioning.
+
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::ffi::{CString, OsStr, OsString};
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{DirBuilderExt, MetadataExt};
+use std::path::PathBuf;
+
+use libc::c_int;
+
+pub struct Scratch {
+    dir: PathBuf,
+    /// Inodes of the mount are numbered from here
+    ino_base: u64,
+    inodes: RefCell<Inodes>,
+}
+
+/// The inodes the files have been given in the mount, in the order they
+/// were first seen
+#[derive(Default)]
+struct Inodes {
+    /// Inodes in the mount by those of the files
+    by_file: HashMap<u64, u64>,
+    /// A name of each file, by inode in the mount
+    names: HashMap<u64, OsString>,
+    /// Inodes of files renamed onto the target, which the kernel knows it by
+    /// until it looks the name up again
+    adopted: HashSet<u64>,
+    last: u64,
+}
+
+impl Scratch {
+    pub fn new(dir: PathBuf, ino_base: u64) -> Self {
+        Scratch { dir, ino_base, inodes: RefCell::new(Inodes::default()) }
+    }
+
+    pub fn path(&self, name: &OsStr) -> PathBuf { self.dir.join(name) }
+
+    /// The file known as `ino` in the mount, by one of its names.
+    pub fn path_of(&self, ino: u64) -> Option<PathBuf> {
+        self.inodes.borrow().names.get(&ino).map(|name| self.path(name))
+    }
+
+    /// Whether the file known as `ino` was renamed onto the target.
+    pub fn adopted(&self, ino: u64) -> bool { self.inodes.borrow().adopted.contains(&ino) }
+
+    /// Whether there is a file `name`.
+    pub fn contains(&self, name: &OsStr) -> bool { fs::symlink_metadata(self.path(name)).is_ok() }
+
+    /// The inode in the mount and metadata of the file `name`.
+    pub fn lookup(&self, name: &OsStr) -> io::Result<(u64, fs::Metadata)> {
+        let meta = fs::symlink_metadata(self.path(name))?;
+        let mut inodes = self.inodes.borrow_mut();
+        let ino = match inodes.by_file.get(&meta.ino()) {
+            Some(&ino) => ino,
+            None => {
+                inodes.last += 1;
+                let ino = self.ino_base + inodes.last;
+                inodes.by_file.insert(meta.ino(), ino);
+                ino
+            },
+        };
+        inodes.names.insert(ino, name.to_os_string());
+        Ok((ino, meta))
+    }
+
+    /// The names of the files, with their inodes in the mount.
+    pub fn entries(&self) -> io::Result<Vec<(u64, OsString)>> {
+        let dir = match fs::read_dir(&self.dir) {
+            Ok(dir) => dir,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+        let mut entries = Vec::new();
+        for entry in dir {
+            let name = entry?.file_name();
+            match self.lookup(&name) {
+                Ok((ino, _)) => entries.push((ino, name)),
+                // removed in the meantime
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {},
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Create the regular file `name` with `mode` and open it with `flags`,
+    /// owned by `uid` and `gid` if the daemon may hand it out. Returns the fd.
+    pub fn create(&self, name: &OsStr, mode: u32, flags: c_int, (uid, gid): (u32, u32)) -> io::Result<c_int> {
+        match fs::DirBuilder::new().mode(0o700).create(&self.dir) {
+            Err(e) if e.kind() != io::ErrorKind::AlreadyExists => return Err(e),
+            _ => {},
+        }
+        let cpath = CString::new(self.path(name).as_os_str().as_bytes()).unwrap();
+        let flags = flags | libc::O_CREAT | libc::O_EXCL | libc::O_CLOEXEC;
+        let fd = match unsafe { libc::open(cpath.as_ptr(), flags, (mode & 0o7777) as libc::c_uint) } {
+            -1 => return Err(io::Error::last_os_error()),
+            fd => fd,
+        };
+        if unsafe { libc::fchown(fd, uid, gid) } == -1 {
+            let e = io::Error::last_os_error();
+            if e.raw_os_error() != Some(libc::EPERM) {
+                unsafe { libc::close(fd) };
+                return Err(e);
+            }
+        }
+        Ok(fd)
+    }
+
+    /// Open the file known as `ino` with `flags`. Returns the fd.
+    pub fn open(&self, ino: u64, flags: c_int) -> io::Result<c_int> {
+        let path = self.path_of(ino).ok_or_else(|| io::Error::from_raw_os_error(libc::ENOENT))?;
+        let cpath = CString::new(path.as_os_str().as_bytes()).unwrap();
+        let flags = (flags & !(libc::O_CREAT | libc::O_EXCL)) | libc::O_CLOEXEC | libc::O_NOFOLLOW;
+        match unsafe { libc::open(cpath.as_ptr(), flags) } {
+            -1 => Err(io::Error::last_os_error()),
+            fd => Ok(fd),
+        }
+    }
+
+    /// Give the file known as `ino` the name `newname` too.
+    pub fn link(&self, ino: u64, newname: &OsStr) -> io::Result<()> {
+        let path = self.path_of(ino).ok_or_else(|| io::Error::from_raw_os_error(libc::ENOENT))?;
+        fs::hard_link(path, self.path(newname))
+    }
+
+    /// Rename the file `name` to `newname`, replacing any file there.
+    pub fn rename(&self, name: &OsStr, newname: &OsStr) -> io::Result<()> {
+        fs::rename(self.path(name), self.path(newname))?;
+        self.forget(newname);
+        self.lookup(newname).map(|_| ())
+    }
+
+    /// Remove the name `name` of a file. With `adopted`, its content was
+    /// taken onto the target.
+    pub fn remove(&self, name: &OsStr, adopted: bool) -> io::Result<()> {
+        let linked = fs::symlink_metadata(self.path(name))?.nlink() > 1;
+        fs::remove_file(self.path(name))?;
+        if linked {
+            // keeps its inode, to be found by its other names
+            self.inodes.borrow_mut().names.retain(|_, other| other.as_os_str() != name);
+        } else if let (Some(ino), true) = (self.forget(name), adopted) {
+            self.inodes.borrow_mut().adopted.insert(ino);
+        }
+        Ok(())
+    }
+
+    /// Stop knowing a file by `name`, returning its inode in the mount.
+    fn forget(&self, name: &OsStr) -> Option<u64> {
+        let mut inodes = self.inodes.borrow_mut();
+        let ino = *inodes.names.iter().find(|(_, other)| other.as_os_str() == name)?.0;
+        inodes.names.remove(&ino);
+        inodes.by_file.retain(|_, other| *other != ino);
+        Some(ino)
+    }
+}
@@ -0,0 +1,1571 @@
+//! The versions of the target kept in `target_dir`.
+
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+use std::ffi::{OsStr, OsString};
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileExt, MetadataExt};
+use std::os::unix::io::AsRawFd;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use log::{info, warn};
+
+use crate::cache::VersionCache;
+use crate::mmap::Mmap;
+use crate::retry::Retry;
+use crate::{chunk, delta, diff, interrupt, notify, xattr, zstd};
+
+/// Store state shared between the FUSE session and background threads.
+pub type SharedStore = Arc<Mutex<Store>>;
+
+/// Size and times of a version, however it is stored.
+pub struct VersionMeta {
+    pub len: u64,
+    pub modified: SystemTime,
+    /// When the version was cut, or its modification time where the store
+    /// doesn't record birth times
+    pub created: SystemTime,
+}
+
+/// Content of a version: mapped if stored whole, rebuilt otherwise.
+pub enum Content {
+    Mapped(Mmap),
+    Rebuilt(Vec<u8>),
+}
+
+impl std::ops::Deref for Content {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            Content::Mapped(map) => map,
+            Content::Rebuilt(data) => data,
+        }
+    }
+}
+
+/// How version numbers are spelled in file names.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Naming {
+    /// `42.target`
+    Plain,
+    /// `000042.target`, so that plain listings sort chronologically
+    Padded,
+}
+
+const PADDED_WIDTH: usize = 6;
+
+/// Bytes of rendered diffs to keep
+const DIFF_CACHE_BYTES: usize = 16 << 20;
+
+/// Whether versions are synced to disk before they count as written.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Durability {
+    /// Sync new versions before publishing them, and writes before
+    /// acknowledging `fsync` and the close that finishes a version
+    Strict,
+    /// Leave writing them back to the kernel, for lower latency
+    Relaxed,
+}
+
+/// The layout of the store this build writes. Each change to it that older
+/// builds would misread bumps it, with a step in `Store::upgrade`.
+pub const FORMAT: u32 = 1;
+
+/// The format of the store, missing from stores of format 0
+const FORMAT_FILE: &str = ".versionfs.format";
+
+/// Held locked by the daemon that has the store mounted.
+const LOCK_FILE: &str = ".versionfs.lock";
+
+/// Names given to versions, one `name version` per line
+const NAMES_FILE: &str = ".versionfs.names";
+
+/// Versions cut by reverting, one `version from [start..end]` per line
+const REVERTS_FILE: &str = ".versionfs.reverts";
+
+/// Versions that are symbolic links, holding the path they point to, one
+/// per line
+const SYMLINKS_FILE: &str = ".versionfs.symlinks";
+
+/// Versions removed to keep within `max_versions`, one `start..end` range
+/// per line
+const PRUNED_FILE: &str = ".versionfs.pruned";
+
+/// Chunks of the versions compacted into manifests, by hash
+const CHUNKS_DIR: &str = ".versionfs.chunks";
+
+/// Files made in the mount next to the target
+const SCRATCH_DIR: &str = ".versionfs.scratch";
+
+/// Files of versions that no longer read back, moved aside by a repair
+const QUARANTINE_DIR: &str = ".versionfs.quarantine";
+
+/// Changes under way, one `create N`, `compact N` or `prune N` per line
+pub const JOURNAL_FILE: &str = ".versionfs.journal";
+
+/// Written and removed to find out whether a degraded store takes changes
+/// again
+const PROBE_FILE: &str = ".versionfs.probe";
+
+/// Undo and redo within this session, each a revert
+#[derive(Default)]
+struct Undo {
+    /// The head cut by the last undo or redo; anything else cut since then
+    /// starts afresh
+    head: usize,
+    /// The version the head holds the content of
+    at: usize,
+    /// Versions undone, most recent last
+    redo: Vec<usize>,
+}
+
+/// Where the content of a version cut by reverting came from
+#[derive(Clone, Debug, PartialEq)]
+pub struct Revert {
+    pub from: usize,
+    /// The bytes put back, if not the whole content
+    pub range: Option<Range<u64>>,
+}
+
+/// A change to the store that a crash could leave half made
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Change {
+    /// Cutting version N
+    Create(usize),
+    /// Replacing version N by its compacted form
+    Compact(usize),
+    /// Removing version N to keep within `max_versions`
+    Prune(usize),
+}
+
+/// `_IOW(0x94, 9, int)`, sharing the extents of one file with another
+#[cfg(target_os = "linux")]
+const FICLONE: u64 = 0x4004_9409;
+
+/// Copy `from` to `to` as a reflink where the filesystem can (btrfs, XFS,
+/// ZFS with block cloning), so versions share their unchanged blocks and
+/// cutting one takes no time whatever the size. Elsewhere, copy the data,
+/// leaving holes as they are. The copy keeps the modification time, so a
+/// version only looks modified once it is written to.
+#[cfg(target_os = "linux")]
+fn clone_or_copy(from: &Path, to: &Path) -> io::Result<()> {
+    let src = File::open(from)?;
+    let dst = File::create(to)?;
+    if unsafe { libc::ioctl(dst.as_raw_fd(), FICLONE as _, src.as_raw_fd()) } != 0 {
+        let e = io::Error::last_os_error();
+        match e.raw_os_error() {
+            Some(libc::EOPNOTSUPP | libc::EXDEV | libc::EINVAL | libc::ENOTTY) => copy_sparse(&src, &dst)?,
+            _ => return Err(e),
+        }
+    }
+    dst.set_modified(src.metadata()?.modified()?)?;
+    keep_ownership(from, to)
+}
+
+/// Bytes to copy between checks for interrupts
+#[cfg(target_os = "linux")]
+const COPY_STEP: i64 = 64 << 20;
+
+/// Copy the data extents of `src` to `dst` and extend it to the same length,
+/// so that sparse files such as disk images don't grow with each version.
+#[cfg(target_os = "linux")]
+fn copy_sparse(src: &File, dst: &File) -> io::Result<()> {
+    let len = src.metadata()?.len() as i64;
+    let (fd_in, fd_out) = (src.as_raw_fd(), dst.as_raw_fd());
+    let mut pos = 0;
+    while pos < len {
+        let mut start = unsafe { libc::lseek(fd_in, pos, libc::SEEK_DATA) };
+        if start == -1 {
+            match io::Error::last_os_error() {
+                // only a hole is left
+                e if e.raw_os_error() == Some(libc::ENXIO) => break,
+                e => return Err(e),
+            }
+        }
+        let end = match unsafe { libc::lseek(fd_in, start, libc::SEEK_HOLE) } {
+            -1 => return Err(io::Error::last_os_error()),
+            end => end,
+        };
+        let mut out = start;
+        while start < end {
+            // in steps, to give up soon after the caller does
+            interrupt::check()?;
+            let step = (end - start).min(COPY_STEP);
+            let n = unsafe { libc::copy_file_range(fd_in, &mut start, fd_out, &mut out, step as usize, 0) };
+            match n {
+                -1 => return Err(io::Error::last_os_error()),
+                // the file shrank under us
+                0 => break,
+                _ => {},
+            }
+        }
+        pos = end;
+    }
+    dst.set_len(len as u64)
+}
+
+/// `fs::copy` already clones files on macOS.
+#[cfg(not(target_os = "linux"))]
+fn clone_or_copy(from: &Path, to: &Path) -> io::Result<()> {
+    fs::copy(from, to)?;
+    File::open(to)?.set_modified(fs::metadata(from)?.modified()?)?;
+    keep_ownership(from, to)
+}
+
+/// `_IOR('f', 1, long)` and `_IOW('f', 2, long)`, getting and setting the
+/// flags `chattr` changes, of which `FS_IMMUTABLE_FL` is `+i`
+#[cfg(target_os = "linux")]
+const FS_IOC_GETFLAGS: u64 = 0x8008_6601;
+#[cfg(target_os = "linux")]
+const FS_IOC_SETFLAGS: u64 = 0x4008_6602;
+#[cfg(target_os = "linux")]
+const FS_IMMUTABLE_FL: libc::c_int = 0x10;
+
+/// Set or clear the immutable flag of `path`, as `chattr +i` and `-i` do,
+/// which takes `CAP_LINUX_IMMUTABLE`. Filesystems without the flag are left
+/// alone.
+#[cfg(target_os = "linux")]
+fn set_immutable(path: &Path, immutable: bool) -> io::Result<()> {
+    let file = File::open(path)?;
+    let mut flags: libc::c_int = 0;
+    if unsafe { libc::ioctl(file.as_raw_fd(), FS_IOC_GETFLAGS as _, &mut flags) } != 0 {
+        let e = io::Error::last_os_error();
+        return match e.raw_os_error() {
+            Some(libc::ENOTTY | libc::EOPNOTSUPP | libc::EINVAL) => Ok(()),
+            _ => Err(e),
+        };
+    }
+    let wanted = match immutable {
+        true => flags | FS_IMMUTABLE_FL,
+        false => flags & !FS_IMMUTABLE_FL,
+    };
+    if wanted != flags && unsafe { libc::ioctl(file.as_raw_fd(), FS_IOC_SETFLAGS as _, &wanted) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_immutable(_path: &Path, _immutable: bool) -> io::Result<()> { Ok(()) }
+
+/// Lift the immutable flag of the version file `path`, if it is there, so
+/// that it can be replaced, moved or removed.
+pub fn unseal(path: &Path) -> io::Result<()> {
+    match set_immutable(path, false) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+/// Remove the version file `path`, lifting its immutable flag first.
+pub fn discard(path: &Path) -> io::Result<()> {
+    unseal(path)?;
+    fs::remove_file(path)
+}
+
+/// Give `to` the mode of `from`, and its owner and group as far as the
+/// daemon may hand them out, which takes root for other users.
+pub fn keep_ownership(from: &Path, to: &Path) -> io::Result<()> {
+    let meta = fs::metadata(from)?;
+    // before the mode, as it clears the set-id bits
+    match std::os::unix::fs::chown(to, Some(meta.uid()), Some(meta.gid())) {
+        Err(e) if e.raw_os_error() == Some(libc::EPERM) => {},
+        result => result?,
+    }
+    fs::set_permissions(to, meta.permissions())
+}
+
+/// Put the complete file `tmp_path` in place as `path`, syncing it before
+/// the rename and the directory after, so that neither a listing nor a crash
+/// ever shows a partial file under `path`.
+pub fn publish(tmp_path: &Path, path: &Path) -> io::Result<()> {
+    File::open(tmp_path)?.sync_all()?;
+    fs::rename(tmp_path, path)?;
+    sync_dir(path)
+}
+
+/// Sync the directory holding `path`, making a new entry in it durable.
+fn sync_dir(path: &Path) -> io::Result<()> {
+    File::open(path.parent().unwrap_or(Path::new(".")))?.sync_all()
+}
+
+/// Bytes free to unprivileged users on the filesystem holding `path`.
+fn free_space(path: &Path) -> io::Result<u64> {
+    let cpath = std::ffi::CString::new(path.as_os_str().as_bytes())?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    match unsafe { libc::statvfs(cpath.as_ptr(), &mut stat) } {
+        0 => Ok(stat.f_bavail as u64 * stat.f_frsize as u64),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+pub struct Store {
+    pub target: OsString,
+    pub target_dir: PathBuf,
+    /// Spread version files over `ab/cd/` subdirectories of `target_dir`
+    pub sharded: bool,
+    pub naming: Naming,
+    /// The head version, 0 until the filesystem is initialized
+    pub version: usize,
+    /// Versions by the names given to them
+    pub names: BTreeMap<String, usize>,
+    /// Versions cut by reverting, never rewritten
+    pub reverts: BTreeMap<usize, Revert>,
+    /// Versions that are symbolic links rather than files
+    pub symlinks: BTreeSet<usize>,
+    /// Ranges of versions removed, by their first version
+    pruned: BTreeMap<usize, usize>,
+    /// How many versions to keep at most, removing the oldest without a name
+    /// as new ones are cut
+    pub max_versions: Option<usize>,
+    /// Bytes to keep free on the disk besides room for a copy of the head,
+    /// removing the oldest versions without a name as new ones are cut
+    pub low_space: Option<u64>,
+    undo: Undo,
+    /// Rendered diffs of versions that can no longer change, the latest used
+    /// up to `DIFF_CACHE_BYTES`
+    diffs: VersionCache,
+    /// Versions recently rebuilt from deltas, chunks or zstd frames
+    cache: RefCell<VersionCache>,
+    /// How to retry reading and cutting versions on transient errors
+    pub retry: Retry,
+    /// Changes begun and not ended yet, as in `JOURNAL_FILE`
+    journal: Vec<Change>,
+    pub durability: Durability,
+    /// Make versions immutable once they are no longer the head
+    pub immutable: bool,
+    /// Tell the desktop of versions cut and of failures to cut them
+    pub notify_desktop: bool,
+    /// Command to run when cutting versions keeps failing
+    pub alert: Option<notify::Alert>,
+    /// Why the store stopped taking changes, e.g. it went read-only or its
+    /// disk went away. No versions are cut until `probe` finds it writable.
+    pub degraded: Option<String>,
+}
+
+impl Store {
+    pub fn new(target: OsString, target_dir: PathBuf) -> Self {
+        Store {
+            target,
+            target_dir,
+            sharded: false,
+            naming: Naming::Plain,
+            version: 0,
+            names: BTreeMap::new(),
+            reverts: BTreeMap::new(),
+            symlinks: BTreeSet::new(),
+            pruned: BTreeMap::new(),
+            max_versions: None,
+            low_space: None,
+            undo: Undo::default(),
+            diffs: VersionCache::new(DIFF_CACHE_BYTES),
+            cache: RefCell::new(VersionCache::new(0)),
+            retry: Retry::none(),
+            journal: Vec::new(),
+            durability: Durability::Strict,
+            immutable: false,
+            notify_desktop: false,
+            alert: None,
+            degraded: None,
+        }
+    }
+
+    pub fn shared(self) -> SharedStore { Arc::new(Mutex::new(self)) }
+
+    /// A copy that reads versions as this store does, with a cache of its
+    /// own, for reading them without holding the store's lock.
+    pub fn reader(&self) -> Store {
+        Store {
+            sharded: self.sharded,
+            naming: self.naming,
+            version: self.version,
+            retry: self.retry,
+            ..Store::new(self.target.clone(), self.target_dir.clone())
+        }
+    }
+
+    pub fn name_for_version(&self, version: usize) -> String {
+        let target = self.target.to_str().unwrap();
+        match self.naming {
+            Naming::Plain => format!("{version}.{target}"),
+            Naming::Padded => format!("{version:0PADDED_WIDTH$}.{target}"),
+        }
+    }
+
+    /// Directory holding the files of `version`.
+    ///
+    /// When sharded, this is two levels below `target_dir`, named after a hash
+    /// of the version number, so that no directory grows too large.
+    fn dir_for_version(&self, version: usize) -> PathBuf {
+        if !self.sharded {
+            return self.target_dir.clone();
+        }
+        let hash = mix(version as u64);
+        self.target_dir
+            .join(format!("{:02x}", hash >> 56))
+            .join(format!("{:02x}", (hash >> 48) & 0xff))
+    }
+
+    fn create_dir_for(&self, version: usize) -> io::Result<()> {
+        match self.sharded {
+            true => fs::create_dir_all(self.dir_for_version(version)),
+            false => Ok(()),
+        }
+    }
+
+    pub fn path_for_version(&self, version: usize) -> PathBuf {
+        self.dir_for_version(version).join(self.name_for_version(version))
+    }
+
+    /// Where `version` is kept once compacted into a delta against `version + 1`.
+    pub fn path_for_delta(&self, version: usize) -> PathBuf {
+        self.dir_for_version(version).join(format!("{}.delta", self.name_for_version(version)))
+    }
+
+    /// Put the version put together at `tmp_path` in place as `path`, synced
+    /// unless durability is relaxed.
+    fn publish_version(&self, tmp_path: &Path, path: &Path) -> io::Result<()> {
+        match self.durability {
+            Durability::Strict => publish(tmp_path, path),
+            Durability::Relaxed => fs::rename(tmp_path, path),
+        }
+    }
+
+    /// Where `version` is put together before it is published.
+    fn path_for_tmp(&self, version: usize) -> PathBuf {
+        self.dir_for_version(version).join(format!(".{}.tmp", self.name_for_version(version)))
+    }
+
+    /// Where `version` is kept once compacted into a manifest of chunks.
+    pub fn path_for_chunks(&self, version: usize) -> PathBuf {
+        self.dir_for_version(version).join(format!("{}.chunks", self.name_for_version(version)))
+    }
+
+    /// Where `version` is kept compressed as a zstd frame.
+    pub fn path_for_compressed(&self, version: usize) -> PathBuf {
+        self.dir_for_version(version).join(format!("{}.zst", self.name_for_version(version)))
+    }
+
+    /// Where `version` is kept once compacted, whichever way it was.
+    pub fn path_for_compacted(&self, version: usize) -> PathBuf {
+        [self.path_for_chunks(version), self.path_for_compressed(version)]
+            .into_iter()
+            .find(|path| path.exists())
+            .unwrap_or_else(|| self.path_for_delta(version))
+    }
+
+    /// The chunks shared by the versions compacted into manifests
+    pub fn chunks_dir(&self) -> PathBuf { self.target_dir.join(CHUNKS_DIR) }
+
+    /// Files made in the mount next to the target
+    pub fn scratch_dir(&self) -> PathBuf { self.target_dir.join(SCRATCH_DIR) }
+
+    /// Files of versions that no longer read back
+    pub fn quarantine_dir(&self) -> PathBuf { self.target_dir.join(QUARANTINE_DIR) }
+
+    /// The files `version` may be kept in, whole or compacted.
+    fn paths_for(&self, version: usize) -> [PathBuf; 4] {
+        [
+            self.path_for_version(version),
+            self.path_for_delta(version),
+            self.path_for_chunks(version),
+            self.path_for_compressed(version),
+        ]
+    }
+
+    /// Take the store's exclusive lock, failing with `WouldBlock` if another
+    /// instance holds it. The lock is released when the file is closed.
+    pub fn lock_dir(&self) -> io::Result<File> {
+        let mut file = File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(self.target_dir.join(LOCK_FILE))?;
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // record who holds it, for whoever runs into the lock next
+        file.set_len(0)?;
+        writeln!(file, "{}", std::process::id())?;
+        Ok(file)
+    }
+
+    /// The pid recorded by the instance holding the store's lock, if one
+    /// does, found without writing to the store.
+    pub fn holder(&self) -> io::Result<Option<String>> {
+        let file = match File::open(self.target_dir.join(LOCK_FILE)) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        // a shared lock is granted unless the exclusive one is held, and
+        // given back when the file is closed
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_SH | libc::LOCK_NB) } == 0 {
+            return Ok(None);
+        }
+        let e = io::Error::last_os_error();
+        if e.kind() != io::ErrorKind::WouldBlock {
+            return Err(e);
+        }
+        Ok(Some(fs::read_to_string(self.target_dir.join(LOCK_FILE))?.trim().to_string()))
+    }
+
+    /// Parse a file name in the store back into its version, and whether it
+    /// holds the content compacted rather than in full.
+    pub fn version_of(&self, name: &OsStr) -> Option<(usize, bool)> {
+        let (n, rest) = name.to_str()?.split_once('.')?;
+        let version = n.parse::<usize>().ok()?;
+        match [".delta", ".chunks", ".zst"].iter().find_map(|suffix| rest.strip_suffix(suffix)) {
+            Some(target) if target == self.target => Some((version, true)),
+            _ if rest == self.target => Some((version, false)),
+            _ => None,
+        }
+    }
+
+    /// Move version files left in `target_dir` by the flat layout into their shards.
+    pub fn migrate_to_sharded(&self) -> io::Result<usize> {
+        let mut moved = 0;
+        for entry in fs::read_dir(&self.target_dir)? {
+            let entry = entry?;
+            let name = entry.file_name();
+            let version = match self.version_of(&name) {
+                Some((version, _)) => version,
+                None => continue,
+            };
+            if !entry.file_type()?.is_file() {
+                continue;
+            }
+            self.create_dir_for(version)?;
+            let path = self.dir_for_version(version).join(&name);
+            unseal(&entry.path())?;
+            fs::rename(entry.path(), &path)?;
+            if version < self.version {
+                self.seal(&path);
+            }
+            moved += 1;
+        }
+        Ok(moved)
+    }
+
+    /// The format of the store, failing if it is newer than `FORMAT`.
+    pub fn format(&self) -> io::Result<u32> {
+        let format = match self.read_lines(FORMAT_FILE)?.first() {
+            Some(line) => line.parse().map_err(|_| {
+                io::Error::new(io::ErrorKind::InvalidData, format!("{FORMAT_FILE} holds no format"))
+            })?,
+            None => 0,
+        };
+        if format > FORMAT {
+            let msg = format!("the store is in format {format}, newer than this versionfs knows ({FORMAT})");
+            return Err(io::Error::new(io::ErrorKind::Unsupported, msg));
+        }
+        Ok(format)
+    }
+
+    /// Bring a store of an older format up to `FORMAT` in place, returning
+    /// the format it was in. Each step leaves the store readable, so an
+    /// interrupted upgrade is simply run again.
+    pub fn upgrade(&mut self) -> io::Result<u32> {
+        let format = self.format()?;
+        if format == FORMAT {
+            return Ok(format);
+        }
+        if format < 1 {
+            // the first chunk manifests didn't name their hash
+            for version in self.versions() {
+                let path = self.path_for_chunks(version);
+                let named = match fs::read(&path) {
+                    Ok(manifest) => chunk::name_hash(&manifest),
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+                    Err(e) => return Err(e),
+                };
+                if let Some(named) = named {
+                    let tmp_path = self.path_for_tmp(version);
+                    fs::write(&tmp_path, named)?;
+                    let modified = fs::metadata(&path)?.modified()?;
+                    File::options().write(true).open(&tmp_path)?.set_modified(modified)?;
+                    xattr::keep(&path, &tmp_path)?;
+                    keep_ownership(&path, &tmp_path)?;
+                    unseal(&path)?;
+                    publish(&tmp_path, &path)?;
+                    self.seal(&path);
+                }
+            }
+        }
+        self.write_lines(FORMAT_FILE, std::iter::once(FORMAT.to_string()))?;
+        Ok(format)
+    }
+
+    /// Pick up the versions already in `target_dir`, making the newest the
+    /// head, so that version numbers stay the same across mounts. Fails if
+    /// the store is of a newer format.
+    pub fn resume(&mut self) -> io::Result<usize> {
+        self.format()?;
+        self.version = self.newest_in(&self.target_dir)?;
+        self.names = self.read_lines(NAMES_FILE)?
+            .iter()
+            .filter_map(|line| line.rsplit_once(' '))
+            .filter_map(|(name, version)| Some((name.to_string(), version.parse().ok()?)))
+            .collect();
+        self.reverts = self.read_lines(REVERTS_FILE)?
+            .iter()
+            .filter_map(|line| {
+                let mut fields = line.split(' ');
+                let version = fields.next()?.parse().ok()?;
+                let from = fields.next()?.parse().ok()?;
+                let range = match fields.next().map(|range| range.split_once("..")) {
+                    Some(Some((start, end))) => Some(start.parse().ok()?..end.parse().ok()?),
+                    _ => None,
+                };
+                Some((version, Revert { from, range }))
+            })
+            .collect();
+        self.symlinks = self.read_lines(SYMLINKS_FILE)?.iter().filter_map(|line| line.parse().ok()).collect();
+        self.pruned = self.read_lines(PRUNED_FILE)?
+            .iter()
+            .filter_map(|line| {
+                let (start, end) = line.split_once("..")?;
+                Some((start.parse().ok()?, end.parse().ok()?))
+            })
+            .collect();
+        Ok(self.version)
+    }
+
+    /// Lines of the bookkeeping file `name` in `target_dir`, if any.
+    fn read_lines(&self, name: &str) -> io::Result<Vec<String>> {
+        match fs::read_to_string(self.target_dir.join(name)) {
+            Ok(text) => Ok(text.lines().map(str::to_string).collect()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Replace the bookkeeping file `name` in `target_dir` with `lines`.
+    fn write_lines(&self, name: &str, lines: impl Iterator<Item = String>) -> io::Result<()> {
+        let text: String = lines.map(|line| line + "\n").collect();
+        let tmp_path = self.target_dir.join(format!("{name}.tmp"));
+        fs::write(&tmp_path, text)?;
+        fs::rename(&tmp_path, self.target_dir.join(name))
+    }
+
+    /// Record that the head was cut by reverting.
+    fn record_revert(&mut self, revert: Revert) -> io::Result<()> {
+        self.reverts.insert(self.version, revert);
+        self.write_reverts()
+    }
+
+    /// Record whether `version` is a symbolic link.
+    fn set_symlink(&mut self, version: usize, symlink: bool) -> io::Result<()> {
+        let changed = match symlink {
+            true => self.symlinks.insert(version),
+            false => self.symlinks.remove(&version),
+        };
+        match changed {
+            true => self.write_lines(SYMLINKS_FILE, self.symlinks.iter().map(usize::to_string)),
+            false => Ok(()),
+        }
+    }
+
+    pub fn is_symlink(&self, version: usize) -> bool {
+        self.symlinks.contains(&version)
+    }
+
+    fn write_reverts(&self) -> io::Result<()> {
+        self.write_lines(REVERTS_FILE, self.reverts.iter().map(|(version, revert)| match &revert.range {
+            Some(range) => format!("{version} {} {}..{}", revert.from, range.start, range.end),
+            None => format!("{version} {}", revert.from),
+        }))
+    }
+
+    /// Record durably that `change` is about to be made, so that it is
+    /// settled on the next mount should it never end.
+    pub fn begin(&mut self, change: Change) -> io::Result<()> {
+        self.journal.push(change);
+        let written = self.write_journal();
+        // not begun after all, e.g. with the disk full, so later journals
+        // mustn't carry it either
+        if written.is_err() {
+            self.journal.pop();
+        }
+        written
+    }
+
+    /// Record that `change` is made, or given up and settled.
+    pub fn end(&mut self, change: Change) -> io::Result<()> {
+        if let Some(i) = self.journal.iter().position(|c| *c == change) {
+            self.journal.remove(i);
+        }
+        self.write_journal()
+    }
+
+    /// Make `change` through `op`, settling it at once if that fails. A new
+    /// version only counts as cut once older ones made room for it.
+    fn journaled<T>(&mut self, change: Change, op: impl FnOnce(&mut Self) -> io::Result<T>) -> io::Result<T> {
+        if let Change::Create(_) = change {
+            // failing at once, rather than each time after waiting on a
+            // store that is gone
+            if self.degraded.is_some() {
+                return Err(io::Error::from_raw_os_error(libc::EROFS));
+            }
+            self.make_space();
+        }
+        let result = self.begin(change).and_then(|()| {
+            let result = op(self).and_then(|value| match change {
+                Change::Create(version) => {
+                    self.make_room()?;
+                    // no longer written to through the mount
+                    if version > 1 && self.contains(version - 1) {
+                        self.seal(&self.path_for_version(version - 1));
+                    }
+                    Ok(value)
+                },
+                _ => Ok(value),
+            });
+            if result.is_err() {
+                let _ = self.settle(change);
+            }
+            self.end(change)?;
+            result
+        });
+        if let Change::Create(version) = change {
+            self.tell(version, result.as_ref().err());
+            if let Err(e) = &result {
+                self.degrade(e);
+            }
+        }
+        result
+    }
+
+    /// Stop cutting versions if `error` says the store no longer takes
+    /// changes, rather than failing on it over and over.
+    fn degrade(&mut self, error: &io::Error) {
+        let unwritable = [libc::EROFS, libc::EIO, libc::ENODEV, libc::ENXIO, libc::ESTALE, libc::ENOTCONN];
+        if self.degraded.is_some() || !error.raw_os_error().is_some_and(|errno| unwritable.contains(&errno)) {
+            return;
+        }
+        warn!("{:?} no longer takes changes ({error}), serving it read-only until it does", self.target_dir);
+        self.degraded = Some(error.to_string());
+    }
+
+    /// Find out whether a degraded store takes changes again, cutting
+    /// versions as before if so.
+    pub fn probe(&mut self) {
+        if self.degraded.is_none() {
+            return;
+        }
+        let path = self.target_dir.join(PROBE_FILE);
+        if fs::write(&path, []).and_then(|_| fs::remove_file(&path)).is_ok() {
+            info!("{:?} takes changes again", self.target_dir);
+            self.degraded = None;
+        }
+    }
+
+    /// Tell whoever asked to be told that cutting `version` succeeded, or
+    /// failed with `error`.
+    fn tell(&mut self, version: usize, error: Option<&io::Error>) {
+        let target = self.target.to_string_lossy().into_owned();
+        if self.notify_desktop {
+            let dir = self.target_dir.display().to_string();
+            match error {
+                None => notify::desktop(&format!("version {version} of {target} saved"), &dir, false),
+                Some(e) => {
+                    let summary = format!("cutting version {version} of {target} failed");
+                    notify::desktop(&summary, &e.to_string(), true)
+                },
+            }
+        }
+        match (&mut self.alert, error) {
+            (Some(alert), None) => alert.succeeded(&target, version),
+            (Some(alert), Some(e)) => alert.failed(&target, version, e),
+            (None, _) => {},
+        }
+    }
+
+    fn write_journal(&self) -> io::Result<()> {
+        let text: String = self.journal
+            .iter()
+            .map(|change| match change {
+                Change::Create(version) => format!("create {version}\n"),
+                Change::Compact(version) => format!("compact {version}\n"),
+                Change::Prune(version) => format!("prune {version}\n"),
+            })
+            .collect();
+        let tmp_path = self.target_dir.join(format!("{JOURNAL_FILE}.tmp"));
+        fs::write(&tmp_path, text)?;
+        publish(&tmp_path, &self.target_dir.join(JOURNAL_FILE))
+    }
+
+    /// Roll back a version that was being cut, finish compacting one whose
+    /// compacted form is in place already, or finish removing one.
+    fn settle(&mut self, change: Change) -> io::Result<()> {
+        match change {
+            Change::Create(version) => {
+                for path in [self.path_for_tmp(version), self.path_for_version(version)] {
+                    match fs::remove_file(path) {
+                        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                        _ => {},
+                    }
+                }
+                if self.reverts.contains_key(&version) {
+                    self.reverts.remove(&version);
+                    self.write_reverts()?;
+                }
+                self.set_symlink(version, false)?;
+                self.version = self.version.min(version - 1);
+            },
+            Change::Compact(version) => {
+                let whole = self.path_for_version(version);
+                if whole.exists() && self.path_for_compacted(version).exists() {
+                    discard(&whole)?;
+                }
+            },
+            Change::Prune(version) => self.prune(version)?,
+        }
+        Ok(())
+    }
+
+    /// Settle the changes a crashed run left in the journal. Returns how many
+    /// there were.
+    pub fn replay_journal(&mut self) -> io::Result<usize> {
+        let changes: Vec<Change> = self.read_lines(JOURNAL_FILE)?
+            .iter()
+            .filter_map(|line| {
+                let (kind, version) = line.split_once(' ')?;
+                match (kind, version.parse().ok()?) {
+                    ("create", version) => Some(Change::Create(version)),
+                    ("compact", version) => Some(Change::Compact(version)),
+                    ("prune", version) => Some(Change::Prune(version)),
+                    _ => None,
+                }
+            })
+            .collect();
+        // newest first, as each version was cut from the one before
+        for &change in changes.iter().rev() {
+            self.settle(change)?;
+        }
+        if !changes.is_empty() {
+            self.write_journal()?;
+        }
+        Ok(changes.len())
+    }
+
+    /// Whether `entry` is a directory of versions rather than of chunks,
+    /// scratch files or quarantined versions.
+    fn is_shard(&self, entry: &fs::DirEntry) -> io::Result<bool> {
+        let name = entry.file_name();
+        let other = [CHUNKS_DIR, SCRATCH_DIR, QUARANTINE_DIR].iter().any(|dir| name == *dir);
+        Ok(self.sharded && entry.file_type()?.is_dir() && !other)
+    }
+
+    fn newest_in(&self, dir: &Path) -> io::Result<usize> {
+        let mut newest = 0;
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            if self.is_shard(&entry)? {
+                newest = newest.max(self.newest_in(&entry.path())?);
+            } else if let Some((version, _)) = self.version_of(&entry.file_name()) {
+                newest = newest.max(version);
+            }
+        }
+        Ok(newest)
+    }
+
+    /// Tidy up after a run that crashed halfway through changing the store:
+    /// remove temporary copies, keep only the form reads use of a version
+    /// left both whole and compacted, and finish a snapshot that linked the
+    /// new head without giving the version before it a file of its own.
+    /// Returns how many leftovers were dealt with.
+    pub fn clean_up(&self) -> io::Result<usize> {
+        let mut cleaned = self.clean_up_in(&self.target_dir)?;
+        match fs::read_dir(self.chunks_dir()) {
+            Ok(dirs) => for dir in dirs {
+                for entry in fs::read_dir(dir?.path())? {
+                    let path = entry?.path();
+                    if path.extension() == Some(OsStr::new("tmp")) {
+                        fs::remove_file(path)?;
+                        cleaned += 1;
+                    }
+                }
+            },
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {},
+            Err(e) => return Err(e),
+        }
+        if self.version > 1 {
+            // only an interrupted snapshot leaves the head sharing its file,
+            // as duplicates are never linked to the head
+            let (head, prev) = (self.path_for_version(self.version), self.path_for_version(self.version - 1));
+            if let (Ok(head_meta), Ok(prev_meta)) = (fs::metadata(&head), fs::metadata(&prev)) {
+                if head_meta.dev() == prev_meta.dev() && head_meta.ino() == prev_meta.ino() {
+                    let tmp_path = self.path_for_tmp(self.version - 1);
+                    clone_or_copy(&head, &tmp_path)?;
+                    xattr::keep(&head, &tmp_path)?;
+                    publish(&tmp_path, &prev)?;
+                    cleaned += 1;
+                }
+            }
+        }
+        Ok(cleaned)
+    }
+
+    fn clean_up_in(&self, dir: &Path) -> io::Result<usize> {
+        let mut cleaned = 0;
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            let name = entry.file_name();
+            if self.is_shard(&entry)? {
+                cleaned += self.clean_up_in(&entry.path())?;
+                continue;
+            }
+            let stale = match self.version_of(&name) {
+                Some((version, _)) if self.is_pruned(version) => true,
+                // reads take the whole version first, then the compacted
+                // forms in the order `path_for_compacted` tries them
+                Some((version, true)) => {
+                    self.path_for_version(version).exists() || self.path_for_compacted(version) != entry.path()
+                },
+                Some((_, false)) => false,
+                None => self.is_temporary(&name),
+            };
+            if stale {
+                discard(&entry.path())?;
+                cleaned += 1;
+            }
+        }
+        Ok(cleaned)
+    }
+
+    /// Whether `name` is the temporary copy of a version or bookkeeping file
+    /// made while replacing it.
+    fn is_temporary(&self, name: &OsStr) -> bool {
+        match name.to_str().and_then(|name| name.strip_suffix(".tmp")) {
+            Some(name) if name.starts_with(".versionfs.") => true,
+            Some(name) => self.version_of(OsStr::new(name.strip_prefix('.').unwrap_or(name))).is_some(),
+            None => false,
+        }
+    }
+
+    pub fn contains(&self, version: usize) -> bool {
+        version > 0 && version <= self.version && !self.is_pruned(version)
+    }
+
+    fn is_pruned(&self, version: usize) -> bool {
+        self.pruned.range(..=version).next_back().is_some_and(|(_, &end)| version < end)
+    }
+
+    /// The versions kept, oldest first.
+    pub fn versions(&self) -> Vec<usize> {
+        let mut versions = Vec::new();
+        let mut next = 1;
+        for (&start, &end) in &self.pruned {
+            versions.extend(next..start);
+            next = end;
+        }
+        versions.extend(next..=self.version);
+        versions
+    }
+
+    /// The newest version kept before `version`.
+    pub fn previous(&self, version: usize) -> Option<usize> {
+        let mut before = version.checked_sub(1)?;
+        while self.is_pruned(before) {
+            before = *self.pruned.range(..=before).next_back().unwrap().0 - 1;
+        }
+        (before > 0).then_some(before)
+    }
+
+    /// Remove the oldest versions without a name until the store is within
+    /// `max_versions`. The head always stays, as do named versions, even if
+    /// that leaves more.
+    fn make_room(&mut self) -> io::Result<()> {
+        let max = match self.max_versions {
+            Some(max) => max,
+            None => return Ok(()),
+        };
+        let versions = self.versions();
+        let excess = versions.len().saturating_sub(max);
+        let named: BTreeSet<usize> = self.names.values().copied().collect();
+        let unnamed: Vec<usize> = versions
+            .into_iter()
+            .filter(|version| *version != self.version && !named.contains(version))
+            .take(excess)
+            .collect();
+        for version in unnamed {
+            self.journaled(Change::Prune(version), |store| store.prune(version))?;
+        }
+        Ok(())
+    }
+
+    /// Prune the oldest versions without a name while the disk has less than
+    /// `low_space` bytes free besides room for a copy of the head, so that
+    /// the version about to be cut fits.
+    fn make_space(&mut self) {
+        let low = match self.low_space {
+            Some(low) => low,
+            None => return,
+        };
+        let needed = low + self.version_meta(self.version).map_or(0, |meta| meta.len);
+        let head = self.version;
+        let named: BTreeSet<usize> = self.names.values().copied().collect();
+        let mut oldest = self.versions().into_iter().filter(|v| *v != head && !named.contains(v));
+        loop {
+            let free = match free_space(&self.target_dir) {
+                Ok(free) if free >= needed => return,
+                Ok(free) => free,
+                Err(e) => {
+                    warn!("cannot tell the space free in {:?}: {e}", self.target_dir);
+                    return;
+                },
+            };
+            let version = match oldest.next() {
+                Some(version) => version,
+                None => {
+                    warn!("{free} bytes free in {:?}, and no version left to prune", self.target_dir);
+                    return;
+                },
+            };
+            warn!("{free} bytes free in {:?}, pruning version {version}", self.target_dir);
+            if let Err(e) = self.journaled(Change::Prune(version), |store| store.prune(version)) {
+                warn!("cannot prune version {version}: {e}");
+                return;
+            }
+        }
+    }
+
+    /// Remove `version`, first storing the version before it whole if it is
+    /// a delta against this one.
+    fn prune(&mut self, version: usize) -> io::Result<()> {
+        let before = version - 1;
+        let delta = self.path_for_delta(before);
+        if before > 0 && !self.is_pruned(before) && delta.exists() {
+            let tmp_path = self.path_for_tmp(before);
+            fs::write(&tmp_path, self.read_version(before)?)?;
+            File::options().write(true).open(&tmp_path)?.set_modified(fs::metadata(&delta)?.modified()?)?;
+            xattr::keep(&delta, &tmp_path)?;
+            keep_ownership(&delta, &tmp_path)?;
+            self.publish_version(&tmp_path, &self.path_for_version(before))?;
+            self.seal(&self.path_for_version(before));
+            discard(&delta)?;
+        }
+        if !self.is_pruned(version) {
+            // joined with the ranges either side
+            let start = match self.pruned.range(..version).next_back() {
+                Some((&start, &end)) if end == version => start,
+                _ => version,
+            };
+            let end = self.pruned.remove(&(version + 1)).unwrap_or(version + 1);
+            self.pruned.insert(start, end);
+            self.write_lines(PRUNED_FILE, self.pruned.iter().map(|(start, end)| format!("{start}..{end}")))?;
+        }
+        for path in self.paths_for(version) {
+            match discard(&path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => {},
+            }
+        }
+        if self.reverts.remove(&version).is_some() {
+            self.write_reverts()?;
+        }
+        self.set_symlink(version, false)?;
+        self.forget_diffs(version);
+        Ok(())
+    }
+
+    /// Cut a new head version, seeded with the previous head unless `truncate`.
+    pub fn new_version(&mut self, truncate: bool) -> io::Result<()> {
+        // the head only moves once the new version is complete, so a failed
+        // attempt is simply made again
+        let version = self.version + 1;
+        self.journaled(Change::Create(version), |store| {
+            store.retry.run(|| {
+                store.create_dir_for(version)?;
+                let tmp_path = store.path_for_tmp(version);
+                if version > 1 && !truncate {
+                    clone_or_copy(&store.path_for_version(version - 1), &tmp_path)?;
+                } else {
+                    fs::write(&tmp_path, [])?;
+                    if version > 1 {
+                        keep_ownership(&store.path_for_version(version - 1), &tmp_path)?;
+                    }
+                }
+                if version > 1 {
+                    xattr::copy(&store.path_for_version(version - 1), &tmp_path)?;
+                }
+                store.publish_version(&tmp_path, &store.path_for_version(version))
+            })?;
+            // a seeded version is whatever the one before it was
+            store.set_symlink(version, !truncate && store.is_symlink(version - 1))?;
+            store.version = version;
+            Ok(())
+        })
+    }
+
+    /// Whether the head differs from the version before it.
+    pub fn head_changed(&self) -> io::Result<bool> {
+        let head = self.version_meta(self.version)?;
+        if self.version == 1 {
+            return Ok(head.len > 0);
+        }
+        if head.len != self.version_meta(self.version - 1)?.len {
+            return Ok(true);
+        }
+        Ok(self.read_version(self.version)? != self.read_version(self.version - 1)?)
+    }
+
+    /// Cut a new head version that is a symbolic link to `link`, keeping the
+    /// owner and attributes of the head.
+    pub fn new_symlink(&mut self, link: &OsStr) -> io::Result<usize> {
+        self.journaled(Change::Create(self.version + 1), |store| {
+            store.create_dir_for(store.version + 1)?;
+            let tmp_path = store.path_for_tmp(store.version + 1);
+            fs::write(&tmp_path, link.as_bytes())?;
+            if store.version > 0 {
+                xattr::copy(&store.path_for_version(store.version), &tmp_path)?;
+                keep_ownership(&store.path_for_version(store.version), &tmp_path)?;
+            }
+            store.publish_version(&tmp_path, &store.path_for_version(store.version + 1))?;
+            store.set_symlink(store.version + 1, true)?;
+            store.version += 1;
+            Ok(store.version)
+        })
+    }
+
+    /// Cut a new head version holding the content of the file at `path`, as
+    /// renaming it onto the target does, along with its mode, owner and
+    /// extended attributes.
+    pub fn adopt(&mut self, path: &Path) -> io::Result<usize> {
+        self.journaled(Change::Create(self.version + 1), |store| {
+            store.create_dir_for(store.version + 1)?;
+            let tmp_path = store.path_for_tmp(store.version + 1);
+            clone_or_copy(path, &tmp_path)?;
+            xattr::keep(path, &tmp_path)?;
+            store.publish_version(&tmp_path, &store.path_for_version(store.version + 1))?;
+            store.set_symlink(store.version + 1, false)?;
+            store.version += 1;
+            Ok(store.version)
+        })
+    }
+
+    /// Freeze the content of the head as a version of its own. Unlike
+    /// `new_version`, the head file moves on to be the new head, so writes
+    /// through handles already open land there rather than in the snapshot.
+    pub fn snapshot(&mut self) -> io::Result<usize> {
+        self.journaled(Change::Create(self.version + 1), |store| {
+            let head = store.path_for_version(store.version);
+            let tmp_path = store.path_for_tmp(store.version);
+            clone_or_copy(&head, &tmp_path)?;
+            xattr::keep(&head, &tmp_path)?;
+            store.create_dir_for(store.version + 1)?;
+            let newpath = store.path_for_version(store.version + 1);
+            fs::hard_link(&head, &newpath)?;
+            if store.durability == Durability::Strict {
+                sync_dir(&newpath)?;
+            }
+            store.publish_version(&tmp_path, &head)?;
+            store.set_symlink(store.version + 1, store.is_symlink(store.version))?;
+            store.version += 1;
+            Ok(store.version)
+        })
+    }
+
+    /// Replace each whole version that is the same as the one before it,
+    /// down to its mode, owner and extended attributes, by a hard link to
+    /// that one. Returns how many versions were linked and the bytes freed.
+    pub fn link_duplicates(&self) -> io::Result<(usize, u64)> {
+        let (mut linked, mut freed) = (0, 0);
+        // the head is still written to
+        for version in 2..self.version {
+            let (path, prev_path) = (self.path_for_version(version), self.path_for_version(version - 1));
+            let (meta, prev) = match (fs::metadata(&path), fs::metadata(&prev_path)) {
+                (Ok(meta), Ok(prev)) => (meta, prev),
+                // compacted
+                (Err(e), _) | (_, Err(e)) if e.kind() == io::ErrorKind::NotFound => continue,
+                (Err(e), _) | (_, Err(e)) => return Err(e),
+            };
+            if self.is_symlink(version) != self.is_symlink(version - 1) {
+                continue;
+            }
+            let linked_already = meta.dev() == prev.dev() && meta.ino() == prev.ino();
+            let same_meta = (meta.len(), meta.mode(), meta.uid(), meta.gid())
+                == (prev.len(), prev.mode(), prev.uid(), prev.gid());
+            if linked_already || !same_meta {
+                continue;
+            }
+            if *Mmap::open(&path)? != *Mmap::open(&prev_path)? || xattr::all(&path)? != xattr::all(&prev_path)? {
+                continue;
+            }
+            let tmp_path = self.path_for_tmp(version);
+            unseal(&prev_path)?;
+            unseal(&path)?;
+            fs::hard_link(&prev_path, &tmp_path)?;
+            fs::rename(&tmp_path, &path)?;
+            // both names share the one inode
+            self.seal(&path);
+            linked += 1;
+            if meta.nlink() == 1 {
+                freed += meta.blocks() * 512;
+            }
+        }
+        Ok((linked, freed))
+    }
+
+    /// Check that `name` can be given to a version.
+    pub fn check_name(&self, name: &str) -> io::Result<()> {
+        let allowed = |c: char| c.is_alphanumeric() || "-_.+@:".contains(c);
+        if name.is_empty() || name.starts_with('.') || !name.chars().all(allowed) {
+            let msg = "names are letters, digits and -_.+@: not starting with a dot";
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+        }
+        if self.names.contains_key(name) {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, "the name is taken"));
+        }
+        Ok(())
+    }
+
+    /// Give `version` the name `name`, which must not be taken yet.
+    pub fn name(&mut self, name: &str, version: usize) -> io::Result<()> {
+        self.check_name(name)?;
+        let mut names = self.names.clone();
+        names.insert(name.to_string(), version);
+        self.write_lines(NAMES_FILE, names.iter().map(|(name, version)| format!("{name} {version}")))?;
+        self.names = names;
+        Ok(())
+    }
+
+    /// Make the version file `path` immutable if versions are to be, warning
+    /// rather than failing where that isn't permitted.
+    pub fn seal(&self, path: &Path) {
+        if self.immutable {
+            if let Err(e) = set_immutable(path, true) {
+                warn!("cannot make {path:?} immutable: {e}");
+            }
+        }
+    }
+
+    /// Make every version below the head immutable, those that offline
+    /// commands or runs without `immutable` left alone included.
+    pub fn seal_all(&self) -> io::Result<()> {
+        for version in self.versions().into_iter().filter(|&version| version < self.version) {
+            let whole = self.path_for_version(version);
+            match whole.exists() {
+                true => set_immutable(&whole, true)?,
+                false => set_immutable(&self.path_for_compacted(version), true)?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Move the files of `version` into the quarantine directory and drop it
+    /// from the store, as if it had been pruned.
+    pub fn quarantine(&mut self, version: usize) -> io::Result<()> {
+        fs::create_dir_all(self.quarantine_dir())?;
+        for path in self.paths_for(version) {
+            unseal(&path)?;
+            match fs::rename(&path, self.quarantine_dir().join(path.file_name().unwrap())) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => {},
+            }
+        }
+        self.journaled(Change::Prune(version), |store| store.prune(version))
+    }
+
+    /// Write the bookkeeping files afresh, leaving out lines that couldn't be
+    /// read and versions the store no longer holds. Returns the names dropped
+    /// with the versions they named.
+    pub fn rebuild_bookkeeping(&mut self) -> io::Result<Vec<(String, usize)>> {
+        let stale: Vec<(String, usize)> = self.names
+            .iter()
+            .filter(|&(_, &version)| !self.contains(version))
+            .map(|(name, &version)| (name.clone(), version))
+            .collect();
+        for (name, _) in &stale {
+            self.names.remove(name);
+        }
+        self.symlinks = self.symlinks.iter().copied().filter(|&version| self.contains(version)).collect();
+        self.reverts = std::mem::take(&mut self.reverts)
+            .into_iter()
+            .filter(|(version, _)| self.contains(*version))
+            .collect();
+        self.write_lines(NAMES_FILE, self.names.iter().map(|(name, version)| format!("{name} {version}")))?;
+        self.write_lines(SYMLINKS_FILE, self.symlinks.iter().map(usize::to_string))?;
+        self.write_lines(PRUNED_FILE, self.pruned.iter().map(|(start, end)| format!("{start}..{end}")))?;
+        self.write_reverts()?;
+        Ok(stale)
+    }
+
+    /// Cut a new head version holding the content of `version`.
+    pub fn restore(&mut self, version: usize) -> io::Result<usize> {
+        if !self.contains(version) {
+            return Err(io::Error::from(io::ErrorKind::NotFound));
+        }
+        let data = self.read_version(version)?;
+        self.restore_from(version, data)
+    }
+
+    /// Cut a new head version holding `data`, the content of `version` read
+    /// beforehand, e.g. through `reader`.
+    pub fn restore_from(&mut self, version: usize, data: Vec<u8>) -> io::Result<usize> {
+        // pruned since
+        if !self.contains(version) {
+            return Err(io::Error::from(io::ErrorKind::NotFound));
+        }
+        self.journaled(Change::Create(self.version + 1), |store| {
+            store.create_dir_for(store.version + 1)?;
+            let tmp_path = store.path_for_tmp(store.version + 1);
+            fs::write(&tmp_path, data)?;
+            // attributes go back along with the content
+            xattr::copy(&store.xattr_path(version), &tmp_path)?;
+            keep_ownership(&store.xattr_path(version), &tmp_path)?;
+            store.publish_version(&tmp_path, &store.path_for_version(store.version + 1))?;
+            store.set_symlink(store.version + 1, store.is_symlink(version))?;
+            store.version += 1;
+            store.record_revert(Revert { from: version, range: None })?;
+            Ok(store.version)
+        })
+    }
+
+    /// Cut a new head version: the head with the bytes in `range` put back as
+    /// they were in `version`, e.g. to recover a corrupted region. `data` is
+    /// those bytes, read beforehand with `read_range`, e.g. through `reader`.
+    pub fn restore_range_from(
+        &mut self,
+        version: usize,
+        range: Range<u64>,
+        data: Vec<u8>,
+    ) -> io::Result<usize> {
+        // pruned since
+        if !self.contains(version) {
+            return Err(io::Error::from(io::ErrorKind::NotFound));
+        }
+        self.journaled(Change::Create(self.version + 1), |store| {
+            store.create_dir_for(store.version + 1)?;
+            let tmp_path = store.path_for_tmp(store.version + 1);
+            // copied as any new version is, so that only the range is written
+            clone_or_copy(&store.path_for_version(store.version), &tmp_path)?;
+            File::options().write(true).open(&tmp_path)?.write_all_at(&data, range.start)?;
+            // mostly still the head, so its attributes stay
+            xattr::copy(&store.path_for_version(store.version), &tmp_path)?;
+            store.publish_version(&tmp_path, &store.path_for_version(store.version + 1))?;
+            store.set_symlink(store.version + 1, store.is_symlink(store.version))?;
+            store.version += 1;
+            store.record_revert(Revert { from: version, range: Some(range) })?;
+            Ok(store.version)
+        })
+    }
+
+    /// The bytes in `range` of `version`, read from its file if it is stored
+    /// whole, and only rebuilt if it was compacted.
+    pub fn read_range(&self, version: usize, range: Range<u64>) -> io::Result<Vec<u8>> {
+        let len = self.version_meta(version)?.len;
+        if range.start >= range.end || range.end > len {
+            let msg = format!("the range must be within the {len} bytes of version {version}");
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+        }
+        let mut data = vec![0; (range.end - range.start) as usize];
+        match File::open(self.path_for_version(version)) {
+            Ok(file) => file.read_exact_at(&mut data, range.start)?,
+            // compacted
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                let whole = self.read_version(version)?;
+                match whole.get(range.start as usize..range.end as usize) {
+                    Some(bytes) => data.copy_from_slice(bytes),
+                    None => return Err(io::Error::from(io::ErrorKind::UnexpectedEof)),
+                }
+            },
+            Err(e) => return Err(e),
+        }
+        Ok(data)
+    }
+
+    /// Cut a new head version holding the content of the version before the
+    /// one the head holds. Repeated, it steps further back.
+    pub fn undo(&mut self) -> io::Result<usize> {
+        if self.undo.head != self.version {
+            let at = match self.reverts.get(&self.version) {
+                Some(Revert { from, range: None }) => *from,
+                _ => self.version,
+            };
+            self.undo = Undo { head: self.version, at, redo: Vec::new() };
+        }
+        let at = self.undo.at;
+        let before = match self.previous(at) {
+            Some(before) => before,
+            None => return Err(io::Error::new(io::ErrorKind::NotFound, "nothing to undo")),
+        };
+        self.undo.head = self.restore(before)?;
+        self.undo.at = before;
+        self.undo.redo.push(at);
+        Ok(self.version)
+    }
+
+    /// Take back the last undo, as long as no other version was cut since.
+    pub fn redo(&mut self) -> io::Result<usize> {
+        let at = match self.undo.redo.last() {
+            Some(&at) if self.undo.head == self.version => at,
+            _ => return Err(io::Error::new(io::ErrorKind::NotFound, "nothing to redo")),
+        };
+        self.undo.head = self.restore(at)?;
+        self.undo.at = at;
+        self.undo.redo.pop();
+        Ok(self.version)
+    }
+
+    /// The file holding the extended attributes of `version`: the version
+    /// itself, or its delta, manifest or compressed frame once compacted.
+    pub fn xattr_path(&self, version: usize) -> PathBuf {
+        match self.path_for_version(version) {
+            path if path.exists() => path,
+            _ => self.path_for_compacted(version),
+        }
+    }
+
+    /// Content of `version`, rebuilt from deltas or chunks or decompressed if
+    /// it has been compacted.
+    pub fn read_version(&self, version: usize) -> io::Result<Vec<u8>> {
+        self.retry.run(|| self.read_version_once(version))
+    }
+
+    fn read_version_once(&self, version: usize) -> io::Result<Vec<u8>> {
+        // walk up to the nearest fully stored, chunked, compressed or cached
+        // version, then apply the deltas back down
+        let mut deltas = Vec::new();
+        let mut v = version;
+        let mut rebuilt = false;
+        let mut data = loop {
+            if let Some(data) = self.cache.borrow_mut().get(v) {
+                break data;
+            }
+            match fs::read(self.path_for_version(v)) {
+                Ok(data) => break data,
+                Err(e) if e.kind() == io::ErrorKind::NotFound && v < self.version => {
+                    match fs::read(self.path_for_chunks(v)) {
+                        Ok(manifest) => {
+                            rebuilt = true;
+                            break chunk::read(&self.chunks_dir(), &manifest)?;
+                        },
+                        Err(e) if e.kind() == io::ErrorKind::NotFound => {},
+                        Err(e) => return Err(e),
+                    }
+                    match fs::read(self.path_for_compressed(v)) {
+                        Ok(frame) => {
+                            rebuilt = true;
+                            break zstd::decode(&frame)?;
+                        },
+                        Err(e) if e.kind() == io::ErrorKind::NotFound => {},
+                        Err(e) => return Err(e),
+                    }
+                    deltas.push(fs::read(self.path_for_delta(v))?);
+                    v += 1;
+                },
+                Err(e) => return Err(e),
+            }
+        };
+        if deltas.is_empty() && !rebuilt {
+            return Ok(data);
+        }
+        for d in deltas.iter().rev() {
+            interrupt::check()?;
+            data = delta::apply(&data, d)?;
+        }
+        // versions rebuilt from the head would go stale with the next write
+        if v < self.version {
+            self.cache.borrow_mut().insert(version, data.clone());
+        }
+        Ok(data)
+    }
+
+    /// Like `read_version`, but maps whole versions instead of reading them.
+    pub fn map_version(&self, version: usize) -> io::Result<Content> {
+        match self.retry.run(|| Mmap::open(&self.path_for_version(version))) {
+            Ok(map) => Ok(Content::Mapped(map)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => self.read_version(version).map(Content::Rebuilt),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Keep up to `limit` bytes of versions rebuilt from deltas, chunks or zstd
+    /// frames in memory.
+    pub fn set_cache_limit(&mut self, limit: usize) {
+        self.cache = RefCell::new(VersionCache::new(limit));
+    }
+
+    pub fn version_meta(&self, version: usize) -> io::Result<VersionMeta> {
+        self.retry.run(|| self.version_meta_once(version))
+    }
+
+    fn version_meta_once(&self, version: usize) -> io::Result<VersionMeta> {
+        match fs::metadata(self.path_for_version(version)) {
+            Ok(m) => {
+                let modified = m.modified()?;
+                Ok(VersionMeta { len: m.len(), modified, created: m.created().unwrap_or(modified) })
+            },
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                // a delta, manifest or frame is born when compacted, but keeps
+                // the modification time
+                let path = self.path_for_compacted(version);
+                let modified = fs::metadata(&path)?.modified()?;
+                let len = match path.extension().and_then(OsStr::to_str) {
+                    Some("chunks") => chunk::target_len(&fs::read(path)?)?,
+                    Some("zst") => zstd::decoded_len(&fs::read(path)?)?,
+                    _ => delta::target_len(File::open(path)?)?,
+                };
+                Ok(VersionMeta { len, modified, created: modified })
+            },
+            Err(e) => Err(e),
+        }
+    }
+
+    /// When the target was created, i.e. its first version kept was.
+    pub fn created(&self) -> Option<SystemTime> {
+        self.version_meta(*self.versions().first()?).ok().map(|m| m.created)
+    }
+
+    /// Drop cached diffs and versions that depend on the content of `version`.
+    pub fn forget_diffs(&mut self, version: usize) {
+        self.diffs.remove(version);
+        self.diffs.remove(version + 1);
+        self.cache.get_mut().forget_through(version);
+    }
+
+    pub fn forget_all_diffs(&mut self) {
+        self.diffs.clear();
+        self.cache.get_mut().clear();
+    }
+
+    /// Unified diff between the version before `version` and it, rendered on
+    /// demand.
+    pub fn diff_for_version(&mut self, version: usize) -> Option<Vec<u8>> {
+        if !self.contains(version) {
+            return None;
+        }
+        if let Some(diff) = self.diffs.get(version) {
+            return Some(diff);
+        }
+        let new_name = self.name_for_version(version);
+        let new = self.read_version(version).ok()?;
+        let (old, old_name) = match self.previous(version) {
+            None => (Vec::new(), "/dev/null".to_string()),
+            Some(v) => (self.read_version(v).ok()?, self.name_for_version(v)),
+        };
+        let diff = diff::unified(&old, &new, &old_name, &new_name);
+        // the head may still be written to, so only older diffs are cached
+        if version < self.version {
+            self.diffs.insert(version, diff.clone());
+        }
+        Some(diff)
+    }
+}
+
+/// The splitmix64 finalizer, spreading consecutive numbers evenly.
+fn mix(n: u64) -> u64 {
+    let mut z = n.wrapping_add(0x9e3779b97f4a7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
@@ -0,0 +1,91 @@
+//! systemd integration: readiness notification and unit generation.
+
+use std::env;
+use std::ffi::OsStr;
+use std::io;
+#[cfg(target_os = "linux")]
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::io::RawFd;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+
+use log::warn;
+
+/// Send `state` (e.g. `READY=1`) to the service manager, if started by one.
+pub fn notify(state: &str) {
+    let socket = match env::var_os("NOTIFY_SOCKET") {
+        Some(socket) => socket,
+        None => return,
+    };
+    if let Err(e) = send(&socket, state) {
+        warn!("sd_notify {state:?} failed: {e}");
+    }
+}
+
+fn send(socket: &OsStr, state: &str) -> io::Result<()> {
+    let path = socket.to_string_lossy();
+    let addr = match path.strip_prefix('@') {
+        #[cfg(target_os = "linux")]
+        Some(name) => SocketAddr::from_abstract_name(name)?,
+        // abstract sockets are Linux-only
+        #[cfg(not(target_os = "linux"))]
+        Some(_) => return Err(io::ErrorKind::Unsupported.into()),
+        None => SocketAddr::from_pathname(socket)?,
+    };
+    UnixDatagram::unbound()?.send_to_addr(state.as_bytes(), &addr)?;
+    Ok(())
+}
+
+/// First file descriptor passed by socket activation
+const LISTEN_FDS_START: RawFd = 3;
+
+/// Take the sockets passed by socket activation, along with their
+/// `FileDescriptorName=`s, so that they aren't inherited any further.
+pub fn listen_fds() -> Vec<(String, RawFd)> {
+    let pid = env::var("LISTEN_PID").ok().and_then(|pid| pid.parse::<u32>().ok());
+    let count = env::var("LISTEN_FDS").ok().and_then(|n| n.parse::<RawFd>().ok());
+    let names = env::var("LISTEN_FDNAMES").unwrap_or_default();
+    for var in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+        env::remove_var(var);
+    }
+    let count = match (pid, count) {
+        (Some(pid), Some(count)) if pid == std::process::id() => count,
+        _ => return Vec::new(),
+    };
+    let mut names = names.split(':');
+    (LISTEN_FDS_START..LISTEN_FDS_START + count)
+        .map(|fd| {
+            unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+            (names.next().unwrap_or("unknown").to_string(), fd)
+        })
+        .collect()
+}
+
+/// A `Type=notify` service unit running `command`.
+pub fn unit(description: &str, command: &[String]) -> String {
+    let exec: Vec<String> = command.iter().map(|arg| quote(arg)).collect();
+    format!(
+        "[Unit]\n\
+         Description={}\n\
+         After=local-fs.target\n\
+         \n\
+         [Service]\n\
+         Type=notify\n\
+         ExecStart={}\n\
+         Restart=on-failure\n\
+         \n\
+         [Install]\n\
+         WantedBy=multi-user.target\n",
+        description.replace('%', "%%"),
+        exec.join(" "),
+    )
+}
+
+/// Quote an `ExecStart=` argument if systemd would otherwise split or expand it.
+fn quote(arg: &str) -> String {
+    if arg.is_empty() || arg.contains(|c: char| c.is_whitespace() || "\"'\\$%;".contains(c)) {
+        let escaped = arg.replace('\\', "\\\\").replace('"', "\\\"").replace('$', "$$").replace('%', "%%");
+        format!("\"{escaped}\"")
+    } else {
+        arg.to_string()
+    }
+}
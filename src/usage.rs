@@ -0,0 +1,104 @@
+//! How much space the versions of a store take, and what compaction and
+//! deduplication save.
+//!
+//! Each file is counted once however many versions are hard linked to it,
+//! and each chunk once, against the oldest version listing it.
+
+use std::collections::HashSet;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::os::unix::fs::MetadataExt;
+
+use crate::chunk;
+use crate::store::Store;
+
+/// How a version is kept
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Form {
+    Whole,
+    /// Hard linked to a file counted for an earlier version
+    Linked,
+    Delta,
+    Chunks,
+    Zstd,
+}
+
+impl fmt::Display for Form {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Form::Whole => "whole",
+            Form::Linked => "linked",
+            Form::Delta => "delta",
+            Form::Chunks => "chunks",
+            Form::Zstd => "zstd",
+        })
+    }
+}
+
+/// Space taken by one version
+pub struct Version {
+    pub version: usize,
+    pub form: Form,
+    /// Length of its content
+    pub len: u64,
+    /// Bytes kept for it alone: its file, and the chunks no earlier version
+    /// lists
+    pub stored: u64,
+    /// Bytes of its content kept once for it and an earlier version
+    pub shared: u64,
+}
+
+/// Space taken by the versions of a store
+#[derive(Default)]
+pub struct Usage {
+    pub versions: Vec<Version>,
+}
+
+impl Usage {
+    /// Length of the content of every version
+    pub fn len(&self) -> u64 { self.versions.iter().map(|v| v.len).sum() }
+
+    /// Bytes kept for the versions
+    pub fn stored(&self) -> u64 { self.versions.iter().map(|v| v.stored).sum() }
+
+    /// Bytes saved by hard linking versions and sharing chunks between them
+    pub fn deduplicated(&self) -> u64 { self.versions.iter().map(|v| v.shared).sum() }
+
+    /// Bytes saved by deltas and compression, less the manifests of chunked
+    /// versions
+    pub fn compacted(&self) -> i64 { self.len() as i64 - self.stored() as i64 - self.deduplicated() as i64 }
+}
+
+/// Measure the versions of `store`.
+pub fn measure(store: &Store) -> io::Result<Usage> {
+    let mut usage = Usage::default();
+    let mut files = HashSet::new();
+    let mut chunks_seen = HashSet::new();
+    for version in store.versions() {
+        let path = store.xattr_path(version);
+        let meta = fs::metadata(&path)?;
+        let len = store.version_meta(version)?.len;
+        let form = match path.extension().and_then(|ext| ext.to_str()) {
+            _ if !files.insert((meta.dev(), meta.ino())) => Form::Linked,
+            _ if path == store.path_for_version(version) => Form::Whole,
+            Some("chunks") => Form::Chunks,
+            Some("zst") => Form::Zstd,
+            _ => Form::Delta,
+        };
+        let (mut stored, mut shared) = match form {
+            Form::Linked => (0, len),
+            _ => (meta.len(), 0),
+        };
+        if form == Form::Chunks {
+            for (name, chunk_len) in chunk::list(&fs::read(&path)?)? {
+                match chunks_seen.insert(name) {
+                    true => stored += chunk_len,
+                    false => shared += chunk_len,
+                }
+            }
+        }
+        usage.versions.push(Version { version, form, len, stored, shared });
+    }
+    Ok(usage)
+}
@@ -0,0 +1,154 @@
+//! Watches `target_dir` for changes made behind the daemon's back.
+//!
+//! Versions added by other tools are picked up as the new head, cached
+//! diffs of versions modified or removed externally are dropped, and a head
+//! that vanishes from the store is reported rather than silently served.
+
+use std::collections::HashMap;
+use std::ffi::{CString, OsStr};
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::thread;
+
+use libc::{
+    IN_CLOEXEC, IN_CLOSE_WRITE, IN_CREATE, IN_DELETE, IN_ISDIR,
+    IN_MOVED_FROM, IN_MOVED_TO, IN_Q_OVERFLOW,
+};
+use log::{error, info, warn};
+
+use crate::store::SharedStore;
+
+const EVENT_SIZE: usize = std::mem::size_of::<libc::inotify_event>();
+
+struct Watcher {
+    fd: i32,
+    store: SharedStore,
+    /// Watched directories by watch descriptor
+    dirs: HashMap<i32, PathBuf>,
+}
+
+pub fn spawn(store: SharedStore) -> io::Result<()> {
+    let fd = unsafe { libc::inotify_init1(IN_CLOEXEC) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let (target_dir, sharded) = {
+        let store = store.lock().unwrap();
+        (store.target_dir.clone(), store.sharded)
+    };
+    let mut watcher = Watcher { fd, store, dirs: HashMap::new() };
+    match sharded {
+        true => watcher.add_tree(&target_dir, false)?,
+        false => watcher.add(&target_dir)?,
+    }
+    thread::spawn(move || watcher.run());
+    Ok(())
+}
+
+impl Watcher {
+    fn add(&mut self, dir: &Path) -> io::Result<()> {
+        let cpath = CString::new(dir.as_os_str().as_bytes())?;
+        let mask = IN_CLOSE_WRITE | IN_CREATE | IN_DELETE | IN_MOVED_FROM | IN_MOVED_TO;
+        match unsafe { libc::inotify_add_watch(self.fd, cpath.as_ptr(), mask) } {
+            -1 => Err(io::Error::last_os_error()),
+            wd => {
+                self.dirs.insert(wd, dir.to_path_buf());
+                Ok(())
+            },
+        }
+    }
+
+    /// Watch `dir` and the directories below it. With `catch_up`, files
+    /// already in there are handled as if they had just been moved in, since
+    /// they may have been created before the watch was in place.
+    fn add_tree(&mut self, dir: &Path, catch_up: bool) -> io::Result<()> {
+        self.add(dir)?;
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            if entry.file_type()?.is_dir() {
+                self.add_tree(&entry.path(), catch_up)?;
+            } else if catch_up {
+                self.file_changed(IN_MOVED_TO, &entry.file_name());
+            }
+        }
+        Ok(())
+    }
+
+    fn run(mut self) {
+        let mut buf = vec![0u8; 64 * (EVENT_SIZE + 256)];
+        loop {
+            let len = unsafe { libc::read(self.fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len()) };
+            if len < 0 {
+                let e = io::Error::last_os_error();
+                if e.kind() != io::ErrorKind::Interrupted {
+                    error!("watching the store failed: {e}");
+                    return;
+                }
+                continue;
+            }
+            let mut events = &buf[..len as usize];
+            while events.len() >= EVENT_SIZE {
+                let field = |i: usize| u32::from_ne_bytes(events[i..i + 4].try_into().unwrap());
+                let (wd, mask, name_len) = (field(0) as i32, field(4), field(12) as usize);
+                let name = &events[EVENT_SIZE..EVENT_SIZE + name_len];
+                let name = OsStr::from_bytes(&name[..name.iter().position(|&b| b == 0).unwrap_or(name.len())]);
+                self.handle(wd, mask, name);
+                events = &events[EVENT_SIZE + name_len..];
+            }
+        }
+    }
+
+    fn handle(&mut self, wd: i32, mask: u32, name: &OsStr) {
+        if mask & IN_Q_OVERFLOW != 0 {
+            warn!("store events were lost, dropping all cached diffs");
+            self.store.lock().unwrap().forget_all_diffs();
+            return;
+        }
+        if mask & IN_ISDIR != 0 {
+            if mask & IN_CREATE != 0 {
+                if let Some(dir) = self.dirs.get(&wd) {
+                    let dir = dir.join(name);
+                    if let Err(e) = self.add_tree(&dir, true) {
+                        warn!("cannot watch {dir:?}: {e}");
+                    }
+                }
+            }
+            return;
+        }
+        self.file_changed(mask, name);
+    }
+
+    fn file_changed(&mut self, mask: u32, name: &OsStr) {
+        let mut store = self.store.lock().unwrap();
+        let (version, is_delta) = match store.version_of(name) {
+            Some(v) => v,
+            None => return,
+        };
+        if mask & (IN_CLOSE_WRITE | IN_MOVED_TO) != 0 {
+            if version > store.version && !is_delta {
+                info!("picked up version {version} added to the store externally");
+                store.version = version;
+            } else if version < store.version && !is_delta {
+                warn!("version {version} was modified after being superseded");
+                store.forget_diffs(version);
+            }
+        } else if mask & (IN_DELETE | IN_MOVED_FROM) != 0 {
+            let other = match is_delta {
+                true => store.path_for_version(version),
+                false => store.path_for_compacted(version),
+            };
+            // compaction replaces a version with its delta, anything else is a loss
+            if other.exists() || version > store.version {
+                return;
+            }
+            if version == store.version {
+                error!("the head version {version} was removed from the store");
+            } else {
+                warn!("version {version} was removed from the store");
+            }
+            store.forget_diffs(version);
+        }
+    }
+}
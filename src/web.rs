@@ -0,0 +1,116 @@
+//! Browser front-end for the versions of the target.
+//!
+//! Requests for any host but the address the UI listens on are refused with
+//! 403, and restoring unless requested by a page served there, so other
+//! pages can neither read the versions nor restore them.
+
+use std::io;
+use std::net::TcpListener;
+
+use log::{info, warn};
+
+use crate::http::{self, Request, Response};
+use crate::store::SharedStore;
+
+pub fn serve(listener: TcpListener, store: SharedStore) -> io::Result<()> {
+    http::serve(listener, move |request| route(&store, request))
+}
+
+fn route(store: &SharedStore, request: &Request) -> Response {
+    let path = request.path.split('?').next().unwrap_or_default();
+    let segments: Vec<&str> = path.trim_start_matches('/').split('/').collect();
+    match (request.method.as_str(), segments.as_slice()) {
+        _ if !request.for_this_host() => Response::text(403, "unknown host\n"),
+        ("POST", _) if !request.same_origin() => Response::text(403, "cross-origin request refused\n"),
+        ("GET", [""]) => index(store),
+        ("GET", ["diff", version]) => with_version(store, version, diff),
+        ("GET", ["download", version]) => with_version(store, version, download),
+        ("POST", ["restore", version]) => with_version(store, version, restore),
+        (_, ["" | "diff" | "download" | "restore", ..]) => Response::text(405, "method not allowed\n"),
+        _ => Response::not_found(),
+    }
+}
+
+fn with_version<F>(store: &SharedStore, version: &str, f: F) -> Response
+where
+    F: FnOnce(&SharedStore, usize) -> Response,
+{
+    match version.parse::<usize>() {
+        Ok(version) if store.lock().unwrap().contains(version) => f(store, version),
+        _ => Response::not_found(),
+    }
+}
+
+fn index(store: &SharedStore) -> Response {
+    let store = store.lock().unwrap();
+    let target = escape(&store.target.to_string_lossy());
+    let mut rows = String::new();
+    for version in store.versions().into_iter().rev() {
+        let (size, mtime) = match store.version_meta(version) {
+            Ok(m) => (m.len, humantime::format_rfc3339_seconds(m.modified)),
+            Err(_) => continue,
+        };
+        let head = if version == store.version { " (head)" } else { "" };
+        let head = match store.reverts.get(&version) {
+            Some(revert) if revert.range.is_some() => format!("{head} (partly from {})", revert.from),
+            Some(revert) => format!("{head} (from {})", revert.from),
+            None => head.to_string(),
+        };
+        rows.push_str(&format!(
+            "<tr><td>{version}{head}</td><td>{size}</td><td>{mtime}</td>\
+             <td><a href=\"/diff/{version}\">diff</a> \
+             <a href=\"/download/{version}\">download</a> \
+             <form method=\"post\" action=\"/restore/{version}\"><button>restore</button></form></td></tr>\n"
+        ));
+    }
+    let page = format!(
+        "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>{target} - versionfs</title>\
+         <style>body {{ font-family: sans-serif }} td {{ padding: 0 1em }} form {{ display: inline }}</style>\
+         </head><body>\n<h1>{target}</h1>\n<table>\n\
+         <tr><th>Version</th><th>Size</th><th>Modified</th><th></th></tr>\n{rows}</table>\n</body></html>\n"
+    );
+    Response::new(200, "text/html; charset=utf-8", page)
+}
+
+fn diff(store: &SharedStore, version: usize) -> Response {
+    match store.lock().unwrap().diff_for_version(version) {
+        Some(diff) => Response::text(200, diff),
+        None => Response::not_found(),
+    }
+}
+
+fn download(store: &SharedStore, version: usize) -> Response {
+    let store = store.lock().unwrap();
+    match store.read_version(version) {
+        Ok(data) => Response::new(200, "application/octet-stream", data).with_header(
+            "Content-Disposition",
+            format!("attachment; filename=\"{}\"", store.name_for_version(version).replace('"', "")),
+        ),
+        Err(_) => Response::not_found(),
+    }
+}
+
+fn restore(store: &SharedStore, version: usize) -> Response {
+    // read unlocked, as reading a large version would hold up the mount
+    let reader = store.lock().unwrap().reader();
+    let restored = reader
+        .read_version(version)
+        .and_then(|data| store.lock().unwrap().restore_from(version, data));
+    match restored {
+        Ok(head) => {
+            info!("restored version {version} as {head}");
+            Response::redirect("/")
+        },
+        Err(e) => {
+            warn!("restoring version {version} failed: {e}");
+            Response::text(500, format!("restore failed: {e}\n"))
+        },
+    }
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
@@ -0,0 +1,269 @@
+//! Extended attributes of the target, kept on the head version and carried
+//! over to each new one.
+
+use std::collections::BTreeMap;
+use std::ffi::{CString, OsStr, OsString};
+use std::io;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::Path;
+
+/// Attributes forwarded to the store: POSIX ACLs, SELinux labels and file
+/// capabilities
+const FORWARDED: &[&str] = &[
+    "system.posix_acl_access",
+    "system.posix_acl_default",
+    "security.selinux",
+    "security.capability",
+];
+
+/// Attributes not carried over to new versions: like the kernel on a write,
+/// a version with new content loses its capabilities.
+const NOT_CARRIED: &[&str] = &["security.capability"];
+
+/// Attributes of the target the mount answers itself, from the store: the
+/// version being read, a hash of its content, where the store is, the
+/// latest versions, and why the store takes no changes, empty if it does.
+pub const VERSION: &str = "user.versionfs.version";
+pub const HEAD_HASH: &str = "user.versionfs.head_hash";
+pub const STORE: &str = "user.versionfs.store";
+pub const HISTORY: &str = "user.versionfs.history";
+pub const DEGRADED: &str = "user.versionfs.degraded";
+pub const STATUS: [&str; 5] = [VERSION, HEAD_HASH, STORE, HISTORY, DEGRADED];
+
+/// How many versions `user.versionfs.history` lists
+pub const HISTORY_LEN: usize = 10;
+
+pub fn forwarded(name: &OsStr) -> bool {
+    FORWARDED.iter().any(|&f| OsStr::new(f) == name)
+}
+
+/// Whether only root may set `name`.
+pub fn privileged(name: &OsStr) -> bool {
+    name.as_bytes().starts_with(b"security.")
+}
+
+// The attribute calls, not following symlinks. macOS has no l* variants but
+// takes a flag, and a position that only resource forks use. FreeBSD has
+// extattr_*_link instead, taking the namespace apart from the name.
+
+#[cfg(target_os = "linux")]
+mod sys {
+    use libc::{c_char, c_int, c_void, size_t, ssize_t};
+
+    pub unsafe fn get(path: *const c_char, name: *const c_char, value: *mut c_void, size: size_t) -> ssize_t {
+        libc::lgetxattr(path, name, value, size)
+    }
+
+    pub unsafe fn list(path: *const c_char, list: *mut c_char, size: size_t) -> ssize_t {
+        libc::llistxattr(path, list, size)
+    }
+
+    pub unsafe fn set(path: *const c_char, name: *const c_char, value: *const c_void, size: size_t, flags: c_int) -> c_int {
+        libc::lsetxattr(path, name, value, size, flags)
+    }
+
+    pub unsafe fn remove(path: *const c_char, name: *const c_char) -> c_int {
+        libc::lremovexattr(path, name)
+    }
+}
+
+#[cfg(target_os = "macos")]
+mod sys {
+    use libc::{c_char, c_int, c_void, size_t, ssize_t, XATTR_NOFOLLOW};
+
+    pub unsafe fn get(path: *const c_char, name: *const c_char, value: *mut c_void, size: size_t) -> ssize_t {
+        libc::getxattr(path, name, value, size, 0, XATTR_NOFOLLOW)
+    }
+
+    pub unsafe fn list(path: *const c_char, list: *mut c_char, size: size_t) -> ssize_t {
+        libc::listxattr(path, list, size, XATTR_NOFOLLOW)
+    }
+
+    pub unsafe fn set(path: *const c_char, name: *const c_char, value: *const c_void, size: size_t, flags: c_int) -> c_int {
+        libc::setxattr(path, name, value, size, 0, flags | XATTR_NOFOLLOW)
+    }
+
+    pub unsafe fn remove(path: *const c_char, name: *const c_char) -> c_int {
+        libc::removexattr(path, name, XATTR_NOFOLLOW)
+    }
+}
+
+#[cfg(target_os = "freebsd")]
+mod sys {
+    use std::ffi::CStr;
+
+    use libc::{c_char, c_int, c_void, size_t, ssize_t, EXTATTR_NAMESPACE_SYSTEM, EXTATTR_NAMESPACE_USER};
+
+    const NAMESPACES: &[(c_int, &[u8])] = &[(EXTATTR_NAMESPACE_USER, b"user."), (EXTATTR_NAMESPACE_SYSTEM, b"system.")];
+
+    unsafe fn fail(errno: c_int) -> c_int {
+        *libc::__error() = errno;
+        -1
+    }
+
+    /// Split `namespace.attribute` into the namespace and the bare name.
+    unsafe fn split(name: *const c_char) -> Option<(c_int, *const c_char)> {
+        let bytes = CStr::from_ptr(name).to_bytes();
+        NAMESPACES
+            .iter()
+            .find(|(_, prefix)| bytes.starts_with(prefix))
+            .map(|&(namespace, prefix)| (namespace, name.add(prefix.len())))
+    }
+
+    pub unsafe fn get(path: *const c_char, name: *const c_char, value: *mut c_void, size: size_t) -> ssize_t {
+        match split(name) {
+            Some((namespace, name)) => libc::extattr_get_link(path, namespace, name, value, size),
+            None => fail(libc::ENOTSUP) as ssize_t,
+        }
+    }
+
+    /// Names in every namespace we may read, as the NUL-separated
+    /// `namespace.attribute` list of the other platforms.
+    pub unsafe fn list(path: *const c_char, list: *mut c_char, size: size_t) -> ssize_t {
+        let mut names = Vec::new();
+        for &(namespace, prefix) in NAMESPACES {
+            let raw = match super::read_sized(|buf, len| libc::extattr_list_link(path, namespace, buf, len)) {
+                Ok(raw) => raw,
+                // only root may list the system namespace
+                Err(e) if e.raw_os_error() == Some(libc::EPERM) => continue,
+                Err(e) => return fail(e.raw_os_error().unwrap_or(libc::EIO)) as ssize_t,
+            };
+            // each name comes prefixed with its length
+            let mut rest = &raw[..];
+            while let Some((&len, tail)) = rest.split_first() {
+                let (name, tail) = tail.split_at((len as usize).min(tail.len()));
+                names.extend_from_slice(prefix);
+                names.extend_from_slice(name);
+                names.push(0);
+                rest = tail;
+            }
+        }
+        if list.is_null() {
+            return names.len() as ssize_t;
+        }
+        if size < names.len() {
+            return fail(libc::ERANGE) as ssize_t;
+        }
+        std::ptr::copy_nonoverlapping(names.as_ptr(), list as *mut u8, names.len());
+        names.len() as ssize_t
+    }
+
+    /// `flags` is ignored: extattr has no XATTR_CREATE or XATTR_REPLACE.
+    pub unsafe fn set(path: *const c_char, name: *const c_char, value: *const c_void, size: size_t, _flags: c_int) -> c_int {
+        match split(name) {
+            Some((namespace, name)) => match libc::extattr_set_link(path, namespace, name, value, size) {
+                -1 => -1,
+                _ => 0,
+            },
+            None => fail(libc::ENOTSUP),
+        }
+    }
+
+    pub unsafe fn remove(path: *const c_char, name: *const c_char) -> c_int {
+        match split(name) {
+            Some((namespace, name)) => libc::extattr_delete_link(path, namespace, name),
+            None => fail(libc::ENOTSUP),
+        }
+    }
+}
+
+fn cstr(s: &OsStr) -> io::Result<CString> {
+    Ok(CString::new(s.as_bytes())?)
+}
+
+/// Call `f` with a buffer of `len` bytes, sized by calling it with none first.
+fn read_sized<F>(f: F) -> io::Result<Vec<u8>>
+where
+    F: Fn(*mut libc::c_void, usize) -> libc::ssize_t,
+{
+    loop {
+        let len = f(std::ptr::null_mut(), 0);
+        if len < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut buf = vec![0u8; len as usize];
+        match f(buf.as_mut_ptr() as *mut libc::c_void, buf.len()) {
+            // grew in between
+            -1 if crate::errno() == libc::ERANGE => continue,
+            -1 => return Err(io::Error::last_os_error()),
+            len => {
+                buf.truncate(len as usize);
+                return Ok(buf);
+            },
+        }
+    }
+}
+
+pub fn get(path: &Path, name: &OsStr) -> io::Result<Vec<u8>> {
+    let (cpath, cname) = (cstr(path.as_os_str())?, cstr(name)?);
+    read_sized(|buf, len| unsafe { sys::get(cpath.as_ptr(), cname.as_ptr(), buf, len) })
+}
+
+/// Names of the forwarded attributes set on `path`.
+pub fn list(path: &Path) -> io::Result<Vec<OsString>> {
+    let cpath = cstr(path.as_os_str())?;
+    let names = match read_sized(|buf, len| unsafe { sys::list(cpath.as_ptr(), buf as *mut libc::c_char, len) }) {
+        Ok(names) => names,
+        Err(e) if e.raw_os_error() == Some(libc::ENOTSUP) => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    Ok(names
+        .split(|&b| b == 0)
+        .filter(|name| !name.is_empty())
+        .map(|name| OsString::from_vec(name.to_vec()))
+        .filter(|name| forwarded(name))
+        .collect())
+}
+
+pub fn set(path: &Path, name: &OsStr, value: &[u8], flags: i32) -> io::Result<()> {
+    let (cpath, cname) = (cstr(path.as_os_str())?, cstr(name)?);
+    let ptr = value.as_ptr() as *const libc::c_void;
+    match unsafe { sys::set(cpath.as_ptr(), cname.as_ptr(), ptr, value.len(), flags) } {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+pub fn remove(path: &Path, name: &OsStr) -> io::Result<()> {
+    let (cpath, cname) = (cstr(path.as_os_str())?, cstr(name)?);
+    match unsafe { sys::remove(cpath.as_ptr(), cname.as_ptr()) } {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+/// The forwarded attributes set on `path`, with their values.
+pub fn all(path: &Path) -> io::Result<BTreeMap<OsString, Vec<u8>>> {
+    list(path)?
+        .into_iter()
+        .map(|name| {
+            let value = get(path, &name)?;
+            Ok((name, value))
+        })
+        .collect()
+}
+
+/// Copy the forwarded attributes of `from` to the new version `to`.
+pub fn copy(from: &Path, to: &Path) -> io::Result<()> {
+    copy_except(from, to, NOT_CARRIED)
+}
+
+/// Copy all the forwarded attributes of `from` to `to`, which holds the
+/// same version, e.g. as a delta.
+pub fn keep(from: &Path, to: &Path) -> io::Result<()> {
+    copy_except(from, to, &[])
+}
+
+fn copy_except(from: &Path, to: &Path, except: &[&str]) -> io::Result<()> {
+    for name in list(from)? {
+        if except.iter().any(|&n| OsStr::new(n) == name) {
+            continue;
+        }
+        match set(to, &name, &get(from, &name)?, 0) {
+            // e.g. a label the store's filesystem can't hold
+            Err(e) if e.raw_os_error() == Some(libc::ENOTSUP) => {},
+            result => result?,
+        }
+    }
+    Ok(())
+}
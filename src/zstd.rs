@@ -0,0 +1,1198 @@
+//! Zstandard frames (RFC 8878), for versions kept compressed.
+//!
+//! Decoding covers everything but dictionaries: raw, RLE and compressed
+//! blocks with Huffman-coded literals and FSE-coded sequences, skippable
+//! frames, and content checksums, which are verified.
+//!
+//! Encoding finds matches through hash chains searched deeper the higher the
+//! level, and codes the sequences with the predefined tables. Literals are
+//! Huffman coded when they are ASCII, where the weights of the code can be
+//! written directly. Frames come out larger than the reference encoder's,
+//! but any decoder reads them.
+
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::io;
+
+const MAGIC: u32 = 0xFD2F_B528;
+const SKIPPABLE_MAGIC: u32 = 0x184D_2A50;
+const SKIPPABLE_MASK: u32 = 0xFFFF_FFF0;
+
+const MAX_BLOCK_SIZE: usize = 128 << 10;
+
+fn corrupt(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("corrupt zstd data: {what}"))
+}
+
+/// The little-endian word at the start of `data`.
+fn u32_at(data: &[u8]) -> io::Result<u32> {
+    let word = data.get(..4).ok_or_else(|| corrupt("truncated frame"))?;
+    Ok(u32::from_le_bytes(word.try_into().unwrap()))
+}
+
+/// Reads bits forward from the lowest bit of each byte, as FSE table
+/// descriptions are written.
+struct ForwardBits<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl ForwardBits<'_> {
+    fn read(&mut self, n: u32) -> io::Result<u32> {
+        let mut value = 0;
+        for i in 0..n {
+            let byte = *self.data.get(self.pos / 8).ok_or_else(|| corrupt("table description overflows"))?;
+            value |= ((byte >> (self.pos % 8)) as u32 & 1) << i;
+            self.pos += 1;
+        }
+        Ok(value)
+    }
+
+    fn bytes_read(&self) -> usize { self.pos.div_ceil(8) }
+}
+
+/// Reads bits backward from the end of a stream, as Huffman and FSE streams
+/// are written. The last byte holds a 1 marking where the stream ends.
+/// Reading past the start yields zeros, which decoders check for with
+/// `overflowed`.
+struct BackwardBits<'a> {
+    data: &'a [u8],
+    /// Bits not read yet, negative once reads went past the start
+    pos: isize,
+}
+
+impl<'a> BackwardBits<'a> {
+    fn new(data: &'a [u8]) -> io::Result<Self> {
+        match data.last() {
+            Some(&last) if last != 0 => {
+                let pos = (data.len() * 8 - 1) as isize - last.leading_zeros() as isize;
+                Ok(BackwardBits { data, pos })
+            },
+            _ => Err(corrupt("bitstream lacks its end mark")),
+        }
+    }
+
+    /// The `n` bits below `pos`, the first of them read as the highest.
+    fn peek(&self, n: u32) -> u64 {
+        if n == 0 {
+            return 0;
+        }
+        let start = self.pos - n as isize;
+        let first = start.div_euclid(8);
+        let mut acc: u128 = 0;
+        for i in 0..9 {
+            let index = first + i;
+            if index >= 0 && (index as usize) < self.data.len() {
+                acc |= (self.data[index as usize] as u128) << (8 * i);
+            }
+        }
+        ((acc >> start.rem_euclid(8)) as u64) & (u64::MAX >> (64 - n))
+    }
+
+    fn read(&mut self, n: u32) -> u64 {
+        let value = self.peek(n);
+        self.pos -= n as isize;
+        value
+    }
+
+    fn overflowed(&self) -> bool { self.pos < 0 }
+}
+
+#[derive(Clone, Copy, Default)]
+struct FseEntry {
+    symbol: u8,
+    bits: u8,
+    base: u16,
+}
+
+/// A table decoding FSE states into symbols
+#[derive(Clone, Default)]
+struct Fse {
+    log: u32,
+    entries: Vec<FseEntry>,
+}
+
+impl Fse {
+    /// Build the table for symbols with the `counts` normalized to
+    /// `1 << log`, -1 standing for "less than 1".
+    fn new(counts: &[i16], log: u32) -> io::Result<Self> {
+        let size = 1usize << log;
+        let mut entries = vec![FseEntry::default(); size];
+        let mut next = vec![0u16; counts.len()];
+        let mut high = size - 1;
+        for (s, &count) in counts.iter().enumerate() {
+            if count == -1 {
+                entries[high].symbol = s as u8;
+                high = high.wrapping_sub(1);
+                next[s] = 1;
+            } else {
+                next[s] = count as u16;
+            }
+        }
+        let step = (size >> 1) + (size >> 3) + 3;
+        let mut pos = 0;
+        for (s, &count) in counts.iter().enumerate() {
+            for _ in 0..count.max(0) {
+                entries[pos].symbol = s as u8;
+                pos = (pos + step) & (size - 1);
+                while pos > high {
+                    pos = (pos + step) & (size - 1);
+                }
+            }
+        }
+        if pos != 0 {
+            return Err(corrupt("FSE counts don't fill the table"));
+        }
+        for entry in entries.iter_mut() {
+            let state = next[entry.symbol as usize];
+            next[entry.symbol as usize] += 1;
+            let bits = log - (15 - state.leading_zeros());
+            entry.bits = bits as u8;
+            entry.base = ((state << bits) as usize - size) as u16;
+        }
+        Ok(Fse { log, entries })
+    }
+
+    /// A table always decoding `symbol`, reading no bits.
+    fn rle(symbol: u8) -> Self {
+        Fse { log: 0, entries: vec![FseEntry { symbol, bits: 0, base: 0 }] }
+    }
+
+    /// Read a table description of at most `max_log` from the start of
+    /// `data`, returning the table and the bytes it took.
+    fn read(data: &[u8], max_log: u32, max_symbol: usize) -> io::Result<(Self, usize)> {
+        let mut bits = ForwardBits { data, pos: 0 };
+        let log = bits.read(4)? + 5;
+        if log > max_log {
+            return Err(corrupt("FSE accuracy too high"));
+        }
+        let mut remaining = 1i32 << log;
+        let mut counts = Vec::new();
+        while remaining > 0 && counts.len() <= max_symbol {
+            let n = 32 - (remaining + 1).leading_zeros();
+            let mut value = bits.read(n)? as i32;
+            let low_mask = (1 << (n - 1)) - 1;
+            let threshold = (1 << n) - 1 - (remaining + 1);
+            if value & low_mask < threshold {
+                bits.pos -= 1;
+                value &= low_mask;
+            } else if value > low_mask {
+                value -= threshold;
+            }
+            let count = value as i16 - 1;
+            remaining -= (count as i32).abs();
+            counts.push(count);
+            if count == 0 {
+                loop {
+                    let repeat = bits.read(2)?;
+                    counts.extend(std::iter::repeat_n(0, repeat as usize));
+                    if repeat != 3 {
+                        break;
+                    }
+                }
+            }
+        }
+        if remaining != 0 || counts.len() > max_symbol + 1 {
+            return Err(corrupt("FSE counts don't add up"));
+        }
+        Ok((Fse::new(&counts, log)?, bits.bytes_read()))
+    }
+
+    fn init(&self, bits: &mut BackwardBits) -> usize { bits.read(self.log) as usize }
+
+    fn symbol(&self, state: usize) -> u8 { self.entries[state].symbol }
+
+    fn update(&self, state: usize, bits: &mut BackwardBits) -> usize {
+        let entry = self.entries[state];
+        entry.base as usize + bits.read(entry.bits as u32) as usize
+    }
+}
+
+/// A table decoding the next `max_bits` of a stream into a symbol and the
+/// length of its code
+#[derive(Clone, Default)]
+struct Huffman {
+    max_bits: u32,
+    entries: Vec<(u8, u8)>,
+}
+
+impl Huffman {
+    /// Read a tree description from the start of `data`, returning the
+    /// table and the bytes it took.
+    fn read(data: &[u8]) -> io::Result<(Self, usize)> {
+        let header = *data.first().ok_or_else(|| corrupt("missing Huffman tree"))? as usize;
+        let (mut weights, len) = if header < 128 {
+            // weights coded with FSE, decoded by two states taking turns
+            let compressed = data.get(1..1 + header).ok_or_else(|| corrupt("Huffman tree overflows"))?;
+            let (fse, table_len) = Fse::read(compressed, 6, 255)?;
+            let mut bits = BackwardBits::new(&compressed[table_len..])?;
+            let mut states = [fse.init(&mut bits), fse.init(&mut bits)];
+            let mut weights = Vec::new();
+            for turn in (0..2).cycle() {
+                if weights.len() > 255 {
+                    return Err(corrupt("too many Huffman weights"));
+                }
+                weights.push(fse.symbol(states[turn]));
+                states[turn] = fse.update(states[turn], &mut bits);
+                if bits.overflowed() {
+                    weights.push(fse.symbol(states[1 - turn]));
+                    break;
+                }
+            }
+            (weights, 1 + header)
+        } else {
+            let count = header - 127;
+            let packed = data.get(1..1 + count.div_ceil(2)).ok_or_else(|| corrupt("Huffman tree overflows"))?;
+            let weights = packed.iter().flat_map(|b| [b >> 4, b & 15]).take(count).collect();
+            (weights, 1 + count.div_ceil(2))
+        };
+
+        // the weight of the last symbol is implied by the others
+        let total: u32 = weights.iter().filter(|&&w| w > 0).map(|&w| 1 << (w - 1)).sum();
+        if total == 0 || weights.iter().any(|&w| w > 11) {
+            return Err(corrupt("bad Huffman weights"));
+        }
+        let max_bits = 32 - total.leading_zeros();
+        let left = (1 << max_bits) - total;
+        if !left.is_power_of_two() {
+            return Err(corrupt("Huffman weights don't add up"));
+        }
+        weights.push(left.trailing_zeros() as u8 + 1);
+        if max_bits > 11 {
+            return Err(corrupt("Huffman codes too long"));
+        }
+
+        let lengths: Vec<u8> = weights.iter().map(|&w| weight_to_len(w, max_bits)).collect();
+        let mut entries = vec![(0, 0); 1 << max_bits];
+        for (symbol, (&bits, start)) in lengths.iter().zip(code_starts(&lengths, max_bits)).enumerate() {
+            if bits > 0 {
+                let span = 1 << (max_bits - bits as u32);
+                entries[start..start + span].fill((symbol as u8, bits));
+            }
+        }
+        Ok((Huffman { max_bits, entries }, len))
+    }
+
+    /// Decode `count` symbols from the single stream `data` into `out`.
+    fn decode(&self, data: &[u8], count: usize, out: &mut Vec<u8>) -> io::Result<()> {
+        let mut bits = BackwardBits::new(data)?;
+        for _ in 0..count {
+            let (symbol, len) = self.entries[bits.peek(self.max_bits) as usize];
+            bits.pos -= len as isize;
+            out.push(symbol);
+        }
+        if bits.pos != 0 {
+            return Err(corrupt("Huffman stream doesn't end with its symbols"));
+        }
+        Ok(())
+    }
+}
+
+/// The length of codes of Huffman `weight`, or the other way around.
+fn weight_to_len(weight: u8, max_bits: u32) -> u8 {
+    if weight > 0 { max_bits as u8 + 1 - weight } else { 0 }
+}
+
+/// Where the code of each symbol with the code `lengths` starts in a table
+/// indexed by the next `max_bits` of a stream. Longer codes come first, and
+/// codes of the same length go by symbol.
+fn code_starts(lengths: &[u8], max_bits: u32) -> Vec<usize> {
+    let mut rank_count = [0usize; 13];
+    for &bits in lengths {
+        rank_count[bits as usize] += 1;
+    }
+    let mut rank_start = [0usize; 13];
+    for bits in (2..=max_bits as usize).rev() {
+        rank_start[bits - 1] = rank_start[bits] + (rank_count[bits] << (max_bits as usize - bits));
+    }
+    lengths
+        .iter()
+        .map(|&bits| {
+            let start = rank_start[bits as usize];
+            if bits > 0 {
+                rank_start[bits as usize] += 1 << (max_bits - bits as u32);
+            }
+            start
+        })
+        .collect()
+}
+
+const LL_BASE: [(u32, u32); 36] = [
+    (0, 0), (1, 0), (2, 0), (3, 0), (4, 0), (5, 0), (6, 0), (7, 0), (8, 0), (9, 0), (10, 0), (11, 0),
+    (12, 0), (13, 0), (14, 0), (15, 0), (16, 1), (18, 1), (20, 1), (22, 1), (24, 2), (28, 2), (32, 3),
+    (40, 3), (48, 4), (64, 6), (128, 7), (256, 8), (512, 9), (1024, 10), (2048, 11), (4096, 12),
+    (8192, 13), (16384, 14), (32768, 15), (65536, 16),
+];
+
+const ML_BASE: [(u32, u32); 53] = [
+    (3, 0), (4, 0), (5, 0), (6, 0), (7, 0), (8, 0), (9, 0), (10, 0), (11, 0), (12, 0), (13, 0), (14, 0),
+    (15, 0), (16, 0), (17, 0), (18, 0), (19, 0), (20, 0), (21, 0), (22, 0), (23, 0), (24, 0), (25, 0),
+    (26, 0), (27, 0), (28, 0), (29, 0), (30, 0), (31, 0), (32, 0), (33, 0), (34, 0), (35, 1), (37, 1),
+    (39, 1), (41, 1), (43, 2), (47, 2), (51, 3), (59, 3), (67, 4), (83, 4), (99, 5), (131, 7), (259, 8),
+    (515, 9), (1027, 10), (2051, 11), (4099, 12), (8195, 13), (16387, 14), (32771, 15), (65539, 16),
+];
+
+const LL_DEFAULT: [i16; 36] = [
+    4, 3, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 1, 1, 1, 2, 2, 2, 2, 2, 2, 2, 2, 2, 3, 2, 1, 1, 1, 1, 1,
+    -1, -1, -1, -1,
+];
+
+const ML_DEFAULT: [i16; 53] = [
+    1, 4, 3, 2, 2, 2, 2, 2, 2, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1,
+    1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, -1, -1, -1, -1, -1, -1, -1,
+];
+
+const OF_DEFAULT: [i16; 29] = [
+    1, 1, 1, 1, 1, 1, 2, 2, 2, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, -1, -1, -1, -1, -1,
+];
+
+/// What blocks of a frame may take over from the blocks before them
+struct FrameState {
+    huffman: Option<Huffman>,
+    ll: Option<Fse>,
+    of: Option<Fse>,
+    ml: Option<Fse>,
+    offsets: [usize; 3],
+}
+
+/// Read the table for one kind of sequence code, as given by its `mode`,
+/// returning the bytes it took.
+fn sequence_table(
+    data: &[u8],
+    mode: u8,
+    previous: &mut Option<Fse>,
+    default: (&[i16], u32),
+    (max_log, max_symbol): (u32, usize),
+) -> io::Result<usize> {
+    let (table, len) = match mode {
+        0 => (Fse::new(default.0, default.1)?, 0),
+        1 => (Fse::rle(*data.first().ok_or_else(|| corrupt("missing RLE symbol"))?), 1),
+        2 => Fse::read(data, max_log, max_symbol)?,
+        _ => return previous.is_some().then_some(0).ok_or_else(|| corrupt("no table to repeat")),
+    };
+    *previous = Some(table);
+    Ok(len)
+}
+
+/// Decode the literals section at the start of `block`, returning the
+/// literals and the bytes the section took.
+fn literals(block: &[u8], state: &mut FrameState) -> io::Result<(Vec<u8>, usize)> {
+    let b = |i: usize| block.get(i).copied().map(usize::from).ok_or_else(|| corrupt("literals overflow"));
+    let kind = b(0)? & 3;
+    let format = (b(0)? >> 2) & 3;
+    if kind < 2 {
+        let (regenerated, header) = match format {
+            0 | 2 => (b(0)? >> 3, 1),
+            1 => ((b(0)? >> 4) + (b(1)? << 4), 2),
+            _ => ((b(0)? >> 4) + (b(1)? << 4) + (b(2)? << 12), 3),
+        };
+        return match kind {
+            0 => {
+                let raw = block.get(header..header + regenerated);
+                Ok((raw.ok_or_else(|| corrupt("literals overflow"))?.to_vec(), header + regenerated))
+            },
+            _ => Ok((vec![b(header)? as u8; regenerated], header + 1)),
+        };
+    }
+
+    let (header, size_bits, streams) = match format {
+        0 => (3, 10, 1),
+        1 => (3, 10, 4),
+        2 => (4, 14, 4),
+        _ => (5, 18, 4),
+    };
+    let mut sizes = 0u64;
+    for i in 0..header {
+        sizes |= (b(i)? as u64) << (8 * i);
+    }
+    sizes >>= 4;
+    let mask = (1 << size_bits) - 1;
+    let (regenerated, compressed) = ((sizes & mask) as usize, ((sizes >> size_bits) & mask) as usize);
+    let mut data = block.get(header..header + compressed).ok_or_else(|| corrupt("literals overflow"))?;
+    if kind == 2 {
+        let (huffman, len) = Huffman::read(data)?;
+        state.huffman = Some(huffman);
+        data = &data[len..];
+    }
+    let huffman = state.huffman.as_ref().ok_or_else(|| corrupt("no Huffman table to repeat"))?;
+
+    let mut out = Vec::with_capacity(regenerated);
+    if streams == 1 {
+        huffman.decode(data, regenerated, &mut out)?;
+    } else {
+        let jump = data.get(..6).ok_or_else(|| corrupt("missing jump table"))?;
+        let mut lens: Vec<usize> = jump.chunks(2).map(|l| u16::from_le_bytes([l[0], l[1]]).into()).collect();
+        let rest = (data.len() - 6).checked_sub(lens.iter().sum()).ok_or_else(|| corrupt("bad jump table"))?;
+        lens.push(rest);
+        let per_stream = regenerated.div_ceil(4);
+        let mut start = 6;
+        for (i, len) in lens.into_iter().enumerate() {
+            let count = if i < 3 { per_stream } else { regenerated.saturating_sub(3 * per_stream) };
+            huffman.decode(&data[start..start + len], count, &mut out)?;
+            start += len;
+        }
+    }
+    Ok((out, header + compressed))
+}
+
+/// Decode a compressed block, appending it to `out`.
+fn compressed_block(block: &[u8], state: &mut FrameState, out: &mut Vec<u8>) -> io::Result<()> {
+    let (literals, mut pos) = literals(block, state)?;
+    let b = |i: usize| block.get(i).copied().map(usize::from).ok_or_else(|| corrupt("sequences overflow"));
+    let count = match b(pos)? {
+        0 => {
+            out.extend_from_slice(&literals);
+            return Ok(());
+        },
+        n @ 1..=127 => {
+            pos += 1;
+            n
+        },
+        n @ 128..=254 => {
+            pos += 2;
+            ((n - 128) << 8) + b(pos - 1)?
+        },
+        _ => {
+            pos += 3;
+            b(pos - 2)? + (b(pos - 1)? << 8) + 0x7F00
+        },
+    };
+    let modes = b(pos)? as u8;
+    pos += 1;
+    let rest = |pos: usize| block.get(pos..).ok_or_else(|| corrupt("sequences overflow"));
+    pos += sequence_table(rest(pos)?, modes >> 6, &mut state.ll, (&LL_DEFAULT, 6), (9, 35))?;
+    pos += sequence_table(rest(pos)?, (modes >> 4) & 3, &mut state.of, (&OF_DEFAULT, 5), (8, 31))?;
+    pos += sequence_table(rest(pos)?, (modes >> 2) & 3, &mut state.ml, (&ML_DEFAULT, 6), (9, 52))?;
+    let (ll, of, ml) = (state.ll.as_ref().unwrap(), state.of.as_ref().unwrap(), state.ml.as_ref().unwrap());
+
+    let mut bits = BackwardBits::new(rest(pos)?)?;
+    let mut ll_state = ll.init(&mut bits);
+    let mut of_state = of.init(&mut bits);
+    let mut ml_state = ml.init(&mut bits);
+    let mut literals = &literals[..];
+    for i in 0..count {
+        let of_code = of.symbol(of_state) as u32;
+        let (ml_code, ll_code) = (ml.symbol(ml_state) as usize, ll.symbol(ll_state) as usize);
+        if of_code > 31 || ml_code >= ML_BASE.len() || ll_code >= LL_BASE.len() {
+            return Err(corrupt("bad sequence code"));
+        }
+        let offset_value = (1usize << of_code) + bits.read(of_code) as usize;
+        let match_len = ML_BASE[ml_code].0 as usize + bits.read(ML_BASE[ml_code].1) as usize;
+        let literal_len = LL_BASE[ll_code].0 as usize + bits.read(LL_BASE[ll_code].1) as usize;
+
+        // offsets 1 to 3 pick a recent offset, shifted by one without literals
+        let offsets = &mut state.offsets;
+        let offset = match offset_value.min(4) - 1 + (literal_len == 0) as usize {
+            0 => offsets[0],
+            1 => {
+                offsets.swap(0, 1);
+                offsets[0]
+            },
+            2 => {
+                *offsets = [offsets[2], offsets[0], offsets[1]];
+                offsets[0]
+            },
+            3 if offset_value == 3 => {
+                let offset = offsets[0].checked_sub(1).filter(|&o| o > 0);
+                let offset = offset.ok_or_else(|| corrupt("bad offset"))?;
+                *offsets = [offset, offsets[0], offsets[1]];
+                offset
+            },
+            _ => {
+                *offsets = [offset_value - 3, offsets[0], offsets[1]];
+                offsets[0]
+            },
+        };
+
+        if literal_len > literals.len() || offset > out.len() + literal_len {
+            return Err(corrupt("sequence out of bounds"));
+        }
+        out.extend_from_slice(&literals[..literal_len]);
+        literals = &literals[literal_len..];
+        let start = out.len() - offset;
+        for j in 0..match_len {
+            out.push(out[start + j]);
+        }
+
+        if i + 1 < count {
+            ll_state = ll.update(ll_state, &mut bits);
+            ml_state = ml.update(ml_state, &mut bits);
+            of_state = of.update(of_state, &mut bits);
+        }
+    }
+    if bits.pos != 0 {
+        return Err(corrupt("sequence stream doesn't end with its sequences"));
+    }
+    out.extend_from_slice(literals);
+    Ok(())
+}
+
+/// The header of a frame: its length, content size if recorded, and
+/// whether a checksum follows the blocks.
+fn frame_header(data: &[u8]) -> io::Result<(usize, Option<u64>, bool)> {
+    let descriptor = *data.first().ok_or_else(|| corrupt("truncated frame header"))?;
+    let fcs_flag = descriptor >> 6;
+    let single_segment = descriptor & 0x20 != 0;
+    let checksum = descriptor & 0x04 != 0;
+    let dict_len = [0, 1, 2, 4][(descriptor & 3) as usize];
+    let fcs_len = match (fcs_flag, single_segment) {
+        (0, false) => 0,
+        (0, true) => 1,
+        (flag, _) => 1 << flag,
+    };
+    let mut pos = 1 + !single_segment as usize;
+    let dict = data.get(pos..pos + dict_len).ok_or_else(|| corrupt("truncated frame header"))?;
+    if dict.iter().any(|&b| b != 0) {
+        return Err(io::Error::new(io::ErrorKind::Unsupported, "zstd dictionaries are not supported"));
+    }
+    pos += dict_len;
+    let fcs = data.get(pos..pos + fcs_len).ok_or_else(|| corrupt("truncated frame header"))?;
+    let mut size = fcs.iter().rev().fold(0u64, |acc, &b| acc << 8 | b as u64);
+    if fcs_len == 2 {
+        size += 256;
+    }
+    Ok((pos + fcs_len, (fcs_len > 0).then_some(size), checksum))
+}
+
+/// Decompress all the frames in `data`.
+pub fn decode(mut data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut out = Vec::new();
+    while !data.is_empty() {
+        let magic = u32_at(data)?;
+        data = &data[4..];
+        if magic & SKIPPABLE_MASK == SKIPPABLE_MAGIC {
+            let len = u32_at(data)?;
+            data = data.get(4 + len as usize..).ok_or_else(|| corrupt("truncated skippable frame"))?;
+            continue;
+        }
+        if magic != MAGIC {
+            return Err(corrupt("not a zstd frame"));
+        }
+        let (header_len, size, checksum) = frame_header(data)?;
+        data = &data[header_len..];
+        let frame_start = out.len();
+        let mut state = FrameState { huffman: None, ll: None, of: None, ml: None, offsets: [1, 4, 8] };
+        loop {
+            let header = data.get(..3).ok_or_else(|| corrupt("truncated block header"))?;
+            let header = u32::from_le_bytes([header[0], header[1], header[2], 0]);
+            let (last, kind, len) = (header & 1 != 0, (header >> 1) & 3, (header >> 3) as usize);
+            data = &data[3..];
+            let block_len = if kind == 1 { 1 } else { len };
+            let block = data.get(..block_len).ok_or_else(|| corrupt("truncated block"))?;
+            match kind {
+                0 => out.extend_from_slice(block),
+                1 => out.resize(out.len() + len, block[0]),
+                2 if len <= MAX_BLOCK_SIZE => compressed_block(block, &mut state, &mut out)?,
+                _ => return Err(corrupt("bad block")),
+            }
+            data = &data[block_len..];
+            if last {
+                break;
+            }
+        }
+        if size.is_some_and(|size| size != (out.len() - frame_start) as u64) {
+            return Err(corrupt("content size doesn't match"));
+        }
+        if checksum {
+            if xxh64(&out[frame_start..]) as u32 != u32_at(data)? {
+                return Err(corrupt("checksum mismatch"));
+            }
+            data = &data[4..];
+        }
+    }
+    Ok(out)
+}
+
+/// Length of the content of `data`, read from the frame headers where they
+/// record it and decompressed otherwise.
+pub fn decoded_len(data: &[u8]) -> io::Result<u64> {
+    let mut rest = data;
+    let mut len = 0;
+    while !rest.is_empty() {
+        let magic = u32_at(rest)?;
+        let frame = &rest[4..];
+        if magic & SKIPPABLE_MASK == SKIPPABLE_MAGIC {
+            let size = u32_at(frame)?;
+            rest = frame.get(4 + size as usize..).ok_or_else(|| corrupt("truncated skippable frame"))?;
+            continue;
+        }
+        if magic != MAGIC {
+            return Err(corrupt("not a zstd frame"));
+        }
+        let (header_len, size, checksum) = frame_header(frame)?;
+        let size = match size {
+            Some(size) => size,
+            None => return Ok(decode(data)?.len() as u64),
+        };
+        // skip over the blocks
+        let mut pos = header_len;
+        loop {
+            let header = frame.get(pos..pos + 3).ok_or_else(|| corrupt("truncated block header"))?;
+            let header = u32::from_le_bytes([header[0], header[1], header[2], 0]);
+            pos += 3 + if (header >> 1) & 3 == 1 { 1 } else { (header >> 3) as usize };
+            if header & 1 != 0 {
+                break;
+            }
+        }
+        pos += if checksum { 4 } else { 0 };
+        rest = frame.get(pos..).ok_or_else(|| corrupt("truncated frame"))?;
+        len += size;
+    }
+    Ok(len)
+}
+
+/// Largest window frames are written with, bounding both the distance of
+/// matches and the memory decoders need
+const WINDOW_LOG: u32 = 22;
+const MIN_MATCH: usize = 4;
+const MAX_LEVEL: u32 = 19;
+
+/// Check a compression level given on the command line.
+pub fn parse_level(s: &str) -> Result<u32, String> {
+    match s.parse() {
+        Ok(level @ 1..=MAX_LEVEL) => Ok(level),
+        _ => Err(format!("invalid zstd level {s:?}, expected 1 to {MAX_LEVEL}")),
+    }
+}
+
+/// Writes bits forward from the lowest bit of each byte, to be read back
+/// with `BackwardBits`.
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    acc: u64,
+    len: u32,
+}
+
+impl BitWriter {
+    fn write(&mut self, value: u64, n: u32) {
+        if n == 0 {
+            return;
+        }
+        self.acc |= (value & (u64::MAX >> (64 - n))) << self.len;
+        self.len += n;
+        while self.len >= 8 {
+            self.bytes.push(self.acc as u8);
+            self.acc >>= 8;
+            self.len -= 8;
+        }
+    }
+
+    /// The bits written, followed by the mark `BackwardBits` starts from.
+    fn finish(mut self) -> Vec<u8> {
+        self.write(1, 1);
+        if self.len > 0 {
+            self.bytes.push(self.acc as u8);
+        }
+        self.bytes
+    }
+}
+
+/// Encodes symbols into the states of an FSE table, last symbol first.
+struct FseEncoder {
+    table: Fse,
+    /// For each symbol, the state to move to from each state after it
+    states: Vec<Vec<u16>>,
+}
+
+impl FseEncoder {
+    fn new(table: Fse) -> Self {
+        let mut states = vec![Vec::new(); 256];
+        for (y, entry) in table.entries.iter().enumerate() {
+            let next = &mut states[entry.symbol as usize];
+            next.resize(table.entries.len(), 0);
+            let base = entry.base as usize;
+            next[base..base + (1 << entry.bits)].fill(y as u16);
+        }
+        FseEncoder { table, states }
+    }
+
+    /// A state decoding the last `symbol`.
+    fn first(&self, symbol: u8) -> usize { self.states[symbol as usize][0] as usize }
+
+    /// Move from `state` to one decoding `symbol`, writing the bits the
+    /// decoder reads to get back.
+    fn encode(&self, state: usize, symbol: u8, out: &mut BitWriter) -> usize {
+        let next = self.states[symbol as usize][state] as usize;
+        let entry = self.table.entries[next];
+        out.write((state - entry.base as usize) as u64, entry.bits as u32);
+        next
+    }
+}
+
+/// Huffman code lengths for the symbol `counts`, none longer than 11 bits.
+fn huffman_lengths(counts: &[u64]) -> Vec<u8> {
+    let mut counts = counts.to_vec();
+    loop {
+        let mut heap: BinaryHeap<_> =
+            counts.iter().enumerate().filter(|&(_, &c)| c > 0).map(|(s, &c)| Reverse((c, s))).collect();
+        let mut parents = vec![usize::MAX; counts.len()];
+        while heap.len() > 1 {
+            let (Reverse((a, i)), Reverse((b, j))) = (heap.pop().unwrap(), heap.pop().unwrap());
+            parents[i] = parents.len();
+            parents[j] = parents.len();
+            heap.push(Reverse((a + b, parents.len())));
+            parents.push(usize::MAX);
+        }
+        let lengths: Vec<u8> = (0..counts.len())
+            .map(|s| match counts[s] {
+                0 => 0,
+                _ => {
+                    let ancestors = std::iter::successors(Some(parents[s]), |&n| parents.get(n).copied());
+                    ancestors.take_while(|&n| n != usize::MAX).count() as u8
+                },
+            })
+            .collect();
+        if lengths.iter().all(|&len| len <= 11) {
+            return lengths;
+        }
+        // flatten the counts until the tree is shallow enough
+        for c in counts.iter_mut() {
+            *c = c.div_ceil(2);
+        }
+    }
+}
+
+/// Write the header of a raw or RLE literals section.
+fn literals_header(kind: u8, len: usize, out: &mut Vec<u8>) {
+    match len {
+        0..=31 => out.push(kind | (len << 3) as u8),
+        32..=4095 => out.extend_from_slice(&(kind as u16 | 1 << 2 | (len << 4) as u16).to_le_bytes()),
+        _ => out.extend_from_slice(&(kind as u32 | 3 << 2 | (len << 4) as u32).to_le_bytes()[..3]),
+    }
+}
+
+/// Write the literals section, Huffman coded where that pays off.
+fn encode_literals(literals: &[u8], out: &mut Vec<u8>) {
+    let mut counts = [0u64; 256];
+    for &b in literals {
+        counts[b as usize] += 1;
+    }
+    let symbols = counts.iter().filter(|&&c| c > 0).count();
+    if symbols == 1 && literals.len() > 1 {
+        literals_header(1, literals.len(), out);
+        out.push(literals[0]);
+        return;
+    }
+    // weights are written directly, which takes symbols below 128
+    if literals.len() < 64 || counts[128..].iter().any(|&c| c > 0) {
+        literals_header(0, literals.len(), out);
+        out.extend_from_slice(literals);
+        return;
+    }
+
+    let lengths = huffman_lengths(&counts[..128]);
+    let max_bits = *lengths.iter().max().unwrap() as u32;
+    // the weight of the last symbol is left for the decoder to work out
+    let last = lengths.iter().rposition(|&len| len > 0).unwrap();
+    let weights: Vec<u8> = lengths[..last].iter().map(|&len| weight_to_len(len, max_bits)).collect();
+    let mut compressed = vec![127 + last as u8];
+    compressed.extend(weights.chunks(2).map(|pair| pair[0] << 4 | pair.get(1).copied().unwrap_or(0)));
+
+    let codes: Vec<u64> = lengths
+        .iter()
+        .zip(code_starts(&lengths, max_bits))
+        .map(|(&len, start)| if len > 0 { (start >> (max_bits - len as u32)) as u64 } else { 0 })
+        .collect();
+    let per_stream = literals.len().div_ceil(4);
+    let streams: Vec<Vec<u8>> = (0..4)
+        .map(|i| {
+            let mut bits = BitWriter::default();
+            let (start, end) = (i * per_stream, ((i + 1) * per_stream).min(literals.len()));
+            // the decoder reads from the end, so the first literal goes last
+            for &b in literals[start..end].iter().rev() {
+                bits.write(codes[b as usize], lengths[b as usize] as u32);
+            }
+            bits.finish()
+        })
+        .collect();
+    for stream in &streams[..3] {
+        compressed.extend_from_slice(&(stream.len() as u16).to_le_bytes());
+    }
+    for stream in &streams {
+        compressed.extend_from_slice(stream);
+    }
+    if compressed.len() >= literals.len() {
+        literals_header(0, literals.len(), out);
+        out.extend_from_slice(literals);
+        return;
+    }
+
+    let (format, size_bits, header_len) = match literals.len().max(compressed.len()) {
+        0..=1023 => (1, 10, 3),
+        1024..=16383 => (2, 14, 4),
+        _ => (3, 18, 5),
+    };
+    let sizes = literals.len() as u64 | (compressed.len() as u64) << size_bits;
+    let header = 2 | format << 2 | sizes << 4;
+    out.extend_from_slice(&header.to_le_bytes()[..header_len]);
+    out.extend_from_slice(&compressed);
+}
+
+/// The code for `value` among codes starting at `base`, and its extra bits.
+fn code_for(value: u32, base: &[(u32, u32)]) -> (u8, u64, u32) {
+    let code = base.iter().rposition(|&(start, _)| start <= value).unwrap();
+    (code as u8, (value - base[code].0) as u64, base[code].1)
+}
+
+/// Write the sequences section, coded with the predefined tables.
+fn encode_sequences(sequences: &[Sequence], tables: &[FseEncoder; 3], out: &mut Vec<u8>) {
+    let count = sequences.len();
+    match count {
+        0..=127 => out.push(count as u8),
+        128..=0x7EFF => out.extend_from_slice(&[(count >> 8) as u8 + 128, count as u8]),
+        _ => {
+            out.push(255);
+            out.extend_from_slice(&((count - 0x7F00) as u16).to_le_bytes());
+        },
+    }
+    if count == 0 {
+        return;
+    }
+    out.push(0);
+
+    // bits go out in the reverse of the order the decoder reads them
+    let [ll, of, ml] = tables;
+    let mut bits = BitWriter::default();
+    let mut states = (0, 0, 0);
+    for (i, seq) in sequences.iter().enumerate().rev() {
+        let (ll_code, ll_extra, ll_bits) = code_for(seq.literals, &LL_BASE);
+        let (ml_code, ml_extra, ml_bits) = code_for(seq.len, &ML_BASE);
+        // offsets above 3 are taken as is, not as recent offsets
+        let offset_value = seq.offset + 3;
+        let of_code = 31 - offset_value.leading_zeros();
+        let of_extra = (offset_value - (1 << of_code)) as u64;
+        if i == count - 1 {
+            states = (ll.first(ll_code), of.first(of_code as u8), ml.first(ml_code));
+        } else {
+            states.1 = of.encode(states.1, of_code as u8, &mut bits);
+            states.2 = ml.encode(states.2, ml_code, &mut bits);
+            states.0 = ll.encode(states.0, ll_code, &mut bits);
+        }
+        bits.write(ll_extra, ll_bits);
+        bits.write(ml_extra, ml_bits);
+        bits.write(of_extra, of_code);
+    }
+    bits.write(states.2 as u64, ml.table.log);
+    bits.write(states.1 as u64, of.table.log);
+    bits.write(states.0 as u64, ll.table.log);
+    out.extend_from_slice(&bits.finish());
+}
+
+/// Literals to copy, then a match to copy from `offset` bytes back
+struct Sequence {
+    literals: u32,
+    offset: u32,
+    len: u32,
+}
+
+/// Finds earlier occurrences of the bytes at a position through chains of
+/// positions with the same hash.
+struct Matcher {
+    hash_log: u32,
+    head: Vec<u32>,
+    chain: Vec<u32>,
+    window: usize,
+    /// Candidates to try per position
+    depth: usize,
+    /// Whether to give up a match for a longer one a byte later
+    lazy: bool,
+    /// Positions below this one are in the chains
+    inserted: usize,
+}
+
+impl Matcher {
+    fn new(level: u32, window: usize) -> Self {
+        let hash_log = (16 + level / 4).min(20);
+        Matcher {
+            hash_log,
+            head: vec![0; 1 << hash_log],
+            chain: vec![0; window],
+            window,
+            depth: 1 << (level / 2),
+            lazy: level >= 3,
+            inserted: 0,
+        }
+    }
+
+    fn hash(&self, data: &[u8], pos: usize) -> usize {
+        let word = u32::from_le_bytes(data[pos..pos + 4].try_into().unwrap());
+        (word.wrapping_mul(0x9E37_79B1) >> (32 - self.hash_log)) as usize
+    }
+
+    /// The longest match for `pos` ending by `end`, as (length, offset),
+    /// or a length of 0 if there is none.
+    fn find(&mut self, data: &[u8], pos: usize, end: usize) -> (usize, usize) {
+        while self.inserted < pos {
+            let p = self.inserted;
+            let h = self.hash(data, p);
+            self.chain[p % self.window] = self.head[h];
+            self.head[h] = p as u32 + 1;
+            self.inserted += 1;
+        }
+        let max_len = end - pos;
+        let (mut best, mut offset) = (0, 0);
+        let mut candidate = self.head[self.hash(data, pos)] as usize;
+        for _ in 0..self.depth {
+            // chain entries are overwritten once they leave the window
+            if candidate == 0 || pos - (candidate - 1) >= self.window {
+                break;
+            }
+            let c = candidate - 1;
+            let len = data[c..].iter().zip(&data[pos..pos + max_len]).take_while(|(a, b)| a == b).count();
+            if len > best {
+                (best, offset) = (len, pos - c);
+                if len == max_len {
+                    break;
+                }
+            }
+            let next = self.chain[c % self.window] as usize;
+            if next >= candidate {
+                break;
+            }
+            candidate = next;
+        }
+        if best < MIN_MATCH {
+            return (0, 0);
+        }
+        (best, offset)
+    }
+
+    /// Split `data[start..end]` into sequences and the literals they copy.
+    fn parse(&mut self, data: &[u8], start: usize, end: usize) -> (Vec<u8>, Vec<Sequence>) {
+        let (mut literals, mut sequences) = (Vec::new(), Vec::new());
+        let (mut pos, mut anchor) = (start, start);
+        while pos + MIN_MATCH <= end {
+            let (mut len, mut offset) = self.find(data, pos, end);
+            if len == 0 {
+                pos += 1;
+                continue;
+            }
+            while self.lazy && pos + 1 + MIN_MATCH <= end {
+                let (next_len, next_offset) = self.find(data, pos + 1, end);
+                if next_len <= len {
+                    break;
+                }
+                (pos, len, offset) = (pos + 1, next_len, next_offset);
+            }
+            literals.extend_from_slice(&data[anchor..pos]);
+            let literals_len = (pos - anchor) as u32;
+            sequences.push(Sequence { literals: literals_len, offset: offset as u32, len: len as u32 });
+            pos += len;
+            anchor = pos;
+        }
+        literals.extend_from_slice(&data[anchor..end]);
+        (literals, sequences)
+    }
+}
+
+/// Compress `data` into a single frame at `level`, from 1 to 19, recording
+/// its size and checksum.
+pub fn encode(data: &[u8], level: u32) -> Vec<u8> {
+    let window_log = (usize::BITS - data.len().saturating_sub(1).leading_zeros()).clamp(10, WINDOW_LOG);
+    let mut out = MAGIC.to_le_bytes().to_vec();
+    match u32::try_from(data.len()) {
+        Ok(len) => {
+            out.extend_from_slice(&[2 << 6 | 0x04, ((window_log - 10) << 3) as u8]);
+            out.extend_from_slice(&len.to_le_bytes());
+        },
+        Err(_) => {
+            out.extend_from_slice(&[3 << 6 | 0x04, ((window_log - 10) << 3) as u8]);
+            out.extend_from_slice(&(data.len() as u64).to_le_bytes());
+        },
+    }
+
+    let tables = [
+        FseEncoder::new(Fse::new(&LL_DEFAULT, 6).unwrap()),
+        FseEncoder::new(Fse::new(&OF_DEFAULT, 5).unwrap()),
+        FseEncoder::new(Fse::new(&ML_DEFAULT, 6).unwrap()),
+    ];
+    let mut matcher = Matcher::new(level.clamp(1, MAX_LEVEL), 1 << window_log);
+    let mut start = 0;
+    loop {
+        let end = data.len().min(start + MAX_BLOCK_SIZE);
+        let last = (end == data.len()) as u32;
+        let (literals, sequences) = matcher.parse(data, start, end);
+        let mut block = Vec::new();
+        encode_literals(&literals, &mut block);
+        encode_sequences(&sequences, &tables, &mut block);
+        if block.len() < end - start {
+            out.extend_from_slice(&(last | 2 << 1 | (block.len() as u32) << 3).to_le_bytes()[..3]);
+            out.extend_from_slice(&block);
+        } else {
+            out.extend_from_slice(&(last | ((end - start) as u32) << 3).to_le_bytes()[..3]);
+            out.extend_from_slice(&data[start..end]);
+        }
+        if last == 1 {
+            break;
+        }
+        start = end;
+    }
+    out.extend_from_slice(&(xxh64(data) as u32).to_le_bytes());
+    out
+}
+
+/// XXH64 with seed 0, whose low 32 bits checksum frames.
+fn xxh64(data: &[u8]) -> u64 {
+    const P1: u64 = 0x9E37_79B1_85EB_CA87;
+    const P2: u64 = 0xC2B2_AE3D_27D4_EB4F;
+    const P3: u64 = 0x1656_67B1_9E37_79F9;
+    const P4: u64 = 0x85EB_CA77_C2B2_AE63;
+    const P5: u64 = 0x27D4_EB2F_1656_67C5;
+    let round = |acc: u64, input: u64| {
+        acc.wrapping_add(input.wrapping_mul(P2)).rotate_left(31).wrapping_mul(P1)
+    };
+    let merge = |acc: u64, v: u64| (acc ^ round(0, v)).wrapping_mul(P1).wrapping_add(P4);
+    let u64_at = |b: &[u8]| u64::from_le_bytes(b[..8].try_into().unwrap());
+
+    let mut stripes = data.chunks_exact(32);
+    let mut h = if data.len() >= 32 {
+        let mut v = [P1.wrapping_add(P2), P2, 0, 0u64.wrapping_sub(P1)];
+        for stripe in &mut stripes {
+            for (i, lane) in v.iter_mut().enumerate() {
+                *lane = round(*lane, u64_at(&stripe[i * 8..]));
+            }
+        }
+        let h = v[0].rotate_left(1)
+            .wrapping_add(v[1].rotate_left(7))
+            .wrapping_add(v[2].rotate_left(12))
+            .wrapping_add(v[3].rotate_left(18));
+        v.iter().fold(h, |h, &lane| merge(h, lane))
+    } else {
+        P5
+    };
+    h = h.wrapping_add(data.len() as u64);
+
+    let mut rest = stripes.remainder();
+    while rest.len() >= 8 {
+        h = (h ^ round(0, u64_at(rest))).rotate_left(27).wrapping_mul(P1).wrapping_add(P4);
+        rest = &rest[8..];
+    }
+    if rest.len() >= 4 {
+        let word = u32::from_le_bytes(rest[..4].try_into().unwrap()) as u64;
+        h = (h ^ word.wrapping_mul(P1)).rotate_left(23).wrapping_mul(P2).wrapping_add(P3);
+        rest = &rest[4..];
+    }
+    for &byte in rest {
+        h = (h ^ (byte as u64).wrapping_mul(P5)).rotate_left(11).wrapping_mul(P1);
+    }
+    h ^= h >> 33;
+    h = h.wrapping_mul(P2);
+    h ^= h >> 29;
+    h = h.wrapping_mul(P3);
+    h ^ (h >> 32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SENTENCES: [&str; 6] = [
+        "The head is the newest version. ",
+        "Each write cuts a new version of the file. ",
+        "Old versions are kept as deltas against newer ones. ",
+        "Snapshots name a version so that it is never pruned. ",
+        "A range can be put back without touching the rest. ",
+        "Versions too far apart are only reported as differing.\n",
+    ];
+
+    fn xorshift(x: &mut u64) -> u64 {
+        *x ^= *x << 13;
+        *x ^= *x >> 7;
+        *x ^= *x << 17;
+        *x
+    }
+
+    /// The text the `text-*.zst` frames under testdata/ were made from.
+    fn text(len: usize) -> Vec<u8> {
+        let mut x = 0x9E37_79B9_7F4A_7C15;
+        let mut out = Vec::new();
+        while out.len() < len {
+            let x = xorshift(&mut x);
+            out.extend_from_slice(SENTENCES[(x % SENTENCES.len() as u64) as usize].as_bytes());
+            out.extend_from_slice(format!("{} ", x >> 54).as_bytes());
+        }
+        out.truncate(len);
+        out
+    }
+
+    /// The bytes `random.zst` was made from.
+    fn random(len: usize) -> Vec<u8> {
+        let mut x = 0x2545_F491_4F6C_DD1D;
+        (0..len).map(|_| (xorshift(&mut x) >> 56) as u8).collect()
+    }
+
+    // frames written by the reference encoder, zstd 1.5.7: `text-1`, `text-19`
+    // and `random` from files, at levels 1, 19 and the default, the rest from
+    // a pipe, so mostly without their size, `text-stream` with `--no-check`
+    const EMPTY: &[u8] = include_bytes!("../testdata/empty.zst");
+    const HELLO: &[u8] = include_bytes!("../testdata/hello.zst");
+    const RLE: &[u8] = include_bytes!("../testdata/rle.zst");
+    const RANDOM: &[u8] = include_bytes!("../testdata/random.zst");
+    const TEXT_1: &[u8] = include_bytes!("../testdata/text-1.zst");
+    const TEXT_19: &[u8] = include_bytes!("../testdata/text-19.zst");
+    const TEXT_STREAM: &[u8] = include_bytes!("../testdata/text-stream.zst");
+
+    #[test]
+    fn decodes_reference_frames() {
+        assert_eq!(decode(EMPTY).unwrap(), b"");
+        assert_eq!(decode(HELLO).unwrap(), b"hello, world\n");
+        assert_eq!(decode(RLE).unwrap(), vec![b'a'; 300_000]);
+        assert_eq!(decode(RANDOM).unwrap(), random(3000));
+        assert_eq!(decode(TEXT_1).unwrap(), text(150_000));
+        assert_eq!(decode(TEXT_19).unwrap(), text(150_000));
+        assert_eq!(decode(TEXT_STREAM).unwrap(), text(150_000));
+    }
+
+    #[test]
+    fn decoded_len_with_and_without_content_size() {
+        assert_eq!(decoded_len(EMPTY).unwrap(), 0);
+        assert_eq!(decoded_len(RANDOM).unwrap(), 3000);
+        assert_eq!(decoded_len(TEXT_19).unwrap(), 150_000);
+        // written from a pipe, so the size was not known up front
+        assert_eq!(decoded_len(HELLO).unwrap(), 13);
+        assert_eq!(decoded_len(TEXT_STREAM).unwrap(), 150_000);
+    }
+
+    #[test]
+    fn decodes_concatenated_and_skippable_frames() {
+        let mut data = (SKIPPABLE_MAGIC | 7).to_le_bytes().to_vec();
+        data.extend_from_slice(&3u32.to_le_bytes());
+        data.extend_from_slice(b"abc");
+        data.extend_from_slice(HELLO);
+        data.extend_from_slice(EMPTY);
+        data.extend_from_slice(HELLO);
+        assert_eq!(decode(&data).unwrap(), b"hello, world\nhello, world\n");
+        assert_eq!(decoded_len(&data).unwrap(), 26);
+    }
+
+    #[test]
+    fn rejects_damaged_frames() {
+        let mut data = HELLO.to_vec();
+        *data.last_mut().unwrap() ^= 1;
+        assert_eq!(decode(&data).unwrap_err().to_string(), "corrupt zstd data: checksum mismatch");
+        assert!(decode(&TEXT_1[..TEXT_1.len() / 2]).is_err());
+        assert!(decode(b"not zstd").is_err());
+    }
+
+    #[test]
+    fn round_trips() {
+        let inputs =
+            [Vec::new(), b"hello, world\n".to_vec(), vec![b'a'; 300_000], random(3000), text(150_000)];
+        for input in &inputs {
+            for level in [1, 3, 9, 19] {
+                let frame = encode(input, level);
+                assert_eq!(&decode(&frame).unwrap(), input, "level {level}");
+                assert_eq!(decoded_len(&frame).unwrap(), input.len() as u64);
+            }
+        }
+        assert!(encode(&text(150_000), 19).len() < 150_000 / 4);
+    }
+
+    #[test]
+    fn xxh64_known_answers() {
+        assert_eq!(xxh64(b""), 0xEF46_DB37_51D8_E999);
+        assert_eq!(xxh64(b"abc"), 0x44BC_2CF5_AD77_0999);
+        assert_eq!(xxh64(&text(150_000)) as u32, 0x28E9_2C9F);
+    }
+}